@@ -0,0 +1,83 @@
+//! Microbenchmarks of the execution core: `cargo bench --bench core`. Each prints the
+//! mean time per iteration over a fixed number of iterations.
+
+use std::{hint::black_box, time::Instant};
+
+use lc3_vm::{asm, console::StreamIo, decode::decode, vm::sign_ext, Flag, Vm};
+
+fn bench(name: &str, iterations: u64, mut f: impl FnMut()) {
+    // warm up
+    for _ in 0..iterations / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iteration = start.elapsed() / iterations as u32;
+    println!("{name:<16} {per_iteration:>10.2?}");
+}
+
+fn vm_with(image: &[u8]) -> Vm {
+    let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+    vm.load_image_bytes(image).unwrap();
+    vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+    vm
+}
+
+// 1000 iterations of register arithmetic
+const ARITHMETIC: &str = "
+        .ORIG x3000
+        LD R1, COUNT
+LOOP    ADD R0, R0, R1
+        AND R2, R0, #15
+        NOT R3, R2
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+COUNT   .FILL #1000
+        .END
+";
+
+// copies 500 words from one array to another
+const MEMORY: &str = "
+        .ORIG x3000
+        LD R1, COUNT
+        LD R2, SRC
+        LD R3, DST
+LOOP    LDR R0, R2, #0
+        STR R0, R3, #0
+        ADD R2, R2, #1
+        ADD R3, R3, #1
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+COUNT   .FILL #500
+SRC     .FILL x4000
+DST     .FILL x5000
+        .END
+";
+
+fn main() {
+    bench("sign_ext", 10_000_000, || {
+        black_box(sign_ext(black_box(0x1F), 5));
+    });
+
+    let mut inst = 0u16;
+    bench("decode", 10_000_000, || {
+        inst = inst.wrapping_add(1);
+        let _ = black_box(decode(black_box(inst)));
+    });
+
+    // includes creating the vm, which is small next to running it
+    let image = asm::assemble(ARITHMETIC).unwrap().image();
+    bench("arithmetic loop", 1000, || {
+        vm_with(&image).run().unwrap();
+    });
+
+    let image = asm::assemble(MEMORY).unwrap().image();
+    bench("memory loop", 1000, || {
+        vm_with(&image).run().unwrap();
+    });
+}
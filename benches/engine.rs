@@ -0,0 +1,42 @@
+//! Compares the execution engines on a tight loop: `cargo bench --bench engine`.
+
+use std::time::Instant;
+
+use lc3_vm::{asm, console::StreamIo, vm::Engine, Flag, Vm};
+
+// about 20 million instructions
+const LOOP: &str = "
+        .ORIG x3000
+        LD R1, OUTER
+L1      LD R2, INNER
+L2      ADD R0, R0, #1
+        ADD R2, R2, #-1
+        BRp L2
+        ADD R1, R1, #-1
+        BRp L1
+        HALT
+OUTER   .FILL #1000
+INNER   .FILL #6666
+        .END
+";
+
+fn main() {
+    let image = asm::assemble(LOOP).unwrap().image();
+
+    for engine in [Engine::Simple, Engine::Cached] {
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&image).unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        vm.set_engine(engine);
+
+        let start = Instant::now();
+        vm.run().unwrap();
+        let elapsed = start.elapsed();
+
+        let mips = vm.instructions() as f64 / elapsed.as_secs_f64() / 1e6;
+        println!(
+            "{engine:?}: {} instructions in {elapsed:.2?}, {mips:.1} MIPS",
+            vm.instructions()
+        );
+    }
+}
@@ -0,0 +1,409 @@
+; 2048 for the terminal. Move with WASD or the arrow keys, q quits.
+;
+; The board holds tile exponents (0 = empty, 1 = 2, 2 = 4, ...). A move
+; slides each of the four lines towards the edge given by the direction's
+; index table, merging equal neighbours once per move.
+
+        .ORIG x3000
+        JSR SPAWN
+        JSR SPAWN
+
+LOOP    JSR DRAW
+        LD R0, WON
+        BRp WIN
+        JSR CANMOVE
+        ADD R0, R0, #0
+        BRz LOST
+
+KEY     GETC
+        LD R1, NKEYQ
+        ADD R1, R0, R1
+        BRz QUIT
+        LD R5, LEFTP
+        LD R1, NKEYA
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R1, NARRL
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R5, RIGHTP
+        LD R1, NKEYD
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R1, NARRR
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R5, UPP
+        LD R1, NKEYW
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R1, NARRU
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R5, DOWNP
+        LD R1, NKEYS
+        ADD R1, R0, R1
+        BRz DOMOVE
+        LD R1, NARRD
+        ADD R1, R0, R1
+        BRz DOMOVE
+        BR KEY
+
+DOMOVE  JSR MOVE
+        LD R0, MOVED
+        BRz KEY
+        JSR SPAWN
+        BR LOOP
+
+WIN     LD R0, WINP
+        PUTS
+        HALT
+LOST    LD R0, LOSTP
+        PUTS
+        HALT
+QUIT    LD R0, QUITP
+        PUTS
+        HALT
+
+NKEYQ   .FILL #-113             ; 'q'
+NKEYA   .FILL #-97              ; 'a'
+NKEYD   .FILL #-100             ; 'd'
+NKEYW   .FILL #-119             ; 'w'
+NKEYS   .FILL #-115             ; 's'
+NARRU   .FILL #-65              ; 'A', last byte of the up arrow sequence
+NARRD   .FILL #-66              ; 'B', down
+NARRR   .FILL #-67              ; 'C', right
+NARRL   .FILL #-68              ; 'D', left
+
+LEFTP   .FILL T_LEFT
+RIGHTP  .FILL T_RIGHT
+UPP     .FILL T_UP
+DOWNP   .FILL T_DOWN
+BOARDP  .FILL BOARD
+TILESP  .FILL TILES
+CLSP    .FILL CLS
+HOMEP   .FILL HOME
+NLP     .FILL NL
+HELPP   .FILL HELP
+WINP    .FILL WINMSG
+LOSTP   .FILL LOSTMSG
+QUITP   .FILL QUITMSG
+CLOCKP  .FILL xFE28
+MASK    .FILL x7FFF
+RINC    .FILL x3619
+
+WON     .FILL #0
+MOVED   .FILL #0
+SEED    .FILL #0
+MV_CNT  .BLKW 1
+MV_R7   .BLKW 1
+SL_R7   .BLKW 1
+SP_R7   .BLKW 1
+SP_CELL .BLKW 1
+DR_R7   .BLKW 1
+LINE    .BLKW 4
+BOARD   .BLKW 16
+
+; Slides all four lines of the board in one direction.
+; R5 points to the direction's table of 16 board indices.
+MOVE    ST R7, MV_R7
+        AND R0, R0, #0
+        ST R0, MOVED
+        ADD R0, R0, #4
+        ST R0, MV_CNT
+MV_LOOP JSR SLIDE
+        ADD R5, R5, #4
+        LD R0, MV_CNT
+        ADD R0, R0, #-1
+        ST R0, MV_CNT
+        BRp MV_LOOP
+        LD R7, MV_R7
+        RET
+
+; Slides and merges one line. R5 points to its 4 board indices, ordered
+; from the edge the tiles move towards. Sets MOVED if the line changed.
+SLIDE   ST R7, SL_R7
+        LEA R1, LINE
+        AND R0, R0, #0
+        STR R0, R1, #0
+        STR R0, R1, #1
+        STR R0, R1, #2
+        STR R0, R1, #3
+        AND R3, R3, #0          ; can the last placed tile merge?
+        AND R4, R4, #0
+        ADD R4, R4, #4
+        ADD R6, R5, #0
+SL_LOOP LDR R0, R6, #0
+        LD R2, BOARDP
+        ADD R0, R0, R2
+        LDR R0, R0, #0
+        BRz SL_NEXT
+        ADD R3, R3, #0
+        BRz SL_PUT
+        LDR R2, R1, #-1
+        NOT R2, R2
+        ADD R2, R2, #1
+        ADD R2, R2, R0
+        BRnp SL_PUT
+        ADD R0, R0, #1
+        STR R0, R1, #-1
+        AND R3, R3, #0
+        ADD R2, R0, #-11        ; 2048 reached?
+        BRnp SL_NEXT
+        ADD R2, R2, #1
+        ST R2, WON
+        BR SL_NEXT
+SL_PUT  STR R0, R1, #0
+        ADD R1, R1, #1
+        AND R3, R3, #0
+        ADD R3, R3, #1
+SL_NEXT ADD R6, R6, #1
+        ADD R4, R4, #-1
+        BRp SL_LOOP
+
+        LEA R1, LINE
+        ADD R6, R5, #0
+        AND R4, R4, #0
+        ADD R4, R4, #4
+SL_WB   LDR R0, R6, #0
+        LD R2, BOARDP
+        ADD R0, R0, R2
+        LDR R2, R0, #0
+        LDR R3, R1, #0
+        STR R3, R0, #0
+        NOT R2, R2
+        ADD R2, R2, #1
+        ADD R2, R2, R3
+        BRz SL_SAME
+        AND R2, R2, #0
+        ADD R2, R2, #1
+        ST R2, MOVED
+SL_SAME ADD R1, R1, #1
+        ADD R6, R6, #1
+        ADD R4, R4, #-1
+        BRp SL_WB
+        LD R7, SL_R7
+        RET
+
+; Puts a 2 (or, one time in ten, a 4) on a random empty cell.
+SPAWN   ST R7, SP_R7
+        LD R1, BOARDP
+        AND R4, R4, #0          ; number of empty cells
+        AND R2, R2, #0
+        ADD R2, R2, #15
+        ADD R2, R2, #1
+SP_CNT  LDR R0, R1, #0
+        BRnp SP_FULL
+        ADD R4, R4, #1
+SP_FULL ADD R1, R1, #1
+        ADD R2, R2, #-1
+        BRp SP_CNT
+        ADD R4, R4, #0
+        BRz SP_DONE
+
+        JSR RAND
+        NOT R3, R4
+        ADD R3, R3, #1
+SP_MOD  ADD R2, R0, R3
+        BRn SP_PICK
+        ADD R0, R2, #0
+        BR SP_MOD
+SP_PICK LD R1, BOARDP           ; find the R0-th empty cell
+SP_FIND LDR R2, R1, #0
+        BRnp SP_SKIP
+        ADD R0, R0, #-1
+        BRn SP_HIT
+SP_SKIP ADD R1, R1, #1
+        BR SP_FIND
+SP_HIT  ST R1, SP_CELL
+
+        JSR RAND
+        AND R3, R3, #0
+        ADD R3, R3, #-10
+SP_MOD2 ADD R2, R0, R3
+        BRn SP_TILE
+        ADD R0, R2, #0
+        BR SP_MOD2
+SP_TILE AND R2, R2, #0
+        ADD R2, R2, #1
+        ADD R0, R0, #0
+        BRnp SP_SET
+        ADD R2, R2, #1
+SP_SET  LD R1, SP_CELL
+        STR R2, R1, #0
+SP_DONE LD R7, SP_R7
+        RET
+
+; Returns a pseudo-random number in 0..x7FFF in R0, mixing the
+; millisecond clock into a linear congruential generator. Clobbers R1.
+RAND    LD R0, SEED
+        ADD R1, R0, R0
+        ADD R1, R1, R1
+        ADD R0, R1, R0
+        LD R1, RINC
+        ADD R0, R0, R1
+        LDI R1, CLOCKP
+        ADD R0, R0, R1
+        ST R0, SEED
+        LD R1, MASK
+        AND R0, R0, R1
+        RET
+
+; Returns 1 in R0 if any move is possible, 0 otherwise.
+CANMOVE LD R1, BOARDP
+        AND R2, R2, #0          ; cells left, including this one
+        ADD R2, R2, #15
+        ADD R2, R2, #1
+        AND R3, R3, #0          ; column
+CM_LOOP LDR R0, R1, #0
+        BRz CM_YES
+        ADD R4, R3, #-3
+        BRz CM_DOWN
+        LDR R4, R1, #1
+        NOT R4, R4
+        ADD R4, R4, #1
+        ADD R4, R4, R0
+        BRz CM_YES
+CM_DOWN ADD R4, R2, #-4
+        BRnz CM_NEXT
+        LDR R4, R1, #4
+        NOT R4, R4
+        ADD R4, R4, #1
+        ADD R4, R4, R0
+        BRz CM_YES
+CM_NEXT ADD R1, R1, #1
+        ADD R3, R3, #1
+        ADD R4, R3, #-4
+        BRnp CM_COL
+        AND R3, R3, #0
+CM_COL  ADD R2, R2, #-1
+        BRp CM_LOOP
+        AND R0, R0, #0
+        RET
+CM_YES  AND R0, R0, #0
+        ADD R0, R0, #1
+        RET
+
+; Clears the screen and prints the board.
+DRAW    ST R7, DR_R7
+        LD R0, CLSP
+        PUTS
+        LD R0, HOMEP
+        PUTS
+        LD R1, BOARDP
+        AND R3, R3, #0
+        ADD R3, R3, #4
+DR_ROW  AND R4, R4, #0
+        ADD R4, R4, #4
+DR_COL  LDR R0, R1, #0
+        ADD R0, R0, R0          ; tile strings are 8 words apart
+        ADD R0, R0, R0
+        ADD R0, R0, R0
+        LD R2, TILESP
+        ADD R0, R0, R2
+        PUTS
+        ADD R1, R1, #1
+        ADD R4, R4, #-1
+        BRp DR_COL
+        LD R0, NLP
+        PUTS
+        ADD R3, R3, #-1
+        BRp DR_ROW
+        LD R0, HELPP
+        PUTS
+        LD R7, DR_R7
+        RET
+
+T_LEFT  .FILL #0
+        .FILL #1
+        .FILL #2
+        .FILL #3
+        .FILL #4
+        .FILL #5
+        .FILL #6
+        .FILL #7
+        .FILL #8
+        .FILL #9
+        .FILL #10
+        .FILL #11
+        .FILL #12
+        .FILL #13
+        .FILL #14
+        .FILL #15
+T_RIGHT .FILL #3
+        .FILL #2
+        .FILL #1
+        .FILL #0
+        .FILL #7
+        .FILL #6
+        .FILL #5
+        .FILL #4
+        .FILL #11
+        .FILL #10
+        .FILL #9
+        .FILL #8
+        .FILL #15
+        .FILL #14
+        .FILL #13
+        .FILL #12
+T_UP    .FILL #0
+        .FILL #4
+        .FILL #8
+        .FILL #12
+        .FILL #1
+        .FILL #5
+        .FILL #9
+        .FILL #13
+        .FILL #2
+        .FILL #6
+        .FILL #10
+        .FILL #14
+        .FILL #3
+        .FILL #7
+        .FILL #11
+        .FILL #15
+T_DOWN  .FILL #12
+        .FILL #8
+        .FILL #4
+        .FILL #0
+        .FILL #13
+        .FILL #9
+        .FILL #5
+        .FILL #1
+        .FILL #14
+        .FILL #10
+        .FILL #6
+        .FILL #2
+        .FILL #15
+        .FILL #11
+        .FILL #7
+        .FILL #3
+
+; one 8-word entry per exponent
+TILES   .STRINGZ "      ."
+        .STRINGZ "      2"
+        .STRINGZ "      4"
+        .STRINGZ "      8"
+        .STRINGZ "     16"
+        .STRINGZ "     32"
+        .STRINGZ "     64"
+        .STRINGZ "    128"
+        .STRINGZ "    256"
+        .STRINGZ "    512"
+        .STRINGZ "   1024"
+        .STRINGZ "   2048"
+        .STRINGZ "   4096"
+        .STRINGZ "   8192"
+        .STRINGZ "  16384"
+        .STRINGZ "  32768"
+
+CLS     .FILL x1B
+        .STRINGZ "[2J"
+HOME    .FILL x1B
+        .STRINGZ "[H"
+NL      .STRINGZ "\n\n"
+HELP    .STRINGZ "WASD or arrow keys to move, q to quit\n"
+WINMSG  .STRINGZ "You reached 2048!\n"
+LOSTMSG .STRINGZ "No moves left. Game over.\n"
+QUITMSG .STRINGZ "Bye!\n"
+        .END
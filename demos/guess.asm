@@ -0,0 +1,66 @@
+; Number guessing game. The secret number is taken from the millisecond
+; clock register when a key is pressed, and guesses are read with GETD.
+
+        .ORIG x3000
+        LEA R0, INTRO
+        PUTS
+        GETC
+
+        ; secret = (clock & x7FFF) mod 100 + 1
+        LDI R1, CLOCK
+        LD R2, MASK
+        AND R1, R1, R2
+        LD R2, NEG100
+MOD     ADD R3, R1, R2
+        BRn MODDONE
+        ADD R1, R3, #0
+        BR MOD
+MODDONE ADD R1, R1, #1
+        NOT R1, R1
+        ADD R1, R1, #1
+        ST R1, NSECRET
+
+        AND R4, R4, #0          ; number of guesses
+
+GUESS   LEA R0, PROMPT
+        PUTS
+        TRAP x29                ; GETD
+        ADD R1, R1, #0
+        BRn BAD
+        ADD R4, R4, #1
+        LD R1, NSECRET
+        ADD R1, R0, R1
+        BRn LOW
+        BRp HIGH
+
+        LEA R0, WIN
+        PUTS
+        ADD R0, R4, #0
+        TRAP x28                ; PUTD
+        LEA R0, TRIES
+        PUTS
+        HALT
+
+LOW     LEA R0, TOOLOW
+        PUTS
+        BR GUESS
+HIGH    LEA R0, TOOHIGH
+        PUTS
+        BR GUESS
+BAD     LEA R0, NOTNUM
+        PUTS
+        BR GUESS
+
+CLOCK   .FILL xFE28
+MASK    .FILL x7FFF
+NEG100  .FILL #-100
+NSECRET .BLKW 1
+
+INTRO   .STRINGZ "I'm thinking of a number between 1 and 100.\nPress any key to start.\n"
+PROMPT  .STRINGZ "Your guess: "
+TOOLOW  .STRINGZ "Too low!\n"
+TOOHIGH .STRINGZ "Too high!\n"
+NOTNUM  .STRINGZ "That's not a number.\n"
+WIN     .STRINGZ "Correct! You got it in "
+TRIES   .STRINGZ " guesses.\n"
+        .END
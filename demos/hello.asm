@@ -0,0 +1,9 @@
+; Prints a greeting and halts.
+
+        .ORIG x3000
+        LEA R0, MSG
+        PUTS
+        HALT
+
+MSG     .STRINGZ "Hello, World!\n"
+        .END
@@ -0,0 +1,25 @@
+//! Runs arbitrary instruction words from arbitrary registers. The input is read as
+//! big-endian words: the first eight are R0-R7, the rest are executed one after the
+//! other at wherever the PC ends up. Errors are fine, panics are bugs.
+
+#![no_main]
+
+use lc3_vm::{console::StreamIo, Flag, Vm};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut words = data
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+
+    let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+    // keys never run out, so reads don't stop the run early
+    vm.set_io(Box::new(StreamIo::new(std::io::repeat(b'\n'), std::io::sink())));
+    for r in 0..8 {
+        vm.set_register(r, words.next().unwrap_or(0));
+    }
+
+    for inst in words {
+        let _ = vm.execute_word(inst);
+    }
+});
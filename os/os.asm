@@ -0,0 +1,169 @@
+; The OS loaded by `--os`: trap service routines for GETC, OUT, PUTS, IN, PUTSP and
+; HALT that talk to the console through its device registers, like the textbook OS.
+; Every routine returns with RET and preserves all registers except R0 (GETC and IN
+; return the key in it) and R7. HALT stops with R0 and R1 changed as well.
+
+        .ORIG x0000
+
+; trap vector table
+        .BLKW x20
+        .FILL TRAP_GETC         ; x20
+        .FILL TRAP_OUT          ; x21
+        .FILL TRAP_PUTS         ; x22
+        .FILL TRAP_IN           ; x23
+        .FILL TRAP_PUTSP        ; x24
+        .FILL TRAP_HALT         ; x25
+        .BLKW xDA
+
+; interrupt vector table, no handlers installed
+        .BLKW x100
+
+; x0200
+TRAP_GETC
+        LDI R0, KBSR
+        BRzp TRAP_GETC
+        LDI R0, KBDR
+        RET
+
+TRAP_OUT
+        ST R1, SAVE_R1
+OUT_WAIT
+        LDI R1, DSR
+        BRzp OUT_WAIT
+        STI R0, DDR
+        LD R1, SAVE_R1
+        RET
+
+TRAP_PUTS
+        ST R0, SAVE_R0
+        ST R1, SAVE_R1
+        ST R2, SAVE_R2
+        ADD R1, R0, #0
+PUTS_NEXT
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+PUTS_WAIT
+        LDI R2, DSR
+        BRzp PUTS_WAIT
+        STI R0, DDR
+        ADD R1, R1, #1
+        BR PUTS_NEXT
+PUTS_DONE
+        LD R0, SAVE_R0
+        LD R1, SAVE_R1
+        LD R2, SAVE_R2
+        RET
+
+TRAP_IN
+        ST R1, SAVE_R1
+        ST R2, SAVE_R2
+        LEA R1, IN_PROMPT
+IN_NEXT
+        LDR R0, R1, #0
+        BRz IN_READ
+IN_WAIT
+        LDI R2, DSR
+        BRzp IN_WAIT
+        STI R0, DDR
+        ADD R1, R1, #1
+        BR IN_NEXT
+IN_READ
+        LDI R0, KBSR
+        BRzp IN_READ
+        LDI R0, KBDR
+IN_ECHO
+        LDI R2, DSR
+        BRzp IN_ECHO
+        STI R0, DDR
+        LD R1, SAVE_R1
+        LD R2, SAVE_R2
+        ADD R0, R0, #0
+        RET
+
+; two characters per word, the low byte first
+TRAP_PUTSP
+        ST R0, SAVE_R0
+        ST R1, SAVE_R1
+        ST R2, SAVE_R2
+        ST R3, SAVE_R3
+        ST R4, SAVE_R4
+        ST R5, SAVE_R5
+        ADD R1, R0, #0
+PUTSP_NEXT
+        LDR R2, R1, #0
+        BRz PUTSP_DONE
+        LD R3, LOW_BYTE
+        AND R0, R2, R3
+PUTSP_WAIT_LOW
+        LDI R3, DSR
+        BRzp PUTSP_WAIT_LOW
+        STI R0, DDR
+        ; there is no shift, so build the high byte one bit at a time
+        AND R0, R0, #0
+        LD R3, BIT_8
+        AND R4, R4, #0
+        ADD R4, R4, #1
+PUTSP_BIT
+        AND R5, R2, R3
+        BRz PUTSP_ZERO
+        ADD R0, R0, R4
+PUTSP_ZERO
+        ADD R4, R4, R4
+        ADD R3, R3, R3
+        BRnp PUTSP_BIT
+        ADD R0, R0, #0
+        BRz PUTSP_DONE
+PUTSP_WAIT_HIGH
+        LDI R3, DSR
+        BRzp PUTSP_WAIT_HIGH
+        STI R0, DDR
+        ADD R1, R1, #1
+        BR PUTSP_NEXT
+PUTSP_DONE
+        LD R0, SAVE_R0
+        LD R1, SAVE_R1
+        LD R2, SAVE_R2
+        LD R3, SAVE_R3
+        LD R4, SAVE_R4
+        LD R5, SAVE_R5
+        RET
+
+; prints HALT and stops the clock by clearing bit 15 of the machine control register
+TRAP_HALT
+        ST R0, SAVE_R0
+        ST R1, SAVE_R1
+        ST R7, SAVE_R7
+        LEA R0, HALT_MESSAGE
+        PUTS
+        LDI R0, MCR
+        LD R1, CLOCK_OFF
+        AND R0, R0, R1
+        STI R0, MCR
+        ; only reached if the clock is started again
+        LD R0, SAVE_R0
+        LD R1, SAVE_R1
+        LD R7, SAVE_R7
+        RET
+
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+DSR     .FILL xFE04
+DDR     .FILL xFE06
+MCR     .FILL xFFFE
+
+LOW_BYTE    .FILL x00FF
+BIT_8       .FILL x0100
+CLOCK_OFF   .FILL x7FFF
+
+SAVE_R0 .BLKW 1
+SAVE_R1 .BLKW 1
+SAVE_R2 .BLKW 1
+SAVE_R3 .BLKW 1
+SAVE_R4 .BLKW 1
+SAVE_R5 .BLKW 1
+SAVE_R7 .BLKW 1
+
+IN_PROMPT       .STRINGZ "Enter a character: "
+HALT_MESSAGE    .STRINGZ "HALT\n"
+
+        .END
@@ -0,0 +1,157 @@
+//! Labels and comments added while reverse-engineering a binary, kept in a `.notes`
+//! file next to it so they survive between debugger sessions. Each line names an
+//! address, e.g.
+//!
+//! ```text
+//! label x3002 LOOP
+//! comment x3004 prints the prompt
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{symbols::SymbolTable, util::parse_literal};
+
+/// Labels and comments by address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Annotations {
+    labels: BTreeMap<u16, String>,
+    comments: BTreeMap<u16, String>,
+}
+
+impl Annotations {
+    /// Parses a `.notes` file. Blank lines and lines starting with `#` are skipped.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut annotations = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, char::is_whitespace);
+            let (Some(kind), Some(addr), Some(text)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                bail!("line {}: expected a kind, an address and a text", i + 1);
+            };
+            let addr = match parse_literal(addr) {
+                Some(addr @ 0..=0xFFFF) => addr as u16,
+                _ => bail!("line {}: bad address: {addr}", i + 1),
+            };
+            let text = text.trim();
+            match kind {
+                "label" if !text.contains(char::is_whitespace) => annotations.set_label(addr, text),
+                "label" => bail!("line {}: bad label: {text}", i + 1),
+                "comment" => annotations.set_comment(addr, Some(text)),
+                _ => bail!("line {}: expected label or comment, got {kind}", i + 1),
+            }
+        }
+
+        Ok(annotations)
+    }
+
+    /// The `.notes` file for the object file `image`, e.g. `prog.notes` for `prog.obj`.
+    pub fn file_for(image: impl AsRef<Path>) -> PathBuf {
+        image.as_ref().with_extension("notes")
+    }
+
+    /// Reads `file`, or returns no annotations if there is none.
+    pub fn read(file: impl AsRef<Path>) -> Result<Self> {
+        let file = file.as_ref();
+        if !file.exists() {
+            return Ok(Self::default());
+        }
+
+        std::fs::read_to_string(file)
+            .map_err(anyhow::Error::from)
+            .and_then(|annotations| Self::parse(&annotations))
+            .map_err(|err| anyhow!("{}: {err}", file.display()))
+    }
+
+    /// Names `addr`, replacing the label it had.
+    pub fn set_label(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+
+    /// Attaches `text` to `addr`, or removes its comment with `None`.
+    pub fn set_comment(&mut self, addr: u16, text: Option<&str>) {
+        match text {
+            Some(text) => self.comments.insert(addr, text.to_string()),
+            None => self.comments.remove(&addr),
+        };
+    }
+
+    pub fn comment(&self, addr: u16) -> Option<&str> {
+        self.comments.get(&addr).map(String::as_str)
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.labels
+            .iter()
+            .map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    /// Renames the labels of `symbols` to the ones given here.
+    pub fn apply(&self, symbols: &mut SymbolTable) {
+        for (addr, name) in self.labels() {
+            symbols.rename(addr, name);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.comments.is_empty()
+    }
+
+    /// The annotations in the format [`Annotations::parse`] reads.
+    pub fn to_file(&self) -> String {
+        let mut out = String::new();
+        for (addr, name) in &self.labels {
+            writeln!(out, "label x{addr:04X} {name}").unwrap();
+        }
+        for (addr, text) in &self.comments {
+            writeln!(out, "comment x{addr:04X} {text}").unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotations() {
+        let mut annotations = Annotations::default();
+        annotations.set_label(0x3002, "LOOP");
+        annotations.set_comment(0x3004, Some("prints the prompt"));
+        annotations.set_comment(0x3005, Some("gone"));
+        annotations.set_comment(0x3005, None);
+
+        let file = annotations.to_file();
+        assert_eq!(
+            file,
+            "label x3002 LOOP\n\
+             comment x3004 prints the prompt\n"
+        );
+        assert_eq!(Annotations::parse(&file).unwrap(), annotations);
+
+        let mut symbols: SymbolTable = [("L1", 0x3002), ("START", 0x3000)].into_iter().collect();
+        annotations.apply(&mut symbols);
+        assert_eq!(symbols.name_at(0x3002), Some("LOOP"));
+        assert_eq!(symbols.lookup("L1"), None);
+        assert_eq!(symbols.lookup("START"), Some(0x3000));
+
+        assert!(Annotations::parse("label x3000").is_err());
+        assert!(Annotations::parse("label x3000 TWO WORDS").is_err());
+        assert!(Annotations::parse("note x3000 text").is_err());
+        assert!(Annotations::parse("# header\n\ncomment 12 text").is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+//! Command-line arguments for the program, laid out in memory like C's `argc` and
+//! `argv` before it starts.
+//!
+//! The block ends just below the device registers at xFE00 (higher addresses at the
+//! bottom of this list):
+//!
+//! ```text
+//! R6, R1-1 -> argc
+//! R1       -> argv[0] .. argv[argc - 1], pointers to the strings
+//!             0, ending argv
+//!             the strings, one character per word, each ending with a 0, the last at
+//!             xFDFF
+//! ```
+//!
+//! R0 is set to `argc` and R1 to `argv`, and R6 points at `argc`, so a program that keeps
+//! its stack in R6 pushes below the block. The block overlaps the video memory, so
+//! programs that use both should copy their arguments first.
+
+use anyhow::{bail, Result};
+
+use crate::vm::Vm;
+
+/// The last word the block may use.
+pub const ARGS_END: u16 = 0xFDFF;
+
+/// The most words the block may take, so it stays clear of the program.
+pub const MAX_ARGS_WORDS: usize = 0x400;
+
+/// Writes `args` below [`ARGS_END`] and sets R0, R1 and R6 to point at them. Returns the
+/// address of `argc`.
+pub fn install(vm: &mut Vm, args: &[&str]) -> Result<u16> {
+    let strings: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    let words = 1 + args.len() + 1 + strings;
+    if words > MAX_ARGS_WORDS {
+        bail!("the arguments take {words} words, more than {MAX_ARGS_WORDS}");
+    }
+
+    let start = ARGS_END - words as u16 + 1;
+    let argv = start + 1;
+    let mut string = argv + args.len() as u16 + 1;
+
+    vm.set_memory(start, args.len() as u16);
+    for (i, arg) in args.iter().enumerate() {
+        vm.set_memory(argv + i as u16, string);
+        for byte in arg.bytes().chain([0]) {
+            vm.set_memory(string, byte as u16);
+            string += 1;
+        }
+    }
+    vm.set_memory(argv + args.len() as u16, 0);
+
+    vm.set_register(0, args.len() as u16);
+    vm.set_register(1, argv);
+    vm.set_register(6, start);
+    Ok(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Flag;
+
+    #[test]
+    fn test_args() {
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        let start = install(&mut vm, &["ab", "c"]).unwrap();
+
+        assert_eq!(start, ARGS_END - 9 + 1);
+        assert_eq!(vm.registers()[0], 2);
+        assert_eq!(vm.registers()[1], start + 1);
+        assert_eq!(vm.registers()[6], start);
+
+        let argv = start as usize + 1;
+        let mem = vm.memory();
+        assert_eq!(mem[start as usize], 2);
+        assert_eq!(mem[argv + 2], 0);
+        assert_eq!(
+            &mem[mem[argv] as usize..][..3],
+            &[b'a' as u16, b'b' as u16, 0]
+        );
+        assert_eq!(&mem[mem[argv + 1] as usize..][..2], &[b'c' as u16, 0]);
+        assert_eq!(mem[ARGS_END as usize], 0);
+
+        assert!(install(&mut vm, &["x"; MAX_ARGS_WORDS]).is_err());
+    }
+}
@@ -0,0 +1,572 @@
+//! A two-pass LC-3 assembler, so programs can be built without lc3as.
+//!
+//! The first pass assigns an address to every label, the second encodes the
+//! instructions. Supports every opcode, the trap aliases (GETC, OUT, PUTS, IN, PUTSP,
+//! HALT) and the `.ORIG`, `.FILL`, `.BLKW`, `.STRINGZ` and `.END` directives. Strings
+//! must be ASCII, as with lc3as.
+//!
+//! Besides the image, a [`Program`] gives its symbol table and a listing in the lc3as
+//! `.lst` format, which [`crate::loader`] reads back.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{image::ImageBuilder, symbols::SymbolTable, util::parse_literal};
+
+/// An assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// Address of every label.
+    pub symbols: BTreeMap<String, u16>,
+    // the source line of .ORIG, and of each statement with the address of its first word
+    orig_line: usize,
+    lines: Vec<(usize, u16)>,
+}
+
+impl Program {
+    /// The program as an object file: the origin followed by the words, big-endian.
+    pub fn image(&self) -> Vec<u8> {
+        ImageBuilder::new(self.origin).words(&self.words).build()
+    }
+
+    pub fn symbol_table(&self) -> SymbolTable {
+        self.symbols
+            .iter()
+            .map(|(name, &addr)| (name.as_str(), addr))
+            .collect()
+    }
+
+    /// A listing of the program in the format of lc3as: each source line with its line
+    /// number, after the address, hex and binary of the first word it assembled to, and
+    /// the words after that on lines of their own. `source` is the text the program was
+    /// assembled from.
+    pub fn listing(&self, source: &str) -> String {
+        let word = |addr: u16, val: u16| format!("  ({addr:04X}) {val:04X}  {val:016b} ");
+        let end = self.origin as usize + self.words.len();
+        let mut statements = self.lines.iter().peekable();
+
+        let mut listing = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let text = text.trim_end();
+            let (prefix, words) = match statements.next_if(|&&(at, _)| at == line) {
+                Some(&(_, addr)) => {
+                    let next = statements.peek().map_or(end, |&&(_, next)| next as usize);
+                    let words = (addr as usize..next).map(|addr| addr as u16);
+                    let mut words =
+                        words.map(|addr| (addr, self.words[(addr - self.origin) as usize]));
+                    match words.next() {
+                        Some((addr, val)) => (word(addr, val), words.collect()),
+                        // an empty .BLKW
+                        None => (" ".repeat(32), Vec::new()),
+                    }
+                }
+                None if line == self.orig_line => (word(0, self.origin), Vec::new()),
+                None => (" ".repeat(32), Vec::new()),
+            };
+
+            writeln!(listing, "{prefix}({line:>4}) {text}").unwrap();
+            for (addr, val) in words {
+                writeln!(listing, "{}", word(addr, val).trim_end()).unwrap();
+            }
+        }
+
+        listing
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => word.clone(),
+            Token::Str(s) => format!("{s:?}"),
+        }
+    }
+}
+
+/// A line with an operation, after its label has been taken off.
+struct Statement {
+    line: usize,
+    addr: u16,
+    op: String,
+    operands: Vec<Token>,
+}
+
+pub fn assemble(source: &str) -> Result<Program> {
+    let mut origin = None;
+    let mut orig_line = 0;
+    let mut addr = 0u16;
+    let mut symbols = BTreeMap::new();
+    let mut statements = Vec::new();
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let at = |err: anyhow::Error| anyhow!("line {line}: {err}");
+
+        let mut tokens = tokenize(text).map_err(at)?.into_iter();
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+        let Token::Word(first) = first else {
+            return Err(at(anyhow!("expected a label or an operation")));
+        };
+
+        let op = if is_operation(&first) {
+            first
+        } else {
+            check_label(&first).map_err(at)?;
+            if origin.is_none() {
+                return Err(at(anyhow!("label {first} before .ORIG")));
+            }
+            if symbols.insert(first.clone(), addr).is_some() {
+                return Err(at(anyhow!("duplicate label {first}")));
+            }
+            match tokens.next() {
+                Some(Token::Word(op)) if is_operation(&op) => op,
+                Some(token) => return Err(at(anyhow!("unknown operation {}", token.describe()))),
+                None => continue,
+            }
+        };
+        let op = op.to_ascii_uppercase();
+        let operands: Vec<_> = tokens.collect();
+
+        match (op.as_str(), origin) {
+            (".ORIG", None) => {
+                let [Token::Word(start)] = &operands[..] else {
+                    return Err(at(anyhow!(".ORIG expects an address")));
+                };
+                let start = parse_number(start, 0, 0xFFFF).map_err(at)?;
+                origin = Some(start);
+                orig_line = line;
+                addr = start;
+                continue;
+            }
+            (".ORIG", Some(_)) => return Err(at(anyhow!("only one .ORIG is supported"))),
+            (".END", _) => break,
+            (_, None) => return Err(at(anyhow!("{op} before .ORIG"))),
+            _ => (),
+        }
+
+        let size = match op.as_str() {
+            ".BLKW" => match &operands[..] {
+                [Token::Word(n)] => parse_number(n, 0, 0xFFFF).map_err(at)?,
+                _ => return Err(at(anyhow!(".BLKW expects a word count"))),
+            },
+            ".STRINGZ" => match &operands[..] {
+                // one word per byte, like lc3as, so only ASCII has a single meaning
+                [Token::Str(s)] if !s.is_ascii() => {
+                    return Err(at(anyhow!(".STRINGZ only supports ASCII")))
+                }
+                [Token::Str(s)] => s.len() as u16 + 1,
+                _ => return Err(at(anyhow!(".STRINGZ expects a string"))),
+            },
+            _ => 1,
+        };
+
+        statements.push(Statement {
+            line,
+            addr,
+            op,
+            operands,
+        });
+        addr = match addr.checked_add(size) {
+            Some(next) => next,
+            None => return Err(at(anyhow!("program runs past xFFFF"))),
+        };
+    }
+
+    let Some(origin) = origin else {
+        bail!("no .ORIG");
+    };
+
+    let mut words = Vec::new();
+    for statement in &statements {
+        encode(statement, &symbols, &mut words)
+            .map_err(|err| anyhow!("line {}: {err}", statement.line))?;
+    }
+
+    Ok(Program {
+        origin,
+        words,
+        symbols,
+        orig_line,
+        lines: statements
+            .iter()
+            .map(|statement| (statement.line, statement.addr))
+            .collect(),
+    })
+}
+
+/// Splits a line into words and string literals, dropping commas and the comment.
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            ',' => {
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('e') => '\x1b',
+                            Some('0') => '\0',
+                            Some(c @ ('"' | '\\')) => c,
+                            Some(c) => bail!("unknown escape \\{c}"),
+                            None => bail!("unterminated string"),
+                        }),
+                        Some(c) => s.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ',' | ';' | '"') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn is_operation(word: &str) -> bool {
+    let upper = word.to_ascii_uppercase();
+    match upper.as_str() {
+        "ADD" | "AND" | "NOT" | "LD" | "LDI" | "LDR" | "LEA" | "ST" | "STI" | "STR" | "JMP"
+        | "RET" | "JSR" | "JSRR" | "RTI" | "TRAP" | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP"
+        | "HALT" | ".ORIG" | ".FILL" | ".BLKW" | ".STRINGZ" | ".END" => true,
+        _ => branch_flags(&upper).is_some(),
+    }
+}
+
+/// The nzp bits of a BR opcode, e.g. `BRnp`. Plain `BR` is unconditional.
+fn branch_flags(upper: &str) -> Option<u16> {
+    let flags = upper.strip_prefix("BR")?;
+    let nzp = match flags {
+        "" | "NZP" => 0b111,
+        "N" => 0b100,
+        "Z" => 0b010,
+        "P" => 0b001,
+        "NZ" => 0b110,
+        "NP" => 0b101,
+        "ZP" => 0b011,
+        _ => return None,
+    };
+    Some(nzp)
+}
+
+fn check_label(label: &str) -> Result<()> {
+    let valid = label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_literal(label).is_none()
+        && parse_register(label).is_none();
+
+    if !valid {
+        bail!("bad label: {label}");
+    }
+    Ok(())
+}
+
+fn parse_register(word: &str) -> Option<u16> {
+    match word.as_bytes() {
+        [b'R' | b'r', n @ b'0'..=b'7'] => Some((n - b'0') as u16),
+        _ => None,
+    }
+}
+
+/// Parses a literal in `min..=max`, returned as its 16-bit two's complement.
+fn parse_number(word: &str, min: i64, max: i64) -> Result<u16> {
+    match parse_literal(word) {
+        Some(n) if (min..=max).contains(&n) => Ok(n as u16),
+        Some(n) => bail!("{n} is out of range {min}..={max}"),
+        None => bail!("bad number: {word}"),
+    }
+}
+
+fn encode(
+    statement: &Statement,
+    symbols: &BTreeMap<String, u16>,
+    words: &mut Vec<u16>,
+) -> Result<()> {
+    let Statement {
+        addr, op, operands, ..
+    } = statement;
+    let op = op.as_str();
+
+    let words_of = |operands: &[Token]| -> Result<Vec<String>> {
+        operands
+            .iter()
+            .map(|token| match token {
+                Token::Word(word) => Ok(word.clone()),
+                Token::Str(_) => bail!("unexpected string in {op}"),
+            })
+            .collect()
+    };
+
+    match op {
+        ".STRINGZ" => {
+            let [Token::Str(s)] = &operands[..] else {
+                unreachable!("checked in the first pass");
+            };
+            words.extend(s.bytes().map(u16::from));
+            words.push(0);
+            return Ok(());
+        }
+        ".BLKW" => {
+            let [Token::Word(n)] = &operands[..] else {
+                unreachable!("checked in the first pass");
+            };
+            let n = parse_number(n, 0, 0xFFFF)?;
+            words.extend(std::iter::repeat_n(0, n as usize));
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let args = words_of(operands)?;
+    let expect = |n: usize| -> Result<()> {
+        if args.len() != n {
+            bail!("{op} expects {n} operands, got {}", args.len());
+        }
+        Ok(())
+    };
+    let reg = |i: usize| -> Result<u16> {
+        parse_register(&args[i]).ok_or_else(|| anyhow!("expected a register, got {}", args[i]))
+    };
+    let imm = |i: usize, bits: u32| -> Result<u16> {
+        let limit = 1i64 << (bits - 1);
+        Ok(parse_number(&args[i], -limit, limit - 1)? & ((1 << bits) - 1))
+    };
+    // a label, or a literal offset
+    let offset = |i: usize, bits: u32| -> Result<u16> {
+        let Some(&target) = symbols.get(&args[i]) else {
+            if parse_literal(&args[i]).is_none() {
+                bail!("unknown label {}", args[i]);
+            }
+            return imm(i, bits);
+        };
+        let offset = target as i64 - (*addr as i64 + 1);
+        let limit = 1i64 << (bits - 1);
+        if !(-limit..limit).contains(&offset) {
+            bail!("{} is too far away ({offset} words)", args[i]);
+        }
+        Ok(offset as u16 & ((1 << bits) - 1))
+    };
+
+    let word = match op {
+        ".FILL" => {
+            expect(1)?;
+            match symbols.get(&args[0]) {
+                Some(&addr) => addr,
+                None if parse_literal(&args[0]).is_none() => bail!("unknown label {}", args[0]),
+                None => parse_number(&args[0], -0x8000, 0xFFFF)?,
+            }
+        }
+        "ADD" | "AND" => {
+            expect(3)?;
+            let opcode = if op == "ADD" { 0x1000 } else { 0x5000 };
+            let operand = match parse_register(&args[2]) {
+                Some(sr2) => sr2,
+                None => 1 << 5 | imm(2, 5)?,
+            };
+            opcode | reg(0)? << 9 | reg(1)? << 6 | operand
+        }
+        "NOT" => {
+            expect(2)?;
+            0x903F | reg(0)? << 9 | reg(1)? << 6
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect(2)?;
+            let opcode = match op {
+                "LD" => 0x2000,
+                "LDI" => 0xA000,
+                "LEA" => 0xE000,
+                "ST" => 0x3000,
+                _ => 0xB000,
+            };
+            opcode | reg(0)? << 9 | offset(1, 9)?
+        }
+        "LDR" | "STR" => {
+            expect(3)?;
+            let opcode = if op == "LDR" { 0x6000 } else { 0x7000 };
+            opcode | reg(0)? << 9 | reg(1)? << 6 | imm(2, 6)?
+        }
+        "JMP" => {
+            expect(1)?;
+            0xC000 | reg(0)? << 6
+        }
+        "RET" => {
+            expect(0)?;
+            0xC1C0
+        }
+        "JSR" => {
+            expect(1)?;
+            0x4800 | offset(0, 11)?
+        }
+        "JSRR" => {
+            expect(1)?;
+            0x4000 | reg(0)? << 6
+        }
+        "RTI" => {
+            expect(0)?;
+            0x8000
+        }
+        "TRAP" => {
+            expect(1)?;
+            0xF000 | parse_number(&args[0], 0, 0xFF)?
+        }
+        "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            expect(0)?;
+            let vector = match op {
+                "GETC" => 0x20,
+                "OUT" => 0x21,
+                "PUTS" => 0x22,
+                "IN" => 0x23,
+                "PUTSP" => 0x24,
+                _ => 0x25,
+            };
+            0xF000 | vector
+        }
+        _ => {
+            let nzp = branch_flags(op).ok_or_else(|| anyhow!("unknown operation {op}"))?;
+            expect(1)?;
+            nzp << 9 | offset(0, 9)?
+        }
+    };
+    words.push(word);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let program = assemble(
+            r#"
+            ; counts down from 3
+                    .ORIG x3000
+                    LD R0, COUNT
+            LOOP    ADD R0, R0, #-1
+                    BRp LOOP
+                    LEA R0, MSG
+                    PUTS
+                    HALT
+            COUNT   .FILL #3
+            MSG     .STRINGZ "hi\n"
+            BUF     .BLKW 2
+                    .FILL BUF
+                    .END
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(program.origin, 0x3000);
+        assert_eq!(
+            program.words,
+            [
+                0x2005, 0x103F, 0x03FE, 0xE003, 0xF022, 0xF025, 0x0003, 0x0068, 0x0069, 0x000A,
+                0x0000, 0x0000, 0x0000, 0x300B
+            ]
+        );
+        assert_eq!(program.symbols["LOOP"], 0x3001);
+        assert_eq!(&program.image()[..4], [0x30, 0x00, 0x20, 0x05]);
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = "\
+; prints hi
+        .ORIG x3000
+        LEA R0, MSG
+        PUTS
+        HALT
+MSG     .STRINGZ \"hi\"
+        .BLKW 0
+        .END
+";
+        let program = assemble(source).unwrap();
+        let listing = program.listing(source);
+
+        assert_eq!(
+            listing,
+            "                                (   1) ; prints hi
+  (0000) 3000  0011000000000000 (   2)         .ORIG x3000
+  (3000) E002  1110000000000010 (   3)         LEA R0, MSG
+  (3001) F022  1111000000100010 (   4)         PUTS
+  (3002) F025  1111000000100101 (   5)         HALT
+  (3003) 0068  0000000001101000 (   6) MSG     .STRINGZ \"hi\"
+  (3004) 0069  0000000001101001
+  (3005) 0000  0000000000000000
+                                (   7)         .BLKW 0
+                                (   8)         .END
+"
+        );
+        assert_eq!(
+            crate::loader::parse_listing(&listing).unwrap(),
+            [crate::image::Segment {
+                origin: 0x3000,
+                words: program.words
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+
+        assert_eq!(error("ADD R0, R0, #1"), "line 1: ADD before .ORIG");
+        assert_eq!(
+            error(".ORIG x3000\nADD R0, R0, #16"),
+            "line 2: 16 is out of range -16..=15"
+        );
+        assert_eq!(
+            error(".ORIG x3000\nBR NOWHERE"),
+            "line 2: unknown label NOWHERE"
+        );
+        assert_eq!(
+            error(".ORIG x3000\nA HALT\nA HALT"),
+            "line 3: duplicate label A"
+        );
+        assert_eq!(
+            error(".ORIG x3000\nJMP R0, R1"),
+            "line 2: JMP expects 1 operands, got 2"
+        );
+        assert_eq!(
+            error(".ORIG x3000\nLEA R0, AFTER\nMSG .STRINGZ \"\u{e9}\"\nAFTER .FILL x1234"),
+            "line 3: .STRINGZ only supports ASCII"
+        );
+    }
+}
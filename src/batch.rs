@@ -0,0 +1,433 @@
+//! Runs many images concurrently, for grading a whole class at once.
+//!
+//! Every case runs in a fresh vm on one of the worker threads, with its input as the
+//! keyboard and its output captured, and is cancelled when it exceeds its time limit.
+//!
+//! Instead of images, a TOML manifest can list the cases to run, each with its own input
+//! and the output it must print to pass:
+//!
+//! ```toml
+//! [[case]]
+//! name = "adds two numbers"   # shown in the summary, the image path by default
+//! image = "sum.obj"           # relative to the manifest
+//! stdin = "3 4\n"             # fed to the program, optional
+//! expected_stdout = "7\n"     # compared with everything it printed, optional
+//! max_instructions = 100000   # optional
+//! ```
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use lc3_vm::{
+    console::{ConsoleOptions, SharedBuf, StreamIo},
+    machine::MachineConfig,
+    RunResult, Vm, VmError,
+};
+use serde::Deserialize;
+
+pub struct BatchOptions {
+    pub jobs: usize,
+    pub timeout: Duration,
+    /// Directory where each run's output is saved as `<case number>-<case name>.out`,
+    /// without the directory and extension of the name, numbering the cases from 1.
+    pub out_dir: Option<PathBuf>,
+    /// The machine every case runs on, with the case's image loaded on top.
+    pub config: MachineConfig,
+    /// See [`Vm::set_strict`].
+    pub strict: bool,
+    pub console: ConsoleOptions,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            timeout: Duration::from_secs(10),
+            out_dir: None,
+            config: MachineConfig::bare_metal(),
+            strict: false,
+            console: ConsoleOptions::default(),
+        }
+    }
+}
+
+/// One run: an image, what to feed it and what it has to print.
+pub struct Case {
+    pub name: String,
+    pub image: PathBuf,
+    /// The run's keyboard input. Runs get no input without one.
+    pub input: Option<Vec<u8>>,
+    /// What the run has to print to count as passed.
+    pub expected_output: Option<Vec<u8>>,
+    pub max_instructions: Option<u64>,
+}
+
+impl Case {
+    pub fn new(image: PathBuf) -> Self {
+        Self {
+            name: image.display().to_string(),
+            image,
+            input: None,
+            expected_output: None,
+            max_instructions: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(rename = "case", default)]
+    cases: Vec<ManifestCase>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestCase {
+    name: Option<String>,
+    image: PathBuf,
+    stdin: Option<String>,
+    expected_stdout: Option<String>,
+    max_instructions: Option<u64>,
+}
+
+/// Reads the cases of the manifest at `path`, see the module documentation.
+pub fn read_manifest(path: &Path) -> Result<Vec<Case>> {
+    let text = std::fs::read_to_string(path)?;
+    let manifest: Manifest =
+        toml::from_str(&text).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    Ok(manifest
+        .cases
+        .into_iter()
+        .map(|case| {
+            let image = dir.join(case.image);
+            Case {
+                name: case.name.unwrap_or_else(|| image.display().to_string()),
+                image,
+                input: case.stdin.map(String::into_bytes),
+                expected_output: case.expected_stdout.map(String::into_bytes),
+                max_instructions: case.max_instructions,
+            }
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+pub enum RunStatus {
+    Halted,
+    /// The vm halted, but the output differs from the expected one.
+    WrongOutput,
+    /// The run stopped with an error, or the image couldn't be loaded, with the message.
+    Failed(String),
+    TimedOut,
+}
+
+impl fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunStatus::Halted => write!(f, "halted"),
+            RunStatus::WrongOutput => write!(f, "mismatch"),
+            RunStatus::Failed(_) => write!(f, "failed"),
+            RunStatus::TimedOut => write!(f, "timeout"),
+        }
+    }
+}
+
+pub struct RunSummary {
+    pub name: String,
+    /// Whether the case had an expected output.
+    pub checked: bool,
+    pub status: RunStatus,
+    pub elapsed: Duration,
+    pub output: Vec<u8>,
+}
+
+/// Runs all `cases`, at most `opts.jobs` at a time, so one job runs them in sequence.
+/// Summaries are returned in the order of `cases`.
+pub fn run_batch(cases: &[Case], opts: &BatchOptions) -> Result<Vec<RunSummary>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(cases.len()));
+
+    thread::scope(|s| {
+        for _ in 0..opts.jobs.max(1) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(case) = cases.get(i) else {
+                    break;
+                };
+
+                let summary = run_one(i, case, opts);
+                results.lock().unwrap().push((i, summary));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, summary)| summary).collect()
+}
+
+fn run_one(i: usize, case: &Case, opts: &BatchOptions) -> Result<RunSummary> {
+    let output = SharedBuf::default();
+    let start = Instant::now();
+    let result =
+        build_vm(case, opts, output.clone()).map(|mut vm| run_timeout(&mut vm, opts.timeout));
+    let elapsed = start.elapsed();
+    let output = output.0.take();
+
+    let status = match result {
+        Ok(Ok(RunResult::Halted)) => match &case.expected_output {
+            Some(expected) if *expected != output => RunStatus::WrongOutput,
+            _ => RunStatus::Halted,
+        },
+        Ok(Ok(_)) => RunStatus::TimedOut,
+        Ok(Err(err)) => RunStatus::Failed(err.to_string()),
+        Err(err) => RunStatus::Failed(err.to_string()),
+    };
+
+    if let Some(out_dir) = &opts.out_dir {
+        let name = Path::new(&case.name);
+        let name = name.file_stem().unwrap_or(name.as_os_str());
+        // numbered, since cases of different directories can have the same name
+        let file = format!("{}-{}.out", i + 1, name.to_string_lossy());
+        std::fs::write(out_dir.join(file), &output)?;
+    }
+
+    Ok(RunSummary {
+        name: case.name.clone(),
+        checked: case.expected_output.is_some(),
+        status,
+        elapsed,
+        output,
+    })
+}
+
+fn build_vm(case: &Case, opts: &BatchOptions, output: SharedBuf) -> Result<Vm> {
+    let mut vm = opts.config.build()?;
+    vm.read_images(&[&case.image])
+        .map_err(|err| anyhow!("{}: {err}", case.image.display()))?;
+    vm.set_strict(opts.strict);
+    vm.set_console_options(opts.console);
+    vm.set_instruction_limit(case.max_instructions);
+    let input = io::Cursor::new(case.input.clone().unwrap_or_default());
+    vm.set_io(Box::new(StreamIo::new(input, output)));
+
+    Ok(vm)
+}
+
+/// Runs `vm` until it halts or fails, or returns [`RunResult::Stopped`] once `timeout`
+/// passes.
+fn run_timeout(vm: &mut Vm, timeout: Duration) -> Result<RunResult, VmError> {
+    let cancel = AtomicBool::new(false);
+    let (done, finished) = mpsc::channel::<()>();
+
+    thread::scope(|s| {
+        let cancel = &cancel;
+        s.spawn(move || {
+            // the sender is dropped when the run ends first
+            if finished.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        let result = vm.run_with_cancel(cancel);
+        drop(done);
+        result
+    })
+}
+
+/// Prints one row per run followed by the totals.
+pub fn print_summary(summaries: &[RunSummary]) {
+    let width = summaries
+        .iter()
+        .map(|s| s.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("CASE".len());
+
+    println!(
+        "{:<width$}  {:<8} {:>8} {:>8}  ERROR",
+        "CASE", "STATUS", "TIME", "OUTPUT"
+    );
+
+    for summary in summaries {
+        let error = match &summary.status {
+            RunStatus::Failed(err) => err.as_str(),
+            _ => "",
+        };
+
+        let row = format!(
+            "{:<width$}  {:<8} {:>7.2}s {:>8}  {}",
+            summary.name,
+            summary.status.to_string(),
+            summary.elapsed.as_secs_f64(),
+            summary.output.len(),
+            error,
+        );
+        println!("{}", row.trim_end());
+    }
+
+    let count = |f: fn(&RunStatus) -> bool| summaries.iter().filter(|s| f(&s.status)).count();
+    print!(
+        "\n{} runs: {} halted, {} mismatched, {} failed, {} timed out",
+        summaries.len(),
+        count(|s| matches!(s, RunStatus::Halted)),
+        count(|s| matches!(s, RunStatus::WrongOutput)),
+        count(|s| matches!(s, RunStatus::Failed(_))),
+        count(|s| matches!(s, RunStatus::TimedOut)),
+    );
+    if summaries.iter().any(|s| s.checked) {
+        let passed = summaries
+            .iter()
+            .filter(|s| s.checked && matches!(s.status, RunStatus::Halted))
+            .count();
+        let checked = summaries.iter().filter(|s| s.checked).count();
+        print!(", {passed} of {checked} passed");
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lc3_vm::image::ImageBuilder;
+
+    /// A directory of its own for `test`, with .obj images of the given words at x3000.
+    fn image_dir(test: &str, images: &[(&str, &[u16])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lc3-batch-{test}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, words) in images {
+            let image = ImageBuilder::new(0x3000).words(words).build();
+            std::fs::write(dir.join(name), image).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let dir = image_dir(
+            "run",
+            &[
+                // GETC; OUT; HALT
+                ("echo.obj", &[0xF020, 0xF021, 0xF025]),
+                // ADD R0, R0, R0 with bit 3 set; HALT
+                ("loose.obj", &[0x1008, 0xF025]),
+                // BR #-1
+                ("spin.obj", &[0x0FFF]),
+            ],
+        );
+        let mut echo = Case::new(dir.join("echo.obj"));
+        echo.input = Some(b"a".to_vec());
+        let cases = [
+            echo,
+            Case::new(dir.join("loose.obj")),
+            Case::new(dir.join("spin.obj")),
+        ];
+        let opts = BatchOptions {
+            jobs: 2,
+            timeout: Duration::from_millis(50),
+            strict: true,
+            ..BatchOptions::default()
+        };
+
+        let summaries = run_batch(&cases, &opts).unwrap();
+        assert!(matches!(summaries[0].status, RunStatus::Halted));
+        assert!(summaries[0].output.starts_with(b"a"));
+        match &summaries[1].status {
+            RunStatus::Failed(err) => assert!(err.contains("breaks the spec"), "{err}"),
+            status => panic!("unexpected status {status}"),
+        }
+        assert!(matches!(summaries[2].status, RunStatus::TimedOut));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = image_dir(
+            "manifest",
+            &[
+                // GETC; OUT; GETC; OUT; HALT
+                ("echo.obj", &[0xF020, 0xF021, 0xF020, 0xF021, 0xF025]),
+            ],
+        );
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::copy(dir.join("echo.obj"), dir.join("a/echo.obj")).unwrap();
+        let manifest = dir.join("cases.toml");
+        std::fs::write(
+            &manifest,
+            r#"
+            [[case]]
+            name = "passes"
+            image = "echo.obj"
+            stdin = "hi"
+            expected_stdout = "hiHALT\n"
+
+            [[case]]
+            image = "a/echo.obj"
+            stdin = "hi"
+            expected_stdout = "ho"
+
+            [[case]]
+            image = "echo.obj"
+            stdin = "h"
+
+            [[case]]
+            image = "echo.obj"
+            stdin = "hi"
+            max_instructions = 3
+            "#,
+        )
+        .unwrap();
+
+        let cases = read_manifest(&manifest).unwrap();
+        assert_eq!(cases[0].name, "passes");
+        assert_eq!(cases[1].image, dir.join("a/echo.obj"));
+        assert_eq!(cases[1].name, dir.join("a/echo.obj").display().to_string());
+        assert_eq!(cases[0].input.as_deref(), Some(&b"hi"[..]));
+        assert_eq!(cases[2].expected_output, None);
+        assert_eq!(cases[3].max_instructions, Some(3));
+
+        let out_dir = dir.join("out");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let opts = BatchOptions {
+            out_dir: Some(out_dir.clone()),
+            ..BatchOptions::default()
+        };
+        let summaries = run_batch(&cases, &opts).unwrap();
+        let statuses: Vec<_> = summaries.iter().map(|s| s.status.to_string()).collect();
+        assert_eq!(statuses, ["halted", "mismatch", "failed", "failed"]);
+        assert!(summaries[0].checked && !summaries[2].checked);
+        match &summaries[3].status {
+            RunStatus::Failed(err) => assert!(err.contains("Instruction limit"), "{err}"),
+            status => panic!("unexpected status {status}"),
+        }
+
+        // the two echo.obj cases don't overwrite each other's output
+        assert_eq!(
+            std::fs::read(out_dir.join("1-passes.out")).unwrap(),
+            b"hiHALT\n"
+        );
+        assert_eq!(
+            std::fs::read(out_dir.join("2-echo.out")).unwrap(),
+            b"hiHALT\n"
+        );
+        assert_eq!(std::fs::read(out_dir.join("3-echo.out")).unwrap(), b"h");
+
+        std::fs::write(&manifest, "[[case]]\nimage = \"echo.obj\"\nstdn = \"x\"\n").unwrap();
+        assert!(read_manifest(&manifest).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,760 @@
+//! Keyboard/display register pairs, and the [`IoDevice`] trait behind the main one.
+//!
+//! The main console is a [`Keyboard`] and a [`Display`] device on top of a shared
+//! [`IoDevice`], which the native traps use as well. It sits at the standard addresses by default but can be moved to match
+//! other simulators. Extra consoles at other addresses read from and write to their own
+//! streams, e.g. a fifo and a file, for programs that talk to two terminals, or to a
+//! Unix socket, so a companion process can exchange bytes with the program. The main
+//! console itself can be served over TCP with [`SocketIo`].
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, BufReader, Read, Write},
+    mem,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    ops::RangeInclusive,
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+
+pub use crate::util::SharedBuf;
+
+use crate::{
+    device::{Device, Interrupt},
+    env::SharedEnv,
+    util::parse_literal,
+};
+
+/// Where the main console gets its keys and sends its output. The vm uses the terminal
+/// by default; see [`crate::vm::Vm::set_io`].
+pub trait IoDevice {
+    /// Whether [`IoDevice::read_key`] would return without blocking.
+    fn key_ready(&mut self) -> bool;
+
+    /// Waits for the next key. Returns `None` once the input has ended.
+    fn read_key(&mut self) -> Option<u8>;
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads keys from a stream and writes output to another, for running programs
+/// headlessly. Keys are always ready, so a program polling KBSR reads through to the end
+/// of the input.
+pub struct StreamIo {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+}
+
+impl StreamIo {
+    pub fn new(input: impl Read + 'static, output: impl Write + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+}
+
+impl IoDevice for StreamIo {
+    fn key_ready(&mut self) -> bool {
+        true
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        match self.input.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// A console on the other end of a TCP connection, e.g. `nc` or a terminal served to a
+/// browser. Keys are read in the background so KBSR can be polled without blocking, and
+/// the input ends when the peer closes the connection.
+pub struct SocketIo {
+    input: Receiver<u8>,
+    next_key: Option<u8>,
+    output: TcpStream,
+}
+
+impl SocketIo {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for byte in BufReader::new(reader).bytes() {
+                match byte {
+                    Ok(byte) if tx.send(byte).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            input: rx,
+            next_key: None,
+            output: stream,
+        })
+    }
+
+    /// Waits for one connection on `addr`, e.g. `0.0.0.0:4000`.
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Self::new(stream)
+    }
+}
+
+impl IoDevice for SocketIo {
+    fn key_ready(&mut self) -> bool {
+        if self.next_key.is_none() {
+            self.next_key = self.input.try_recv().ok();
+        }
+
+        self.next_key.is_some()
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        self.next_key.take().or_else(|| self.input.recv().ok())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+}
+
+/// Bit 15 of KBSR and DSR, set when a key can be read or a character written.
+pub const STATUS_READY: u16 = 1 << 15;
+
+/// Bit 14 of KBSR, set by the program to have key presses interrupt it.
+pub const KBSR_IE: u16 = 1 << 14;
+
+pub const KEYBOARD_INTERRUPT: u8 = 0x80;
+pub const KEYBOARD_PRIORITY: u8 = 4;
+
+/// End of transmission, which [`EofMode::Eot`] delivers at the end of the input.
+pub const EOT: u8 = 0x04;
+
+/// How long the main console's registers report busy, in executed instructions. The
+/// default of zero makes both devices ready whenever the host is, so a program that
+/// polls incorrectly still works; nonzero delays make such bugs show.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceTiming {
+    /// Instructions after a DDR write during which DSR reads not ready. Characters
+    /// written while the display is busy are lost.
+    pub display_busy: u64,
+    /// Instructions between a key arriving and KBSR reporting it.
+    pub key_delay: u64,
+}
+
+impl DeviceTiming {
+    /// Parses `DISPLAY_BUSY,KEY_DELAY`, e.g. `50,200`.
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.split_once(',').map(|(a, b)| (a.parse(), b.parse())) {
+            Some((Ok(display_busy), Ok(key_delay))) => Ok(Self {
+                display_busy,
+                key_delay,
+            }),
+            _ => bail!("expected two instruction counts: DISPLAY_BUSY,KEY_DELAY"),
+        }
+    }
+}
+
+/// What the main console does when the program reads past the end of its input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EofMode {
+    /// Stop the program with [`crate::VmError::InputExhausted`].
+    #[default]
+    Stop,
+    /// Deliver EOT (x04, what Ctrl-D types) for every read, and let the program decide.
+    Eot,
+}
+
+impl EofMode {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "eot" => Ok(Self::Eot),
+            _ => bail!("expected stop or eot"),
+        }
+    }
+}
+
+/// How the main console translates the program's input and output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsoleOptions {
+    /// Write each `\n` the program prints as `\r\n`, for terminals and files that
+    /// expect both.
+    pub crlf: bool,
+    pub eof: EofMode,
+}
+
+/// Parses an input recording written by [`crate::vm::Vm::record_input`]: one line per
+/// key with the instruction count at which it was read and the byte, e.g. `1523 x61`.
+pub fn parse_recording(s: &str) -> Result<VecDeque<(u64, u8)>> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let key = line.split_once(' ').and_then(|(at, byte)| {
+                let byte = parse_literal(byte).filter(|byte| (0..=0xFF).contains(byte))?;
+                Some((at.parse().ok()?, byte as u8))
+            });
+            key.ok_or_else(|| anyhow!("line {}: expected an instruction count and a byte", i + 1))
+        })
+        .collect()
+}
+
+/// Sees the bytes written to the main console, see [`SharedIo::set_tap`].
+pub(crate) type OutputTap = Box<dyn FnMut(&[u8])>;
+
+/// The main console's [`IoDevice`] and keyboard state, shared by the vm and the
+/// [`Keyboard`] and [`Display`] devices.
+#[derive(Clone)]
+pub(crate) struct SharedIo(Rc<RefCell<IoState>>);
+
+struct IoState {
+    io: Box<dyn IoDevice>,
+    env: SharedEnv,
+    // set when a read found the end of the input
+    input_exhausted: bool,
+    interrupt_enable: bool,
+    timing: DeviceTiming,
+    options: ConsoleOptions,
+    // the last byte written, so a `\r\n` isn't translated to `\r\r\n`
+    last_written: u8,
+    // instructions until the waiting key shows up in KBSR, `None` until one is seen
+    key_delay_left: Option<u64>,
+    // the last key taken from `io`, which KBDR keeps returning until the next one
+    kbdr: u8,
+    // KBSR's ready bit, set when a key is latched into `kbdr` and cleared by reading it
+    key_latched: bool,
+    display_busy_left: u64,
+    // instructions executed before the current one
+    instructions: u64,
+    recording: Option<Box<dyn Write>>,
+    // keys to deliver instead of the ones from `io`, with the instruction count at which
+    // each becomes ready
+    replay: Option<VecDeque<(u64, u8)>>,
+    // keys given by the embedder, delivered before any others
+    queued: VecDeque<u8>,
+    // sees every byte written, before newlines are translated
+    tap: Option<OutputTap>,
+}
+
+impl IoState {
+    fn key_ready(&mut self) -> bool {
+        if !self.queued.is_empty() {
+            return true;
+        }
+        match &self.replay {
+            Some(replay) => matches!(replay.front(), Some(&(at, _)) if at <= self.instructions),
+            None => {
+                let io = &mut self.io;
+                self.env.borrow_mut().key_ready(&mut || io.key_ready())
+            }
+        }
+    }
+
+    fn next_key(&mut self) -> Option<u8> {
+        let key = self.queued.pop_front().or_else(|| match &mut self.replay {
+            Some(replay) => replay.pop_front().map(|(_, byte)| byte),
+            None => self.io.read_key(),
+        });
+        let key = match (key, self.options.eof) {
+            (None, EofMode::Eot) => Some(EOT),
+            _ => key,
+        };
+
+        if let (Some(byte), Some(recording)) = (key, &mut self.recording) {
+            let written = writeln!(recording, "{} x{byte:02X}", self.instructions)
+                .and_then(|()| recording.flush());
+            if let Err(err) = written {
+                warn!("Stopped recording input: {err}");
+                self.recording = None;
+            }
+        }
+
+        key
+    }
+}
+
+impl SharedIo {
+    pub fn new(io: Box<dyn IoDevice>, env: SharedEnv) -> Self {
+        Self(Rc::new(RefCell::new(IoState {
+            io,
+            env,
+            input_exhausted: false,
+            interrupt_enable: false,
+            timing: DeviceTiming::default(),
+            options: ConsoleOptions::default(),
+            last_written: 0,
+            key_delay_left: None,
+            kbdr: 0,
+            key_latched: false,
+            display_busy_left: 0,
+            instructions: 0,
+            recording: None,
+            replay: None,
+            queued: VecDeque::new(),
+            tap: None,
+        })))
+    }
+
+    pub fn set_instructions(&self, instructions: u64) {
+        self.0.borrow_mut().instructions = instructions;
+    }
+
+    pub fn record(&self, recording: Box<dyn Write>) {
+        self.0.borrow_mut().recording = Some(recording);
+    }
+
+    pub fn queue(&self, keys: &[u8]) {
+        self.0.borrow_mut().queued.extend(keys);
+    }
+
+    pub fn replay(&self, keys: VecDeque<(u64, u8)>) {
+        self.0.borrow_mut().replay = Some(keys);
+    }
+
+    /// Replaces the tap, returning the one it replaces.
+    pub fn set_tap(&self, tap: Option<OutputTap>) -> Option<OutputTap> {
+        mem::replace(&mut self.0.borrow_mut().tap, tap)
+    }
+
+    pub fn set_timing(&self, timing: DeviceTiming) {
+        self.0.borrow_mut().timing = timing;
+    }
+
+    pub fn set_options(&self, options: ConsoleOptions) {
+        self.0.borrow_mut().options = options;
+    }
+
+    /// Whether KBSR reports a key, which with a key delay lags behind the host. A key
+    /// that becomes ready is taken from the host and latched into KBDR, so the program
+    /// reads the key that made KBSR ready no matter what is typed in between. If the
+    /// program is `reading` a register when the input has ended,
+    /// [`SharedIo::input_exhausted`] is set, so polling for a key that will never come
+    /// stops the program.
+    fn keyboard_ready(&self, reading: bool) -> bool {
+        let mut state = self.0.borrow_mut();
+        if state.key_latched {
+            return true;
+        }
+        if !state.key_ready() {
+            return false;
+        }
+        let delay = state.timing.key_delay;
+        if *state.key_delay_left.get_or_insert(delay) != 0 {
+            return false;
+        }
+
+        state.key_delay_left = None;
+        match state.next_key() {
+            Some(byte) => {
+                state.kbdr = byte;
+                state.key_latched = true;
+                true
+            }
+            None => {
+                state.input_exhausted |= reading;
+                false
+            }
+        }
+    }
+
+    /// Reads KBDR: the latched key, which clears KBSR's ready bit. Reading it again
+    /// returns the same key until a new one is latched.
+    fn read_kbdr(&self) -> u8 {
+        self.keyboard_ready(true);
+        let mut state = self.0.borrow_mut();
+        state.key_latched = false;
+        state.kbdr
+    }
+
+    fn display_ready(&self) -> bool {
+        self.0.borrow().display_busy_left == 0
+    }
+
+    /// Counts an executed instruction for the [`DeviceTiming`] delays, run by the vm's
+    /// scheduler.
+    pub(crate) fn tick(&self) {
+        let mut state = self.0.borrow_mut();
+        state.display_busy_left = state.display_busy_left.saturating_sub(1);
+        if let Some(left) = &mut state.key_delay_left {
+            *left = left.saturating_sub(1);
+        }
+    }
+
+    pub fn set(&self, io: Box<dyn IoDevice>) {
+        self.0.borrow_mut().io = io;
+    }
+
+    /// Waits for the next key, starting with one latched in KBDR that the program hasn't
+    /// read yet, and writes it back if `echo` is set. At the end of the input returns 0
+    /// and sets [`SharedIo::input_exhausted`], unless the console delivers EOT instead.
+    pub fn read_key(&self, echo: bool) -> io::Result<u8> {
+        let key = {
+            let mut state = self.0.borrow_mut();
+            if state.key_latched {
+                state.key_latched = false;
+                Some(state.kbdr)
+            } else {
+                let key = state.next_key();
+                state.kbdr = key.unwrap_or(state.kbdr);
+                state.input_exhausted |= key.is_none();
+                key
+            }
+        };
+
+        match key {
+            Some(EOT) | None => Ok(key.unwrap_or(0)),
+            Some(byte) => {
+                if echo {
+                    self.write(&[byte])?;
+                    self.flush()?;
+                }
+                Ok(byte)
+            }
+        }
+    }
+
+    pub fn input_exhausted(&self) -> bool {
+        self.0.borrow().input_exhausted
+    }
+
+    /// Writes `bytes` to the console, translating newlines if the options say so.
+    pub fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.0.borrow_mut();
+        let Some(&last) = bytes.last() else {
+            return Ok(());
+        };
+        if let Some(tap) = &mut state.tap {
+            tap(bytes);
+        }
+        if !state.options.crlf {
+            state.last_written = last;
+            return state.io.write(bytes);
+        }
+
+        let mut translated = Vec::with_capacity(bytes.len());
+        let mut prev = state.last_written;
+        for &byte in bytes {
+            if byte == b'\n' && prev != b'\r' {
+                translated.push(b'\r');
+            }
+            translated.push(byte);
+            prev = byte;
+        }
+        state.last_written = last;
+        state.io.write(&translated)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.borrow_mut().io.flush()
+    }
+
+    pub fn interrupt_enable(&self) -> bool {
+        self.0.borrow().interrupt_enable
+    }
+
+    pub fn set_interrupt_enable(&self, enable: bool) {
+        self.0.borrow_mut().interrupt_enable = enable;
+    }
+}
+
+/// KBSR and KBDR of the main console.
+pub struct Keyboard {
+    kbsr: u16,
+    kbdr: u16,
+    io: SharedIo,
+}
+
+impl Keyboard {
+    pub(crate) fn new(addrs: ConsoleAddrs, io: SharedIo) -> Self {
+        Self {
+            kbsr: addrs.kbsr,
+            kbdr: addrs.kbdr,
+            io,
+        }
+    }
+
+    /// The smallest range covering both registers.
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.kbsr.min(self.kbdr)..=self.kbsr.max(self.kbdr)
+    }
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, addr: u16) -> u16 {
+        if addr == self.kbsr {
+            let ready = if self.io.keyboard_ready(true) {
+                STATUS_READY
+            } else {
+                0
+            };
+            ready | (self.io.interrupt_enable() as u16 * KBSR_IE)
+        } else {
+            self.io.read_kbdr() as u16
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        if addr == self.kbsr {
+            self.io.set_interrupt_enable(val & KBSR_IE != 0);
+        }
+        Ok(())
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        addr == self.kbsr || addr == self.kbdr
+    }
+
+    fn interrupt(&mut self) -> Option<Interrupt> {
+        (self.io.interrupt_enable() && self.io.keyboard_ready(false)).then_some(Interrupt {
+            vector: KEYBOARD_INTERRUPT,
+            priority: KEYBOARD_PRIORITY,
+        })
+    }
+}
+
+/// DSR and DDR of the main console.
+pub struct Display {
+    dsr: u16,
+    ddr: u16,
+    io: SharedIo,
+}
+
+impl Display {
+    pub(crate) fn new(addrs: ConsoleAddrs, io: SharedIo) -> Self {
+        Self {
+            dsr: addrs.dsr,
+            ddr: addrs.ddr,
+            io,
+        }
+    }
+
+    /// The smallest range covering both registers.
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.dsr.min(self.ddr)..=self.dsr.max(self.ddr)
+    }
+}
+
+impl Device for Display {
+    fn read(&mut self, addr: u16) -> u16 {
+        if addr == self.dsr && self.io.display_ready() {
+            STATUS_READY
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        if addr == self.ddr && self.io.display_ready() {
+            self.io.write(&[val as u8])?;
+            self.io.flush()?;
+            let mut state = self.io.0.borrow_mut();
+            state.display_busy_left = state.timing.display_busy;
+        }
+        Ok(())
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        addr == self.dsr || addr == self.ddr
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleReg {
+    Kbsr,
+    Kbdr,
+    Dsr,
+    Ddr,
+}
+
+/// Addresses of the four registers of a console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleAddrs {
+    pub kbsr: u16,
+    pub kbdr: u16,
+    pub dsr: u16,
+    pub ddr: u16,
+}
+
+impl Default for ConsoleAddrs {
+    fn default() -> Self {
+        Self {
+            kbsr: 0xFE00,
+            kbdr: 0xFE02,
+            dsr: 0xFE04,
+            ddr: 0xFE06,
+        }
+    }
+}
+
+impl ConsoleAddrs {
+    /// Parses `KBSR,KBDR,DSR,DDR`, e.g. `xFE10,xFE12,xFE14,xFE16`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let addrs = s
+            .split(',')
+            .map(|addr| match parse_literal(addr) {
+                Some(n) if (0..=0xFFFF).contains(&n) => Ok(n as u16),
+                _ => bail!("bad device address: {addr}"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        match addrs[..] {
+            [kbsr, kbdr, dsr, ddr] => Ok(Self {
+                kbsr,
+                kbdr,
+                dsr,
+                ddr,
+            }),
+            _ => bail!("expected four addresses: KBSR,KBDR,DSR,DDR"),
+        }
+    }
+
+    /// The smallest range covering all four registers.
+    pub fn range(&self) -> RangeInclusive<u16> {
+        let addrs = [self.kbsr, self.kbdr, self.dsr, self.ddr];
+        *addrs.iter().min().unwrap()..=*addrs.iter().max().unwrap()
+    }
+
+    pub fn register(&self, addr: u16) -> Option<ConsoleReg> {
+        if addr == self.kbsr {
+            Some(ConsoleReg::Kbsr)
+        } else if addr == self.kbdr {
+            Some(ConsoleReg::Kbdr)
+        } else if addr == self.dsr {
+            Some(ConsoleReg::Dsr)
+        } else if addr == self.ddr {
+            Some(ConsoleReg::Ddr)
+        } else {
+            None
+        }
+    }
+}
+
+/// A console fed by its own input stream, read in the background so KBSR can be polled
+/// without blocking.
+pub struct ExtraConsole {
+    pub addrs: ConsoleAddrs,
+    input: Receiver<u8>,
+    next_key: Option<u8>,
+    // the last key read from KBDR, which it keeps returning until the next one
+    kbdr: u8,
+    output: Box<dyn Write>,
+}
+
+impl ExtraConsole {
+    pub fn new(
+        addrs: ConsoleAddrs,
+        input: impl Read + Send + 'static,
+        output: Box<dyn Write>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for byte in BufReader::new(input).bytes() {
+                match byte {
+                    Ok(byte) if tx.send(byte).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+
+        Self {
+            addrs,
+            input: rx,
+            next_key: None,
+            kbdr: 0,
+            output,
+        }
+    }
+
+    /// Connects to the Unix socket at `path`, which another process listens on.
+    #[cfg(unix)]
+    pub fn connect(addrs: ConsoleAddrs, path: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Self::new(addrs, stream.try_clone()?, Box::new(stream)))
+    }
+
+    /// Creates a Unix socket at `path` and waits for another process to connect to it.
+    /// The socket file is removed once connected.
+    #[cfg(unix)]
+    pub fn listen(addrs: ConsoleAddrs, path: impl AsRef<Path>) -> io::Result<Self> {
+        let (stream, _) = UnixListener::bind(&path)?.accept()?;
+        std::fs::remove_file(path)?;
+        Ok(Self::new(addrs, stream.try_clone()?, Box::new(stream)))
+    }
+
+    fn key_ready(&mut self) -> bool {
+        if self.next_key.is_none() {
+            self.next_key = self.input.try_recv().ok();
+        }
+
+        self.next_key.is_some()
+    }
+}
+
+impl Device for ExtraConsole {
+    fn read(&mut self, addr: u16) -> u16 {
+        let Some(reg) = self.addrs.register(addr) else {
+            return 0;
+        };
+        match reg {
+            ConsoleReg::Kbsr => {
+                if self.key_ready() {
+                    STATUS_READY
+                } else {
+                    0
+                }
+            }
+            ConsoleReg::Kbdr => {
+                self.key_ready();
+                if let Some(key) = self.next_key.take() {
+                    self.kbdr = key;
+                }
+                self.kbdr as u16
+            }
+            ConsoleReg::Dsr => STATUS_READY,
+            ConsoleReg::Ddr => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        if self.addrs.register(addr) == Some(ConsoleReg::Ddr) {
+            self.output.write_all(&[val as u8])?;
+            self.output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        self.addrs.register(addr).is_some()
+    }
+}
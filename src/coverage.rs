@@ -0,0 +1,172 @@
+//! Which addresses a run executed and which way its conditional branches went, for
+//! checking that tests exercise all of a program. Enabled with [`Vm::enable_coverage`].
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Write},
+    ops::RangeInclusive,
+};
+
+use crate::{
+    decode::{decode, Instruction},
+    disasm::disassemble_with_symbols,
+    vm::Vm,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    executed: BTreeMap<u16, u64>,
+    // conditional branches, as (taken, not taken)
+    branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Coverage {
+    /// Counts the instruction `inst` at `pc`, which executed with the condition codes
+    /// `cc`.
+    pub(crate) fn record(&mut self, pc: u16, inst: u16, cc: u16) {
+        *self.executed.entry(pc).or_default() += 1;
+
+        // BR and BRnzp always branch and NOP never does, so only the others count
+        if let Ok(Instruction::Br {
+            nzp: nzp @ 0b001..=0b110,
+            ..
+        }) = decode(inst)
+        {
+            let (taken, not_taken) = self.branches.entry(pc).or_default();
+            if nzp & cc != 0 {
+                *taken += 1;
+            } else {
+                *not_taken += 1;
+            }
+        }
+    }
+
+    /// How many times the instruction at `addr` was executed.
+    pub fn count(&self, addr: u16) -> u64 {
+        self.executed.get(&addr).copied().unwrap_or_default()
+    }
+
+    /// How many times the conditional branch at `addr` was taken and not taken.
+    pub fn branch(&self, addr: u16) -> Option<(u64, u64)> {
+        self.branches.get(&addr).copied()
+    }
+
+    /// The report as JSON. `ranges` are the program's addresses, usually those of the
+    /// loaded images; the summary counts how many of them executed, so data words count
+    /// as not covered.
+    pub fn to_json(&self, ranges: &[RangeInclusive<u16>]) -> String {
+        let total: usize = ranges.iter().map(|range| range.clone().count()).sum();
+        let covered = self
+            .executed
+            .keys()
+            .filter(|addr| ranges.iter().any(|range| range.contains(addr)))
+            .count();
+        let fully_covered = self
+            .branches
+            .values()
+            .filter(|&&(taken, not_taken)| taken > 0 && not_taken > 0)
+            .count();
+
+        let mut json = String::from(r#"{"ranges":["#);
+        for (i, range) in ranges.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(json, r#"{sep}[{},{}]"#, range.start(), range.end()).unwrap();
+        }
+        write!(
+            json,
+            r#"],"addresses":{total},"covered":{covered},"branches":{},"branches_covered":{fully_covered},"executed":{{"#,
+            self.branches.len()
+        )
+        .unwrap();
+        for (i, (addr, count)) in self.executed.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(json, r#"{sep}"{addr}":{count}"#).unwrap();
+        }
+        json.push_str(r#"},"branch_counts":{"#);
+        for (i, (addr, (taken, not_taken))) in self.branches.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(
+                json,
+                r#"{sep}"{addr}":{{"taken":{taken},"not_taken":{not_taken}}}"#
+            )
+            .unwrap();
+        }
+        json.push_str("}}");
+
+        json
+    }
+
+    /// Disassembles `ranges` of `vm`'s memory with each line's execution count, marking
+    /// lines that never ran with `#####` and branches that only went one way.
+    pub fn write_listing(
+        &self,
+        vm: &Vm,
+        ranges: &[RangeInclusive<u16>],
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let symbols = vm.symbols();
+        for range in ranges {
+            for addr in range.clone() {
+                let Some(&inst) = vm.memory().get(addr as usize) else {
+                    break;
+                };
+                if let Some(name) = symbols.name_at(addr) {
+                    writeln!(out, "{:>10}{name}:", "")?;
+                }
+
+                let count = match self.count(addr) {
+                    0 => "#####".to_string(),
+                    count => count.to_string(),
+                };
+                let line = format!(
+                    "{count:>8}  x{addr:04X}: x{inst:04X}  {}",
+                    disassemble_with_symbols(inst, addr, symbols)
+                );
+                match self.branch(addr) {
+                    Some((taken, not_taken)) if taken == 0 || not_taken == 0 => {
+                        writeln!(out, "{line:<48}; taken {taken}, not taken {not_taken}")?
+                    }
+                    _ => writeln!(out, "{line}")?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console::StreamIo, vm::Flag};
+
+    #[test]
+    fn test_coverage() {
+        // ADD R0, R0, #1; BRp #1; ADD R1, R1, #1; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[0x30, 0x00, 0x10, 0x21, 0x02, 0x01, 0x12, 0x61, 0xF0, 0x25])
+            .unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        vm.enable_coverage();
+        vm.run().unwrap();
+
+        let coverage = vm.coverage().unwrap();
+        assert_eq!(coverage.count(0x3000), 1);
+        assert_eq!(coverage.count(0x3002), 0);
+        assert_eq!(coverage.branch(0x3001), Some((1, 0)));
+
+        let ranges = [0x3000..=0x3003];
+        assert_eq!(
+            coverage.to_json(&ranges),
+            r#"{"ranges":[[12288,12291]],"addresses":4,"covered":3,"branches":1,"branches_covered":0,"executed":{"12288":1,"12289":1,"12291":1},"branch_counts":{"12289":{"taken":1,"not_taken":0}}}"#
+        );
+
+        let mut listing = Vec::new();
+        coverage.write_listing(&vm, &ranges, &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+        assert!(lines[1].ends_with("; taken 1, not taken 0"), "{listing}");
+        assert!(lines[2].starts_with("   #####  x3002: x1261"), "{listing}");
+    }
+}
@@ -0,0 +1,109 @@
+//! What instructions cost in simulated cycles, see [`crate::vm::Vm::set_cycle_model`].
+//!
+//! A model can be read from a TOML file; everything left out keeps its default:
+//!
+//! ```toml
+//! memory_read = 2     # every read, instruction fetches included
+//! memory_write = 2
+//! device_access = 10  # on top of the read or write, for device registers
+//!
+//! [opcodes]           # executing the instruction, after fetching it
+//! LDI = 3
+//! TRAP = 5
+//! ```
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::vm::Opcode;
+
+const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR", "RTI", "NOT", "LDI", "STI", "JMP",
+    "RESERVED", "LEA", "TRAP",
+];
+
+/// Cycles per opcode and per memory access. The default charges one cycle to execute any
+/// instruction and one per memory access, so an LDI costs four: fetch, execute, pointer
+/// and value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleModel {
+    /// Indexed by opcode, see [`CycleModel::set_opcode`].
+    pub opcodes: [u64; 16],
+    pub memory_read: u64,
+    pub memory_write: u64,
+    /// Added to reads and writes of device registers.
+    pub device_access: u64,
+}
+
+impl Default for CycleModel {
+    fn default() -> Self {
+        Self {
+            opcodes: [1; 16],
+            memory_read: 1,
+            memory_write: 1,
+            device_access: 0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelFile {
+    memory_read: Option<u64>,
+    memory_write: Option<u64>,
+    device_access: Option<u64>,
+    #[serde(default)]
+    opcodes: BTreeMap<String, u64>,
+}
+
+impl CycleModel {
+    /// Parses a model in the TOML format described in the [module docs](self). Opcodes
+    /// are named by their mnemonics, with JSR covering JSRR and JMP covering RET.
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: ModelFile = toml::from_str(text)?;
+
+        let mut model = Self::default();
+        model.memory_read = file.memory_read.unwrap_or(model.memory_read);
+        model.memory_write = file.memory_write.unwrap_or(model.memory_write);
+        model.device_access = file.device_access.unwrap_or(model.device_access);
+        for (name, cycles) in file.opcodes {
+            let Some(op) = OPCODE_NAMES
+                .iter()
+                .position(|op| op.eq_ignore_ascii_case(&name))
+            else {
+                bail!("unknown opcode: {name}");
+            };
+            model.opcodes[op] = cycles;
+        }
+
+        Ok(model)
+    }
+
+    pub fn opcode(&self, op: Opcode) -> u64 {
+        self.opcodes[op as usize]
+    }
+
+    pub fn set_opcode(&mut self, op: Opcode, cycles: u64) {
+        self.opcodes[op as usize] = cycles;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let model = CycleModel::parse("memory_read = 2\n[opcodes]\nldi = 3\nTRAP = 5\n").unwrap();
+        assert_eq!(model.memory_read, 2);
+        assert_eq!(model.memory_write, 1);
+        assert_eq!(model.opcode(Opcode::Ldi), 3);
+        assert_eq!(model.opcode(Opcode::Trap), 5);
+        assert_eq!(model.opcode(Opcode::Add), 1);
+
+        assert!(CycleModel::parse("[opcodes]\nJSRR = 2\n").is_err());
+        assert!(CycleModel::parse("memory = 2\n").is_err());
+    }
+}
@@ -0,0 +1,995 @@
+//! Interactive debugger, entered with `--debug` before the program starts.
+
+use std::{
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    annotations::Annotations,
+    disasm::disassemble_with_symbols,
+    predicate::Predicate,
+    terminal::{catch_interrupts, enable_raw_mode, interrupted, take_interrupt},
+    util::{parse_literal, parse_register},
+    vm::{CallKind, HookAction, R7Check, RunResult, Vm, VmError, WatchKind},
+};
+
+const HELP: &str = "\
+Commands:
+    break|b [ADDR] [if COND]
+                            set a breakpoint that stops when COND holds, or always
+                            without it; stops at any address while COND holds
+                            without ADDR; lists breakpoints without arguments
+    tbreak VECTOR           stop before every TRAP VECTOR
+    delete|d ADDR           remove a breakpoint
+    delete tbreak VECTOR    remove a trap breakpoint
+    delete if N             remove the Nth condition listed by `break`
+    watch|w [ADDR [KIND]]   stop when ADDR is accessed, KIND is read, write (default)
+                            or rw; lists watchpoints without ADDR
+    unwatch ADDR            remove a watchpoint
+    step|s [N]              execute N instructions (default 1)
+    next|n                  step, running subroutine calls and traps to completion
+    finish                  run until the current subroutine returns
+    continue|c              run until a breakpoint, watchpoint or HALT
+    back|bs [N]             undo the last N instructions (default 1)
+    reverse-continue|rc     undo instructions until a breakpoint or the start of the
+                            history; device state and console I/O are not undone
+    r7check [off|warn|strict]
+                            check that RETs return to their callers, warning or
+                            stopping when R7 was overwritten; shows the setting
+                            without an argument
+    regs|r                  show registers
+    bt                      show the calls that haven't returned yet, innermost first:
+                            where each returns to and the instruction that made it
+    mem|m ADDR [N]          show N words of memory (default 8)
+    disas [ADDR] [N]        disassemble N instructions (default 8) at ADDR or the PC
+    x[/NFMT] ADDR           examine N units (default 1) at ADDR, which can also be a
+                            register or PC; FMT is x for hex words (default), d for
+                            signed decimal words, s for strings or i for instructions,
+                            and may end in w, the word size, e.g. x/4iw PC
+    label ADDR NAME         name ADDR, replacing its label
+    comment ADDR [TEXT]     attach TEXT to ADDR in disassembly, or remove its comment
+    reload FILE [keep]      load the .obj image FILE again after reassembling it,
+                            keeping breakpoints and watchpoints; with keep, the
+                            registers, PC and PSR too, otherwise the PC starts at its
+                            origin; the history is forgotten
+    assert COND             fail unless COND holds, which ends a script
+    help|h                  show this help
+    quit|q [CODE]           exit, with exit status CODE (default 0)
+An empty line repeats the last command. COND compares registers (R0-R7, PC, PSR),
+memory (mem[ADDR], mem[R1]) and numbers with ==, !=, <, <=, > and >=, combined with
+&& and ||, e.g. `R0 == 10 && mem[x4000] != 0`. `in LABEL` holds inside a call to
+LABEL and the routines it calls. Labels and comments are saved to the .notes file
+next to the program and loaded again in the next session.
+";
+
+// instructions `back` can undo
+const HISTORY_LIMIT: usize = 100_000;
+
+enum Flow {
+    Continue,
+    Quit(i32),
+}
+
+pub struct Debugger<'a> {
+    vm: &'a mut Vm,
+    /// Set once the program halted or stopped with an error.
+    finished: bool,
+    last_command: String,
+    annotations: Annotations,
+    /// Where `label` and `comment` save the annotations.
+    annotations_file: Option<PathBuf>,
+}
+
+/// Runs the debugger REPL on stdin and stdout until `quit` or end of input, and
+/// returns the exit status given to `quit`. Ctrl-C pauses the running program; it
+/// replaces any pre-step hook of `vm`. Annotations are read from and saved to
+/// `annotations`, if given.
+pub fn run(vm: &mut Vm, annotations: Option<&Path>) -> Result<i32> {
+    pause_on_interrupt(vm)?;
+    let mut debugger = Debugger::new(vm);
+    if let Some(file) = annotations {
+        debugger.load_annotations(file)?;
+    }
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    writeln!(stdout, "Type `help` for a list of commands.")?;
+    debugger.show_location(&mut stdout)?;
+
+    loop {
+        write!(stdout, "(lc3) ")?;
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            writeln!(stdout)?;
+            return Ok(0);
+        }
+
+        match debugger.execute(&line, &mut stdout) {
+            Ok(Flow::Continue) => (),
+            Ok(Flow::Quit(code)) => return Ok(code),
+            Err(err) => writeln!(stdout, "{err}")?,
+        }
+    }
+}
+
+/// Runs the debugger commands in `script`, one per line, writing each after a prompt
+/// followed by its output to `out`, so the session reads like an interactive one.
+/// Blank lines and lines starting with `#` are skipped. Returns the exit status: the
+/// one given to `quit`, 0 at the end of the script, or 1 after the first command that
+/// fails, e.g. an `assert`.
+pub fn run_script(
+    vm: &mut Vm,
+    script: &str,
+    annotations: Option<&Path>,
+    out: &mut dyn Write,
+) -> Result<i32> {
+    pause_on_interrupt(vm)?;
+    let mut debugger = Debugger::new(vm);
+    if let Some(file) = annotations {
+        debugger.load_annotations(file)?;
+    }
+
+    for line in script.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        writeln!(out, "(lc3) {line}")?;
+        match debugger.execute(line, out) {
+            Ok(Flow::Continue) => (),
+            Ok(Flow::Quit(code)) => return Ok(code),
+            Err(err) => {
+                writeln!(out, "{err}")?;
+                return Ok(1);
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+fn pause_on_interrupt(vm: &mut Vm) -> Result<()> {
+    catch_interrupts(false)?;
+    vm.set_pre_step_hook(|_, _| {
+        if interrupted() {
+            HookAction::Stop
+        } else {
+            HookAction::Continue
+        }
+    });
+    Ok(())
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: &'a mut Vm) -> Self {
+        vm.enable_history(HISTORY_LIMIT);
+        Self {
+            vm,
+            finished: false,
+            last_command: String::new(),
+            annotations: Annotations::default(),
+            annotations_file: None,
+        }
+    }
+
+    /// Reads the labels and comments in `file`, if it exists, and saves them there
+    /// when they change.
+    pub fn load_annotations(&mut self, file: &Path) -> Result<()> {
+        self.annotations = Annotations::read(file)?;
+        for (addr, name) in self.annotations.labels() {
+            self.vm.rename_symbol(addr, name);
+        }
+        self.annotations_file = Some(file.to_path_buf());
+        Ok(())
+    }
+
+    fn save_annotations(&self) -> Result<()> {
+        if let Some(file) = &self.annotations_file {
+            std::fs::write(file, self.annotations.to_file())
+                .map_err(|err| anyhow!("{}: {err}", file.display()))?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, line: &str, out: &mut dyn Write) -> Result<Flow> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => {
+                self.last_command = line.to_string();
+                line.to_string()
+            }
+        };
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Flow::Continue);
+        };
+        let args: Vec<_> = words.collect();
+
+        match command {
+            "break" | "b" => {
+                let (addr, condition) = match args.iter().position(|&arg| arg == "if") {
+                    Some(i) => (
+                        &args[..i],
+                        Some(Predicate::parse(&args[i + 1..].join(" "))?),
+                    ),
+                    None => (&args[..], None),
+                };
+                let addr = match addr {
+                    [] => None,
+                    [addr] => Some(self.parse_addr(addr)?),
+                    _ => bail!("break expects an address and a condition"),
+                };
+                match (addr, condition) {
+                    (Some(addr), Some(condition)) => {
+                        writeln!(out, "Breakpoint at x{addr:04X} if {condition}")?;
+                        self.vm.add_conditional_breakpoint(addr, condition);
+                    }
+                    (Some(addr), None) => {
+                        self.vm.add_breakpoint(addr);
+                        writeln!(out, "Breakpoint at x{addr:04X}")?;
+                    }
+                    (None, Some(condition)) => {
+                        writeln!(out, "Breakpoint if {condition}")?;
+                        self.vm.add_break_condition(condition);
+                    }
+                    (None, None) => self.show_breakpoints(out)?,
+                }
+            }
+            "tbreak" => {
+                let vector = parse_vector(args.first())?;
+                self.vm.add_trap_breakpoint(vector);
+                writeln!(out, "Breakpoint at TRAP x{vector:02X}")?;
+            }
+            "delete" | "d" => match args.first().copied() {
+                Some("tbreak") => {
+                    let vector = parse_vector(args.get(1))?;
+                    if !self.vm.remove_trap_breakpoint(vector) {
+                        bail!("No breakpoint at TRAP x{vector:02X}");
+                    }
+                }
+                Some("if") => {
+                    let n = args
+                        .get(1)
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .ok_or_else(|| anyhow!("delete if expects a condition number"))?;
+                    if n == 0 || self.vm.remove_break_condition(n - 1).is_none() {
+                        bail!("No condition {n}");
+                    }
+                }
+                Some(addr) => {
+                    let addr = self.parse_addr(addr)?;
+                    if !self.vm.remove_breakpoint(addr) {
+                        bail!("No breakpoint at x{addr:04X}");
+                    }
+                }
+                None => bail!("delete expects an address"),
+            },
+            "watch" | "w" => match args.first() {
+                Some(addr) => {
+                    let addr = self.parse_addr(addr)?;
+                    let kind = match args.get(1).copied() {
+                        None | Some("write") => WatchKind::Write,
+                        Some("read") => WatchKind::Read,
+                        Some("rw") => WatchKind::ReadWrite,
+                        Some(kind) => bail!("bad watchpoint kind: {kind}"),
+                    };
+                    self.vm.add_watchpoint(addr, kind);
+                    writeln!(out, "Watchpoint at x{addr:04X}")?;
+                }
+                None => {
+                    for (addr, kind) in self.vm.watchpoints() {
+                        let kind = match kind {
+                            WatchKind::Read => "read",
+                            WatchKind::Write => "write",
+                            WatchKind::ReadWrite => "rw",
+                        };
+                        writeln!(out, "x{addr:04X}: {kind}")?;
+                    }
+                }
+            },
+            "unwatch" => {
+                let addr = self.parse_addr(
+                    args.first()
+                        .ok_or_else(|| anyhow!("unwatch expects an address"))?,
+                )?;
+                if !self.vm.remove_watchpoint(addr) {
+                    bail!("No watchpoint at x{addr:04X}");
+                }
+            }
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(n) => n.parse().map_err(|_| anyhow!("bad step count: {n}"))?,
+                    None => 1,
+                };
+                self.step(count, out)?;
+            }
+            "continue" | "c" => self.run_with(Vm::resume, out)?,
+            "back" | "bs" => {
+                let count = match args.first() {
+                    Some(n) => n.parse().map_err(|_| anyhow!("bad step count: {n}"))?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    if !self.vm.step_back() {
+                        writeln!(out, "Reached the start of the history")?;
+                        break;
+                    }
+                    self.finished = false;
+                }
+                self.show_location(out)?;
+            }
+            "reverse-continue" | "rc" => {
+                if self.vm.history_len() > 0 {
+                    self.finished = false;
+                }
+                match self.vm.reverse_resume() {
+                    Some(addr) => writeln!(out, "Breakpoint at x{addr:04X}")?,
+                    None => writeln!(out, "Reached the start of the history")?,
+                }
+                self.show_location(out)?;
+            }
+            "next" | "n" => self.run_with(Vm::step_over, out)?,
+            "finish" => {
+                if self.vm.call_depth() == 0 {
+                    bail!("Not in a subroutine");
+                }
+                self.run_with(Vm::step_out, out)?;
+            }
+            "r7check" => match args.first().copied() {
+                Some("off") => self.vm.set_r7_check(R7Check::Off),
+                Some("warn") => self.vm.set_r7_check(R7Check::Warn),
+                Some("strict") => self.vm.set_r7_check(R7Check::Strict),
+                Some(mode) => bail!("bad r7check mode: {mode}"),
+                None => {
+                    let mode = match self.vm.r7_check() {
+                        R7Check::Off => "off",
+                        R7Check::Warn => "warn",
+                        R7Check::Strict => "strict",
+                    };
+                    writeln!(out, "r7check {mode}")?;
+                }
+            },
+            "regs" | "r" => self.show_registers(out)?,
+            "bt" => self.show_backtrace(out)?,
+            "mem" | "m" => {
+                let addr = self.parse_addr(
+                    args.first()
+                        .ok_or_else(|| anyhow!("mem expects an address"))?,
+                )?;
+                let count = parse_count(args.get(1))?;
+                self.show_memory(addr, count, out)?;
+            }
+            "disas" => {
+                let addr = match args.first() {
+                    Some(addr) => self.parse_addr(addr)?,
+                    None => self.vm.pc(),
+                };
+                let count = parse_count(args.get(1))?;
+                self.show_disassembly(addr, count, out)?;
+            }
+            _ if command == "x" || command.starts_with("x/") => {
+                let [addr] = args[..] else {
+                    bail!("x expects an address");
+                };
+                self.examine(&command[1..], addr, out)?;
+            }
+            "label" => {
+                let [addr, name] = args[..] else {
+                    bail!("label expects an address and a name");
+                };
+                let addr = self.parse_addr(addr)?;
+                if parse_literal(name).is_some() {
+                    bail!("bad label: {name}");
+                }
+                self.vm.rename_symbol(addr, name);
+                self.annotations.set_label(addr, name);
+                self.save_annotations()?;
+            }
+            "comment" => {
+                let addr = self.parse_addr(
+                    args.first()
+                        .ok_or_else(|| anyhow!("comment expects an address"))?,
+                )?;
+                let text = args[1..].join(" ");
+                self.annotations
+                    .set_comment(addr, Some(text.as_str()).filter(|text| !text.is_empty()));
+                self.save_annotations()?;
+            }
+            "reload" => {
+                let (file, keep) = match args[..] {
+                    [file] => (file, false),
+                    [file, "keep"] => (file, true),
+                    _ => bail!("reload expects a file and optionally `keep`"),
+                };
+                self.vm.reload_image(file, keep)?;
+                self.finished = false;
+                writeln!(out, "Reloaded {file}")?;
+                self.show_location(out)?;
+            }
+            "assert" => {
+                let condition = Predicate::parse(&args.join(" "))?;
+                if !self.vm.holds(&condition) {
+                    bail!("Assertion failed: {condition}");
+                }
+            }
+            "help" | "h" => write!(out, "{HELP}")?,
+            "quit" | "q" => {
+                let code = match args.first() {
+                    Some(code) => code
+                        .parse()
+                        .map_err(|_| anyhow!("bad exit status: {code}"))?,
+                    None => 0,
+                };
+                return Ok(Flow::Quit(code));
+            }
+            _ => bail!("Unknown command: {command}. Type `help` for a list of commands."),
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    fn check_running(&self) -> Result<()> {
+        if self.finished {
+            bail!("The program is not running");
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, count: u32, out: &mut dyn Write) -> Result<()> {
+        self.check_running()?;
+
+        let _terminal = enable_raw_mode()?;
+        // a Ctrl-C at the prompt doesn't stop what comes next
+        take_interrupt();
+        for _ in 0..count {
+            let result = self.vm.step();
+            self.show_warnings(out)?;
+            match result {
+                Ok(RunResult::Halted) => {
+                    self.finished = true;
+                    writeln!(out, "Program halted")?;
+                    return Ok(());
+                }
+                Ok(RunResult::Watchpoint { addr, old, new }) => {
+                    writeln!(out, "Watchpoint x{addr:04X}: x{old:04X} -> x{new:04X}")?;
+                    break;
+                }
+                Ok(RunResult::Stopped) => {
+                    writeln!(out, "{}", stop_reason())?;
+                    break;
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    self.finished = true;
+                    bail!("Program stopped: {err}");
+                }
+            }
+        }
+
+        self.show_location(out)
+    }
+
+    /// Runs the program with `run`, e.g. [`Vm::resume`], and reports where it stopped.
+    fn run_with(
+        &mut self,
+        run: impl FnOnce(&mut Vm) -> Result<RunResult, VmError>,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        self.check_running()?;
+
+        let _terminal = enable_raw_mode()?;
+        take_interrupt();
+        let result = run(self.vm);
+        self.show_warnings(out)?;
+        match result {
+            Ok(RunResult::Running) => self.show_location(out),
+            Ok(RunResult::Breakpoint(addr)) => {
+                writeln!(out, "Breakpoint at x{addr:04X}")?;
+                self.show_location(out)
+            }
+            Ok(RunResult::Watchpoint { addr, old, new }) => {
+                writeln!(out, "Watchpoint x{addr:04X}: x{old:04X} -> x{new:04X}")?;
+                self.show_location(out)
+            }
+            Ok(RunResult::Stopped) => {
+                writeln!(out, "{}", stop_reason())?;
+                self.show_location(out)
+            }
+            Ok(_) => {
+                self.finished = true;
+                writeln!(out, "Program halted")?;
+                Ok(())
+            }
+            Err(err) => {
+                self.finished = true;
+                bail!("Program stopped: {err}");
+            }
+        }
+    }
+
+    fn show_warnings(&mut self, out: &mut dyn Write) -> Result<()> {
+        for warning in self.vm.take_warnings() {
+            writeln!(out, "Warning: {warning}")?;
+        }
+        Ok(())
+    }
+
+    fn show_breakpoints(&self, out: &mut dyn Write) -> Result<()> {
+        for addr in self.vm.breakpoints() {
+            let inst = self.vm.memory()[addr as usize];
+            let asm = disassemble_with_symbols(inst, addr, self.vm.symbols());
+            match self.vm.breakpoint_condition(addr) {
+                Some(condition) => writeln!(out, "x{addr:04X}: {asm}  if {condition}")?,
+                None => writeln!(out, "x{addr:04X}: {asm}")?,
+            }
+        }
+        for vector in self.vm.trap_breakpoints() {
+            writeln!(out, "TRAP x{vector:02X}")?;
+        }
+        for (i, condition) in self.vm.break_conditions().iter().enumerate() {
+            writeln!(out, "{}: if {condition}", i + 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parses a number or a label.
+    fn parse_addr(&self, s: &str) -> Result<u16> {
+        self.vm.symbols().parse_addr(s)
+    }
+
+    /// `x/FMT ADDR`, with `format` the part from the slash on, if any.
+    fn examine(&self, format: &str, addr: &str, out: &mut dyn Write) -> Result<()> {
+        let format = format.strip_prefix('/').unwrap_or(format);
+        let digits = format.len()
+            - format
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        let count = match &format[..digits] {
+            "" => 1,
+            n => n.parse().map_err(|_| anyhow!("bad count: {n}"))?,
+        };
+        let letters = format[digits..]
+            .strip_suffix('w')
+            .unwrap_or(&format[digits..]);
+
+        let addr = if addr.eq_ignore_ascii_case("pc") {
+            self.vm.pc()
+        } else if let Some(r) = parse_register(addr) {
+            self.vm.registers()[r]
+        } else {
+            self.parse_addr(addr)?
+        };
+
+        match letters {
+            "" | "x" => self.show_memory(addr, count, out)?,
+            "i" => self.show_disassembly(addr, count, out)?,
+            "d" => {
+                let memory = self.vm.memory();
+                for addr in (addr as usize..memory.len()).take(count) {
+                    writeln!(out, "x{addr:04X}: {}", memory[addr] as i16)?;
+                }
+            }
+            "s" => {
+                let mut addr = addr;
+                for _ in 0..count {
+                    let s = self.vm.string_at(addr);
+                    writeln!(out, "x{addr:04X}: {s:?}")?;
+                    addr = addr.wrapping_add(s.chars().count() as u16 + 1);
+                }
+            }
+            _ => bail!("bad format: {format}, expected x, d, s or i"),
+        }
+
+        Ok(())
+    }
+
+    fn show_location(&self, out: &mut dyn Write) -> Result<()> {
+        self.show_disassembly(self.vm.pc(), 1, out)
+    }
+
+    fn show_registers(&self, out: &mut dyn Write) -> Result<()> {
+        for (row, regs) in self.vm.registers().chunks(4).enumerate() {
+            let regs: Vec<_> = regs
+                .iter()
+                .enumerate()
+                .map(|(i, val)| format!("R{}: x{val:04X}", row * 4 + i))
+                .collect();
+            writeln!(out, "{}", regs.join("  "))?;
+        }
+
+        let psr = self.vm.psr();
+        let cc = match psr & 0b111 {
+            0b100 => "N",
+            0b010 => "Z",
+            0b001 => "P",
+            _ => "?",
+        };
+        writeln!(out, "PC: x{:04X}  PSR: x{psr:04X}  CC: {cc}", self.vm.pc())?;
+
+        Ok(())
+    }
+
+    fn show_backtrace(&self, out: &mut dyn Write) -> Result<()> {
+        let symbols = self.vm.symbols();
+        let location = |addr: u16| match symbols.describe(addr) {
+            Some(label) => format!("x{addr:04X} {label}"),
+            None => format!("x{addr:04X}"),
+        };
+
+        writeln!(out, "#0  {}", location(self.vm.pc()))?;
+        for (i, frame) in self.vm.backtrace().enumerate() {
+            let call = match frame.kind {
+                CallKind::Handler(vector) => format!("vector x{vector:02X}"),
+                CallKind::Subroutine | CallKind::Trap(_) => {
+                    let inst = self.vm.memory()[frame.caller as usize];
+                    disassemble_with_symbols(inst, frame.caller, symbols)
+                }
+            };
+            writeln!(out, "#{}  {}  ({call})", i + 1, location(frame.ret))?;
+        }
+
+        Ok(())
+    }
+
+    fn show_memory(&self, addr: u16, count: usize, out: &mut dyn Write) -> Result<()> {
+        let memory = self.vm.memory();
+
+        for (i, addr) in (addr as usize..memory.len()).take(count).enumerate() {
+            if i % 8 == 0 {
+                if i != 0 {
+                    writeln!(out)?;
+                }
+                write!(out, "x{addr:04X}:")?;
+            }
+            write!(out, " x{:04X}", memory[addr])?;
+        }
+        writeln!(out)?;
+
+        Ok(())
+    }
+
+    fn show_disassembly(&self, addr: u16, count: usize, out: &mut dyn Write) -> Result<()> {
+        let memory = self.vm.memory();
+        let breakpoints: Vec<_> = self.vm.breakpoints().collect();
+        let symbols = self.vm.symbols();
+
+        for addr in (addr as usize..memory.len()).take(count) {
+            let addr = addr as u16;
+            let marker = match (addr == self.vm.pc(), breakpoints.contains(&addr)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            if let Some(name) = symbols.name_at(addr) {
+                writeln!(out, "{name}:")?;
+            }
+            let inst = memory[addr as usize];
+            let line = format!(
+                "{marker} x{addr:04X}: x{inst:04X}  {}",
+                disassemble_with_symbols(inst, addr, symbols)
+            );
+            match self.annotations.comment(addr) {
+                Some(comment) => writeln!(out, "{line:<36}; {comment}")?,
+                None => writeln!(out, "{line}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Why a step hook stopped the program: Ctrl-C, or one set by an embedder.
+fn stop_reason() -> &'static str {
+    if take_interrupt() {
+        "Paused"
+    } else {
+        "Stopped by a step hook"
+    }
+}
+
+fn parse_vector(s: Option<&&str>) -> Result<u8> {
+    let s = s.ok_or_else(|| anyhow!("expected a trap vector"))?;
+    parse_literal(s)
+        .and_then(|vector| u8::try_from(vector).ok())
+        .ok_or_else(|| anyhow!("bad trap vector: {s}"))
+}
+
+fn parse_count(s: Option<&&str>) -> Result<usize> {
+    match s {
+        Some(n) => n.parse().map_err(|_| anyhow!("bad count: {n}")),
+        None => Ok(8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console::StreamIo, image::ImageBuilder, testkit::vm_with_program, vm::Flag};
+
+    #[test]
+    fn test_debugger() {
+        // ADD R0, R0, #1; ADD R0, R0, #1; ADD R0, R0, #1; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[0x30, 0x00, 0x10, 0x21, 0x10, 0x21, 0x10, 0x21, 0xF0, 0x25])
+            .unwrap();
+        vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+
+        let mut debugger = Debugger::new(&mut vm);
+        let mut out = Vec::new();
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            out.clear();
+            debugger.execute(line, &mut out).unwrap();
+            String::from_utf8(out.clone()).unwrap()
+        };
+
+        run(&mut debugger, "break x3002");
+        assert_eq!(run(&mut debugger, "s"), "=> x3001: x1021  ADD R0, R0, #1\n");
+        assert!(run(&mut debugger, "c").starts_with("Breakpoint at x3002"));
+        assert!(run(&mut debugger, "regs").starts_with("R0: x0002"));
+        assert_eq!(
+            run(&mut debugger, "mem x3000 4"),
+            "x3000: x1021 x1021 x1021 xF025\n"
+        );
+        run(&mut debugger, "watch x4000 rw");
+        assert_eq!(run(&mut debugger, "watch"), "x4000: rw\n");
+        assert_eq!(run(&mut debugger, "c"), "Program halted\n");
+        assert!(debugger.execute("step", &mut Vec::new()).is_err());
+
+        assert_eq!(run(&mut debugger, "back"), "=> x3003: xF025  HALT\n");
+        assert!(run(&mut debugger, "rc").starts_with("Breakpoint at x3002"));
+        assert!(run(&mut debugger, "regs").starts_with("R0: x0002"));
+        assert!(run(&mut debugger, "back 5").starts_with("Reached the start of the history"));
+        assert!(run(&mut debugger, "regs").starts_with("R0: x0000"));
+    }
+
+    #[test]
+    fn test_examine() {
+        // LEA R0, MSG; HALT; #-1; MSG: "hi", "!"
+        let mut vm = vm_with_program(&[0xE002, 0xF025, 0xFFFF, 0x68, 0x69, 0, 0x21, 0]);
+        vm.add_symbols(&[("MSG", 0x3003)].into_iter().collect());
+        vm.set_register(1, 0x3002);
+
+        let mut debugger = Debugger::new(&mut vm);
+        let mut run = |line: &str| {
+            let mut out = Vec::new();
+            debugger.execute(line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(run("x/2xw x3000"), "x3000: xE002 xF025\n");
+        assert_eq!(run("x R1"), "x3002: xFFFF\n");
+        assert_eq!(run("x/d x3002"), "x3002: -1\n");
+        assert_eq!(run("x/2s MSG"), "x3003: \"hi\"\nx3006: \"!\"\n");
+        assert_eq!(
+            run("x/2iw PC"),
+            "=> x3000: xE002  LEA R0, MSG\n   x3001: xF025  HALT\n"
+        );
+        assert!(debugger.execute("x/4z x3000", &mut Vec::new()).is_err());
+        assert!(debugger.execute("x/4x", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_annotations() {
+        let file = std::env::temp_dir().join(format!("lc3-notes-{}.notes", std::process::id()));
+        // BRnzp #0; HALT
+        let mut vm = vm_with_program(&[0x0E00, 0xF025]);
+        vm.add_symbols(&[("L1", 0x3001)].into_iter().collect());
+
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.load_annotations(&file).unwrap();
+        debugger.execute("label L1 DONE", &mut Vec::new()).unwrap();
+        debugger
+            .execute("comment x3000 skips nothing", &mut Vec::new())
+            .unwrap();
+        assert!(debugger.execute("label x3000 x1", &mut Vec::new()).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "label x3001 DONE\ncomment x3000 skips nothing\n"
+        );
+
+        // the next session
+        let mut vm = vm_with_program(&[0x0E00, 0xF025]);
+        vm.add_symbols(&[("L1", 0x3001)].into_iter().collect());
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.load_annotations(&file).unwrap();
+        let mut out = Vec::new();
+        debugger.execute("disas x3000 2", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "=> x3000: x0E00  BRnzp DONE         ; skips nothing\n\
+             DONE:\n   x3001: xF025  HALT\n"
+        );
+
+        debugger.execute("comment x3000", &mut Vec::new()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "label x3001 DONE\n"
+        );
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        // loop: ADD R0, R0, #1; ADD R1, R0, #-5; BRn loop; TRAP x21; STI R0, #1; HALT; x4000
+        let mut vm = vm_with_program(&[0x1021, 0x123B, 0x09FD, 0xF021, 0xB001, 0xF025, 0x4000]);
+
+        let mut debugger = Debugger::new(&mut vm);
+        let mut out = Vec::new();
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            out.clear();
+            debugger.execute(line, &mut out).unwrap();
+            String::from_utf8(out.clone()).unwrap()
+        };
+
+        assert_eq!(
+            run(&mut debugger, "break x3001 if r0 == 3"),
+            "Breakpoint at x3001 if R0 == x0003\n"
+        );
+        run(&mut debugger, "tbreak x21");
+        run(&mut debugger, "break if mem[x4000] != 0");
+        assert_eq!(
+            run(&mut debugger, "break"),
+            "x3001: ADD R1, R0, #-5  if R0 == x0003\n\
+             TRAP x21\n\
+             1: if mem[x4000] != x0000\n"
+        );
+
+        assert!(run(&mut debugger, "c").starts_with("Breakpoint at x3001"));
+        assert!(run(&mut debugger, "regs").starts_with("R0: x0003"));
+        run(&mut debugger, "delete x3001");
+        assert!(run(&mut debugger, "c").starts_with("Breakpoint at x3003"));
+        run(&mut debugger, "delete tbreak x21");
+        assert!(run(&mut debugger, "c").starts_with("Breakpoint at x3005"));
+        assert!(run(&mut debugger, "regs").starts_with("R0: x0005"));
+        run(&mut debugger, "delete if 1");
+        assert!(debugger.execute("delete if 1", &mut Vec::new()).is_err());
+        assert!(debugger.execute("tbreak x100", &mut Vec::new()).is_err());
+        assert_eq!(run(&mut debugger, "c"), "Program halted\n");
+    }
+
+    #[test]
+    fn test_run_script() {
+        // ADD R0, R0, #1; ADD R0, R0, #1; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x1021, 0xF025]);
+
+        let mut out = Vec::new();
+        let script = "# stop before the second add\nbreak x3001\n\nc\nassert R0 == 1\nquit 3\nc\n";
+        assert_eq!(run_script(&mut vm, script, None, &mut out).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "(lc3) break x3001\n\
+             Breakpoint at x3001\n\
+             (lc3) c\n\
+             Breakpoint at x3001\n\
+             => x3001: x1021  ADD R0, R0, #1\n\
+             (lc3) assert R0 == 1\n\
+             (lc3) quit 3\n"
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(
+            run_script(&mut vm, "s\nassert R0 == 1", None, &mut out).unwrap(),
+            1
+        );
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("Assertion failed: R0 == x0001\n"));
+        assert_eq!(run_script(&mut vm, "c", None, &mut Vec::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("lc3-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("prog.obj");
+        // ADD R0, R0, #1; ADD R0, R0, #1; HALT
+        std::fs::write(&file, [0x30, 0x00, 0x10, 0x21, 0x10, 0x21, 0xF0, 0x25]).unwrap();
+
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.read_image(&file).unwrap();
+        vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+        let mut debugger = Debugger::new(&mut vm);
+        let mut out = Vec::new();
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            out.clear();
+            debugger.execute(line, &mut out).unwrap();
+            String::from_utf8(out.clone()).unwrap()
+        };
+
+        run(&mut debugger, "break x3001");
+        assert!(run(&mut debugger, "c").starts_with("Breakpoint at x3001"));
+        // the second ADD becomes ADD R0, R0, #2
+        std::fs::write(&file, [0x30, 0x00, 0x10, 0x21, 0x10, 0x22, 0xF0, 0x25]).unwrap();
+        let reload = format!("reload {} keep", file.display());
+        assert!(run(&mut debugger, &reload).ends_with("=> x3001: x1022  ADD R0, R0, #2\n"));
+        assert!(run(&mut debugger, "back").starts_with("Reached the start of the history"));
+        assert_eq!(run(&mut debugger, "c"), "Program halted\n");
+        assert_eq!(debugger.vm.registers()[0], 3);
+
+        // without keep, the program starts over and the breakpoint still stops it
+        let reload = format!("reload {}", file.display());
+        assert!(run(&mut debugger, &reload).ends_with("=> x3000: x1021  ADD R0, R0, #1\n"));
+        assert!(run(&mut debugger, "c").starts_with("Breakpoint at x3001"));
+        assert_eq!(debugger.vm.registers()[0], 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_backtrace() {
+        // MAIN: JSR SUB; HALT; SUB: JSR SUB2; RET; SUB2: TRAP x21; RET
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        let image = ImageBuilder::new(0x3000)
+            .words(&[0x4801, 0xF025, 0x4801, 0xC1C0, 0xF021, 0xC1C0])
+            .build();
+        vm.load_image_bytes(&image).unwrap();
+        vm.add_symbols(
+            &[("MAIN", 0x3000), ("SUB", 0x3002), ("SUB2", 0x3004)]
+                .into_iter()
+                .collect(),
+        );
+        vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+
+        let mut debugger = Debugger::new(&mut vm);
+        let mut out = Vec::new();
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            out.clear();
+            debugger.execute(line, &mut out).unwrap();
+            String::from_utf8(out.clone()).unwrap()
+        };
+
+        assert_eq!(run(&mut debugger, "bt"), "#0  x3000 MAIN\n");
+        run(&mut debugger, "s 2");
+        assert_eq!(
+            run(&mut debugger, "bt"),
+            "#0  x3004 SUB2\n\
+             #1  x3003 SUB+1  (JSR SUB2)\n\
+             #2  x3001 MAIN+1  (JSR SUB)\n"
+        );
+        run(&mut debugger, "s 2");
+        assert_eq!(debugger.vm.call_depth(), 1);
+        run(&mut debugger, "back 2");
+        assert!(run(&mut debugger, "bt").starts_with("#0  x3004 SUB2\n#1  x3003 SUB+1"));
+    }
+
+    #[test]
+    fn test_next_finish() {
+        // JSR #1; BR #-2; ADD R0, R0, #1; ADD R0, R0, #1; RET
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[
+            0x30, 0x00, 0x48, 0x01, 0x0F, 0xFE, 0x10, 0x21, 0x10, 0x21, 0xC1, 0xC0,
+        ])
+        .unwrap();
+        vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+
+        let mut debugger = Debugger::new(&mut vm);
+        let mut out = Vec::new();
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            out.clear();
+            debugger.execute(line, &mut out).unwrap();
+            String::from_utf8(out.clone()).unwrap()
+        };
+
+        assert!(debugger.execute("finish", &mut Vec::new()).is_err());
+        // the subroutine returns properly, so nothing below prints warnings
+        run(&mut debugger, "r7check warn");
+        assert_eq!(run(&mut debugger, "r7check"), "r7check warn\n");
+        assert_eq!(run(&mut debugger, "next"), "=> x3001: x0FFE  BRnzp x3000\n");
+        assert_eq!(debugger.vm.registers()[0], 2);
+
+        run(&mut debugger, "s 3");
+        assert_eq!(debugger.vm.call_depth(), 1);
+        assert_eq!(
+            run(&mut debugger, "finish"),
+            "=> x3001: x0FFE  BRnzp x3000\n"
+        );
+        assert_eq!(debugger.vm.registers()[0], 4);
+    }
+}
@@ -0,0 +1,271 @@
+//! Instruction words split into their fields, shared by the executor, the disassembler
+//! and the tools that need to know what an instruction does.
+
+use std::fmt;
+
+use crate::vm::{sign_ext, Opcode};
+
+/// The second operand of ADD and AND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegOrImm {
+    Reg(usize),
+    /// A sign-extended 5-bit immediate.
+    Imm(i16),
+}
+
+/// A decoded instruction. Registers are 0-7 and offsets are sign-extended; PC-relative
+/// ones are relative to the address after the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Branch if any of the condition codes in `nzp` (N in bit 2, Z in 1, P in 0) is set.
+    Br {
+        nzp: u16,
+        offset: i16,
+    },
+    Add {
+        dr: usize,
+        sr1: usize,
+        src2: RegOrImm,
+    },
+    Ld {
+        dr: usize,
+        offset: i16,
+    },
+    St {
+        sr: usize,
+        offset: i16,
+    },
+    Jsr {
+        offset: i16,
+    },
+    Jsrr {
+        base: usize,
+    },
+    And {
+        dr: usize,
+        sr1: usize,
+        src2: RegOrImm,
+    },
+    Ldr {
+        dr: usize,
+        base: usize,
+        offset: i16,
+    },
+    Str {
+        sr: usize,
+        base: usize,
+        offset: i16,
+    },
+    Rti,
+    Not {
+        dr: usize,
+        sr: usize,
+    },
+    Ldi {
+        dr: usize,
+        offset: i16,
+    },
+    Sti {
+        sr: usize,
+        offset: i16,
+    },
+    /// `JMP R7` is RET.
+    Jmp {
+        base: usize,
+    },
+    Lea {
+        dr: usize,
+        offset: i16,
+    },
+    Trap {
+        vector: u8,
+    },
+}
+
+/// A word with the reserved opcode, which isn't an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub inst: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x{:04X} has the reserved opcode", self.inst)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes `inst`. Bits the LC-3 requires to be zero or one are ignored, like the
+/// executor always has.
+pub fn decode(inst: u16) -> Result<Instruction, DecodeError> {
+    let r = |shift: u16| (inst >> shift & 0b111) as usize;
+    let offset = |bits: u16| sign_ext(inst, bits) as i16;
+    let src2 = if inst & (1 << 5) != 0 {
+        RegOrImm::Imm(offset(5))
+    } else {
+        RegOrImm::Reg(r(0))
+    };
+
+    Ok(match inst >> 12 {
+        0b0000 => Instruction::Br {
+            nzp: inst >> 9 & 0b111,
+            offset: offset(9),
+        },
+        0b0001 => Instruction::Add {
+            dr: r(9),
+            sr1: r(6),
+            src2,
+        },
+        0b0010 => Instruction::Ld {
+            dr: r(9),
+            offset: offset(9),
+        },
+        0b0011 => Instruction::St {
+            sr: r(9),
+            offset: offset(9),
+        },
+        0b0100 if inst & (1 << 11) != 0 => Instruction::Jsr { offset: offset(11) },
+        0b0100 => Instruction::Jsrr { base: r(6) },
+        0b0101 => Instruction::And {
+            dr: r(9),
+            sr1: r(6),
+            src2,
+        },
+        0b0110 => Instruction::Ldr {
+            dr: r(9),
+            base: r(6),
+            offset: offset(6),
+        },
+        0b0111 => Instruction::Str {
+            sr: r(9),
+            base: r(6),
+            offset: offset(6),
+        },
+        0b1000 => Instruction::Rti,
+        0b1001 => Instruction::Not { dr: r(9), sr: r(6) },
+        0b1010 => Instruction::Ldi {
+            dr: r(9),
+            offset: offset(9),
+        },
+        0b1011 => Instruction::Sti {
+            sr: r(9),
+            offset: offset(9),
+        },
+        0b1100 => Instruction::Jmp { base: r(6) },
+        0b1110 => Instruction::Lea {
+            dr: r(9),
+            offset: offset(9),
+        },
+        0b1111 => Instruction::Trap { vector: inst as u8 },
+        _ => return Err(DecodeError { inst }),
+    })
+}
+
+/// Which bits of `inst` break the LC-3 specification, which requires bits that aren't
+/// operands to be zero, or ones in NOT. [`decode`] ignores them.
+pub fn spec_violation(inst: u16) -> Option<&'static str> {
+    let bits = |mask: u16| inst & mask != 0;
+    match inst >> 12 {
+        0b0001 | 0b0101 if !bits(1 << 5) && bits(0b11 << 3) => Some("bits 4-3 must be zero"),
+        0b0100 if !bits(1 << 11) && bits(0x063F) => Some("bits 10-9 and 5-0 must be zero"),
+        0b1000 if bits(0x0FFF) => Some("bits 11-0 must be zero"),
+        0b1001 if inst & 0x3F != 0x3F => Some("bits 5-0 must be ones"),
+        0b1100 if bits(0x0E3F) => Some("bits 11-9 and 5-0 must be zero"),
+        0b1111 if bits(0x0F00) => Some("bits 11-8 must be zero"),
+        _ => None,
+    }
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Instruction::Br { .. } => Opcode::Br,
+            Instruction::Add { .. } => Opcode::Add,
+            Instruction::Ld { .. } => Opcode::Ld,
+            Instruction::St { .. } => Opcode::St,
+            Instruction::Jsr { .. } | Instruction::Jsrr { .. } => Opcode::Jsr,
+            Instruction::And { .. } => Opcode::And,
+            Instruction::Ldr { .. } => Opcode::Ldr,
+            Instruction::Str { .. } => Opcode::Str,
+            Instruction::Rti => Opcode::Rti,
+            Instruction::Not { .. } => Opcode::Not,
+            Instruction::Ldi { .. } => Opcode::Ldi,
+            Instruction::Sti { .. } => Opcode::Sti,
+            Instruction::Jmp { .. } => Opcode::Jmp,
+            Instruction::Lea { .. } => Opcode::Lea,
+            Instruction::Trap { .. } => Opcode::Trap,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode(0x123A),
+            Ok(Instruction::Add {
+                dr: 1,
+                sr1: 0,
+                src2: RegOrImm::Imm(-6)
+            })
+        );
+        assert_eq!(
+            decode(0x5042),
+            Ok(Instruction::And {
+                dr: 0,
+                sr1: 1,
+                src2: RegOrImm::Reg(2)
+            })
+        );
+        assert_eq!(
+            decode(0x0BFE),
+            Ok(Instruction::Br {
+                nzp: 0b101,
+                offset: -2
+            })
+        );
+        assert_eq!(decode(0x4C00), Ok(Instruction::Jsr { offset: -1024 }));
+        assert_eq!(decode(0x4080), Ok(Instruction::Jsrr { base: 2 }));
+        assert_eq!(
+            decode(0x697D),
+            Ok(Instruction::Ldr {
+                dr: 4,
+                base: 5,
+                offset: -3
+            })
+        );
+        assert_eq!(decode(0xF025), Ok(Instruction::Trap { vector: 0x25 }));
+        assert_eq!(decode(0xD123), Err(DecodeError { inst: 0xD123 }));
+        assert_eq!(decode(0x1218), decode(0x1200));
+
+        // every other word decodes to its own opcode
+        for inst in 0..=u16::MAX {
+            if let Ok(decoded) = decode(inst) {
+                assert_eq!(decoded.opcode() as u16, inst >> 12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spec_violation() {
+        // ADD R1, R0, R0 with bit 3 set, NOT R0, R0 with bit 0 clear, RET with bit 0 set
+        assert_eq!(spec_violation(0x1208), Some("bits 4-3 must be zero"));
+        assert_eq!(spec_violation(0x903E), Some("bits 5-0 must be ones"));
+        assert_eq!(
+            spec_violation(0xC1C1),
+            Some("bits 11-9 and 5-0 must be zero")
+        );
+        assert_eq!(spec_violation(0xF125), Some("bits 11-8 must be zero"));
+        assert_eq!(spec_violation(0x8001), Some("bits 11-0 must be zero"));
+
+        for inst in [
+            0x1200, 0x123F, 0x903F, 0xC1C0, 0x4080, 0x4FFF, 0xF025, 0x8000,
+        ] {
+            assert_eq!(spec_violation(inst), None, "x{inst:04X}");
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Known-good programs bundled into the binary, so a setup can be checked before
+//! debugging your own code. The sources live next to the images in `demos/`.
+
+pub struct Demo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub image: &'static [u8],
+}
+
+pub const DEMOS: &[Demo] = &[
+    Demo {
+        name: "hello",
+        description: "prints a greeting and halts",
+        image: include_bytes!("../demos/hello.obj"),
+    },
+    Demo {
+        name: "guess",
+        description: "guess a number between 1 and 100",
+        image: include_bytes!("../demos/guess.obj"),
+    },
+    Demo {
+        name: "2048",
+        description: "the sliding tile game, played with WASD or the arrow keys",
+        image: include_bytes!("../demos/2048.obj"),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Demo> {
+    DEMOS.iter().find(|demo| demo.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_demos_load() {
+        for demo in DEMOS {
+            let mut vm = Vm::default();
+            vm.load_image_bytes(demo.image).unwrap();
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Memory-mapped devices. A [`Device`] installed with [`crate::vm::Vm::map_device`]
+//! handles the loads and stores to its address range instead of memory. The console
+//! keyboard and display are devices too, see [`crate::console`], and so are the
+//! [`Timer`] and the random number generator [`Rng`].
+
+use std::{
+    cell::{Cell, RefCell},
+    io,
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use crate::{console::STATUS_READY, env::SharedEnv};
+
+/// An interrupt request from a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    /// Entry in the interrupt vector table, x80-xFF.
+    pub vector: u8,
+    /// Priority level 0-7. The interrupt is taken once the processor runs below it.
+    pub priority: u8,
+}
+
+/// A peripheral with registers in the address space.
+pub trait Device {
+    /// Loads the register at `addr`.
+    fn read(&mut self, addr: u16) -> u16;
+
+    /// Stores `val` in the register at `addr`. An error stops the program with
+    /// [`crate::vm::VmError::Io`].
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()>;
+
+    /// Whether the device has a register at `addr`, for devices whose registers don't
+    /// fill their mapped range. Accesses to other addresses in the range fall through to
+    /// the next device or to memory.
+    fn handles(&self, addr: u16) -> bool {
+        let _ = addr;
+        true
+    }
+
+    /// The interrupt the device requests, checked before every instruction. Requests
+    /// are level triggered: the device keeps requesting until the handler has dealt
+    /// with it, e.g. by reading a register.
+    fn interrupt(&mut self) -> Option<Interrupt> {
+        None
+    }
+}
+
+/// A device that is also reached from outside the map, e.g. by a task of
+/// [`crate::vm::Vm::every`] that advances its time.
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, addr: u16) -> u16 {
+        self.borrow_mut().read(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        self.borrow_mut().write(addr, val)
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        self.borrow().handles(addr)
+    }
+
+    fn interrupt(&mut self) -> Option<Interrupt> {
+        self.borrow_mut().interrupt()
+    }
+}
+
+/// Timer control register: bit 15 is set when the interval elapsed and cleared by
+/// reading it, bit 14 enables the interrupt and bit 0 selects milliseconds instead of
+/// instructions as the unit.
+pub const TMR: u16 = 0xFE08;
+/// Timer interval register: the number of units between expiries, 0 stops the timer.
+pub const TMI: u16 = 0xFE0A;
+
+const TMR_IE: u16 = 1 << 14;
+const TMR_MS: u16 = 1;
+
+pub const TIMER_INTERRUPT: u8 = 0x81;
+pub const TIMER_PRIORITY: u8 = 6;
+
+/// A timer that expires every [`TMI`] executed instructions or milliseconds of the
+/// [`crate::env::HostEnv`] and can interrupt the program when it does, for preemptive
+/// scheduling. Writing either register restarts the interval.
+pub struct Timer {
+    control: u16,
+    interval: u16,
+    expired: bool,
+    // instructions executed since the interval started
+    count: u64,
+    // instructions executed since the timer was created, for the env's clock
+    ticks: u64,
+    started_ms: u64,
+    env: SharedEnv,
+}
+
+impl Timer {
+    pub(crate) fn new(env: SharedEnv) -> Self {
+        let started_ms = env.borrow_mut().now_ms(0);
+        Self {
+            control: 0,
+            interval: 0,
+            expired: false,
+            count: 0,
+            ticks: 0,
+            started_ms,
+            env,
+        }
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.env.borrow_mut().now_ms(self.ticks)
+    }
+
+    fn restart(&mut self) {
+        self.count = 0;
+        self.started_ms = self.now_ms();
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, addr: u16) -> u16 {
+        if addr == TMR {
+            let expired = std::mem::take(&mut self.expired);
+            self.control | if expired { STATUS_READY } else { 0 }
+        } else {
+            self.interval
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        if addr == TMR {
+            self.control = val & (TMR_IE | TMR_MS);
+        } else {
+            self.interval = val;
+        }
+        self.restart();
+        Ok(())
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        addr == TMR || addr == TMI
+    }
+
+    fn interrupt(&mut self) -> Option<Interrupt> {
+        (self.expired && self.control & TMR_IE != 0).then_some(Interrupt {
+            vector: TIMER_INTERRUPT,
+            priority: TIMER_PRIORITY,
+        })
+    }
+}
+
+impl Timer {
+    /// Counts an executed instruction, run by the vm's scheduler.
+    pub(crate) fn tick(&mut self) {
+        self.ticks += 1;
+        if self.interval == 0 {
+            return;
+        }
+
+        self.count += 1;
+        let elapsed = if self.control & TMR_MS != 0 {
+            self.now_ms() - self.started_ms
+        } else {
+            self.count
+        };
+        if elapsed >= self.interval as u64 {
+            self.expired = true;
+            self.restart();
+        }
+    }
+}
+
+/// Random number register: each read returns the next pseudo-random word, and a write
+/// seeds the generator with the value written.
+pub const RNG: u16 = 0xFE2C;
+
+/// A splitmix64 generator behind [`RNG`]. The state is shared with the vm, so it can be
+/// reseeded after the device is mapped, see [`crate::vm::Vm::seed_rng`].
+pub struct Rng(pub(crate) Rc<Cell<u64>>);
+
+impl Rng {
+    /// The next word for the seed `state`, advancing it.
+    fn next(state: &Cell<u64>) -> u16 {
+        let x = state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state.set(x);
+        let x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        let x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((x ^ (x >> 31)) >> 48) as u16
+    }
+}
+
+impl Device for Rng {
+    fn read(&mut self, _addr: u16) -> u16 {
+        Self::next(&self.0)
+    }
+
+    fn write(&mut self, _addr: u16, val: u16) -> io::Result<()> {
+        self.0.set(val as u64);
+        Ok(())
+    }
+}
+
+/// Names of the devices every vm starts with besides the main console, which can be
+/// removed with [`crate::vm::Vm::unmap_device`].
+pub const BUILTIN_DEVICES: [&str; 2] = ["timer", "rng"];
+
+pub(crate) struct MappedDevice {
+    pub name: Option<String>,
+    pub range: RangeInclusive<u16>,
+    pub device: Box<dyn Device>,
+}
+
+/// The devices of a vm, looked up in the order they were mapped.
+#[derive(Default)]
+pub(crate) struct DeviceMap {
+    devices: Vec<MappedDevice>,
+}
+
+impl DeviceMap {
+    pub fn map(&mut self, name: Option<&str>, range: RangeInclusive<u16>, device: Box<dyn Device>) {
+        self.devices.push(MappedDevice {
+            name: name.map(str::to_string),
+            range,
+            device,
+        });
+    }
+
+    /// Removes the devices named `name`. Returns `false` if there were none.
+    pub fn unmap(&mut self, name: &str) -> bool {
+        let len = self.devices.len();
+        self.devices
+            .retain(|mapped| mapped.name.as_deref() != Some(name));
+        self.devices.len() != len
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.devices
+            .iter()
+            .filter_map(|mapped| mapped.name.as_deref())
+    }
+
+    /// Replaces the first `count` devices.
+    pub fn replace_front(&mut self, count: usize, devices: Vec<MappedDevice>) {
+        self.devices.splice(..count, devices);
+    }
+
+    /// The highest priority interrupt requested by any device.
+    pub fn interrupt(&mut self) -> Option<Interrupt> {
+        self.devices
+            .iter_mut()
+            .filter_map(|mapped| mapped.device.interrupt())
+            .max_by_key(|interrupt| interrupt.priority)
+    }
+
+    pub fn get(&mut self, addr: u16) -> Option<&mut dyn Device> {
+        self.devices
+            .iter_mut()
+            .find(|mapped| mapped.range.contains(&addr) && mapped.device.handles(addr))
+            .map(|mapped| &mut *mapped.device as &mut dyn Device)
+    }
+}
@@ -0,0 +1,218 @@
+//! Compares two images word by word, e.g. an assembler's output against a reference
+//! build, or two snapshots taken by [`Vm::save_state`](crate::Vm::save_state).
+
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{disasm::disassemble_with_symbols, image::Segment, symbols::SymbolTable, vm::Snapshot};
+
+/// A word the images disagree on. `None` means the image doesn't load anything there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WordDiff {
+    pub addr: u16,
+    pub left: Option<u16>,
+    pub right: Option<u16>,
+}
+
+/// The words at which the segments of `left` and `right` differ, by address. Later
+/// segments overwrite earlier ones, as when loading them.
+pub fn diff_images(left: &[Segment], right: &[Segment]) -> Vec<WordDiff> {
+    let words = |segments: &[Segment]| {
+        let mut words = BTreeMap::new();
+        for segment in segments {
+            words.extend((segment.origin..=0xFFFF).zip(segment.words.iter().copied()));
+        }
+        words
+    };
+    let (left, right) = (words(left), words(right));
+
+    let mut addrs: Vec<_> = left.keys().chain(right.keys()).copied().collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    addrs
+        .into_iter()
+        .map(|addr| WordDiff {
+            addr,
+            left: left.get(&addr).copied(),
+            right: right.get(&addr).copied(),
+        })
+        .filter(|diff| diff.left != diff.right)
+        .collect()
+}
+
+/// Writes `diffs` like a unified diff: a line with the address, and its label from
+/// either table, then the left word prefixed with `-` and the right one with `+`, each
+/// disassembled with its own symbols.
+pub fn write_diff(
+    diffs: &[WordDiff],
+    left_symbols: &SymbolTable,
+    right_symbols: &SymbolTable,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    for diff in diffs {
+        let label = left_symbols
+            .describe(diff.addr)
+            .or_else(|| right_symbols.describe(diff.addr));
+        match label {
+            Some(label) => writeln!(out, "@ x{:04X} {label}", diff.addr)?,
+            None => writeln!(out, "@ x{:04X}", diff.addr)?,
+        }
+
+        for (sign, word, symbols) in [
+            ('-', diff.left, left_symbols),
+            ('+', diff.right, right_symbols),
+        ] {
+            if let Some(word) = word {
+                let inst = disassemble_with_symbols(word, diff.addr, symbols);
+                writeln!(out, "{sign} x{word:04X}  {inst}")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The registers and words at which two snapshots differ. Registers are given by name
+/// with their left and right values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    pub registers: Vec<(String, u64, u64)>,
+    pub memory: Vec<WordDiff>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.memory.is_empty()
+    }
+}
+
+pub fn diff_snapshots(left: &Snapshot, right: &Snapshot) -> SnapshotDiff {
+    let registers = |snapshot: &Snapshot| {
+        let mut registers: Vec<(String, u64)> = (0..8)
+            .map(|r| (format!("R{r}"), u64::from(snapshot.reg[r])))
+            .collect();
+        registers.extend([
+            ("PC".to_string(), u64::from(snapshot.pc)),
+            ("PSR".to_string(), u64::from(snapshot.psr)),
+            ("SSP".to_string(), u64::from(snapshot.saved_ssp)),
+            ("USP".to_string(), u64::from(snapshot.saved_usp)),
+            ("IE".to_string(), u64::from(snapshot.interrupt_enable)),
+            ("instructions".to_string(), snapshot.instructions),
+            ("cycles".to_string(), snapshot.cycles),
+        ]);
+        registers
+    };
+    let registers = registers(left)
+        .into_iter()
+        .zip(registers(right))
+        .filter(|((_, left), (_, right))| left != right)
+        .map(|((name, left), (_, right))| (name, left, right))
+        .collect();
+
+    let len = left.memory.len().max(right.memory.len());
+    let memory = (0..len)
+        .map(|i| WordDiff {
+            addr: i as u16,
+            left: left.memory.get(i).copied(),
+            right: right.memory.get(i).copied(),
+        })
+        .filter(|diff| diff.left != diff.right)
+        .collect();
+
+    SnapshotDiff { registers, memory }
+}
+
+/// Writes the changed registers as `NAME: left -> right`, then the changed words as
+/// [`write_diff`] does, with `symbols` for both sides.
+pub fn write_snapshot_diff(
+    diff: &SnapshotDiff,
+    symbols: &SymbolTable,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    for (name, left, right) in &diff.registers {
+        match name.as_str() {
+            "instructions" | "cycles" | "IE" => writeln!(out, "{name}: {left} -> {right}")?,
+            _ => writeln!(out, "{name}: x{left:04X} -> x{right:04X}")?,
+        }
+    }
+    write_diff(&diff.memory, symbols, symbols, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_images() {
+        let left = [Segment {
+            origin: 0x3000,
+            words: vec![0x1021, 0xF025],
+        }];
+        let right = [Segment {
+            origin: 0x3000,
+            words: vec![0x1022, 0xF025, 0x0041],
+        }];
+
+        let diffs = diff_images(&left, &right);
+        assert_eq!(
+            diffs,
+            [
+                WordDiff {
+                    addr: 0x3000,
+                    left: Some(0x1021),
+                    right: Some(0x1022)
+                },
+                WordDiff {
+                    addr: 0x3002,
+                    left: None,
+                    right: Some(0x0041)
+                },
+            ]
+        );
+        assert!(diff_images(&left, &left).is_empty());
+
+        let symbols = [("MAIN", 0x3000)].into_iter().collect();
+        let mut out = Vec::new();
+        write_diff(&diffs, &symbols, &SymbolTable::default(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@ x3000 MAIN\n\
+             - x1021  ADD R0, R0, #1\n\
+             + x1022  ADD R0, R0, #2\n\
+             @ x3002 MAIN+2\n\
+             + x0041  NOP x3044\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let left = Snapshot {
+            instructions: 2,
+            cycles: 4,
+            pc: 0x3002,
+            psr: 0x8002,
+            saved_ssp: 0x3000,
+            saved_usp: 0,
+            interrupt_enable: false,
+            reg: [0; 8],
+            memory: vec![0x1021, 0xF025, 0],
+        };
+        let mut right = left.clone();
+        right.instructions = 3;
+        right.reg[0] = 1;
+        right.memory[2] = 0x0041;
+        assert!(diff_snapshots(&left, &left).is_empty());
+
+        let diff = diff_snapshots(&left, &right);
+        let symbols = [("DATA", 2)].into_iter().collect();
+        let mut out = Vec::new();
+        write_snapshot_diff(&diff, &symbols, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "R0: x0000 -> x0001\n\
+             instructions: 2 -> 3\n\
+             @ x0002 DATA\n\
+             - x0000  NOP DATA+1\n\
+             + x0041  NOP DATA+66\n"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Differential testing: runs a program on the vm and compares its trace, instruction by
+//! instruction, with one from a reference simulator, reporting where they first disagree.
+//!
+//! The reference trace is either a file, in the binary format of [`crate::trace`] or as
+//! text lines like those of `trace-dump`, or the standard output of a command that runs
+//! the images. Reference simulators such as lc3sim or lc3tools need a small adapter that
+//! prints their state in that text format:
+//!
+//! ```text
+//! pc=0x3000 inst=0x1021 r0=0x0001 r1=0x0000 ... r7=0x0000 psr=0x8001 mem[0x4000]=0x0001
+//! ```
+//!
+//! Each line is the state after one instruction; lines that don't start with `pc=` are
+//! skipped, so the command can print other output.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    disasm::disassemble,
+    trace::{TraceReader, TraceRecord, TraceWriter, MAGIC},
+    util::SharedBuf,
+    vm::{Vm, VmError},
+};
+
+/// Runs `vm`, which should have its images, input and instruction limit set up, and
+/// returns its trace and the error it stopped with, if any. Replaces any trace of `vm`.
+pub fn trace_vm(vm: &mut Vm) -> Result<(Vec<TraceRecord>, Option<VmError>)> {
+    let trace = SharedBuf::default();
+    vm.set_trace(TraceWriter::new(Box::new(trace.clone()))?);
+    let error = vm.run().err();
+
+    let bytes = trace.0.borrow();
+    let records = TraceReader::new(&bytes[..])?.collect::<io::Result<_>>()?;
+    Ok((records, error))
+}
+
+/// Reads a reference trace, binary or text.
+pub fn read_reference(bytes: &[u8]) -> Result<Vec<TraceRecord>> {
+    if bytes.starts_with(MAGIC) {
+        return Ok(TraceReader::new(bytes)?.collect::<io::Result<_>>()?);
+    }
+
+    let text = std::str::from_utf8(bytes).map_err(|_| anyhow!("not a trace file"))?;
+    text.lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("pc="))
+        .map(TraceRecord::parse)
+        .collect()
+}
+
+/// Runs `command`, split on whitespace, with `images` appended as arguments and `input`
+/// on its standard input, and reads the trace it prints.
+pub fn run_reference(command: &str, images: &[String], input: &[u8]) -> Result<Vec<TraceRecord>> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| anyhow!("empty reference command"))?;
+
+    let mut child = Command::new(program)
+        .args(words)
+        .args(images)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("{program}: {err}"))?;
+    // a reference that doesn't read its input closes the pipe early
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(input) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err.into()),
+            _ => (),
+        }
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("{program} failed: {}", output.status);
+    }
+
+    read_reference(&output.stdout)
+}
+
+/// Where two traces first disagree. `None` means that trace ended before `step`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the first differing record.
+    pub step: usize,
+    pub actual: Option<TraceRecord>,
+    pub reference: Option<TraceRecord>,
+}
+
+/// Compares the vm's trace with the reference's and returns where they first differ,
+/// in the PC, the instruction, the registers, the PSR or the memory write.
+pub fn first_divergence(actual: &[TraceRecord], reference: &[TraceRecord]) -> Option<Divergence> {
+    let step = actual
+        .iter()
+        .zip(reference)
+        .position(|(actual, reference)| actual != reference)
+        .unwrap_or(actual.len().min(reference.len()));
+    if step == actual.len() && step == reference.len() {
+        return None;
+    }
+
+    Some(Divergence {
+        step,
+        actual: actual.get(step).cloned(),
+        reference: reference.get(step).cloned(),
+    })
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.step + 1;
+        let (actual, reference) = match (&self.actual, &self.reference) {
+            (Some(actual), Some(reference)) => (actual, reference),
+            (None, Some(reference)) => {
+                return write!(
+                    f,
+                    "the vm stopped after {} instructions, the reference went on at x{:04X}",
+                    self.step, reference.pc
+                );
+            }
+            (Some(actual), None) => {
+                return write!(
+                    f,
+                    "the reference stopped after {} instructions, the vm went on at x{:04X}",
+                    self.step, actual.pc
+                );
+            }
+            (None, None) => return write!(f, "both traces end after {} instructions", self.step),
+        };
+
+        if actual.pc != reference.pc {
+            return write!(
+                f,
+                "instruction {n}: the vm executed x{:04X}, the reference x{:04X}",
+                actual.pc, reference.pc
+            );
+        }
+        write!(
+            f,
+            "instruction {n} at x{:04X} ({}): ",
+            actual.pc,
+            disassemble(actual.inst, actual.pc)
+        )?;
+        if actual.inst != reference.inst {
+            return write!(
+                f,
+                "the vm read x{:04X}, the reference x{:04X}",
+                actual.inst, reference.inst
+            );
+        }
+        if let Some(r) = (0..8).find(|&r| actual.reg[r] != reference.reg[r]) {
+            return write!(
+                f,
+                "R{r} is x{:04X}, the reference has x{:04X}",
+                actual.reg[r], reference.reg[r]
+            );
+        }
+        if actual.psr != reference.psr {
+            return write!(
+                f,
+                "PSR is x{:04X}, the reference has x{:04X}",
+                actual.psr, reference.psr
+            );
+        }
+
+        let write = |write: Option<(u16, u16)>| match write {
+            Some((addr, val)) => format!("wrote x{val:04X} to x{addr:04X}"),
+            None => "wrote nothing".into(),
+        };
+        write!(
+            f,
+            "the vm {}, the reference {}",
+            write(actual.write),
+            write(reference.write)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::vm_with_program;
+
+    #[test]
+    fn test_first_divergence() {
+        // ADD R0, R0, #1; ST R0, #1; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x3001, 0xF025]);
+        let (trace, error) = trace_vm(&mut vm).unwrap();
+        assert!(error.is_none());
+        assert_eq!(trace.len(), 3);
+
+        let text: String = trace
+            .iter()
+            .map(|record| format!("{record}\nother output\n"))
+            .collect();
+        let reference = read_reference(text.as_bytes()).unwrap();
+        assert_eq!(first_divergence(&trace, &reference), None);
+
+        let mut reference = trace.clone();
+        reference[1].write = Some((0x3003, 2));
+        let divergence = first_divergence(&trace, &reference).unwrap();
+        assert_eq!(divergence.step, 1);
+        assert_eq!(
+            divergence.to_string(),
+            "instruction 2 at x3001 (ST R0, x3003): the vm wrote x0001 to x3003, \
+             the reference wrote x0002 to x3003"
+        );
+
+        reference[0].reg[0] = 2;
+        assert_eq!(
+            first_divergence(&trace, &reference).unwrap().to_string(),
+            "instruction 1 at x3000 (ADD R0, R0, #1): R0 is x0001, the reference has x0002"
+        );
+
+        assert_eq!(
+            first_divergence(&trace[..2], &trace).unwrap().to_string(),
+            "the vm stopped after 2 instructions, the reference went on at x3002"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Turns instruction words back into LC-3 assembly.
+
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    annotations::Annotations,
+    decode::{decode, Instruction, RegOrImm},
+    symbols::SymbolTable,
+};
+
+/// Disassembles `inst`, located at `addr`. PC-relative operands are shown as the
+/// absolute address they refer to.
+pub fn disassemble(inst: u16, addr: u16) -> String {
+    disassemble_with_symbols(inst, addr, &SymbolTable::default())
+}
+
+/// Like [`disassemble`], but shows PC-relative operands as labels from `symbols` where
+/// possible, e.g. `BRp LOOP+2`.
+pub fn disassemble_with_symbols(inst: u16, addr: u16, symbols: &SymbolTable) -> String {
+    let target =
+        |offset: i16| symbols.format_addr(addr.wrapping_add(1).wrapping_add_signed(offset));
+    let src2 = |src2| match src2 {
+        RegOrImm::Reg(r) => format!("R{r}"),
+        RegOrImm::Imm(imm) => format!("#{imm}"),
+    };
+
+    let Ok(decoded) = decode(inst) else {
+        // the reserved opcode
+        return format!(".FILL x{inst:04X}");
+    };
+    match decoded {
+        // never taken
+        Instruction::Br { nzp: 0, offset } => format!("NOP {}", target(offset)),
+        Instruction::Br { nzp, offset } => {
+            let n = if nzp & 0b100 != 0 { "n" } else { "" };
+            let z = if nzp & 0b010 != 0 { "z" } else { "" };
+            let p = if nzp & 0b001 != 0 { "p" } else { "" };
+            format!("BR{n}{z}{p} {}", target(offset))
+        }
+        Instruction::Add { dr, sr1, src2: s } => format!("ADD R{dr}, R{sr1}, {}", src2(s)),
+        Instruction::And { dr, sr1, src2: s } => format!("AND R{dr}, R{sr1}, {}", src2(s)),
+        Instruction::Ld { dr, offset } => format!("LD R{dr}, {}", target(offset)),
+        Instruction::St { sr, offset } => format!("ST R{sr}, {}", target(offset)),
+        Instruction::Jsr { offset } => format!("JSR {}", target(offset)),
+        Instruction::Jsrr { base } => format!("JSRR R{base}"),
+        Instruction::Ldr { dr, base, offset } => format!("LDR R{dr}, R{base}, #{offset}"),
+        Instruction::Str { sr, base, offset } => format!("STR R{sr}, R{base}, #{offset}"),
+        Instruction::Rti => "RTI".into(),
+        Instruction::Not { dr, sr } => format!("NOT R{dr}, R{sr}"),
+        Instruction::Ldi { dr, offset } => format!("LDI R{dr}, {}", target(offset)),
+        Instruction::Sti { sr, offset } => format!("STI R{sr}, {}", target(offset)),
+        Instruction::Jmp { base: 7 } => "RET".into(),
+        Instruction::Jmp { base } => format!("JMP R{base}"),
+        Instruction::Lea { dr, offset } => format!("LEA R{dr}, {}", target(offset)),
+        Instruction::Trap { vector } => match vector {
+            0x20 => "GETC".into(),
+            0x21 => "OUT".into(),
+            0x22 => "PUTS".into(),
+            0x23 => "IN".into(),
+            0x24 => "PUTSP".into(),
+            0x25 => "HALT".into(),
+            trap => format!("TRAP x{trap:02X}"),
+        },
+    }
+}
+
+/// Writes a listing of an object file: one line per word with its address, value and
+/// disassembly. Words that are printable characters are annotated with them, since
+/// strings are otherwise hard to spot among the instructions, unless `annotations` has
+/// a comment for them. Labels from `symbols` get a line of their own and are used for
+/// operands.
+pub fn write_listing(
+    image: &[u8],
+    symbols: &SymbolTable,
+    annotations: &Annotations,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if image.len() < 2 || !image.len().is_multiple_of(2) {
+        bail!("an image is an origin followed by 16-bit words");
+    }
+
+    let mut words = image
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]));
+    let origin = words.next().unwrap_or_default();
+
+    for (addr, inst) in (origin..=0xFFFF).zip(words) {
+        if let Some(name) = symbols.name_at(addr) {
+            writeln!(out, "{name}:")?;
+        }
+        let line = format!(
+            "x{addr:04X}: x{inst:04X}  {}",
+            disassemble_with_symbols(inst, addr, symbols)
+        );
+        let c = char::from_u32(inst as u32).filter(|&c| c.is_ascii_graphic() || c == ' ');
+        match (annotations.comment(addr), c) {
+            (Some(comment), _) => writeln!(out, "{line:<34}; {comment}")?,
+            (None, Some(c)) => writeln!(out, "{line:<34}; '{c}'")?,
+            (None, None) => writeln!(out, "{line}")?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(0x1021, 0x3000), "ADD R0, R0, #1");
+        assert_eq!(disassemble(0x123A, 0x3000), "ADD R1, R0, #-6");
+        assert_eq!(disassemble(0x5042, 0x3000), "AND R0, R1, R2");
+        assert_eq!(disassemble(0x0BFE, 0x3002), "BRnp x3001");
+        assert_eq!(disassemble(0x2001, 0x3000), "LD R0, x3002");
+        assert_eq!(disassemble(0x4FFF, 0x3000), "JSR x3000");
+        assert_eq!(disassemble(0xC1C0, 0x3000), "RET");
+        assert_eq!(disassemble(0xF025, 0x3000), "HALT");
+        assert_eq!(disassemble(0xF030, 0x3000), "TRAP x30");
+        assert_eq!(disassemble(0xD123, 0x3000), ".FILL xD123");
+    }
+
+    #[test]
+    fn test_write_listing() {
+        let mut out = Vec::new();
+        let symbols = SymbolTable::default();
+        write_listing(
+            &[0x30, 0x00, 0xF0, 0x25, 0x00, 0x41],
+            &symbols,
+            &Annotations::default(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "x3000: xF025  HALT\n\
+             x3001: x0041  NOP x3043           ; 'A'\n"
+        );
+        assert!(
+            write_listing(&[0x30], &symbols, &Annotations::default(), &mut Vec::new()).is_err()
+        );
+
+        // BRnzp LOOP; LOOP: HALT
+        let symbols = [("LOOP", 0x3001)].into_iter().collect();
+        let mut out = Vec::new();
+        write_listing(
+            &[0x30, 0x00, 0x0E, 0x00, 0xF0, 0x25],
+            &symbols,
+            &Annotations::default(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "x3000: x0E00  BRnzp LOOP\n\
+             LOOP:\n\
+             x3001: xF025  HALT\n"
+        );
+
+        let mut annotations = Annotations::default();
+        annotations.set_comment(0x3000, Some("the letter"));
+        let mut out = Vec::new();
+        write_listing(
+            &[0x30, 0x00, 0x00, 0x41],
+            &SymbolTable::default(),
+            &annotations,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "x3000: x0041  NOP x3042           ; the letter\n"
+        );
+    }
+}
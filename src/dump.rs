@@ -0,0 +1,124 @@
+//! Hexdump-style views of memory, see [`Vm::dump_memory`].
+
+use std::{fmt, ops::RangeInclusive};
+
+use anyhow::{bail, Result};
+
+use crate::{disasm::disassemble, util::parse_literal, vm::Vm};
+
+const WORDS_PER_ROW: usize = 8;
+
+/// A region of memory that prints as rows of words with an ASCII sidebar, or one
+/// disassembled word per line.
+pub struct MemoryDump<'a> {
+    memory: &'a [u16],
+    range: RangeInclusive<u16>,
+    disassemble: bool,
+}
+
+impl<'a> MemoryDump<'a> {
+    pub(crate) fn new(vm: &'a Vm, range: RangeInclusive<u16>) -> Self {
+        Self {
+            memory: vm.memory(),
+            range,
+            disassemble: false,
+        }
+    }
+
+    /// Prints one word per line with its disassembly instead of rows.
+    pub fn with_disassembly(mut self) -> Self {
+        self.disassemble = true;
+        self
+    }
+
+    fn words(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let start = *self.range.start() as usize;
+        let end = (*self.range.end() as usize).min(self.memory.len().saturating_sub(1));
+
+        (start..=end).filter_map(|addr| Some((addr as u16, *self.memory.get(addr)?)))
+    }
+}
+
+/// Parses an address range such as `x3000-x30FF`, both ends included.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let Some((start, end)) = s.split_once('-') else {
+        bail!("expected START-END, e.g. x3000-x30FF: {s}");
+    };
+    let parse = |addr: &str| match parse_literal(addr) {
+        Some(n) if (0..=0xFFFF).contains(&n) => Ok(n as u16),
+        _ => bail!("bad address: {addr}"),
+    };
+
+    Ok(parse(start)?..=parse(end)?)
+}
+
+/// The character a word shows as in the sidebar.
+fn ascii(word: u16) -> char {
+    match char::from_u32(word as u32) {
+        Some(c) if c.is_ascii_graphic() || c == ' ' => c,
+        _ => '.',
+    }
+}
+
+impl fmt::Display for MemoryDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.disassemble {
+            for (addr, word) in self.words() {
+                writeln!(
+                    f,
+                    "x{addr:04X}: x{word:04X}  {}  {}",
+                    ascii(word),
+                    disassemble(word, addr)
+                )?;
+            }
+            return Ok(());
+        }
+
+        let words: Vec<_> = self.words().collect();
+        for row in words.chunks(WORDS_PER_ROW) {
+            write!(f, "x{:04X}:", row[0].0)?;
+            for (_, word) in row {
+                write!(f, " x{word:04X}")?;
+            }
+            // keep the sidebar aligned on a short last row
+            write!(f, "{:1$}", "", 6 * (WORDS_PER_ROW - row.len()))?;
+
+            let text: String = row.iter().map(|&(_, word)| ascii(word)).collect();
+            writeln!(f, "  |{text}|")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Flag;
+
+    #[test]
+    fn test_dump_memory() {
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        // "Hi!" then HALT
+        vm.load_image_bytes(&[
+            0x30, 0x00, 0x00, 0x48, 0x00, 0x69, 0x00, 0x21, 0x00, 0x00, 0xF0, 0x25,
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vm.dump_memory(0x3000..=0x3009).to_string(),
+            "x3000: x0048 x0069 x0021 x0000 xF025 x0000 x0000 x0000  |Hi!.....|\n\
+             x3008: x0000 x0000                                      |..|\n"
+        );
+        assert_eq!(
+            vm.dump_memory(0x3003..=0x3004)
+                .with_disassembly()
+                .to_string(),
+            "x3003: x0000  .  NOP x3004\n\
+             x3004: xF025  .  HALT\n"
+        );
+
+        assert_eq!(parse_range("x3000-x30FF").unwrap(), 0x3000..=0x30FF);
+        assert!(parse_range("x3000").is_err());
+    }
+}
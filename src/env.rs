@@ -0,0 +1,88 @@
+//! Everything the vm takes from the host that would make two runs of the same program
+//! differ: the time, the seed of the random number register and whether a key is
+//! waiting. Replacing the [`HostEnv`] with [`DeterministicEnv`] makes a run depend only
+//! on the program and its input, so runs can be compared, e.g. those of two engines, or
+//! repeated.
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The host as the vm sees it, see [`crate::vm::Vm::set_host_env`].
+pub trait HostEnv {
+    /// Milliseconds since the vm started, when it has executed `instructions`. Used by
+    /// the clock registers and the timer.
+    fn now_ms(&mut self, instructions: u64) -> u64;
+
+    /// The seed of the random number register.
+    fn seed(&mut self) -> u64;
+
+    /// Whether the main console has a key for the program. `host_ready` tells whether
+    /// one is waiting on the host; an environment may say yes without asking, so the
+    /// next read waits for the key instead.
+    fn key_ready(&mut self, host_ready: &mut dyn FnMut() -> bool) -> bool;
+}
+
+pub(crate) type SharedEnv = Rc<RefCell<Box<dyn HostEnv>>>;
+
+/// The host's clock, a seed from the time of day and the keys as they arrive.
+pub struct RealEnv {
+    start: Instant,
+}
+
+impl Default for RealEnv {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl HostEnv for RealEnv {
+    fn now_ms(&mut self, _instructions: u64) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn seed(&mut self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    }
+
+    fn key_ready(&mut self, host_ready: &mut dyn FnMut() -> bool) -> bool {
+        host_ready()
+    }
+}
+
+/// Time that advances with executed instructions, a fixed seed, and a key that is
+/// always ready, so polling the keyboard doesn't depend on when the keys were typed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeterministicEnv {
+    pub insts_per_ms: u64,
+    pub seed: u64,
+}
+
+impl Default for DeterministicEnv {
+    fn default() -> Self {
+        Self {
+            insts_per_ms: 1000,
+            seed: 0,
+        }
+    }
+}
+
+impl HostEnv for DeterministicEnv {
+    fn now_ms(&mut self, instructions: u64) -> u64 {
+        instructions / self.insts_per_ms.max(1)
+    }
+
+    fn seed(&mut self) -> u64 {
+        self.seed
+    }
+
+    fn key_ready(&mut self, _host_ready: &mut dyn FnMut() -> bool) -> bool {
+        true
+    }
+}
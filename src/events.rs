@@ -0,0 +1,252 @@
+//! A live stream of what a running program does, for front-ends and loggers in other
+//! threads, see [`Vm::run_with_events`].
+//!
+//! ```
+//! use std::{sync::mpsc, thread};
+//! use lc3_vm::{console::StreamIo, events::{Delivery, Event}, Flag, Vm};
+//!
+//! let (sender, receiver) = mpsc::sync_channel(1024);
+//! let logger = thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+//!
+//! // ADD R0, R0, #5; HALT
+//! let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+//! vm.load_image_bytes(&[0x30, 0x00, 0x10, 0x25, 0xF0, 0x25]).unwrap();
+//! vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+//! vm.run_with_events(&sender, Delivery::Blocking).unwrap();
+//! drop(sender);
+//!
+//! let events = logger.join().unwrap();
+//! assert_eq!(events[0], Event::InstructionExecuted { pc: 0x3000, inst: 0x1025 });
+//! assert_eq!(events.last(), Some(&Event::Halted));
+//! ```
+
+use std::{
+    cell::RefCell,
+    mem,
+    rc::Rc,
+    sync::mpsc::{SyncSender, TrySendError},
+};
+
+use crate::{
+    console::OutputTap,
+    step::MemAccess,
+    vm::{RunResult, Vm, VmError},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The instruction `inst` at `pc` executed. Sent before what it did.
+    InstructionExecuted {
+        pc: u16,
+        inst: u16,
+    },
+    /// A store to memory or a device register.
+    MemoryWrite {
+        addr: u16,
+        val: u16,
+    },
+    TrapInvoked {
+        vector: u8,
+    },
+    /// A byte written to the console.
+    OutputByte(u8),
+    /// How many events [`Delivery::Lossy`] dropped since the last time it reported some.
+    Dropped(u64),
+    /// The program halted. The last event, unless the run stopped early or failed.
+    Halted,
+}
+
+/// What happens when the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Wait for the receiver, so every event arrives.
+    Blocking,
+    /// Drop [`Event::InstructionExecuted`] and [`Event::MemoryWrite`], which come with
+    /// nearly every instruction, and report how many were dropped with
+    /// [`Event::Dropped`] before the next event that does get sent. The rest still wait.
+    Lossy,
+}
+
+struct Sink<'a> {
+    sender: &'a SyncSender<Event>,
+    delivery: Delivery,
+    dropped: u64,
+    // the receiver hung up, so nobody is listening
+    closed: bool,
+}
+
+impl Sink<'_> {
+    fn send(&mut self, event: Event) {
+        if self.closed {
+            return;
+        }
+        let frequent = matches!(
+            event,
+            Event::InstructionExecuted { .. } | Event::MemoryWrite { .. }
+        );
+        if self.dropped != 0 {
+            let dropped = Event::Dropped(self.dropped);
+            match self.sender.try_send(dropped) {
+                Ok(()) => self.dropped = 0,
+                Err(TrySendError::Full(dropped)) if !frequent => {
+                    self.dropped = 0;
+                    self.closed = self.sender.send(dropped).is_err();
+                }
+                Err(TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    return;
+                }
+                Err(TrySendError::Disconnected(_)) => self.closed = true,
+            }
+        }
+
+        if self.delivery == Delivery::Blocking || !frequent {
+            self.closed |= self.sender.send(event).is_err();
+            return;
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            Err(TrySendError::Disconnected(_)) => self.closed = true,
+        }
+    }
+}
+
+pub(crate) fn run(
+    vm: &mut Vm,
+    sender: &SyncSender<Event>,
+    delivery: Delivery,
+) -> Result<(), VmError> {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    // a tap that was already installed still sees the output, and gets it back after
+    let previous: Rc<RefCell<Option<OutputTap>>> = Rc::new(RefCell::new(None));
+    let (tap, forward) = (output.clone(), previous.clone());
+    *previous.borrow_mut() = vm.set_output_tap(Some(Box::new(move |bytes| {
+        tap.borrow_mut().extend_from_slice(bytes);
+        if let Some(previous) = &mut *forward.borrow_mut() {
+            previous(bytes);
+        }
+    })));
+
+    let mut sink = Sink {
+        sender,
+        delivery,
+        dropped: 0,
+        closed: false,
+    };
+    let result = loop {
+        let result = vm.step();
+
+        // a failed step, or one a hook stopped or skipped, executed nothing to report
+        if let (Ok(_), Some((pc, inst))) = (&result, vm.last_executed()) {
+            sink.send(Event::InstructionExecuted { pc, inst });
+            for &access in vm.last_accesses() {
+                if let MemAccess::Write { addr, val } = access {
+                    sink.send(Event::MemoryWrite { addr, val });
+                }
+            }
+            if inst >> 12 == 0xF {
+                sink.send(Event::TrapInvoked { vector: inst as u8 });
+            }
+        }
+        for byte in mem::take(&mut *output.borrow_mut()) {
+            sink.send(Event::OutputByte(byte));
+        }
+
+        match result {
+            Ok(RunResult::Halted) => {
+                sink.send(Event::Halted);
+                break Ok(());
+            }
+            Ok(RunResult::Stopped) => break Ok(()),
+            Ok(_) => (),
+            Err(err) => break Err(err),
+        }
+    };
+
+    vm.set_output_tap(previous.take());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::vm_with_program;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_events() {
+        // ADD R0, R0, #1; ST R0, #2; OUT; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x3002, 0xF021, 0xF025]);
+        vm.set_register(0, 0x40);
+        let (sender, receiver) = mpsc::sync_channel(100);
+        vm.run_with_events(&sender, Delivery::Blocking).unwrap();
+        let events: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            events[..5],
+            [
+                Event::InstructionExecuted {
+                    pc: 0x3000,
+                    inst: 0x1021
+                },
+                Event::InstructionExecuted {
+                    pc: 0x3001,
+                    inst: 0x3002
+                },
+                Event::MemoryWrite {
+                    addr: 0x3004,
+                    val: 0x41
+                },
+                Event::InstructionExecuted {
+                    pc: 0x3002,
+                    inst: 0xF021
+                },
+                Event::TrapInvoked { vector: 0x21 },
+            ]
+        );
+        assert_eq!(events[5], Event::OutputByte(b'A'));
+        assert_eq!(events.last(), Some(&Event::Halted));
+
+        // whatever gets dropped, the trap and its output arrive
+        let mut vm = vm_with_program(&[0x1021, 0x1021, 0xF021, 0xF025]);
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let reader = std::thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+        vm.run_with_events(&sender, Delivery::Lossy).unwrap();
+        drop(sender);
+        let events = reader.join().unwrap();
+        assert!(events.contains(&Event::TrapInvoked { vector: 0x21 }));
+        assert!(events.contains(&Event::OutputByte(2)));
+        assert_eq!(events.last(), Some(&Event::Halted));
+    }
+
+    #[test]
+    fn test_events_failed_step() {
+        // OUT; TRAP x99
+        let mut vm = vm_with_program(&[0xF021, 0xF099]);
+        vm.set_register(0, b'A' as u16);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let tap = seen.clone();
+        vm.set_output_tap(Some(Box::new(move |bytes| {
+            tap.borrow_mut().extend_from_slice(bytes)
+        })));
+
+        let (sender, receiver) = mpsc::sync_channel(100);
+        assert!(vm.run_with_events(&sender, Delivery::Blocking).is_err());
+        let events: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                Event::InstructionExecuted {
+                    pc: 0x3000,
+                    inst: 0xF021
+                },
+                Event::TrapInvoked { vector: 0x21 },
+                Event::OutputByte(b'A'),
+            ]
+        );
+
+        // the caller's tap saw the output and is back in place
+        assert_eq!(*seen.borrow(), b"A");
+        assert!(vm.set_output_tap(None).is_some());
+    }
+}
@@ -0,0 +1,196 @@
+//! Traps that give programs files on the host, confined to a sandbox directory:
+//!
+//! - `TRAP x30` opens the file named by the string at R0, relative to the sandbox, for
+//!   reading if R1 is 0, writing (created or truncated) if R1 is 1 or appending if R1 is
+//!   2. R0 gets a file descriptor.
+//! - `TRAP x31` reads up to R2 bytes from descriptor R0 into the buffer at R1, one byte
+//!   per word. R0 gets the number of bytes read, 0 at the end of the file.
+//! - `TRAP x32` writes the low bytes of the R2 words at R1 to descriptor R0. R0 gets the
+//!   number of bytes written.
+//! - `TRAP x33` closes descriptor R0. R0 gets 0.
+//!
+//! On failure R0 gets -1 instead. The condition codes are set from R0 either way.
+
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::vm::Vm;
+
+pub const OPEN: u8 = 0x30;
+pub const READ: u8 = 0x31;
+pub const WRITE: u8 = 0x32;
+pub const CLOSE: u8 = 0x33;
+
+// files a program can have open at once
+const MAX_FILES: usize = 16;
+
+const FAILED: u16 = 0xFFFF;
+
+struct Files {
+    root: PathBuf,
+    open: Vec<Option<File>>,
+}
+
+impl Files {
+    fn open(&mut self, name: &str, mode: u16) -> Option<u16> {
+        // only plain relative paths, so the program can't leave the sandbox
+        let path = Path::new(name);
+        if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            _ => return None,
+        };
+        let file = options.open(self.root.join(path)).ok()?;
+
+        let fd = match self.open.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.open.len() < MAX_FILES => {
+                self.open.push(None);
+                self.open.len() - 1
+            }
+            None => return None,
+        };
+        self.open[fd] = Some(file);
+        Some(fd as u16)
+    }
+
+    fn file(&mut self, fd: u16) -> Option<&mut File> {
+        self.open.get_mut(fd as usize)?.as_mut()
+    }
+}
+
+/// Registers the file traps on `vm`, with file names relative to `root`.
+pub fn install(vm: &mut Vm, root: impl Into<PathBuf>) {
+    let files = Rc::new(RefCell::new(Files {
+        root: root.into(),
+        open: Vec::new(),
+    }));
+
+    let returns = |vm: &mut Vm, result: Option<u16>| {
+        vm.set_register(0, result.unwrap_or(FAILED));
+        vm.set_cc(0);
+        Ok(())
+    };
+
+    let state = files.clone();
+    vm.register_trap(OPEN, move |vm: &mut Vm, _| {
+        let name = vm.string_at(vm.registers()[0]);
+        let fd = state.borrow_mut().open(&name, vm.registers()[1]);
+        returns(vm, fd)
+    });
+
+    let state = files.clone();
+    vm.register_trap(READ, move |vm: &mut Vm, _| {
+        let [fd, buf, len, ..] = *vm.registers();
+        let mut bytes = vec![0; len as usize];
+        let read = match state.borrow_mut().file(fd) {
+            Some(file) => file.read(&mut bytes).ok(),
+            None => None,
+        };
+        if let Some(read) = read {
+            for (i, &byte) in bytes[..read].iter().enumerate() {
+                vm.poke(buf.wrapping_add(i as u16), byte as u16);
+            }
+        }
+        returns(vm, read.map(|read| read as u16))
+    });
+
+    let state = files.clone();
+    vm.register_trap(WRITE, move |vm: &mut Vm, _| {
+        let [fd, buf, len, ..] = *vm.registers();
+        let bytes: Vec<_> = (0..len)
+            .map(|i| {
+                vm.memory()
+                    .get(buf.wrapping_add(i) as usize)
+                    .copied()
+                    .unwrap_or_default() as u8
+            })
+            .collect();
+        let written = match state.borrow_mut().file(fd) {
+            Some(file) => file.write_all(&bytes).ok().map(|()| len),
+            None => None,
+        };
+        returns(vm, written)
+    });
+
+    vm.register_trap(CLOSE, move |vm: &mut Vm, _| {
+        let fd = vm.registers()[0];
+        let closed = files
+            .borrow_mut()
+            .open
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .map(|_| 0);
+        returns(vm, closed)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, console::StreamIo, vm::Flag};
+
+    #[test]
+    fn test_file_traps() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, NAME
+            AND R1, R1, #0
+            ADD R1, R1, #1
+            TRAP x30        ; open for writing
+            ST R0, FD
+            LEA R1, TEXT
+            AND R2, R2, #0
+            ADD R2, R2, #2
+            TRAP x32
+            LD R0, FD
+            TRAP x33
+            LEA R0, NAME
+            AND R1, R1, #0
+            TRAP x30        ; open for reading
+            LEA R1, BUF
+            AND R2, R2, #0
+            ADD R2, R2, #5
+            TRAP x31
+            ADD R3, R0, #0
+            LEA R0, OUTSIDE
+            AND R1, R1, #0
+            TRAP x30
+            ADD R4, R0, #0
+            HALT
+            FD .BLKW 1
+            NAME .STRINGZ "out.txt"
+            OUTSIDE .STRINGZ "../out.txt"
+            TEXT .STRINGZ "hi"
+            BUF .BLKW 5
+            .END
+        "#;
+        let program = asm::assemble(source).unwrap();
+        let buf = program.symbol_table().lookup("BUF").unwrap();
+
+        let dir = std::env::temp_dir().join(format!("lc3-file-traps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&program.image()).unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        install(&mut vm, &dir);
+        vm.run().unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "hi");
+        assert_eq!(vm.registers()[3], 2);
+        assert_eq!(&vm.memory()[buf as usize..buf as usize + 2], [0x68, 0x69]);
+        assert_eq!(vm.registers()[4], FAILED);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
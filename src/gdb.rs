@@ -0,0 +1,431 @@
+//! A minimal GDB remote serial protocol server, started with `--gdb PORT`.
+//!
+//! The LC-3 is word addressed, and so is the protocol as spoken here: addresses in memory,
+//! breakpoint and watchpoint packets are word addresses, and `m ADDR,LEN` returns `LEN`
+//! bytes starting at word `ADDR`, two bytes per word in little endian order. The registers
+//! are R0-R7, PC and PSR, 16 bits each, also little endian. PSR is read-only.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::Result;
+
+use crate::{
+    terminal::enable_raw_mode,
+    vm::{RunResult, Vm, VmError, WatchKind},
+};
+
+// the number of registers in `g` and `G` packets
+const NUM_REGS: usize = 10;
+const PC: usize = 8;
+const PSR: usize = 9;
+
+// Ctrl-C from GDB, sent outside of a packet
+const INTERRUPT: u8 = 0x03;
+
+// how many instructions `c` runs between checks for an interrupt
+const INTERRUPT_CHECK_PERIOD: u64 = 4096;
+
+/// Waits for GDB to connect on `port` and serves it until it detaches or kills the program.
+pub fn serve(vm: &mut Vm, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB on port {port}");
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut stub = GdbStub::new(vm);
+
+    let _terminal = enable_raw_mode()?;
+    while let Some(packet) = read_packet(&mut reader, &mut writer)? {
+        let reply = stub.handle(&packet, &mut || interrupted(&mut reader));
+        if let Some(reply) = reply {
+            write_packet(&mut writer, &reply)?;
+        }
+        if stub.detached {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers GDB packets for one program.
+pub struct GdbStub<'a> {
+    vm: &'a mut Vm,
+    /// Set once the program halted or stopped with an error.
+    finished: bool,
+    /// Set by `D` and `k`, after which the connection is closed.
+    detached: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(vm: &'a mut Vm) -> Self {
+        Self {
+            vm,
+            finished: false,
+            detached: false,
+        }
+    }
+
+    /// Returns the reply to `packet` (without the `$` and checksum), or `None` if it
+    /// doesn't get one. `interrupted` is polled while the program runs and stops it when
+    /// it returns `true`.
+    pub fn handle(
+        &mut self,
+        packet: &str,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<String> {
+        let Some(command) = packet.get(..1) else {
+            return Some(String::new());
+        };
+        let args = &packet[1..];
+        let reply = match command {
+            "?" => Some(self.stop_reply()),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" => self.resume(args, false, interrupted),
+            "s" => self.resume(args, true, interrupted),
+            "Z" => self.set_breakpoint(args, true),
+            "z" => self.set_breakpoint(args, false),
+            "H" => Some("OK".to_string()),
+            "D" => {
+                self.detached = true;
+                Some("OK".to_string())
+            }
+            "k" => {
+                self.detached = true;
+                return None;
+            }
+            "q" => Some(query(args).to_string()),
+            // an empty reply tells GDB the packet isn't supported
+            _ => Some(String::new()),
+        };
+
+        Some(reply.unwrap_or_else(|| "E01".to_string()))
+    }
+
+    fn stop_reply(&self) -> String {
+        if self.finished {
+            "W00".to_string()
+        } else {
+            "S05".to_string()
+        }
+    }
+
+    fn register(&self, r: usize) -> Option<u16> {
+        match r {
+            0..=7 => Some(self.vm.registers()[r]),
+            PC => Some(self.vm.pc()),
+            PSR => Some(self.vm.psr()),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, r: usize, val: u16) -> Option<()> {
+        match r {
+            0..=7 => self.vm.set_register(r, val),
+            PC => self.vm.set_pc(val),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGS)
+            .map(|r| encode_word(self.register(r).unwrap()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let words = decode_words(args)?;
+        if words.len() != NUM_REGS {
+            return None;
+        }
+        for (r, &val) in words.iter().enumerate().take(PSR) {
+            self.set_register(r, val)?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let r = usize::from_str_radix(args, 16).ok()?;
+        self.register(r).map(encode_word)
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (r, val) = args.split_once('=')?;
+        let r = usize::from_str_radix(r, 16).ok()?;
+        let [val] = decode_words(val)?[..] else {
+            return None;
+        };
+        self.set_register(r, val)?;
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let addr = parse_hex(addr)?;
+        let len = usize::from_str_radix(len, 16).ok()?;
+
+        let memory = self.vm.memory();
+        let words = memory.get(addr as usize..)?.iter().take(len.div_ceil(2));
+        let mut reply: String = words.copied().map(encode_word).collect();
+        // an odd length ends halfway through a word
+        reply.truncate(len.saturating_mul(2));
+        Some(reply)
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, _) = range.split_once(',')?;
+        let addr = parse_hex(addr)?;
+
+        for (i, val) in decode_words(data)?.into_iter().enumerate() {
+            let addr = addr.checked_add(i.try_into().ok()?)?;
+            if !self.vm.set_memory(addr, val) {
+                return None;
+            }
+        }
+        Some("OK".to_string())
+    }
+
+    fn resume(
+        &mut self,
+        args: &str,
+        single_step: bool,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<String> {
+        if self.finished {
+            return None;
+        }
+        if !args.is_empty() {
+            self.vm.set_pc(parse_hex(args)?);
+        }
+
+        let mut count: u64 = 0;
+        loop {
+            let result = match self.vm.step() {
+                Ok(result) => result,
+                Err(err) => {
+                    self.finished = true;
+                    return Some(exit_reply(&err));
+                }
+            };
+            count += 1;
+
+            match result {
+                RunResult::Halted => {
+                    self.finished = true;
+                    return Some("W00".to_string());
+                }
+                RunResult::Watchpoint { addr, .. } => {
+                    let kind = self
+                        .vm
+                        .watchpoints()
+                        .find(|&(watched, _)| watched == addr)
+                        .map(|(_, kind)| kind);
+                    let name = match kind {
+                        Some(WatchKind::Read) => "rwatch",
+                        Some(WatchKind::ReadWrite) => "awatch",
+                        _ => "watch",
+                    };
+                    return Some(format!("T05{name}:{addr:x};"));
+                }
+                RunResult::Stopped => return Some("S05".to_string()),
+                _ => (),
+            }
+
+            if single_step || self.vm.breakpoints().any(|addr| addr == self.vm.pc()) {
+                return Some("S05".to_string());
+            }
+            if count.is_multiple_of(INTERRUPT_CHECK_PERIOD) && interrupted() {
+                return Some("S02".to_string());
+            }
+        }
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+
+        let watch_kind = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::ReadWrite),
+            _ => return Some(String::new()),
+        };
+
+        match (watch_kind, insert) {
+            (None, true) => {
+                self.vm.add_breakpoint(addr);
+            }
+            (None, false) => {
+                self.vm.remove_breakpoint(addr);
+            }
+            (Some(kind), true) => self.vm.add_watchpoint(addr, kind),
+            (Some(_), false) => {
+                self.vm.remove_watchpoint(addr);
+            }
+        }
+        Some("OK".to_string())
+    }
+}
+
+fn query(args: &str) -> &'static str {
+    match args.split(':').next() {
+        Some("Supported") => "PacketSize=1000",
+        Some("Attached") => "1",
+        _ => "",
+    }
+}
+
+/// `X` reply with a signal matching the error, since the program can't go on after it.
+fn exit_reply(err: &VmError) -> String {
+    let signal = match err {
+        VmError::BadOpcode { .. } => 4,    // SIGILL
+        VmError::MemoryFault { .. } => 11, // SIGSEGV
+        _ => 6,                            // SIGABRT
+    };
+    format!("X{signal:02x}")
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn encode_word(word: u16) -> String {
+    let [lo, hi] = word.to_le_bytes();
+    format!("{lo:02x}{hi:02x}")
+}
+
+fn decode_words(hex: &str) -> Option<Vec<u16>> {
+    if !hex.len().is_multiple_of(4) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(4)
+        .map(|i| {
+            let lo = u8::from_str_radix(&hex[i..i + 2], 16).ok()?;
+            let hi = u8::from_str_radix(&hex[i + 2..i + 4], 16).ok()?;
+            Some(u16::from_le_bytes([lo, hi]))
+        })
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, u8::wrapping_add)
+}
+
+/// Reads the next `$data#xx` packet and acknowledges it on `ack`. Acks from GDB and stray
+/// interrupts are skipped, and packets with a bad checksum are asked for again. Returns
+/// `None` at the end of input.
+fn read_packet(reader: &mut impl BufRead, ack: &mut impl Write) -> io::Result<Option<String>> {
+    loop {
+        let mut skipped = Vec::new();
+        if reader.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        reader.read_until(b'#', &mut data)?;
+        if data.pop() != Some(b'#') {
+            return Ok(None);
+        }
+        let mut sum = [0; 2];
+        reader.read_exact(&mut sum)?;
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let sum = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if sum == Some(checksum(&data)) {
+            ack.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        ack.write_all(b"-")?;
+    }
+}
+
+fn write_packet(out: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(out, "${data}#{:02x}", checksum(data))?;
+    out.flush()
+}
+
+/// Whether GDB sent an interrupt while the program was running.
+fn interrupted(reader: &mut BufReader<TcpStream>) -> bool {
+    if reader.buffer().is_empty() {
+        let stream = reader.get_ref();
+        let mut byte = [0];
+        let ready = stream.set_nonblocking(true).is_ok() && matches!(stream.peek(&mut byte), Ok(1));
+        let _ = stream.set_nonblocking(false);
+        if !ready {
+            return false;
+        }
+    }
+
+    let mut byte = [0];
+    reader.read_exact(&mut byte).is_ok() && byte[0] == INTERRUPT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console::StreamIo, vm::Flag};
+
+    #[test]
+    fn test_read_packet() {
+        let mut input: &[u8] = b"+$g#67$m3000,2#00$m3000,2#8e";
+        let mut acks = Vec::new();
+        assert_eq!(
+            read_packet(&mut input, &mut acks).unwrap().as_deref(),
+            Some("g")
+        );
+        // the bad checksum is nacked and skipped
+        assert_eq!(
+            read_packet(&mut input, &mut acks).unwrap().as_deref(),
+            Some("m3000,2")
+        );
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), None);
+        assert_eq!(acks, b"+-+");
+    }
+
+    #[test]
+    fn test_gdb_stub() {
+        // ADD R0, R0, #1; ADD R0, R0, #1; ST R0, #1; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[0x30, 0x00, 0x10, 0x21, 0x10, 0x21, 0x30, 0x01, 0xF0, 0x25])
+            .unwrap();
+        vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+
+        let mut stub = GdbStub::new(&mut vm);
+        let mut run = |packet: &str| stub.handle(packet, &mut || false).unwrap();
+
+        assert_eq!(run("m3000,4"), "21102110");
+        assert_eq!(run("s"), "S05");
+        assert_eq!(run("p8"), "0130");
+        assert_eq!(run("P1=3412"), "OK");
+        assert!(run("g").starts_with("0100341200000000"));
+
+        assert_eq!(run("Z2,3004,2"), "OK");
+        assert_eq!(run("c"), "T05watch:3004;");
+        assert_eq!(run("m3004,2"), "0200");
+        assert_eq!(run("M3004,2:0500"), "OK");
+        assert_eq!(run("m3004,1"), "05");
+        // the length comes from the client, reads stop at the end of memory
+        assert_eq!(run("mfffe,ffffffffffffffff").len(), 8);
+
+        assert_eq!(run("c"), "W00");
+        assert_eq!(run("c"), "E01");
+        assert_eq!(run("vMustReplyEmpty"), "");
+    }
+}
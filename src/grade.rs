@@ -0,0 +1,274 @@
+//! Grades images against a declarative TOML rubric, so instructors can check
+//! submissions without writing Rust.
+//!
+//! ```toml
+//! input = "3 4\n"             # fed to the program's keyboard, optional
+//! max_instructions = 100000   # run budget, defaults to 1000000
+//!
+//! [[check]]
+//! name = "sum in R0"
+//! points = 2
+//! reg = "R0"
+//! equals = 7                  # numbers can also be written as "x0007" or "#7"
+//!
+//! [[check]]
+//! name = "array sorted"
+//! points = 3
+//! mem = "x4000"
+//! values = [1, 2, 3]
+//!
+//! [[check]]
+//! name = "prints the sum"
+//! points = 4
+//! output = "Sum: 7"           # a regex matched against everything the program printed
+//!
+//! [[check]]
+//! name = "fast enough"
+//! points = 1
+//! max_instructions = 500
+//! ```
+
+use std::{fmt, io, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    console::StreamIo,
+    util::{self, SharedBuf, Word},
+    vm::{Flag, Vm},
+};
+
+const DEFAULT_MAX_INSTRUCTIONS: u64 = 1_000_000;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rubric {
+    #[serde(default)]
+    input: String,
+    max_instructions: Option<u64>,
+    #[serde(rename = "check", default)]
+    checks: Vec<Check>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Check {
+    name: String,
+    #[serde(default = "one")]
+    points: u32,
+    #[serde(flatten)]
+    condition: Condition,
+}
+
+fn one() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Condition {
+    Register { reg: String, equals: Word },
+    Memory { mem: Word, values: Vec<Word> },
+    Output { output: String },
+    MaxInstructions { max_instructions: u64 },
+}
+
+impl Rubric {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let rubric: Rubric = toml::from_str(&text)?;
+
+        for check in &rubric.checks {
+            if let Condition::Output { output } = &check.condition {
+                Regex::new(output)?;
+            }
+            if let Condition::Register { reg, .. } = &check.condition {
+                parse_register(reg)?;
+            }
+        }
+
+        Ok(rubric)
+    }
+
+    pub fn total_points(&self) -> u32 {
+        self.checks.iter().map(|check| check.points).sum()
+    }
+}
+
+fn parse_register(reg: &str) -> Result<usize> {
+    match util::parse_register(reg) {
+        Some(r) => Ok(r),
+        None => bail!("bad register: {reg}"),
+    }
+}
+
+pub struct CheckResult {
+    pub name: String,
+    pub points: u32,
+    pub max_points: u32,
+    /// Why the check failed.
+    pub failure: Option<String>,
+}
+
+pub struct Report {
+    pub score: u32,
+    pub max_score: u32,
+    /// The error that stopped the program, if it didn't halt normally.
+    pub run_error: Option<String>,
+    pub checks: Vec<CheckResult>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "score: {}/{}", self.score, self.max_score)?;
+        if let Some(err) = &self.run_error {
+            writeln!(f, "  run stopped: {err}")?;
+        }
+
+        for check in &self.checks {
+            let mark = if check.failure.is_none() {
+                "pass"
+            } else {
+                "FAIL"
+            };
+            write!(
+                f,
+                "  [{mark}] {} ({}/{})",
+                check.name, check.points, check.max_points
+            )?;
+            if let Some(failure) = &check.failure {
+                write!(f, ": {failure}")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `image` with the rubric's input and budget, then scores the final state.
+pub fn grade(rubric: &Rubric, image: &[u8]) -> Result<Report> {
+    let output = SharedBuf::default();
+
+    let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+    vm.load_image_bytes(image)?;
+    vm.set_io(Box::new(StreamIo::new(
+        io::Cursor::new(rubric.input.clone().into_bytes()),
+        output.clone(),
+    )));
+    vm.set_instruction_limit(Some(
+        rubric.max_instructions.unwrap_or(DEFAULT_MAX_INSTRUCTIONS),
+    ));
+
+    let run_error = vm.run().err().map(|err| err.to_string());
+
+    let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+
+    let checks: Vec<_> = rubric
+        .checks
+        .iter()
+        .map(|check| {
+            let failure = evaluate(&check.condition, &vm, &output)?;
+            Ok(CheckResult {
+                name: check.name.clone(),
+                points: if failure.is_none() { check.points } else { 0 },
+                max_points: check.points,
+                failure,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Report {
+        score: checks.iter().map(|check| check.points).sum(),
+        max_score: rubric.total_points(),
+        run_error,
+        checks,
+    })
+}
+
+/// Returns why `condition` does not hold, or `None` if it does.
+fn evaluate(condition: &Condition, vm: &Vm, output: &str) -> Result<Option<String>> {
+    let failure = match condition {
+        Condition::Register { reg, equals } => {
+            let r = parse_register(reg)?;
+            let actual = vm.registers()[r];
+            (actual != equals.0)
+                .then(|| format!("{reg} is x{actual:04X}, expected x{:04X}", equals.0))
+        }
+        Condition::Memory { mem, values } => values.iter().enumerate().find_map(|(i, expected)| {
+            let addr = mem.0.wrapping_add(i as u16);
+            let actual = vm
+                .memory()
+                .get(addr as usize)
+                .copied()
+                .ok_or_else(|| anyhow!("address x{addr:04X} out of range"));
+            match actual {
+                Ok(actual) if actual == expected.0 => None,
+                Ok(actual) => Some(format!(
+                    "x{addr:04X} is x{actual:04X}, expected x{:04X}",
+                    expected.0
+                )),
+                Err(err) => Some(err.to_string()),
+            }
+        }),
+        Condition::Output { output: pattern } => {
+            let re = Regex::new(pattern)?;
+            (!re.is_match(output)).then(|| format!("output does not match /{pattern}/"))
+        }
+        Condition::MaxInstructions { max_instructions } => {
+            let executed = vm.instructions();
+            (executed > *max_instructions)
+                .then(|| format!("executed {executed} instructions, limit is {max_instructions}"))
+        }
+    };
+
+    Ok(failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grade() {
+        let rubric: Rubric = toml::from_str(
+            r#"
+            input = "a"
+
+            [[check]]
+            name = "echoes the key"
+            points = 2
+            output = "^a"
+
+            [[check]]
+            name = "key in R0"
+            reg = "R0"
+            equals = "x61"
+
+            [[check]]
+            name = "stores it"
+            points = 3
+            mem = "x3004"
+            values = [97, 0]
+
+            [[check]]
+            name = "quick"
+            max_instructions = 3
+            "#,
+        )
+        .unwrap();
+
+        // GETC; OUT; ST R0, x3004; HALT
+        let image = [
+            0x30, 0x00, 0xF0, 0x20, 0xF0, 0x21, 0x30, 0x01, 0xF0, 0x25, 0x00, 0x00,
+        ];
+
+        let report = grade(&rubric, &image).unwrap();
+        assert!(report.run_error.is_none());
+        assert_eq!(report.max_score, 7);
+        // x3005 holds nothing, so the second value matches, and 4 instructions ran
+        assert_eq!(report.score, 6);
+        assert!(report.checks[3].failure.is_some());
+    }
+}
@@ -0,0 +1,68 @@
+//! A bounded record of what each executed instruction changed, so the vm can step
+//! backwards, see [`crate::vm::Vm::enable_history`].
+
+use std::collections::VecDeque;
+
+use crate::vm::Frame;
+
+/// The state before one instruction, and the old contents of the memory it stored to.
+#[derive(Debug, Clone)]
+pub(crate) struct Delta {
+    pub pc: u16,
+    pub psr: u16,
+    pub reg: [u16; 8],
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    pub instructions: u64,
+    pub cycles: u64,
+    pub call_stack_len: usize,
+    pub call_stack_top: Option<Frame>,
+    /// (address, old value) in the order of the stores.
+    pub memory: Vec<(u16, u16)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct History {
+    limit: usize,
+    deltas: VecDeque<Delta>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Starts recording an instruction, forgetting the oldest one if the history is full.
+    pub fn begin(&mut self, delta: Delta) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.deltas.len() == self.limit {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    /// Records a store to `addr` by the current instruction, which held `old` before.
+    pub fn store(&mut self, addr: u16, old: u16) {
+        if let Some(delta) = self.deltas.back_mut() {
+            delta.memory.push((addr, old));
+        }
+    }
+
+    /// Takes the newest instruction out of the history.
+    pub fn pop(&mut self) -> Option<Delta> {
+        self.deltas.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+}
@@ -0,0 +1,267 @@
+//! Image formats. Besides lc3as .obj files, images can be raw words loaded at a given
+//! origin or Intel HEX style text, in either byte order, so the output of other
+//! toolchains loads without converting it first.
+
+use std::{io, path::Path};
+
+use anyhow::{bail, Result};
+
+/// How an image file is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    /// An origin followed by the words to load there.
+    #[default]
+    Obj,
+    /// Only words, loaded at `origin`.
+    Raw { origin: u16 },
+    /// Intel HEX records, `:LLAAAATT` followed by the data bytes and a checksum. Unlike
+    /// real Intel HEX the address is a word address, and every two data bytes are one
+    /// word. Only data (00) and end of file (01) records are supported.
+    Hex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoadOptions {
+    pub format: ImageFormat,
+    /// Words (and the .obj origin) are little-endian instead of big-endian.
+    pub little_endian: bool,
+}
+
+/// Consecutive words loaded at `origin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Segment {
+    /// The addresses the segment covers, as a half-open range since it may end at
+    /// x10000.
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.origin as usize..self.origin as usize + self.words.len()
+    }
+}
+
+/// Decodes an image into the segments it loads. Execution starts at the origin of the
+/// first one.
+pub fn decode(data: &[u8], options: &LoadOptions) -> Result<Vec<Segment>> {
+    let word = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1]];
+        if options.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    };
+
+    match options.format {
+        ImageFormat::Obj => {
+            if data.len() < 2 {
+                bail!("Input file too small - missing origin");
+            }
+            let (origin, data) = data.split_at(2);
+            Ok(vec![Segment {
+                origin: word(origin),
+                words: data.chunks_exact(2).map(word).collect(),
+            }])
+        }
+        ImageFormat::Raw { origin } => Ok(vec![Segment {
+            origin,
+            words: data.chunks_exact(2).map(word).collect(),
+        }]),
+        ImageFormat::Hex => {
+            let Ok(text) = std::str::from_utf8(data) else {
+                bail!("HEX images are text");
+            };
+            decode_hex(text, word)
+        }
+    }
+}
+
+/// Builds a big-endian .obj image word by word, for tests and tools that generate
+/// programs.
+///
+/// ```
+/// use lc3_vm::image::ImageBuilder;
+///
+/// // LEA R0, MSG; PUTS; HALT; MSG: .STRINGZ "Hi"
+/// let image = ImageBuilder::new(0x3000)
+///     .word(0xE002)
+///     .words(&[0xF022, 0xF025])
+///     .string("Hi")
+///     .build();
+/// assert_eq!(&image[..4], [0x30, 0x00, 0xE0, 0x02]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageBuilder {
+    origin: u16,
+    words: Vec<u16>,
+}
+
+impl ImageBuilder {
+    pub fn new(origin: u16) -> Self {
+        Self {
+            origin,
+            words: Vec::new(),
+        }
+    }
+
+    pub fn word(mut self, word: u16) -> Self {
+        self.words.push(word);
+        self
+    }
+
+    pub fn words(mut self, words: &[u16]) -> Self {
+        self.words.extend_from_slice(words);
+        self
+    }
+
+    /// One character per word followed by x0000, like `.STRINGZ`.
+    pub fn string(mut self, s: &str) -> Self {
+        self.words.extend(s.bytes().map(u16::from));
+        self.words.push(0);
+        self
+    }
+
+    /// `count` copies of `word`, like `.BLKW`.
+    pub fn fill(mut self, word: u16, count: usize) -> Self {
+        self.words.extend(std::iter::repeat_n(word, count));
+        self
+    }
+
+    /// The address the next word goes to.
+    pub fn next_addr(&self) -> u16 {
+        self.origin.wrapping_add(self.words.len() as u16)
+    }
+
+    /// The origin followed by the words.
+    pub fn build(&self) -> Vec<u8> {
+        std::iter::once(self.origin)
+            .chain(self.words.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect()
+    }
+
+    pub fn write_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.build())
+    }
+}
+
+fn decode_hex(text: &str, word: impl Fn(&[u8]) -> u16) -> Result<Vec<Segment>> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(record) = line.strip_prefix(':') else {
+            bail!("line {}: records start with ':'", i + 1);
+        };
+
+        let bytes = (0..record.len())
+            .step_by(2)
+            .map(|at| {
+                record
+                    .get(at..at + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>();
+        let Some(bytes) = bytes.filter(|bytes| bytes.len() >= 5) else {
+            bail!("line {}: bad record", i + 1);
+        };
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            bail!("line {}: bad checksum", i + 1);
+        }
+
+        let len = bytes[0] as usize;
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let data = &bytes[4..bytes.len() - 1];
+        if data.len() != len || !len.is_multiple_of(2) {
+            bail!("line {}: bad record length", i + 1);
+        }
+
+        match bytes[3] {
+            0x00 => {
+                let words = data.chunks_exact(2).map(&word);
+                // records usually continue where the previous one ended
+                match segments.last_mut() {
+                    Some(last) if last.range().end == addr as usize => last.words.extend(words),
+                    _ => segments.push(Segment {
+                        origin: addr,
+                        words: words.collect(),
+                    }),
+                }
+            }
+            0x01 => return Ok(segments),
+            kind => bail!("line {}: unsupported record type {kind:02X}", i + 1),
+        }
+    }
+
+    bail!("missing end of file record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_builder() {
+        let builder = ImageBuilder::new(0x3000)
+            .word(0xF025)
+            .string("ab")
+            .fill(0xFFFF, 2);
+        assert_eq!(builder.next_addr(), 0x3006);
+
+        let segments = decode(&builder.build(), &LoadOptions::default()).unwrap();
+        assert_eq!(
+            segments,
+            [Segment {
+                origin: 0x3000,
+                words: vec![0xF025, 0x61, 0x62, 0, 0xFFFF, 0xFFFF]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let little = LoadOptions {
+            format: ImageFormat::Obj,
+            little_endian: true,
+        };
+        assert_eq!(
+            decode(&[0x00, 0x30, 0x25, 0xF0], &little).unwrap(),
+            [Segment {
+                origin: 0x3000,
+                words: vec![0xF025]
+            }]
+        );
+
+        let raw = LoadOptions {
+            format: ImageFormat::Raw { origin: 0x4000 },
+            little_endian: false,
+        };
+        assert_eq!(
+            decode(&[0x12, 0x34, 0x56, 0x78], &raw).unwrap(),
+            [Segment {
+                origin: 0x4000,
+                words: vec![0x1234, 0x5678]
+            }]
+        );
+
+        let hex = LoadOptions {
+            format: ImageFormat::Hex,
+            little_endian: false,
+        };
+        let text = ":02300000F025B9\n:02300100123487\n:00000001FF\n";
+        assert_eq!(
+            decode(text.as_bytes(), &hex).unwrap(),
+            [Segment {
+                origin: 0x3000,
+                words: vec![0xF025, 0x1234]
+            }]
+        );
+        assert!(decode(b":02300000F02500\n:00000001FF\n", &hex).is_err());
+        assert!(decode(b":02300000F025B9\n", &hex).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! An LC-3 virtual machine.
+//!
+//! The [`Vm`] can be embedded in other programs: load an image, give it your own
+//! [`console::IoDevice`] instead of the terminal, and run it to completion or one
+//! instruction at a time.
+//!
+//! ```
+//! use lc3_vm::{console::StreamIo, Flag, RunResult, Vm};
+//!
+//! // ADD R0, R0, #5; HALT
+//! let image = [0x30, 0x00, 0x10, 0x25, 0xF0, 0x25];
+//!
+//! let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+//! vm.load_image_bytes(&image).unwrap();
+//! vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+//!
+//! assert_eq!(vm.step().unwrap(), RunResult::Running);
+//! assert_eq!(vm.registers()[0], 5);
+//! assert_eq!(vm.step().unwrap(), RunResult::Halted);
+//! ```
+
+pub mod annotations;
+pub mod args;
+pub mod asm;
+pub mod console;
+pub mod coverage;
+pub mod cycles;
+pub mod debugger;
+pub mod decode;
+pub mod device;
+pub mod diff;
+pub mod difftest;
+pub mod disasm;
+pub mod dump;
+pub mod env;
+pub mod events;
+pub mod fileio;
+pub mod gdb;
+pub mod grade;
+mod history;
+pub mod image;
+pub mod loader;
+pub mod machine;
+pub mod manifest;
+pub mod memory;
+mod pace;
+pub mod predicate;
+pub mod profile;
+pub mod psr;
+pub mod scheduler;
+pub mod shared;
+mod smc;
+pub mod sound;
+pub mod step;
+pub mod symbols;
+pub mod terminal;
+pub mod testkit;
+pub mod trace;
+pub mod trace_check;
+#[cfg(feature = "tui")]
+pub mod tui;
+mod uninit;
+mod util;
+pub mod video;
+pub mod vm;
+
+pub use vm::{Flag, HookAction, Protection, R7Check, RunResult, Vm, VmError, Warning, WatchKind};
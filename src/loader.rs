@@ -0,0 +1,214 @@
+//! Loads the text listings some course toolchains produce instead of binary objects:
+//! lc3as `.lst` files, `ADDR: VALUE` pairs like `disas` prints, and `.hex` files with one
+//! word per line after the origin. The format is picked by file extension, see
+//! [`decode_file`].
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Result};
+
+use crate::image::{self, ImageFormat, LoadOptions, Segment};
+
+/// Decodes the image `data` read from `file`. `.lst` files and `.hex` files that aren't
+/// Intel HEX are parsed as text with [`parse_listing`]; anything else, or any file when
+/// `options` asks for a format other than .obj, is decoded with [`image::decode`].
+pub fn decode_file(file: &Path, data: &[u8], options: &LoadOptions) -> Result<Vec<Segment>> {
+    if options.format != ImageFormat::Obj {
+        return image::decode(data, options);
+    }
+
+    let extension = file.extension().and_then(|ext| ext.to_str());
+    match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("lst") => parse_listing(&text(data)?),
+        Some("hex") => {
+            let text = text(data)?;
+            if text.trim_start().starts_with(':') {
+                let options = LoadOptions {
+                    format: ImageFormat::Hex,
+                    ..*options
+                };
+                image::decode(data, &options)
+            } else {
+                parse_listing(&text)
+            }
+        }
+        _ => image::decode(data, options),
+    }
+}
+
+fn text(data: &[u8]) -> Result<String> {
+    match std::str::from_utf8(data) {
+        Ok(text) => Ok(text.to_string()),
+        Err(_) => bail!("listings are text"),
+    }
+}
+
+/// Parses a text listing into the segments it loads. Execution starts at the first
+/// address listed. Lines can be
+///
+/// - `x3000: x5020`, optionally followed by anything, e.g. the disassembly
+/// - `(3000) 5020 0101000000100000 ...` as in lc3as listings, whose `.ORIG` lines and
+///   source lines without words, which only have a line number, are skipped
+/// - a bare hex word, stored after the previous one; the first one is the origin
+///
+/// Blank lines, `;` comments and label lines like `LOOP:` are skipped. Giving an address
+/// twice with different values is an error.
+pub fn parse_listing(text: &str) -> Result<Vec<Segment>> {
+    let mut words = BTreeMap::new();
+    let mut first = None;
+    // where the next bare word goes
+    let mut next: Option<u32> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+        let fields: Vec<_> = line.split_whitespace().collect();
+        // a label line, e.g. LOOP:
+        if fields.is_empty() || fields.len() == 1 && line.ends_with(':') {
+            continue;
+        }
+
+        let binary = |field: Option<&&str>| {
+            field
+                .is_some_and(|field| field.len() == 16 && field.bytes().all(|b| b"01".contains(&b)))
+        };
+        if fields[0].starts_with('(') && !binary(fields.get(2)) {
+            continue;
+        }
+
+        let (addr, value) = if let Some(addr) = fields[0]
+            .strip_prefix('(')
+            .and_then(|addr| addr.strip_suffix(')'))
+        {
+            if line.to_ascii_uppercase().contains(".ORIG") {
+                continue;
+            }
+            let value = fields.get(1).copied().unwrap_or_default();
+            (parse_word(addr, i)? as u32, parse_word(value, i)?)
+        } else if let Some(addr) = fields[0].strip_suffix(':') {
+            let value = fields.get(1).copied().unwrap_or_default();
+            (parse_word(addr, i)? as u32, parse_word(value, i)?)
+        } else if fields.len() == 1 {
+            let word = parse_word(fields[0], i)?;
+            match next {
+                Some(addr) => (addr, word),
+                None => {
+                    next = Some(word as u32);
+                    continue;
+                }
+            }
+        } else {
+            bail!("line {}: expected ADDR: VALUE", i + 1);
+        };
+
+        // a bare word after xFFFF
+        let Ok(addr) = u16::try_from(addr) else {
+            bail!("line {}: runs past the end of memory", i + 1);
+        };
+        if let Some(old) = words.insert(addr, value) {
+            if old != value {
+                bail!("line {}: address x{addr:04X} given twice", i + 1);
+            }
+        }
+        first.get_or_insert(addr);
+        next = Some(addr as u32 + 1);
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for (addr, value) in words {
+        match segments.last_mut() {
+            Some(last) if last.range().end == addr as usize => last.words.push(value),
+            _ => segments.push(Segment {
+                origin: addr,
+                words: vec![value],
+            }),
+        }
+    }
+    // the segment holding the entry point goes first, so the PC starts there
+    if let Some(first) = first {
+        let entry = segments
+            .iter()
+            .position(|segment| segment.range().contains(&(first as usize)))
+            .unwrap_or_default();
+        let mut segment = segments.remove(entry);
+        let tail = segment.words.split_off((first - segment.origin) as usize);
+        if !segment.words.is_empty() {
+            segments.push(segment);
+        }
+        segments.insert(
+            0,
+            Segment {
+                origin: first,
+                words: tail,
+            },
+        );
+    }
+
+    Ok(segments)
+}
+
+/// Parses a hex word, with or without an `x` or `0x` prefix.
+fn parse_word(s: &str, line: usize) -> Result<u16> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix(['x', 'X']))
+        .unwrap_or(s);
+    match u16::from_str_radix(hex, 16) {
+        Ok(word) => Ok(word),
+        Err(_) => bail!("line {}: bad word {s}", line + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listing() {
+        let disas = "\
+START:
+x3000: x5020  AND R0, R0, #0
+x3001: xF025  HALT
+DATA:
+x3005: x0041  NOP x3047           ; 'A'
+";
+        assert_eq!(
+            parse_listing(disas).unwrap(),
+            [
+                Segment {
+                    origin: 0x3000,
+                    words: vec![0x5020, 0xF025]
+                },
+                Segment {
+                    origin: 0x3005,
+                    words: vec![0x0041]
+                }
+            ]
+        );
+
+        let lc3as = "\
+  (0000) 3000  0011000000000000 (   1)                 .ORIG x3000
+  (3000) 5020  0101000000100000 (   2)                 AND R0, R0, #0
+  (3001) F025  1111000000100101 (   3)                 HALT
+";
+        let hex = "3000\n5020\nF025\n";
+        assert_eq!(parse_listing(lc3as).unwrap(), parse_listing(hex).unwrap());
+        assert_eq!(
+            parse_listing(hex).unwrap(),
+            [Segment {
+                origin: 0x3000,
+                words: vec![0x5020, 0xF025]
+            }]
+        );
+
+        assert!(parse_listing("x3000: x1234\nx3000: x5678\n").is_err());
+        assert_eq!(
+            parse_listing("xFFFF: x1234\n").unwrap(),
+            [Segment {
+                origin: 0xFFFF,
+                words: vec![0x1234]
+            }]
+        );
+        assert!(parse_listing("xFFFF: x1234\nx5678\n").is_err());
+        assert!(parse_listing("x3000 x1234\n").is_err());
+    }
+}
@@ -0,0 +1,519 @@
+//! How a new vm starts out: bare metal, with nothing in memory and the traps handled by
+//! the vm, or booted like a real LC-3 system with an OS in memory, and which devices and
+//! host trap routines it has.
+//!
+//! A [`MachineConfig`] can be read from a TOML file; everything left out keeps its
+//! bare metal default:
+//!
+//! ```toml
+//! os = true                   # see MachineConfig::with_os
+//! trap_mode = "native"        # or "os", the default with the OS
+//! pc = "x3000"                # the entry point, over the images' origin
+//! psr = "x8002"               # user mode with Z set
+//! supervisor_stack = "x2FF0"
+//! hz = 100000                 # see Vm::set_speed
+//! images = ["os.obj", "game.obj"]  # relative to the file
+//!
+//! [devices]                   # built-in devices, all enabled by default
+//! rng = false
+//!
+//! [[protect]]
+//! range = "x0000-x2FFF"
+//! access = "ro"               # ro, nx or none
+//!
+//! [trap_vectors]
+//! x40 = "x1000"
+//!
+//! [interrupt_vectors]
+//! x81 = "x1200"
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::{
+    device::{Device, BUILTIN_DEVICES},
+    dump::parse_range,
+    util::{parse_literal, Word},
+    vm::{Flag, Protection, TrapHandler, TrapMode, Vm, IVT, MCR, MCR_CLOCK},
+};
+
+/// Builds a [`Vm`] with a chosen startup state.
+///
+/// ```
+/// use lc3_vm::{machine::MachineConfig, psr::PSR_USER, vm::Flag};
+///
+/// // a user program under the bundled OS, with a handler for privilege violations
+/// let vm = MachineConfig::with_os()
+///     .psr(PSR_USER | Flag::Zero as u16)
+///     .supervisor_stack(0x2FF0)
+///     .interrupt_vector(0x00, 0x1000)
+///     .build()
+///     .unwrap();
+/// assert_eq!(vm.memory()[0x0100], 0x1000);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineConfig {
+    pc: Option<u16>,
+    psr: u16,
+    os: bool,
+    trap_mode: TrapMode,
+    supervisor_stack: Option<u16>,
+    trap_vectors: BTreeMap<u8, u16>,
+    interrupt_vectors: BTreeMap<u8, u16>,
+    protections: Vec<(RangeInclusive<u16>, Protection)>,
+    disabled_devices: Vec<String>,
+    speed: Option<u64>,
+    images: Vec<PathBuf>,
+    detect_uninit: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    os: bool,
+    trap_mode: Option<String>,
+    pc: Option<Word>,
+    psr: Option<Word>,
+    supervisor_stack: Option<Word>,
+    hz: Option<u64>,
+    #[serde(default)]
+    images: Vec<PathBuf>,
+    #[serde(default)]
+    devices: BTreeMap<String, bool>,
+    #[serde(default)]
+    protect: Vec<ProtectEntry>,
+    #[serde(default)]
+    trap_vectors: BTreeMap<String, Word>,
+    #[serde(default)]
+    interrupt_vectors: BTreeMap<String, Word>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProtectEntry {
+    range: String,
+    access: String,
+}
+
+fn parse_vector(s: &str) -> Result<u8> {
+    match parse_literal(s) {
+        Some(n @ 0..=0xFF) => Ok(n as u8),
+        _ => bail!("bad vector: {s}"),
+    }
+}
+
+/// Removes the built-in device `name` from `vm`.
+fn disable_device(vm: &mut Vm, name: &str) -> Result<()> {
+    if !BUILTIN_DEVICES.contains(&name) {
+        bail!("unknown device: {name}");
+    }
+    vm.unmap_device(name);
+    Ok(())
+}
+
+impl MachineConfig {
+    /// Zeroed memory, traps handled by the vm, starting at x3000 in supervisor mode, like
+    /// [`Vm::new`].
+    pub fn bare_metal() -> Self {
+        Self {
+            pc: None,
+            psr: Flag::Zero as u16,
+            os: false,
+            trap_mode: TrapMode::Native,
+            supervisor_stack: None,
+            trap_vectors: BTreeMap::new(),
+            interrupt_vectors: BTreeMap::new(),
+            protections: Vec::new(),
+            disabled_devices: Vec::new(),
+            speed: None,
+            images: Vec::new(),
+            detect_uninit: false,
+        }
+    }
+
+    /// The bundled OS loaded with its trap vector table, see [`Vm::load_os`], TRAPs
+    /// going through the table and the clock bit of the MCR set.
+    pub fn with_os() -> Self {
+        Self {
+            os: true,
+            trap_mode: TrapMode::Os,
+            ..Self::bare_metal()
+        }
+    }
+
+    /// Reads a config in the TOML format described in the [module docs](self), with
+    /// images relative to the current directory.
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: ConfigFile = toml::from_str(text)?;
+
+        let mut config = if file.os {
+            Self::with_os()
+        } else {
+            Self::bare_metal()
+        };
+        config.trap_mode = match file.trap_mode.as_deref() {
+            None => config.trap_mode,
+            Some("native") => TrapMode::Native,
+            Some("os") => TrapMode::Os,
+            Some(mode) => bail!("bad trap_mode {mode}, expected native or os"),
+        };
+        config.pc = file.pc.map(|Word(pc)| pc);
+        config.psr = file.psr.map_or(config.psr, |Word(psr)| psr);
+        config.supervisor_stack = file.supervisor_stack.map(|Word(sp)| sp);
+        config.speed = file.hz;
+        config.images = file.images;
+        for (name, enabled) in file.devices {
+            if !BUILTIN_DEVICES.contains(&name.as_str()) {
+                bail!("unknown device: {name}");
+            }
+            if !enabled {
+                config.disabled_devices.push(name);
+            }
+        }
+        for entry in file.protect {
+            let protection = Protection::parse(&entry.access)?;
+            config
+                .protections
+                .push((parse_range(&entry.range)?, protection));
+        }
+        for (vector, Word(routine)) in file.trap_vectors {
+            config.trap_vectors.insert(parse_vector(&vector)?, routine);
+        }
+        for (vector, Word(handler)) in file.interrupt_vectors {
+            config
+                .interrupt_vectors
+                .insert(parse_vector(&vector)?, handler);
+        }
+
+        Ok(config)
+    }
+
+    /// Like [`MachineConfig::parse`], with images relative to the file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let mut config = Self::parse(&text)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for image in &mut config.images {
+            *image = dir.join(&*image);
+        }
+
+        Ok(config)
+    }
+
+    /// The images to load, relative to the file for configurations read with
+    /// [`MachineConfig::from_file`].
+    pub fn images(&self) -> &[PathBuf] {
+        &self.images
+    }
+
+    /// Where execution starts, even with images loaded. Without it, that's the first
+    /// image's origin, or x3000.
+    pub fn pc(mut self, pc: u16) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    pub fn psr(mut self, psr: u16) -> Self {
+        self.psr = psr;
+        self
+    }
+
+    pub fn trap_mode(mut self, trap_mode: TrapMode) -> Self {
+        self.trap_mode = trap_mode;
+        self
+    }
+
+    /// See [`Vm::set_supervisor_stack`]. Defaults to x3000.
+    pub fn supervisor_stack(mut self, sp: u16) -> Self {
+        self.supervisor_stack = Some(sp);
+        self
+    }
+
+    /// Points entry `vector` of the trap vector table at `routine`, over the OS's own.
+    /// TRAPs only go through the table in [`TrapMode::Os`].
+    pub fn trap_vector(mut self, vector: u8, routine: u16) -> Self {
+        self.trap_vectors.insert(vector, routine);
+        self
+    }
+
+    /// Points entry `vector` of the interrupt vector table at `handler`.
+    pub fn interrupt_vector(mut self, vector: u8, handler: u16) -> Self {
+        self.interrupt_vectors.insert(vector, handler);
+        self
+    }
+
+    /// See [`Vm::protect`].
+    pub fn protect(mut self, range: RangeInclusive<u16>, protection: Protection) -> Self {
+        self.protections.push((range, protection));
+        self
+    }
+
+    /// Leaves out one of the [`BUILTIN_DEVICES`], so its addresses are memory.
+    pub fn without_device(mut self, name: &str) -> Self {
+        self.disabled_devices.push(name.into());
+        self
+    }
+
+    /// See [`Vm::set_speed`].
+    pub fn speed(mut self, hz: u64) -> Self {
+        self.speed = Some(hz);
+        self
+    }
+
+    /// Loads the image file at `path`, after the OS. Images are checked for overlaps
+    /// like [`Vm::read_images`] does.
+    pub fn image(mut self, path: impl Into<PathBuf>) -> Self {
+        self.images.push(path.into());
+        self
+    }
+
+    /// Enables [`Vm::enable_uninit_detection`] before the OS and images are loaded.
+    pub fn detect_uninit(mut self) -> Self {
+        self.detect_uninit = true;
+        self
+    }
+
+    /// Fails if an image can't be loaded or a device left out isn't built in.
+    pub fn build(&self) -> Result<Vm> {
+        let mut vm = Vm::new(self.pc.unwrap_or(0x3000), self.psr);
+        if self.detect_uninit {
+            vm.enable_uninit_detection();
+        }
+        if self.os {
+            vm.load_os()?;
+            vm.set_memory(MCR, MCR_CLOCK);
+        }
+        vm.set_trap_mode(self.trap_mode);
+        if let Some(sp) = self.supervisor_stack {
+            vm.set_supervisor_stack(sp);
+        }
+        for (&vector, &routine) in &self.trap_vectors {
+            vm.set_memory(vector as u16, routine);
+        }
+        for (&vector, &handler) in &self.interrupt_vectors {
+            vm.set_memory(IVT + vector as u16, handler);
+        }
+        for name in &self.disabled_devices {
+            disable_device(&mut vm, name)?;
+        }
+        vm.set_speed(self.speed);
+        vm.read_images(&self.images)?;
+        if let Some(pc) = self.pc {
+            vm.set_pc(pc);
+        }
+        for (range, protection) in &self.protections {
+            vm.protect(range.clone(), *protection);
+        }
+
+        Ok(vm)
+    }
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self::bare_metal()
+    }
+}
+
+/// Builds a [`Vm`] with devices and trap routines from other crates, see [`Vm::builder`].
+///
+/// ```
+/// use std::io;
+/// use lc3_vm::{device::Device, vm::Vm};
+///
+/// struct Switches(u16);
+///
+/// impl Device for Switches {
+///     fn read(&mut self, _addr: u16) -> u16 {
+///         self.0
+///     }
+///
+///     fn write(&mut self, _addr: u16, _val: u16) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let vm = Vm::builder()
+///     .device("switches", 0xFE40..=0xFE40, Switches(0b1010))
+///     .trap(0x40, |vm: &mut Vm, _| {
+///         vm.set_register(0, 42);
+///         Ok(())
+///     })
+///     .without_device("rng")
+///     .build()
+///     .unwrap();
+/// assert_eq!(vm.device_names().collect::<Vec<_>>(), ["timer", "switches"]);
+/// ```
+#[derive(Default)]
+pub struct VmBuilder {
+    config: MachineConfig,
+    devices: Vec<(String, RangeInclusive<u16>, Box<dyn Device>)>,
+    traps: Vec<(u8, Box<dyn TrapHandler>)>,
+    disabled: Vec<String>,
+}
+
+impl VmBuilder {
+    pub fn config(mut self, config: MachineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Maps `device` over `range` under `name`, see [`Vm::map_named_device`]. Devices
+    /// added earlier take precedence.
+    pub fn device(
+        mut self,
+        name: &str,
+        range: RangeInclusive<u16>,
+        device: impl Device + 'static,
+    ) -> Self {
+        self.devices.push((name.into(), range, Box::new(device)));
+        self
+    }
+
+    /// Registers `routine` for `TRAP vector`, see [`Vm::register_trap`].
+    pub fn trap(mut self, vector: u8, routine: impl TrapHandler + 'static) -> Self {
+        self.traps.push((vector, Box::new(routine)));
+        self
+    }
+
+    /// Leaves out one of the [`BUILTIN_DEVICES`], so its addresses are memory.
+    pub fn without_device(mut self, name: &str) -> Self {
+        self.disabled.push(name.into());
+        self
+    }
+
+    /// Fails if a device left out isn't built in.
+    pub fn build(self) -> Result<Vm> {
+        let mut vm = self.config.build()?;
+        for name in &self.disabled {
+            disable_device(&mut vm, name)?;
+        }
+        for (name, range, device) in self.devices {
+            vm.map_named_device(&name, range, device);
+        }
+        for (vector, mut routine) in self.traps {
+            vm.register_trap(vector, move |vm: &mut Vm, vector| routine.call(vm, vector));
+        }
+
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        console::StreamIo, device::RNG, image::ImageBuilder, psr::PSR_USER, util::SharedBuf,
+    };
+
+    #[test]
+    fn test_machine_config() {
+        let vm = MachineConfig::bare_metal().build().unwrap();
+        assert_eq!(vm.pc(), 0x3000);
+        assert!(vm.memory().iter().all(|&word| word == 0));
+
+        // RTI in user mode is a privilege violation, whose handler halts
+        let mut vm = MachineConfig::with_os()
+            .psr(PSR_USER | Flag::Zero as u16)
+            .supervisor_stack(0x2FF0)
+            .interrupt_vector(0x00, 0x4000)
+            .build()
+            .unwrap();
+        assert_eq!(vm.memory()[MCR as usize], MCR_CLOCK);
+        assert_ne!(vm.memory()[0x25], 0);
+        vm.load_image_bytes(&ImageBuilder::new(0x3000).word(0x8000).build())
+            .unwrap();
+        vm.load_image_bytes(&ImageBuilder::new(0x4000).word(0xF025).build())
+            .unwrap();
+        vm.set_pc(0x3000);
+        let out = SharedBuf::default();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), out.clone())));
+        vm.run().unwrap();
+
+        // the PSR and PC were pushed on the supervisor stack
+        assert_eq!(vm.memory()[0x2FEF], PSR_USER | Flag::Zero as u16);
+        assert_eq!(vm.memory()[0x2FEE], 0x3001);
+        assert!(!out.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = MachineConfig::parse(
+            r#"
+            os = true
+            pc = "x3100"
+            hz = 1000
+            [devices]
+            rng = false
+            timer = true
+            [[protect]]
+            range = "x0000-x2FFF"
+            access = "ro"
+            [interrupt_vectors]
+            x81 = "x1200"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            MachineConfig::with_os()
+                .pc(0x3100)
+                .speed(1000)
+                .without_device("rng")
+                .protect(0x0000..=0x2FFF, Protection::ReadOnly)
+                .interrupt_vector(0x81, 0x1200)
+        );
+        let vm = config.build().unwrap();
+        assert_eq!(vm.pc(), 0x3100);
+        assert_eq!(vm.device_names().collect::<Vec<_>>(), ["timer"]);
+
+        assert!(MachineConfig::parse("[devices]\ndisk = true\n").is_err());
+        assert!(MachineConfig::parse("trap_mode = \"bios\"\n").is_err());
+        assert!(MachineConfig::parse("[trap_vectors]\nx100 = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_builder() {
+        struct Constant(u16);
+
+        impl Device for Constant {
+            fn read(&mut self, _addr: u16) -> u16 {
+                self.0
+            }
+
+            fn write(&mut self, _addr: u16, _val: u16) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // LDI R0, #3; LDI R1, #3; TRAP x40; HALT; xFE40; xFE2C
+        let mut vm = Vm::builder()
+            .device("constant", 0xFE40..=0xFE40, Constant(7))
+            .trap(0x40, |vm: &mut Vm, _| {
+                vm.set_register(2, 9);
+                Ok(())
+            })
+            .without_device("rng")
+            .build()
+            .unwrap();
+        let image = ImageBuilder::new(0x3000)
+            .words(&[0xA003, 0xA203, 0xF040, 0xF025, 0xFE40, RNG])
+            .build();
+        vm.load_image_bytes(&image).unwrap();
+        vm.set_memory(RNG, 5);
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        vm.run().unwrap();
+        assert_eq!(vm.registers()[..3], [7, 5, 9]);
+
+        assert!(Vm::builder().without_device("disk").build().is_err());
+    }
+}
@@ -1,13 +1,192 @@
-mod vm;
+mod batch;
+mod demos;
 
 use std::{
-    io::{self, stdin, Read},
-    os::unix::prelude::AsRawFd,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::RangeInclusive,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::Result;
-use nix::sys::termios;
-use vm::Vm;
+use anyhow::{anyhow, bail, Result};
+use batch::{BatchOptions, Case};
+use lc3_vm::{
+    annotations::Annotations,
+    asm,
+    console::{
+        ConsoleAddrs, ConsoleOptions, DeviceTiming, EofMode, ExtraConsole, SocketIo, StreamIo,
+    },
+    cycles::CycleModel,
+    debugger,
+    device::BUILTIN_DEVICES,
+    difftest, disasm, dump,
+    env::DeterministicEnv,
+    fileio, gdb, grade,
+    grade::Rubric,
+    image::{ImageFormat, LoadOptions},
+    loader,
+    machine::MachineConfig,
+    manifest::{FileHash, RunManifest},
+    predicate::Predicate,
+    sound::{self, Bell, ToneLog},
+    symbols::SymbolTable,
+    terminal::{
+        catch_interrupts, enable_raw_mode, interrupted, take_interrupt, ArrowKeys, InputMode,
+        TerminalIo,
+    },
+    trace::{TraceReader, TraceWriter},
+    trace_check, video,
+    vm::{
+        self, ClockMode, Engine, HookAction, Protection, R7Check, Snapshot, TrapMode, UnknownTrap,
+        Vm, VmError,
+    },
+};
+
+const USAGE: &str = "\
+Usage: lc3-vm [run] [options] binaries...
+       lc3-vm debug [options] binaries...
+       lc3-vm asm <source.asm> [-o <image.obj>] [--listing]
+       lc3-vm disas <image>
+       lc3-vm diff <left> <right>
+       lc3-vm snapdiff [--sym FILE] <left.snap> <right.snap>
+       lc3-vm dump [--disas] [--range START-END] images...
+       lc3-vm examples list|run <name>
+       lc3-vm trace-dump <trace>
+       lc3-vm trace-check [--input FILE] <image> <expected.csv>
+       lc3-vm difftest [--input FILE] [--engine simple|cached] [--limit N]
+                       (--golden TRACE | --reference CMD) images...
+       lc3-vm grade <rubric.toml> images...
+       lc3-vm batch [--jobs N] [--input FILE] [--timeout SECS] [--out-dir DIR]
+                    [--config FILE] [--strict] [--crlf] [--on-eof stop|eot]
+                    [--max-insts N] images or manifest.toml...
+       lc3-vm rerun <manifest.toml>
+
+Each binary is loaded at its own origin, e.g. an OS image and a user program, and
+execution starts at the origin of the last one.
+
+Options:
+    -h, --help                      show this help
+    -V, --version                   show the version
+    --config FILE                   set the machine up as FILE describes: the OS, trap
+                                    mode, entry point, devices, protections, speed and
+                                    images to load, see the machine module; the other
+                                    options and images come on top
+    --debug                         start in the interactive debugger (like debug)
+    --debug-script FILE             run the debugger commands in FILE instead, exiting
+                                    with the status given to quit, or 1 when a command
+                                    or an assert fails
+    --pc, --entry ADDR              start at ADDR, a label or an address like x3000,
+                                    instead of the origin of the last binary
+    --reg Rn=VALUE                  set a register before running, e.g. R0=x1234 or R1=#-1
+    --mem ADDR=VALUE                store VALUE at ADDR before running, e.g. x4000=xBEEF
+    --protect START-END:ro|nx|none  make an address range read-only, not executable or
+                                    inaccessible, e.g. x0000-x2FFF:ro; violations raise
+                                    an access control violation exception, or stop the
+                                    program if there is no handler
+    --tui                           show registers, memory and the console in a full
+                                    screen front-end (needs the tui feature)
+    --gdb PORT                      wait for GDB to connect on localhost:PORT and let it
+                                    control the program
+    --unknown-trap vector|error     what to do on a trap without a native routine
+    --format obj|raw|hex            how the binaries are laid out: lc3as .obj (the
+                                    default), raw words, or Intel HEX records with word
+                                    addresses; with obj, binaries ending in .lst or .hex
+                                    are read as text listings
+    --origin ADDR                   where --format raw binaries load, default x3000
+    --little-endian                 the binaries' words are little-endian
+    --os                            load the bundled OS and run the standard traps in it
+                                    instead of natively
+    --stdin-file FILE               read the program's keyboard input from FILE
+    --stdout-file FILE              write the program's console output to FILE
+                                    (either option turns off raw terminal mode)
+    --listen ADDR                   wait for a TCP connection on ADDR, e.g. 0.0.0.0:4000,
+                                    and use it as the program's console
+    --record FILE                   write every key the program reads to FILE, with the
+                                    instruction count at which it was read
+    --replay FILE                   read keys from a recording instead of the keyboard,
+                                    delivering each at the point it was recorded
+    --coverage FILE                 write which addresses executed and which way branches
+                                    went to FILE as JSON, and an annotated listing of the
+                                    binaries to FILE with the extension .lst
+    --stats                         print the instructions executed, the simulated cycles,
+                                    the time taken and the resulting MIPS when the program
+                                    stops
+    --profile                       count executed instructions per opcode and address and
+                                    print the hottest code and loops when the program stops
+    --profile-out FILE              write the instructions executed per call stack to FILE
+                                    in the folded format of flamegraph tools
+    --escape-sequences              deliver arrow/function keys as whole escape sequences
+    --arrow-keys UP,DOWN,RIGHT,LEFT deliver the arrow keys as single codes instead, e.g.
+                                    w,s,d,a or x80,x81,x82,x83
+    --args 'ARG...'                 pass the space-separated arguments to the program in a
+                                    block ending at xFDFF: R0 is argc, R1 points at argv
+                                    and R6 at argc
+    --crlf                          print each newline as \\r\\n
+    --on-eof stop|eot               at the end of the input, stop the program (the
+                                    default) or give it EOT (x04) for every read
+    --getenv                        enable the GETENV trap (x26)
+    --file-traps DIR                enable the file traps x30-x33 (open, read, write and
+                                    close), with file names relative to DIR
+    --video FILE                    draw the video memory xC000-xFDFF to FILE, e.g. another
+                                    terminal's /dev/pts/N, and set bit 15 of the status
+                                    register xFE0C every frame
+    --sound bell|log                play the tones written to the sound registers xFE30
+                                    and xFE32 as terminal bells, or list them on stderr
+    --max-insts N                   stop with an error after N instructions
+    --max-cycles N                  stop with an error after N simulated cycles
+    --cycle-costs FILE              read the cycles each opcode and memory access costs
+                                    from a TOML file, see the cycles module
+    --detect-r7-clobber[=strict]    warn when a RET doesn't return to the instruction after
+                                    its call, usually because the subroutine overwrote R7;
+                                    with strict, stop the program instead
+    --detect-uninit                 warn when an instruction uses a register or loads from
+                                    an address the program never wrote
+    --detect-smc                    warn when the program stores to an address it executed
+                                    an instruction from, usually a stray ST into its code
+    --strict                        stop at the first instruction whose unused bits aren't
+                                    what the LC-3 spec requires, e.g. ADD with bits 4-3 set
+    --device NAME=on|off            enable or disable a built-in device: timer (xFE08,
+                                    xFE0A) or rng (xFE2C); disabled, its addresses are
+                                    memory
+    --device-timing BUSY,DELAY      keep DSR busy for BUSY instructions after each
+                                    character and KBSR from reporting a key for DELAY
+                                    instructions, to catch incorrect polling
+    --engine simple|cached          cached keeps decoded instructions until they are
+                                    overwritten, which runs long loops faster
+    --hz N                          run at most about N instructions per second
+    --deterministic-clock N         advance the clock registers 1ms every N instructions
+    --frozen-clock                  the same with N = 1000, for tests that read the clock
+    --deterministic                 take nothing from the host: the clock and timer advance
+                                    1ms every 1000 instructions, the random seed is 0 and
+                                    KBSR is always ready, so reads wait for the key
+    --seed N                        seed the random number register xFE2C, which is seeded
+                                    from the host clock otherwise
+    --emit-state-json FILE          when the program halts or fails, write its registers,
+                                    PC, PSR, instruction count and error to FILE as JSON
+    --memory-digest                 add a hash of memory to --emit-state-json
+    --manifest FILE                 when the program stops, write what rerun needs to
+                                    repeat the run to FILE as TOML: the arguments, hashes
+                                    of the files it read, the random seed, the instruction
+                                    count and how it ended; record or replay the input to
+                                    repeat it too
+    --save-on-halt FILE             write a snapshot of the machine to FILE when it halts
+    --resume FILE                   start from a snapshot instead of a binary
+    --trace FILE                    write one JSON line per executed instruction to FILE,
+                                    or to stdout if FILE is -
+    --trace-bin FILE                write a compact binary trace, see trace-dump
+    --console-addrs KBSR,KBDR,DSR,DDR
+                                    move the console registers, e.g. xFE10,xFE12,xFE14,xFE16
+    --extra-console ADDRS IN OUT    add a keyboard/display pair at ADDRS (as above),
+                                    reading keys from file IN and writing to file OUT
+    --uart-connect ADDRS SOCKET     add a keyboard/display pair at ADDRS connected to
+                                    the Unix socket SOCKET
+    --uart-listen ADDRS SOCKET      like --uart-connect, but create SOCKET and wait for
+                                    a peer to connect before running
+    --trace-when EXPR               only trace instructions after which EXPR holds,
+                                    e.g. 'R5 != 0' or 'PC >= x3010 && PC < x3020',
+                                    or that run inside a call to a label, 'in FOO'
+";
 
 fn main() {
     if let Err(err) = try_main() {
@@ -17,23 +196,674 @@ fn main() {
 }
 
 fn try_main() -> Result<()> {
-    let mut args = std::env::args();
-    args.next();
-
     env_logger::init();
 
-    let file = match args.next() {
-        Some(file) => file,
-        None => {
-            eprintln!("Usage: lc3-vm binary");
-            std::process::exit(1)
+    run_cli(std::env::args().skip(1).collect())
+}
+
+/// Does what `argv`, the arguments after `lc3-vm`, ask for.
+fn run_cli(argv: Vec<String>) -> Result<()> {
+    let mut debug = false;
+    let mut debug_script = None;
+    let mut args = argv.iter().cloned().peekable();
+    match args.peek().map(String::as_str) {
+        Some("--help" | "-h") => {
+            print!("{USAGE}");
+            return Ok(());
+        }
+        Some("--version" | "-V") => {
+            println!("lc3-vm {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Some("run") => {
+            args.next();
+        }
+        Some("debug") => {
+            args.next();
+            debug = true;
+        }
+        Some("asm") => {
+            args.next();
+            return assemble(args);
+        }
+        Some("disas") => {
+            args.next();
+            let image = args
+                .next()
+                .ok_or_else(|| anyhow!("disas expects an image"))?;
+            return disas(image);
+        }
+        Some("diff") => {
+            args.next();
+            let (Some(left), Some(right)) = (args.next(), args.next()) else {
+                bail!("diff expects two images");
+            };
+            return diff(left, right);
+        }
+        Some("snapdiff") => {
+            args.next();
+            return snapdiff(args);
+        }
+        Some("dump") => {
+            args.next();
+            return dump(args);
+        }
+        Some("examples") => {
+            args.next();
+            return examples(args);
+        }
+        Some("grade") => {
+            args.next();
+            return grade(args);
+        }
+        Some("batch") => {
+            args.next();
+            return batch(args);
+        }
+        Some("rerun") => {
+            args.next();
+            return rerun(args);
+        }
+        Some("trace-dump") => {
+            args.next();
+            let file = args
+                .next()
+                .ok_or_else(|| anyhow!("trace-dump expects a trace file"))?;
+            return trace_dump(file);
+        }
+        Some("trace-check") => {
+            args.next();
+            return trace_check(args);
+        }
+        Some("difftest") => {
+            args.next();
+            return difftest(args);
+        }
+        _ => (),
+    }
+
+    let mut files = Vec::new();
+    let mut unknown_trap = UnknownTrap::Error;
+    let mut input_mode = InputMode::Bytes;
+    let mut getenv = false;
+    let mut file_root = None;
+    let mut video = None;
+    let mut sound = None;
+    let mut os = false;
+    let mut clock_mode = ClockMode::Host;
+    let mut seed = None;
+    let mut deterministic = false;
+    let mut engine = Engine::Simple;
+    let mut speed = None;
+    let mut device_timing = DeviceTiming::default();
+    let mut console_options = ConsoleOptions::default();
+    let mut program_args = None;
+    let mut trace_file = None;
+    let mut json_trace = None;
+    let mut trace_when = None;
+    let mut console_addrs = ConsoleAddrs::default();
+    let mut extra_consoles = Vec::new();
+    let mut gdb_port = None;
+    let mut tui = false;
+    let mut stdin_file = None;
+    let mut stdout_file = None;
+    let mut listen = None;
+    let mut max_instructions = None;
+    let mut max_cycles = None;
+    let mut disabled_devices = Vec::new();
+    let mut config_file = None;
+    let mut cycle_model = CycleModel::default();
+    let mut save_on_halt = None;
+    let mut state_json = None;
+    let mut memory_digest = false;
+    let mut manifest = None;
+    // besides the images, for the manifest
+    let mut read_files = Vec::new();
+    let mut resume = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut profile = false;
+    let mut profile_out = None;
+    let mut stats = false;
+    let mut coverage = None;
+    let mut format = "obj".to_string();
+    let mut origin = 0x3000;
+    let mut little_endian = false;
+    let mut pc = None;
+    let mut reg_values = Vec::new();
+    let mut mem_values = Vec::new();
+    let mut protections = Vec::new();
+    let mut r7_check = R7Check::Off;
+    let mut detect_uninit = false;
+    let mut detect_smc = false;
+    let mut strict = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--unknown-trap" => {
+                unknown_trap = match args.next().as_deref() {
+                    Some("vector") => UnknownTrap::Vector,
+                    Some("error") => UnknownTrap::Error,
+                    _ => bail!("--unknown-trap expects one of: vector, error"),
+                }
+            }
+            "--engine" => {
+                engine = match args.next().as_deref() {
+                    Some("simple") => Engine::Simple,
+                    Some("cached") => Engine::Cached,
+                    _ => bail!("--engine expects one of: simple, cached"),
+                }
+            }
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                return Ok(());
+            }
+            "--version" | "-V" => {
+                println!("lc3-vm {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            "--config" => {
+                config_file = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--config expects a file name"))?,
+                );
+            }
+            "--debug" => debug = true,
+            "--debug-script" => {
+                debug_script = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--debug-script expects a file name"))?,
+                );
+            }
+            "--pc" | "--entry" => {
+                pc = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("{arg} expects an address"))?,
+                );
+            }
+            "--reg" => {
+                let assignment = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--reg expects Rn=VALUE"))?;
+                reg_values.push(split_assignment(&assignment)?);
+            }
+            "--mem" => {
+                let assignment = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--mem expects ADDR=VALUE"))?;
+                mem_values.push(split_assignment(&assignment)?);
+            }
+            "--protect" => {
+                let protection = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--protect expects START-END:ro|nx|none"))?;
+                protections.push(parse_protection(&protection)?);
+            }
+            "--emit-state-json" => {
+                state_json = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--emit-state-json expects a file name"))?,
+                );
+            }
+            "--memory-digest" => memory_digest = true,
+            "--manifest" => {
+                manifest = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--manifest expects a file name"))?,
+                );
+            }
+            "--detect-r7-clobber" => r7_check = R7Check::Warn,
+            "--detect-r7-clobber=strict" => r7_check = R7Check::Strict,
+            "--detect-uninit" => detect_uninit = true,
+            "--detect-smc" => detect_smc = true,
+            "--strict" => strict = true,
+            "--tui" => tui = true,
+            "--gdb" => {
+                gdb_port = match args.next().map(|port| port.parse()) {
+                    Some(Ok(port)) => Some(port),
+                    _ => bail!("--gdb expects a port number"),
+                };
+            }
+            "--stdin-file" => {
+                stdin_file = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--stdin-file expects a file name"))?,
+                );
+            }
+            "--stdout-file" => {
+                stdout_file = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--stdout-file expects a file name"))?,
+                );
+            }
+            "--listen" => {
+                listen =
+                    Some(args.next().ok_or_else(|| {
+                        anyhow!("--listen expects an address, e.g. 0.0.0.0:4000")
+                    })?);
+            }
+            "--max-cycles" => {
+                max_cycles = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => Some(n),
+                    _ => bail!("--max-cycles expects a cycle count"),
+                };
+            }
+            "--device" => {
+                let arg = args.next().unwrap_or_default();
+                let Some((name, state)) = arg.split_once('=') else {
+                    bail!("--device expects NAME=on|off");
+                };
+                if !BUILTIN_DEVICES.contains(&name) {
+                    bail!(
+                        "unknown device: {name}, expected one of: {}",
+                        BUILTIN_DEVICES.join(", ")
+                    );
+                }
+                match state {
+                    "on" => disabled_devices.retain(|disabled| disabled != name),
+                    "off" => disabled_devices.push(name.to_string()),
+                    _ => bail!("--device expects NAME=on|off"),
+                }
+            }
+            "--cycle-costs" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--cycle-costs expects a file name"))?;
+                let text =
+                    std::fs::read_to_string(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+                cycle_model = CycleModel::parse(&text).map_err(|err| anyhow!("{file}: {err}"))?;
+                read_files.push(file);
+            }
+            "--max-insts" => {
+                max_instructions = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => Some(n),
+                    _ => bail!("--max-insts expects an instruction count"),
+                };
+            }
+            "--os" => os = true,
+            "--save-on-halt" => {
+                save_on_halt = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--save-on-halt expects a file name"))?,
+                );
+            }
+            "--resume" => {
+                resume = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--resume expects a snapshot file"))?,
+                );
+            }
+            "--record" => {
+                record = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--record expects a file name"))?,
+                );
+            }
+            "--replay" => {
+                replay = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--replay expects a recording"))?,
+                );
+            }
+            "--profile" => profile = true,
+            "--profile-out" => {
+                profile_out = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--profile-out expects a file name"))?,
+                );
+            }
+            "--stats" => stats = true,
+            "--format" => {
+                format = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--format expects one of: obj, raw, hex"))?;
+            }
+            "--origin" => {
+                let addr = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--origin expects an address"))?;
+                origin = SymbolTable::default().parse_addr(&addr)?;
+            }
+            "--little-endian" => little_endian = true,
+            "--coverage" => {
+                coverage = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--coverage expects a file name"))?,
+                );
+            }
+            "--escape-sequences" => input_mode = InputMode::EscapeSequences,
+            "--arrow-keys" => {
+                let keys = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--arrow-keys expects UP,DOWN,RIGHT,LEFT"))?;
+                input_mode = InputMode::ArrowKeys(ArrowKeys::parse(&keys)?);
+            }
+            "--args" => {
+                program_args = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--args expects the program's arguments"))?,
+                );
+            }
+            "--crlf" => console_options.crlf = true,
+            "--on-eof" => {
+                let mode = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--on-eof expects stop or eot"))?;
+                console_options.eof = EofMode::parse(&mode)?;
+            }
+            "--getenv" => getenv = true,
+            "--file-traps" => {
+                file_root = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--file-traps expects a directory"))?,
+                );
+            }
+            "--video" => {
+                video = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--video expects a file name, e.g. a tty"))?,
+                );
+            }
+            "--sound" => {
+                sound = match args.next().as_deref() {
+                    Some("bell") => Some(true),
+                    Some("log") => Some(false),
+                    _ => bail!("--sound expects one of: bell, log"),
+                };
+            }
+            "--device-timing" => {
+                let timing = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--device-timing expects two instruction counts"))?;
+                device_timing = DeviceTiming::parse(&timing)?;
+            }
+            "--hz" => {
+                speed = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => Some(n),
+                    _ => bail!("--hz expects a positive number of instructions per second"),
+                };
+            }
+            "--deterministic-clock" => {
+                let insts_per_ms = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => bail!("--deterministic-clock expects a positive instruction count"),
+                };
+                clock_mode = ClockMode::Deterministic { insts_per_ms };
+            }
+            "--frozen-clock" => clock_mode = ClockMode::FROZEN,
+            "--deterministic" => deterministic = true,
+            "--seed" => {
+                seed = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => Some(n),
+                    _ => bail!("--seed expects a number"),
+                };
+            }
+            "--trace" => {
+                json_trace = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--trace expects a file name"))?,
+                );
+            }
+            "--trace-bin" => {
+                trace_file = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--trace-bin expects a file name"))?,
+                );
+            }
+            "--trace-when" => {
+                let expr = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--trace-when expects a predicate"))?;
+                trace_when = Some(Predicate::parse(&expr)?);
+            }
+            "--console-addrs" => {
+                let addrs = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--console-addrs expects four addresses"))?;
+                console_addrs = ConsoleAddrs::parse(&addrs)?;
+            }
+            "--extra-console" => {
+                let (Some(addrs), Some(input), Some(output)) =
+                    (args.next(), args.next(), args.next())
+                else {
+                    bail!("--extra-console expects addresses, an input and an output file");
+                };
+                let console = ExtraConsole::new(
+                    ConsoleAddrs::parse(&addrs)?,
+                    File::open(input)?,
+                    Box::new(File::create(output)?),
+                );
+                extra_consoles.push(console);
+            }
+            #[cfg(unix)]
+            "--uart-connect" | "--uart-listen" => {
+                let (Some(addrs), Some(socket)) = (args.next(), args.next()) else {
+                    bail!("{arg} expects addresses and a socket path");
+                };
+                let addrs = ConsoleAddrs::parse(&addrs)?;
+                let console = if arg == "--uart-connect" {
+                    ExtraConsole::connect(addrs, &socket)
+                } else {
+                    ExtraConsole::listen(addrs, &socket)
+                };
+                extra_consoles.push(console.map_err(|err| anyhow!("{socket}: {err}"))?);
+            }
+            #[cfg(not(unix))]
+            "--uart-connect" | "--uart-listen" => bail!("{arg} needs Unix sockets"),
+            _ if arg.starts_with('-') => bail!("unknown option {arg}, see --help"),
+            _ => files.push(arg),
         }
+    }
+
+    if files.is_empty() && resume.is_none() && config_file.is_none() {
+        eprint!("{USAGE}");
+        std::process::exit(1)
+    }
+
+    let load_options = LoadOptions {
+        format: match format.as_str() {
+            "obj" => ImageFormat::Obj,
+            "raw" => ImageFormat::Raw { origin },
+            "hex" => ImageFormat::Hex,
+            _ => bail!("--format expects one of: obj, raw, hex"),
+        },
+        little_endian,
     };
 
-    let mut vm = Vm::new(0x3000, vm::Flag::Zero as u16);
-    vm.read_image(file)?;
+    let mut config = match &config_file {
+        Some(file) => MachineConfig::from_file(file).map_err(|err| anyhow!("{file}: {err}"))?,
+        None => MachineConfig::bare_metal(),
+    };
+    read_files.extend(
+        config
+            .images()
+            .iter()
+            .map(|image| image.display().to_string()),
+    );
+    read_files.extend(
+        [&config_file, &stdin_file, &replay, &record, &resume]
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    if detect_uninit {
+        config = config.detect_uninit();
+    }
+    let mut vm = config.build()?;
+    for name in &disabled_devices {
+        vm.unmap_device(name);
+    }
+    vm.set_unknown_trap(unknown_trap);
+    vm.set_instruction_limit(max_instructions);
+    vm.set_cycle_limit(max_cycles);
+    vm.set_cycle_model(cycle_model);
+    vm.set_r7_check(r7_check);
+    if detect_smc {
+        vm.enable_smc_detection();
+    }
+    vm.set_strict(strict);
+    vm.set_warning_handler(|warning| eprintln!("warning: {warning}"));
+    if os {
+        vm.load_os()?;
+        vm.set_trap_mode(TrapMode::Os);
+    }
+    let headless = stdin_file.is_some() || stdout_file.is_some() || listen.is_some();
+    if let Some(addr) = listen {
+        if stdin_file.is_some() || stdout_file.is_some() {
+            bail!("--listen can't be combined with --stdin-file or --stdout-file");
+        }
+        eprintln!("Waiting for a console connection on {addr}");
+        let io = SocketIo::listen(&addr).map_err(|err| anyhow!("{addr}: {err}"))?;
+        vm.set_io(Box::new(io));
+    } else if headless {
+        let input: Box<dyn Read> = match stdin_file {
+            Some(file) => Box::new(BufReader::new(
+                File::open(&file).map_err(|err| anyhow!("{file}: {err}"))?,
+            )),
+            None => Box::new(io::stdin()),
+        };
+        let output: Box<dyn Write> = match stdout_file {
+            Some(file) => Box::new(BufWriter::new(
+                File::create(&file).map_err(|err| anyhow!("{file}: {err}"))?,
+            )),
+            None => Box::new(io::stdout()),
+        };
+        vm.set_io(Box::new(StreamIo::new(input, output)));
+    } else {
+        vm.set_io(Box::new(TerminalIo::new(input_mode)));
+    }
+    if let Some(file) = record {
+        let out = File::create(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+        vm.record_input(Box::new(BufWriter::new(out)));
+    }
+    if let Some(file) = replay {
+        let recording = std::fs::read_to_string(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+        vm.replay_input(&recording)
+            .map_err(|err| anyhow!("{file}: {err}"))?;
+    }
+    vm.enable_getenv(getenv);
+    if let Some(root) = file_root {
+        fileio::install(&mut vm, root);
+    }
+    if let Some(file) = video {
+        let mut screen = File::create(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+        // clear it and hide the cursor
+        write!(screen, "\x1b[2J\x1b[?25l")?;
+        video::install(&mut vm, video::DEFAULT_FRAME_PERIOD, Some(Box::new(screen)));
+    }
+    match sound {
+        Some(true) => sound::install(&mut vm, Box::new(Bell(Box::new(io::stderr())))),
+        Some(false) => sound::install(&mut vm, Box::new(ToneLog(Box::new(io::stderr())))),
+        None => (),
+    }
+    if deterministic {
+        vm.set_host_env(Box::new(DeterministicEnv::default()));
+    }
+    vm.set_clock_mode(clock_mode);
+    // a run with a manifest needs a seed that can be given again, unlike the host's
+    if manifest.is_some() && !deterministic {
+        seed = seed.or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|time| time.as_nanos() as u64)
+        });
+    }
+    if let Some(seed) = seed {
+        vm.seed_rng(seed);
+    }
+    vm.set_engine(engine);
+    if speed.is_some() {
+        vm.set_speed(speed);
+    }
+    vm.set_device_timing(device_timing);
+    vm.set_console_options(console_options);
+    if profile || profile_out.is_some() {
+        vm.enable_profiling();
+    }
+    if coverage.is_some() {
+        vm.enable_coverage();
+    }
+    if let Some(trace_file) = trace_file {
+        let out = BufWriter::new(File::create(trace_file)?);
+        vm.set_trace(TraceWriter::new(Box::new(out))?);
+    }
+    if let Some(trace_when) = trace_when {
+        vm.set_trace_when(trace_when);
+    }
+    vm.set_console_addrs(console_addrs);
+    for console in extra_consoles {
+        vm.add_console(console);
+    }
+    match resume {
+        Some(snapshot) => vm
+            .restore_state(&std::fs::read(&snapshot)?)
+            .map_err(|err| anyhow!("{snapshot}: {err}"))?,
+        None => vm.read_images_with(&files, &load_options)?,
+    }
+    if let Some(program_args) = program_args {
+        let program_args: Vec<_> = program_args.split_whitespace().collect();
+        lc3_vm::args::install(&mut vm, &program_args)?;
+    }
+    if let Some(pc) = pc {
+        vm.set_pc(vm.symbols().parse_addr(&pc)?);
+    }
+    for (reg, value) in reg_values {
+        let r = match reg.strip_prefix(['R', 'r']).map(str::parse) {
+            Some(Ok(r @ 0..=7)) => r,
+            _ => bail!("bad register: {reg}"),
+        };
+        vm.set_register(r, parse_word(&vm, &value)?);
+    }
+    for (addr, value) in mem_values {
+        let addr = vm.symbols().parse_addr(&addr)?;
+        if !vm.set_memory(addr, parse_word(&vm, &value)?) {
+            bail!("address x{addr:04X} is outside memory");
+        }
+    }
+
+    for (range, protection) in protections {
+        vm.protect(range, protection);
+    }
+
+    if debug || debug_script.is_some() {
+        // the program is loaded last, after any OS image
+        let annotations = files.last().map(Annotations::file_for);
+        let annotations = annotations.as_deref();
+        let code = match debug_script {
+            Some(file) => {
+                let script =
+                    std::fs::read_to_string(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+                debugger::run_script(&mut vm, &script, annotations, &mut io::stdout())?
+            }
+            None => debugger::run(&mut vm, annotations)?,
+        };
+        io::stdout().flush()?;
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return Ok(());
+    }
+    if tui {
+        #[cfg(feature = "tui")]
+        return lc3_vm::tui::run(&mut vm);
+        #[cfg(not(feature = "tui"))]
+        bail!("--tui needs lc3-vm to be built with the tui feature");
+    }
+    if let Some(port) = gdb_port {
+        return gdb::serve(&mut vm, port);
+    }
 
-    let _terminal = enable_raw_mode()?;
+    // Ctrl-C stops the program, so the terminal gets restored
+    catch_interrupts(true)?;
+    vm.set_pre_step_hook(|_, _| {
+        if interrupted() {
+            HookAction::Stop
+        } else {
+            HookAction::Continue
+        }
+    });
+    let terminal = if headless { None } else { enable_raw_mode()? };
 
     // loop {
     //     match getch()? {
@@ -44,47 +874,635 @@ fn try_main() -> Result<()> {
     //     }
     // }
 
-    vm.run();
+    let start = Instant::now();
+    let result = match json_trace {
+        Some(file) => {
+            let mut out: Box<dyn Write> = if file == "-" {
+                Box::new(io::stdout())
+            } else {
+                Box::new(BufWriter::new(File::create(file)?))
+            };
+            let mut result = Ok(());
+            let symbols = vm.symbols().clone();
+            for event in vm.iter_steps() {
+                match event {
+                    Ok(event) => writeln!(out, "{}", event.to_json_with_symbols(&symbols))?,
+                    Err(err) => result = Err(err),
+                }
+            }
+            out.flush()?;
+            result
+        }
+        None => vm.run(),
+    };
+    drop(terminal);
+    if stats {
+        let elapsed = start.elapsed();
+        let mips = vm.instructions() as f64 / elapsed.as_secs_f64().max(1e-9) / 1e6;
+        eprintln!(
+            "\n{} instructions, {} cycles in {elapsed:.2?}, {mips:.2} MIPS",
+            vm.instructions(),
+            vm.cycles()
+        );
+    }
+    if let Some(counts) = vm.profile().filter(|_| profile) {
+        eprint!("\n{}", counts.report(&vm));
+    }
+    if let (Some(file), Some(profile)) = (profile_out, vm.profile()) {
+        let mut out = BufWriter::new(File::create(&file).map_err(|err| anyhow!("{file}: {err}"))?);
+        profile.write_folded(vm.symbols(), &mut out)?;
+        out.flush()?;
+    }
+    if let (Some(file), Some(coverage)) = (coverage, vm.coverage()) {
+        let ranges = image_ranges(&files, &load_options)?;
+        std::fs::write(&file, coverage.to_json(&ranges) + "\n")
+            .map_err(|err| anyhow!("{file}: {err}"))?;
+        let listing = Path::new(&file).with_extension("lst");
+        let mut out = BufWriter::new(File::create(&listing)?);
+        coverage.write_listing(&vm, &ranges, &mut out)?;
+        out.flush()?;
+    }
+    if let Some(file) = manifest {
+        let manifest = RunManifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            args: rerun_args(&argv, seed),
+            seed: seed.unwrap_or_default(),
+            files: files
+                .iter()
+                .chain(&read_files)
+                .map(|file| FileHash::of(file))
+                .collect::<Result<_>>()?,
+            instructions: vm.instructions(),
+            cycles: vm.cycles(),
+            halted: vm.halted(),
+            error: result.as_ref().err().map(ToString::to_string),
+        };
+        std::fs::write(&file, manifest.to_toml()?).map_err(|err| anyhow!("{file}: {err}"))?;
+    }
+    if let Some(file) = state_json {
+        let json = vm.state_json(result.as_ref().err(), memory_digest);
+        std::fs::write(&file, json + "\n").map_err(|err| anyhow!("{file}: {err}"))?;
+    }
+    if take_interrupt() {
+        io::stdout().flush()?;
+        eprintln!("\n{}", describe_interrupt(&vm));
+        std::process::exit(130);
+    }
+    result.map_err(|err| describe_error(&vm, err))?;
+
+    if let Some(file) = save_on_halt {
+        std::fs::write(file, vm.save_state())?;
+    }
+
+    Ok(())
+}
+
+/// The arguments that repeat a run made with `argv`: without --manifest, replaying the
+/// input it recorded, and with the seed it used.
+fn rerun_args(argv: &[String], seed: Option<u64>) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut argv = argv.iter();
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--manifest" | "--seed" => {
+                argv.next();
+            }
+            "--record" => args.push("--replay".to_string()),
+            _ => args.push(arg.clone()),
+        }
+    }
+    if let Some(seed) = seed {
+        args.extend(["--seed".to_string(), seed.to_string()]);
+    }
+
+    args
+}
+
+/// Repeats the run a manifest describes, failing if its files changed or it ends
+/// differently.
+fn rerun(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let file = args
+        .next()
+        .ok_or_else(|| anyhow!("rerun expects a manifest"))?;
+    let text = std::fs::read_to_string(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+    let manifest = RunManifest::parse(&text).map_err(|err| anyhow!("{file}: {err}"))?;
+    if manifest.version != env!("CARGO_PKG_VERSION") {
+        eprintln!("warning: {file} was written by lc3-vm {}", manifest.version);
+    }
+    for read in &manifest.files {
+        read.check()?;
+    }
+
+    let out = std::env::temp_dir().join(format!("lc3-rerun-{}.toml", std::process::id()));
+    let mut argv = manifest.args.clone();
+    argv.extend(["--manifest".to_string(), out.display().to_string()]);
+    let result = run_cli(argv);
+    let again = std::fs::read_to_string(&out);
+    let _ = std::fs::remove_file(&out);
+    let Ok(again) = again else {
+        result?;
+        bail!("the rerun stopped before the program ended");
+    };
+    let again = RunManifest::parse(&again)?;
+    if let Err(err) = result {
+        eprintln!("{err}");
+    }
+
+    let differences = manifest.differences(&again);
+    if differences.is_empty() {
+        eprintln!("Reproduced {file}: {} instructions", again.instructions);
+        return Ok(());
+    }
+    for difference in differences {
+        eprintln!("{difference}");
+    }
+    bail!("{file} was not reproduced")
+}
+
+fn assemble(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut source = None;
+    let mut output = None;
+    let mut listing = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("-o expects a file name"))?,
+                );
+            }
+            "--listing" => listing = true,
+            _ if arg.starts_with('-') => bail!("unknown option {arg}, see --help"),
+            _ => source = Some(arg),
+        }
+    }
+
+    let source = source.ok_or_else(|| anyhow!("asm expects a source file"))?;
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("obj")
+            .to_string_lossy()
+            .into_owned()
+    });
 
+    let text = std::fs::read_to_string(&source)?;
+    let program = asm::assemble(&text).map_err(|err| anyhow!("{source}: {err}"))?;
+    std::fs::write(&output, program.image())?;
+    std::fs::write(
+        Path::new(&output).with_extension("sym"),
+        program.symbol_table().to_sym_file(),
+    )?;
+    if listing {
+        std::fs::write(
+            Path::new(&output).with_extension("lst"),
+            program.listing(&text),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn disas(image: String) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+    let mut symbols = SymbolTable::for_image(&image)?;
+    let annotations = Annotations::read(Annotations::file_for(&image))?;
+    annotations.apply(&mut symbols);
+    disasm::write_listing(&std::fs::read(&image)?, &symbols, &annotations, &mut stdout)
+        .map_err(|err| anyhow!("{image}: {err}"))?;
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Prints the words at which two images differ and exits with status 1 if there are any,
+/// like diff(1).
+fn diff(left: String, right: String) -> Result<()> {
+    let segments = |image: &String| {
+        let data = std::fs::read(image).map_err(|err| anyhow!("{image}: {err}"))?;
+        loader::decode_file(Path::new(image), &data, &LoadOptions::default())
+            .map_err(|err| anyhow!("{image}: {err}"))
+    };
+    let diffs = lc3_vm::diff::diff_images(&segments(&left)?, &segments(&right)?);
+
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+    lc3_vm::diff::write_diff(
+        &diffs,
+        &SymbolTable::for_image(&left)?,
+        &SymbolTable::for_image(&right)?,
+        &mut stdout,
+    )?;
+    stdout.flush()?;
+
+    if !diffs.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Prints the registers and words at which two snapshots differ and exits with status
+/// 1 if there are any, like [`diff`].
+fn snapdiff(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut symbols = SymbolTable::default();
+    let mut files = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sym" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--sym expects a file name"))?;
+                symbols = SymbolTable::parse(&std::fs::read_to_string(&file)?)
+                    .map_err(|err| anyhow!("{file}: {err}"))?;
+            }
+            _ if arg.starts_with('-') => bail!("unknown option {arg}, see --help"),
+            _ => files.push(arg),
+        }
+    }
+    let [left, right] = &files[..] else {
+        bail!("snapdiff expects two snapshots");
+    };
+
+    let snapshot = |file: &String| {
+        std::fs::read(file)
+            .map_err(anyhow::Error::from)
+            .and_then(|state| Snapshot::parse(&state))
+            .map_err(|err| anyhow!("{file}: {err}"))
+    };
+    let diff = lc3_vm::diff::diff_snapshots(&snapshot(left)?, &snapshot(right)?);
+
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+    lc3_vm::diff::write_snapshot_diff(&diff, &symbols, &mut stdout)?;
+    stdout.flush()?;
+
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
     Ok(())
 }
 
-fn getch() -> io::Result<u8> {
-    let mut buf = [0u8; 1];
-    let mut stdin = stdin();
+fn dump(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut disassemble = false;
+    let mut range = None;
+    let mut images = Vec::new();
 
-    loop {
-        if stdin.read(&mut buf)? != 0 {
-            return Ok(buf[0]);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disas" => disassemble = true,
+            "--range" => {
+                let arg = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--range expects START-END"))?;
+                range = Some(dump::parse_range(&arg)?);
+            }
+            _ => images.push(arg),
         }
     }
+
+    if images.is_empty() {
+        bail!("dump expects at least one image");
+    }
+
+    let mut vm = Vm::default();
+    vm.read_images(&images)?;
+
+    // everything the images cover by default
+    let range = match range {
+        Some(range) => range,
+        None => {
+            let ranges = image_ranges(&images, &LoadOptions::default())?;
+            let start = ranges.iter().map(|range| *range.start()).min();
+            let end = ranges.iter().map(|range| *range.end()).max();
+            start.unwrap_or(u16::MAX)..=end.unwrap_or_default()
+        }
+    };
+
+    let dump = vm.dump_memory(range);
+    if disassemble {
+        print!("{}", dump.with_disassembly());
+    } else {
+        print!("{dump}");
+    }
+
+    Ok(())
 }
 
-struct Terminal(termios::Termios);
+/// The addresses each of `images` loads to, skipping empty segments.
+fn image_ranges(images: &[String], options: &LoadOptions) -> Result<Vec<RangeInclusive<u16>>> {
+    let mut ranges = Vec::new();
+    for image in images {
+        let data = std::fs::read(image)?;
+        for segment in loader::decode_file(Path::new(image), &data, options)? {
+            let range = segment.range();
+            if !range.is_empty() {
+                ranges.push(range.start as u16..=(range.end - 1) as u16);
+            }
+        }
+    }
 
-impl Drop for Terminal {
-    fn drop(&mut self) {
-        use termios::*;
+    Ok(ranges)
+}
 
-        tcsetattr(stdin().as_raw_fd(), SetArg::TCSAFLUSH, &self.0).unwrap();
+/// Splits `NAME=VALUE` for --reg and --mem.
+fn split_assignment(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+        None => bail!("expected NAME=VALUE: {s}"),
     }
 }
 
-fn enable_raw_mode() -> Result<Terminal> {
-    use termios::*;
+fn parse_protection(s: &str) -> Result<(RangeInclusive<u16>, Protection)> {
+    let Some((range, kind)) = s.rsplit_once(':') else {
+        bail!("expected START-END:ro|nx|none: {s}");
+    };
+    Ok((dump::parse_range(range)?, Protection::parse(kind)?))
+}
 
-    let stdin = stdin().as_raw_fd();
-    let mut termios = tcgetattr(stdin)?;
+/// Parses a word given on the command line: a label, an unsigned literal like `xBEEF`,
+/// or a negative decimal like `#-1`.
+fn parse_word(vm: &Vm, s: &str) -> Result<u16> {
+    match s.strip_prefix('#').unwrap_or(s).parse::<i16>() {
+        Ok(n) => Ok(n as u16),
+        Err(_) => vm.symbols().parse_addr(s),
+    }
+}
 
-    let local_flags = termios.local_flags;
+/// Adds the offending instruction to errors that have one.
+fn describe_error(vm: &Vm, err: VmError) -> anyhow::Error {
+    let Some(pc) = err.pc() else {
+        return err.into();
+    };
+    let inst = vm.memory().get(pc as usize).copied().unwrap_or_default();
+    anyhow!(
+        "{err}\n  x{pc:04X}: x{inst:04X}  {}",
+        disasm::disassemble(inst, pc)
+    )
+}
+
+/// Where the program was when Ctrl-C stopped it, and its registers.
+fn describe_interrupt(vm: &Vm) -> String {
+    let pc = vm.pc();
+    let inst = vm.memory().get(pc as usize).copied().unwrap_or_default();
+    let registers: Vec<_> = vm
+        .registers()
+        .iter()
+        .enumerate()
+        .map(|(r, val)| format!("R{r}=x{val:04X}"))
+        .collect();
+    format!(
+        "Interrupted\n  x{pc:04X}: x{inst:04X}  {}\n  {}  PSR=x{:04X}",
+        disasm::disassemble(inst, pc),
+        registers.join(" "),
+        vm.psr()
+    )
+}
 
-    let flags_to_remove = LocalFlags::ICANON | LocalFlags::ECHO;
-    termios.local_flags &= flags_to_remove.complement();
+fn examples(mut args: impl Iterator<Item = String>) -> Result<()> {
+    match args.next().as_deref() {
+        Some("list") | None => {
+            for demo in demos::DEMOS {
+                println!("{:<8} {}", demo.name, demo.description);
+            }
+        }
+        Some("run") => {
+            let name = args
+                .next()
+                .ok_or_else(|| anyhow!("examples run expects an example name"))?;
+            let demo = demos::find(&name).ok_or_else(|| anyhow!("Unknown example: {name}"))?;
 
-    tcsetattr(stdin, SetArg::TCSAFLUSH, &termios)?;
+            let mut vm = Vm::new(0x3000, vm::Flag::Zero as u16);
+            vm.load_image_bytes(demo.image)?;
 
-    termios.local_flags = local_flags;
+            let _terminal = enable_raw_mode()?;
+            vm.run()?;
+        }
+        Some(cmd) => bail!("Unknown examples command: {cmd}"),
+    }
 
-    // this struct has now the original attributes of the terminal
-    Ok(Terminal(termios))
+    Ok(())
+}
+
+fn batch(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut opts = BatchOptions::default();
+    let mut input = None;
+    let mut max_instructions = None;
+    let mut cases = Vec::new();
+    // the cases of images given directly, which get --input and --max-insts
+    let mut images = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--jobs" => {
+                opts.jobs = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => bail!("--jobs expects a positive number"),
+                }
+            }
+            "--input" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--input expects a file name"))?;
+                input = Some(std::fs::read(&file).map_err(|err| anyhow!("{file}: {err}"))?);
+            }
+            "--timeout" => {
+                opts.timeout = match args
+                    .next()
+                    .map(|n| n.parse().map(Duration::try_from_secs_f64))
+                {
+                    Some(Ok(Ok(timeout))) => timeout,
+                    _ => bail!("--timeout expects a number of seconds"),
+                };
+            }
+            "--out-dir" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--out-dir expects a directory"))?;
+                std::fs::create_dir_all(&dir)?;
+                opts.out_dir = Some(dir.into());
+            }
+            "--config" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--config expects a file name"))?;
+                opts.config =
+                    MachineConfig::from_file(&file).map_err(|err| anyhow!("{file}: {err}"))?;
+            }
+            "--strict" => opts.strict = true,
+            "--crlf" => opts.console.crlf = true,
+            "--on-eof" => {
+                let mode = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--on-eof expects stop or eot"))?;
+                opts.console.eof = EofMode::parse(&mode)?;
+            }
+            "--max-insts" => {
+                max_instructions = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => Some(n),
+                    _ => bail!("--max-insts expects an instruction count"),
+                };
+            }
+            _ if arg.starts_with('-') => bail!("unknown option {arg}, see --help"),
+            _ if arg.ends_with(".toml") => cases.extend(batch::read_manifest(Path::new(&arg))?),
+            _ => {
+                images.push(cases.len());
+                cases.push(Case::new(arg.into()));
+            }
+        }
+    }
+
+    if cases.is_empty() {
+        bail!("batch expects at least one image or case");
+    }
+    for i in images {
+        cases[i].input.clone_from(&input);
+        cases[i].max_instructions = max_instructions;
+    }
+
+    let summaries = batch::run_batch(&cases, &opts)?;
+    batch::print_summary(&summaries);
+
+    Ok(())
+}
+
+fn grade(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let rubric = args
+        .next()
+        .ok_or_else(|| anyhow!("grade expects a rubric file"))?;
+    let rubric = Rubric::from_file(&rubric).map_err(|err| anyhow!("{rubric}: {err}"))?;
+
+    for image in args {
+        let report = grade::grade(&rubric, &std::fs::read(&image)?)?;
+        print!("{image}: {report}");
+    }
+
+    Ok(())
+}
+
+fn trace_dump(file: String) -> Result<()> {
+    let reader = TraceReader::new(BufReader::new(File::open(file)?))?;
+
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+    for record in reader {
+        writeln!(stdout, "{}", record?)?;
+    }
+
+    Ok(())
+}
+
+/// Runs images on the vm and compares its trace with a golden trace, or with the one
+/// printed by a reference simulator, see [`lc3_vm::difftest`].
+fn difftest(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut input = Vec::new();
+    let mut engine = Engine::Simple;
+    let mut limit = 1_000_000;
+    let mut golden = None;
+    let mut reference = None;
+    let mut files = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--input expects a file name"))?;
+                input = std::fs::read(file)?;
+            }
+            "--engine" => {
+                engine = match args.next().as_deref() {
+                    Some("simple") => Engine::Simple,
+                    Some("cached") => Engine::Cached,
+                    _ => bail!("--engine expects one of: simple, cached"),
+                }
+            }
+            "--limit" => {
+                limit = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => bail!("--limit expects a positive instruction count"),
+                };
+            }
+            "--golden" => {
+                golden = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--golden expects a trace file"))?,
+                );
+            }
+            "--reference" => {
+                reference = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--reference expects a command"))?,
+                );
+            }
+            _ if arg.starts_with('-') => bail!("unknown option {arg}, see --help"),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        bail!("difftest expects images");
+    }
+
+    let expected = match (golden, reference) {
+        (Some(golden), None) => difftest::read_reference(&std::fs::read(&golden)?)
+            .map_err(|err| anyhow!("{golden}: {err}"))?,
+        (None, Some(command)) => difftest::run_reference(&command, &files, &input)?,
+        _ => bail!("difftest expects one of --golden and --reference"),
+    };
+
+    let mut vm = Vm::new(0x3000, vm::Flag::Zero as u16);
+    vm.set_engine(engine);
+    // the reference can't see the host's clock or random seed
+    vm.set_host_env(Box::new(DeterministicEnv::default()));
+    vm.set_io(Box::new(StreamIo::new(io::Cursor::new(input), io::sink())));
+    vm.set_instruction_limit(Some(limit));
+    for file in &files {
+        vm.load_image_bytes(&std::fs::read(file)?)
+            .map_err(|err| anyhow!("{file}: {err}"))?;
+    }
+    let (actual, error) = difftest::trace_vm(&mut vm)?;
+
+    match difftest::first_divergence(&actual, &expected) {
+        Some(divergence) => {
+            println!("{divergence}");
+            if let (None, Some(err)) = (&divergence.actual, error) {
+                println!("the vm stopped: {err}");
+            }
+            std::process::exit(1);
+        }
+        None => println!("traces agree on {} instructions", actual.len()),
+    }
+
+    Ok(())
+}
+
+fn trace_check(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut input = Vec::new();
+    let mut files = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--input expects a file name"))?;
+                input = std::fs::read(file)?;
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    let [image, expected] = &files[..] else {
+        bail!("trace-check expects an image and an expected trace");
+    };
+    let expected = trace_check::ExpectedTrace::parse(&std::fs::read_to_string(expected)?)
+        .map_err(|err| anyhow!("{expected}: {err}"))?;
+
+    match trace_check::check(&std::fs::read(image)?, input, &expected)? {
+        Some(mismatch) => bail!("{mismatch}"),
+        None => println!("trace matches"),
+    }
+
+    Ok(())
 }
@@ -0,0 +1,205 @@
+//! A record of one run of `lc3-vm` that `lc3-vm rerun` repeats, for bug reports and
+//! grading audits: the arguments that reproduce the run, hashes of the files it read,
+//! the random seed, and how it ended. Written as TOML:
+//!
+//! ```toml
+//! version = "0.1.0"
+//! args = ["--replay", "keys.txt", "prog.obj", "--seed", "7"]
+//! seed = "7"
+//! instructions = 1234
+//! cycles = 5678
+//! halted = true
+//!
+//! [[files]]
+//! path = "prog.obj"
+//! fnv1a = "af63bd4c8601b7df"
+//! ```
+//!
+//! `error` holds the message of the error the run stopped with, if any. The seed and
+//! the hashes are strings since TOML integers are signed. Paths are as they were given,
+//! so reruns start in the same directory. Keyboard input is only repeated when it came
+//! from a recording or a file, and programs that read the clock only run the same way
+//! with a deterministic one.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::util::fnv1a;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunManifest {
+    /// The version of lc3-vm that made the run.
+    pub version: String,
+    /// The arguments after `lc3-vm` that repeat the run.
+    pub args: Vec<String>,
+    /// The seed of the random number register.
+    #[serde(with = "u64_string")]
+    pub seed: u64,
+    pub instructions: u64,
+    pub cycles: u64,
+    pub halted: bool,
+    /// The message of the error the run stopped with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub files: Vec<FileHash>,
+}
+
+/// A file the run read, and the FNV-1a hash of its contents at the time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileHash {
+    pub path: String,
+    #[serde(with = "u64_string::hex")]
+    pub fnv1a: u64,
+}
+
+impl FileHash {
+    pub fn of(path: &str) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|err| anyhow!("{path}: {err}"))?;
+        Ok(Self {
+            path: path.to_string(),
+            fnv1a: fnv1a(data),
+        })
+    }
+
+    /// Fails if the file is gone or has changed since it was hashed.
+    pub fn check(&self) -> Result<()> {
+        if FileHash::of(&self.path)?.fnv1a != self.fnv1a {
+            bail!("{} has changed since the run", self.path);
+        }
+        Ok(())
+    }
+}
+
+impl RunManifest {
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// How the run described by `other` ended differently, one line per difference.
+    pub fn differences(&self, other: &RunManifest) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.halted != other.halted {
+            let state = |halted| if halted { "halted" } else { "didn't halt" };
+            differences.push(format!(
+                "the program {} instead of {}",
+                state(other.halted),
+                state(self.halted)
+            ));
+        }
+        if self.error != other.error {
+            let error = |error: &Option<String>| match error {
+                Some(error) => format!("`{error}`"),
+                None => "no error".to_string(),
+            };
+            differences.push(format!(
+                "the run ended with {} instead of {}",
+                error(&other.error),
+                error(&self.error)
+            ));
+        }
+        if self.instructions != other.instructions {
+            differences.push(format!(
+                "{} instructions executed instead of {}",
+                other.instructions, self.instructions
+            ));
+        }
+        if self.cycles != other.cycles {
+            differences.push(format!(
+                "{} cycles instead of {}",
+                other.cycles, self.cycles
+            ));
+        }
+
+        differences
+    }
+}
+
+/// Writes a `u64` as a decimal string, since it may not fit a TOML integer.
+mod u64_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(n: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(n)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| D::Error::custom(format!("bad number: {s}")))
+    }
+
+    /// The same in hex, padded to 16 digits.
+    pub mod hex {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(n: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(&format_args!("{n:016x}"))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            u64::from_str_radix(&s, 16).map_err(|_| D::Error::custom(format!("bad hash: {s}")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_toml() {
+        let manifest = RunManifest {
+            version: "0.1.0".to_string(),
+            args: vec![
+                "--seed".to_string(),
+                "7".to_string(),
+                "dir/\"odd\".obj".to_string(),
+            ],
+            seed: 7,
+            files: vec![FileHash {
+                path: "dir/\"odd\".obj".to_string(),
+                fnv1a: 0xaf63_bd4c_8601_b7df,
+            }],
+            instructions: 1234,
+            cycles: 5678,
+            halted: false,
+            error: Some("Bad trap 0x99 at pc 0x3000".to_string()),
+        };
+        let toml = manifest.to_toml().unwrap();
+        assert!(toml.contains(r#"fnv1a = "af63bd4c8601b7df""#), "{toml}");
+        assert_eq!(RunManifest::parse(&toml).unwrap(), manifest);
+
+        assert!(RunManifest::parse(&toml.replace("seed", "sead")).is_err());
+        assert!(RunManifest::parse(&toml.replace("\"7\"", "\"seven\"")).is_err());
+
+        let big = RunManifest {
+            seed: u64::MAX,
+            error: None,
+            ..manifest.clone()
+        };
+        let toml = big.to_toml().unwrap();
+        assert!(!toml.contains("error"), "{toml}");
+        assert_eq!(RunManifest::parse(&toml).unwrap(), big);
+
+        let again = RunManifest {
+            instructions: 1000,
+            error: None,
+            ..manifest.clone()
+        };
+        assert_eq!(
+            manifest.differences(&again),
+            [
+                "the run ended with no error instead of `Bad trap 0x99 at pc 0x3000`",
+                "1000 instructions executed instead of 1234"
+            ]
+        );
+        assert!(manifest.differences(&manifest).is_empty());
+    }
+}
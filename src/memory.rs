@@ -0,0 +1,105 @@
+//! The vm's memory behind a trait, so it can be backed by something other than a
+//! vector of its own, see [`crate::vm::Vm::set_memory_bus`].
+
+use std::{
+    ops::{Index, IndexMut},
+    rc::Rc,
+    slice::SliceIndex,
+};
+
+/// Memory as the vm sees it, before devices. Addresses past the end of
+/// [`MemoryBus::words`] are outside memory, and accessing them faults.
+pub trait MemoryBus {
+    /// The word at `addr`, or `None` outside memory.
+    fn read(&self, addr: u16) -> Option<u16> {
+        self.words().get(addr as usize).copied()
+    }
+
+    /// Stores `val` at `addr`. Returns `false` outside memory.
+    fn write(&mut self, addr: u16, val: u16) -> bool {
+        match self.words_mut().get_mut(addr as usize) {
+            Some(word) => {
+                *word = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// All of memory, for snapshots, dumps and loading images.
+    fn words(&self) -> &[u16];
+
+    fn words_mut(&mut self) -> &mut [u16];
+}
+
+impl<I: SliceIndex<[u16]>> Index<I> for dyn MemoryBus {
+    type Output = I::Output;
+
+    fn index(&self, index: I) -> &I::Output {
+        &self.words()[index]
+    }
+}
+
+impl<I: SliceIndex<[u16]>> IndexMut<I> for dyn MemoryBus {
+    fn index_mut(&mut self, index: I) -> &mut I::Output {
+        &mut self.words_mut()[index]
+    }
+}
+
+impl MemoryBus for Vec<u16> {
+    fn read(&self, addr: u16) -> Option<u16> {
+        self.get(addr as usize).copied()
+    }
+
+    fn words(&self) -> &[u16] {
+        self
+    }
+
+    fn words_mut(&mut self) -> &mut [u16] {
+        self
+    }
+}
+
+/// Memory that is copied only when written to while another [`CowMemory`] shares it.
+/// Cloning is cheap, so a loaded machine can be forked many times, e.g. to run one
+/// program against many inputs.
+#[derive(Clone)]
+pub struct CowMemory(Rc<Vec<u16>>);
+
+impl CowMemory {
+    pub fn new(words: Vec<u16>) -> Self {
+        Self(Rc::new(words))
+    }
+}
+
+impl MemoryBus for CowMemory {
+    fn read(&self, addr: u16) -> Option<u16> {
+        self.0.get(addr as usize).copied()
+    }
+
+    fn words(&self) -> &[u16] {
+        &self.0
+    }
+
+    fn words_mut(&mut self) -> &mut [u16] {
+        Rc::make_mut(&mut self.0).as_mut_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cow_memory() {
+        let mut a = CowMemory::new(vec![1, 2, 3]);
+        let b = a.clone();
+
+        assert!(a.write(1, 5));
+        assert!(!a.write(3, 5));
+        assert_eq!(a.words(), [1, 5, 3]);
+        assert_eq!(b.words(), [1, 2, 3]);
+        assert_eq!(b.read(2), Some(3));
+        assert_eq!(b.read(3), None);
+    }
+}
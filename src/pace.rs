@@ -0,0 +1,53 @@
+//! Slows execution down to a given number of instructions per second, see
+//! [`crate::vm::Vm::set_speed`].
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+// how far the program may fall behind, e.g. while waiting for a key, before the pacer
+// stops trying to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Pacer {
+    hz: u64,
+    // sleeping after every instruction would cost more than the instructions, so the
+    // pacer only checks the time every `batch` instructions, about every 10ms
+    batch: u64,
+    start: Instant,
+    // the instruction count at `start`
+    base: u64,
+}
+
+impl Pacer {
+    pub fn new(hz: u64, instructions: u64) -> Self {
+        let hz = hz.max(1);
+        Self {
+            hz,
+            batch: (hz / 100).max(1),
+            start: Instant::now(),
+            base: instructions,
+        }
+    }
+
+    /// Called after each instruction with the number executed so far. Sleeps until the
+    /// time they should have taken at the configured speed.
+    pub fn wait(&mut self, instructions: u64) {
+        let done = instructions.saturating_sub(self.base);
+        if !done.is_multiple_of(self.batch) {
+            return;
+        }
+
+        let due = Duration::from_secs_f64(done as f64 / self.hz as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        } else if elapsed - due > MAX_LAG {
+            // running at full speed to catch up would make the program jump ahead
+            self.start = Instant::now();
+            self.base = instructions;
+        }
+    }
+}
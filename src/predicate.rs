@@ -0,0 +1,365 @@
+//! Conditions on the machine state, used to only trace the instructions of interest and
+//! for conditional breakpoints.
+//!
+//! ```text
+//! R5 != 0
+//! PC >= x3010 && PC < x3020
+//! mem[x4000] == #-1 || (R0 == R1 && PSR == 4)
+//! in PRINT_NUM && R0 == 0
+//! ```
+//!
+//! Operands are `R0`-`R7`, `PC` (the address of the executed instruction), `PSR`,
+//! `mem[addr]` and numbers written as `x3000`, `#-5` or plain decimal. Comparisons are
+//! unsigned, so `#-1` is the same as `xFFFF`. `in LABEL` holds while the routine at
+//! LABEL runs, including the routines it calls, as long as the call hasn't returned; it
+//! never holds if there is no such label.
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{symbols::SymbolTable, trace::TraceRecord, util::parse_literal, vm::Frame};
+
+#[derive(Debug)]
+pub enum Predicate {
+    Compare(Operand, CmpOp, Operand),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    /// Inside a call to the routine at the label.
+    In(String),
+}
+
+#[derive(Debug)]
+pub enum Operand {
+    Reg(usize),
+    Pc,
+    Psr,
+    Mem(Box<Operand>),
+    Const(u16),
+}
+
+/// What a predicate is evaluated against besides the registers.
+pub struct Context<'a> {
+    pub memory: &'a [u16],
+    /// Calls that haven't returned yet, see [`crate::vm::Vm::backtrace`].
+    pub frames: &'a [Frame],
+    pub symbols: &'a SymbolTable,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Predicate {
+    pub fn parse(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        let predicate = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("unexpected `{token}` in predicate");
+        }
+
+        Ok(predicate)
+    }
+
+    /// Evaluates the predicate on the state after an instruction executed.
+    pub fn eval(&self, state: &TraceRecord, context: &Context) -> bool {
+        match self {
+            Predicate::Compare(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(state, context), rhs.eval(state, context));
+                match op {
+                    CmpOp::Eq => lhs == rhs,
+                    CmpOp::Ne => lhs != rhs,
+                    CmpOp::Lt => lhs < rhs,
+                    CmpOp::Le => lhs <= rhs,
+                    CmpOp::Gt => lhs > rhs,
+                    CmpOp::Ge => lhs >= rhs,
+                }
+            }
+            Predicate::And(lhs, rhs) => lhs.eval(state, context) && rhs.eval(state, context),
+            Predicate::Or(lhs, rhs) => lhs.eval(state, context) || rhs.eval(state, context),
+            Predicate::In(label) => context
+                .symbols
+                .lookup(label)
+                .is_some_and(|entry| context.frames.iter().any(|frame| frame.entry == entry)),
+        }
+    }
+}
+
+impl Operand {
+    fn eval(&self, state: &TraceRecord, context: &Context) -> u16 {
+        match self {
+            Operand::Reg(r) => state.reg[*r],
+            Operand::Pc => state.pc,
+            Operand::Psr => state.psr,
+            Operand::Mem(addr) => {
+                let addr = addr.eval(state, context);
+                context
+                    .memory
+                    .get(addr as usize)
+                    .copied()
+                    .unwrap_or_default()
+            }
+            Operand::Const(val) => *val,
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Predicate::Compare(lhs, op, rhs) => {
+                let op = match op {
+                    CmpOp::Eq => "==",
+                    CmpOp::Ne => "!=",
+                    CmpOp::Lt => "<",
+                    CmpOp::Le => "<=",
+                    CmpOp::Gt => ">",
+                    CmpOp::Ge => ">=",
+                };
+                write!(f, "{lhs} {op} {rhs}")
+            }
+            Predicate::And(lhs, rhs) => {
+                // && binds tighter than ||
+                for (i, side) in [lhs, rhs].into_iter().enumerate() {
+                    if i == 1 {
+                        write!(f, " && ")?;
+                    }
+                    match **side {
+                        Predicate::Or(..) => write!(f, "({side})")?,
+                        _ => write!(f, "{side}")?,
+                    }
+                }
+                Ok(())
+            }
+            Predicate::Or(lhs, rhs) => write!(f, "{lhs} || {rhs}"),
+            Predicate::In(label) => write!(f, "in {label}"),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "R{r}"),
+            Operand::Pc => write!(f, "PC"),
+            Operand::Psr => write!(f, "PSR"),
+            Operand::Mem(addr) => write!(f, "mem[{addr}]"),
+            Operand::Const(val) => write!(f, "x{val:04X}"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '#' || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                // '-' only after '#', as in #-5
+                if c.is_ascii_alphanumeric() || "#_".contains(c) || (c == '-' && word == "#") {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(word);
+        } else {
+            chars.next();
+            let two = chars.peek().map(|&next| format!("{c}{next}"));
+            match two.as_deref() {
+                Some("==" | "!=" | "<=" | ">=" | "&&" | "||") => {
+                    chars.next();
+                    tokens.push(two.unwrap());
+                }
+                _ if "<>()[]".contains(c) => tokens.push(c.to_string()),
+                _ => bail!("unexpected `{c}` in predicate"),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("unexpected end of predicate"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => bail!("expected `{expected}`, found `{token}`"),
+        }
+    }
+
+    fn or(&mut self) -> Result<Predicate> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            lhs = Predicate::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Predicate> {
+        let mut lhs = self.compare()?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            lhs = Predicate::And(Box::new(lhs), Box::new(self.compare()?));
+        }
+        Ok(lhs)
+    }
+
+    fn compare(&mut self) -> Result<Predicate> {
+        if self.peek() == Some("(") {
+            self.pos += 1;
+            let inner = self.or()?;
+            self.expect(")")?;
+            return Ok(inner);
+        }
+        if self
+            .peek()
+            .is_some_and(|token| token.eq_ignore_ascii_case("in"))
+        {
+            self.pos += 1;
+            return match self.next()? {
+                label
+                    if label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                        && parse_literal(label).is_none() =>
+                {
+                    Ok(Predicate::In(label.to_string()))
+                }
+                token => bail!("expected a label after `in`, found `{token}`"),
+            };
+        }
+
+        let lhs = self.operand()?;
+        let op = match self.next()? {
+            "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            token => bail!("expected a comparison, found `{token}`"),
+        };
+        let rhs = self.operand()?;
+
+        Ok(Predicate::Compare(lhs, op, rhs))
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let token = self.next()?.to_ascii_uppercase();
+
+        let operand = match token.as_str() {
+            "PC" => Operand::Pc,
+            "PSR" => Operand::Psr,
+            "MEM" => {
+                self.expect("[")?;
+                let addr = self.operand()?;
+                self.expect("]")?;
+                Operand::Mem(Box::new(addr))
+            }
+            reg if reg.len() == 2
+                && reg.starts_with('R')
+                && matches!(reg.as_bytes()[1], b'0'..=b'7') =>
+            {
+                Operand::Reg((reg.as_bytes()[1] - b'0') as usize)
+            }
+            _ => match parse_literal(&token) {
+                Some(n) if (-0x8000..=0xFFFF).contains(&n) => Operand::Const(n as u16),
+                _ => bail!("bad operand `{token}` in predicate"),
+            },
+        };
+
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::CallKind;
+
+    #[test]
+    fn test_predicate() {
+        let state = TraceRecord {
+            pc: 0x3012,
+            inst: 0,
+            reg: [0, 1, 0, 0, 0, 5, 0, 0],
+            psr: 1,
+            write: None,
+        };
+        let mut memory = vec![0; 0x5000];
+        memory[0x4000] = 0xFFFF;
+        let mut symbols = SymbolTable::default();
+        symbols.insert("OUTER", 0x3100);
+        symbols.insert("PRINT_NUM", 0x3200);
+        symbols.insert("OTHER", 0x3300);
+        // OUTER called PRINT_NUM, which called a routine without a label
+        let frames = [0x3100, 0x3200, 0x3010].map(|entry| Frame {
+            kind: CallKind::Subroutine,
+            caller: 0x3000,
+            entry,
+            ret: 0x3001,
+        });
+        let context = Context {
+            memory: &memory,
+            frames: &frames,
+            symbols: &symbols,
+        };
+
+        let eval = |s: &str| Predicate::parse(s).unwrap().eval(&state, &context);
+
+        assert!(eval("R5 != 0"));
+        assert!(!eval("r0 != 0"));
+        assert!(eval("PC >= x3010 && PC < x3020"));
+        assert!(eval("mem[x4000] == #-1"));
+        assert!(eval("mem[R1] == 0 || R0 == 1"));
+        assert!(!eval("(R0 == 1 || R1 == 1) && PSR == 2"));
+        assert!(eval("in OUTER && IN PRINT_NUM"));
+        assert!(!eval("in OTHER || in MISSING"));
+
+        let predicate = Predicate::parse("(r0 == 1 || R1 >= #2) && mem[R1] != 10").unwrap();
+        assert_eq!(
+            predicate.to_string(),
+            "(R0 == x0001 || R1 >= x0002) && mem[R1] != x000A"
+        );
+
+        assert!(Predicate::parse("R8 == 0").is_err());
+        assert!(Predicate::parse("R0 = 0").is_err());
+        assert!(Predicate::parse("R0 == 0 R1").is_err());
+        assert!(Predicate::parse("in x3000").is_err());
+        assert_eq!(
+            Predicate::parse("IN LOOP || R0 == 0").unwrap().to_string(),
+            "in LOOP || R0 == x0000"
+        );
+    }
+}
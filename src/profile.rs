@@ -0,0 +1,222 @@
+//! Instruction counts per opcode, per address and per call stack, and the hottest loops,
+//! for finding out where a program spends its time. Enabled with
+//! [`Vm::enable_profiling`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    io,
+};
+
+use crate::{
+    decode::{decode, Instruction},
+    disasm::disassemble_with_symbols,
+    symbols::SymbolTable,
+    vm::{CallKind, Frame, Opcode, Vm},
+};
+
+// how many addresses and loops the report lists
+const REPORT_TOP: usize = 10;
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    by_opcode: [u64; 16],
+    by_pc: HashMap<u16, u64>,
+    // taken backward branches and jumps, as (branch address, target)
+    loops: HashMap<(u16, u16), u64>,
+    // instructions per call stack, outermost call first, see Vm::backtrace
+    stacks: HashMap<Vec<(CallKind, u16)>, u64>,
+    // where the first counted instruction was, which names the stack with no calls
+    root: Option<u16>,
+    stack: Vec<(CallKind, u16)>,
+}
+
+impl Profile {
+    /// Counts the instruction `inst` at `pc`, after which execution continued at
+    /// `next_pc`.
+    pub(crate) fn record(&mut self, pc: u16, inst: u16, next_pc: u16) {
+        self.by_opcode[(inst >> 12) as usize] += 1;
+        *self.by_pc.entry(pc).or_default() += 1;
+
+        // JMP R7 is RET, which goes back to the caller rather than around a loop
+        let branch = matches!(
+            decode(inst),
+            Ok(Instruction::Br { .. } | Instruction::Jmp { base: 0..=6 })
+        );
+        if branch && next_pc <= pc {
+            *self.loops.entry((pc, next_pc)).or_default() += 1;
+        }
+    }
+
+    /// Counts an instruction at `pc` executed inside the calls `frames`.
+    pub(crate) fn record_stack(&mut self, pc: u16, frames: &[Frame]) {
+        self.root.get_or_insert(pc);
+        self.stack.clear();
+        self.stack
+            .extend(frames.iter().map(|frame| (frame.kind, frame.entry)));
+        match self.stacks.get_mut(&self.stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.stack.clone(), 1);
+            }
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.by_opcode.iter().sum()
+    }
+
+    /// Executed instructions with opcode `op`.
+    pub fn opcode_count(&self, op: Opcode) -> u64 {
+        self.by_opcode[op as usize]
+    }
+
+    /// How many times the instruction at `pc` was executed.
+    pub fn pc_count(&self, pc: u16) -> u64 {
+        self.by_pc.get(&pc).copied().unwrap_or_default()
+    }
+
+    /// Loops as (branch address, loop start, iterations), most iterations first.
+    pub fn loops(&self) -> Vec<(u16, u16, u64)> {
+        let mut loops: Vec<_> = self
+            .loops
+            .iter()
+            .map(|(&(branch, start), &count)| (branch, start, count))
+            .collect();
+        loops.sort_by_key(|&(branch, start, count)| (std::cmp::Reverse(count), branch, start));
+        loops
+    }
+
+    /// Writes the instruction counts per call stack in the folded format of flamegraph
+    /// tools, one stack per line like `MAIN;DRAW;TRAP_x21 1234`. Routines are named by
+    /// their labels in `symbols`, or by their address or vector.
+    pub fn write_folded(&self, symbols: &SymbolTable, out: &mut dyn io::Write) -> io::Result<()> {
+        let name = |addr: u16, fallback: String| match symbols.name_at(addr) {
+            Some(name) => name.to_string(),
+            None => fallback,
+        };
+        let root = self.root.unwrap_or_default();
+        let root = name(root, format!("x{root:04X}"));
+
+        // routines without labels can share a name
+        let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+        for (stack, &count) in &self.stacks {
+            let mut line = root.clone();
+            for &(kind, entry) in stack {
+                line.push(';');
+                line.push_str(&match kind {
+                    CallKind::Subroutine => name(entry, format!("x{entry:04X}")),
+                    CallKind::Trap(vector) => name(entry, format!("TRAP_x{vector:02X}")),
+                    CallKind::Handler(vector) => name(entry, format!("VECTOR_x{vector:02X}")),
+                });
+            }
+            *folded.entry(line).or_default() += count;
+        }
+
+        for (line, count) in folded {
+            writeln!(out, "{line} {count}")?;
+        }
+        Ok(())
+    }
+
+    /// A printable summary, using `vm`'s memory and symbols to show the hot code.
+    pub fn report(&self, vm: &Vm) -> String {
+        let total = self.total();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let mut out = format!("{total} instructions executed\n\nBy opcode:\n");
+
+        let mut opcodes: Vec<_> = (0..16u16)
+            .filter(|&op| self.by_opcode[op as usize] > 0)
+            .map(|op| (Opcode::try_from(op).unwrap(), self.by_opcode[op as usize]))
+            .collect();
+        opcodes.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        for (op, count) in opcodes {
+            let name = format!("{op:?}").to_uppercase();
+            writeln!(out, "  {name:<8} {count:>10}  {:5.1}%", percent(count)).unwrap();
+        }
+
+        let mut pcs: Vec<_> = self.by_pc.iter().map(|(&pc, &count)| (pc, count)).collect();
+        pcs.sort_by_key(|&(pc, count)| (std::cmp::Reverse(count), pc));
+        out.push_str("\nHottest instructions:\n");
+        for (pc, count) in pcs.into_iter().take(REPORT_TOP) {
+            let inst = vm.memory().get(pc as usize).copied().unwrap_or_default();
+            let asm = disassemble_with_symbols(inst, pc, vm.symbols());
+            let addr = vm.symbols().format_addr(pc);
+            writeln!(
+                out,
+                "  {addr:<12} {count:>10}  {:5.1}%  {asm}",
+                percent(count)
+            )
+            .unwrap();
+        }
+
+        let loops = self.loops();
+        if !loops.is_empty() {
+            out.push_str("\nHottest loops:\n");
+        }
+        for (branch, start, count) in loops.into_iter().take(REPORT_TOP) {
+            // instructions executed in the loop body, counted at the loop start
+            let body: u64 = (start..=branch).map(|pc| self.pc_count(pc)).sum();
+            writeln!(
+                out,
+                "  {} -> {}  {count} iterations, {:.1}% of instructions",
+                vm.symbols().format_addr(branch),
+                vm.symbols().format_addr(start),
+                percent(body)
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console::StreamIo, vm::Flag};
+
+    #[test]
+    fn test_profile() {
+        // AND R0, R0, #0; ADD R0, R0, #1; ADD R1, R0, #-3; BRn #-3; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[
+            0x30, 0x00, 0x50, 0x20, 0x10, 0x21, 0x12, 0x3D, 0x09, 0xFD, 0xF0, 0x25,
+        ])
+        .unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        vm.enable_profiling();
+        vm.run().unwrap();
+
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.total(), 11);
+        assert_eq!(profile.opcode_count(Opcode::Add), 6);
+        assert_eq!(profile.pc_count(0x3001), 3);
+        assert_eq!(profile.loops(), [(0x3003, 0x3001, 2)]);
+
+        let report = profile.report(&vm);
+        assert!(report.starts_with("11 instructions executed\n"));
+        assert!(report.contains("  x3003 -> x3001  2 iterations"));
+    }
+
+    #[test]
+    fn test_folded() {
+        // JSR SUB; HALT; SUB: ADD R0, R0, #1; RET
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[0x30, 0x00, 0x48, 0x01, 0xF0, 0x25, 0x10, 0x21, 0xC1, 0xC0])
+            .unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        vm.enable_profiling();
+        vm.run().unwrap();
+
+        let mut symbols = SymbolTable::default();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("SUB", 0x3002);
+        let mut out = Vec::new();
+        vm.profile()
+            .unwrap()
+            .write_folded(&symbols, &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "MAIN 2\nMAIN;SUB 2\n");
+    }
+}
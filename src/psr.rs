@@ -0,0 +1,114 @@
+//! The processor status register: the privilege mode in bit 15, the priority level in
+//! bits 10-8 and the N, Z and P condition codes in bits 2-0. Each field is updated on its
+//! own, so e.g. setting the condition codes keeps the privilege and priority that RTI
+//! and interrupts depend on.
+
+use crate::vm::Flag;
+
+pub const PSR_USER: u16 = 1 << 15;
+pub const PSR_PRIORITY: u16 = 0b111 << 8;
+pub const PSR_CC: u16 = 0b111;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    Supervisor,
+    User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Psr(u16);
+
+impl Psr {
+    pub const fn new(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn privilege(self) -> Privilege {
+        if self.0 & PSR_USER != 0 {
+            Privilege::User
+        } else {
+            Privilege::Supervisor
+        }
+    }
+
+    pub fn is_user(self) -> bool {
+        self.privilege() == Privilege::User
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.0 = match privilege {
+            Privilege::User => self.0 | PSR_USER,
+            Privilege::Supervisor => self.0 & !PSR_USER,
+        };
+    }
+
+    /// Priority level 0-7.
+    pub fn priority(self) -> u8 {
+        ((self.0 & PSR_PRIORITY) >> 8) as u8
+    }
+
+    /// Sets the priority level, keeping only its low three bits.
+    pub fn set_priority(&mut self, priority: u8) {
+        self.0 = self.0 & !PSR_PRIORITY | (priority as u16 & 0b111) << 8;
+    }
+
+    /// The condition codes, one of the [`Flag`] values unless the bits were set
+    /// directly.
+    pub fn cc(self) -> u16 {
+        self.0 & PSR_CC
+    }
+
+    pub fn set_cc(&mut self, flag: Flag) {
+        self.0 = self.0 & !PSR_CC | flag as u16;
+    }
+}
+
+impl From<u16> for Psr {
+    fn from(bits: u16) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<Psr> for u16 {
+    fn from(psr: Psr) -> Self {
+        psr.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cc() {
+        let mut psr = Psr::new(PSR_USER | 5 << 8 | Flag::Zero as u16);
+        psr.set_cc(Flag::Neg);
+        assert_eq!(psr.cc(), Flag::Neg as u16);
+        assert_eq!(psr.bits(), PSR_USER | 5 << 8 | Flag::Neg as u16);
+    }
+
+    #[test]
+    fn test_privilege() {
+        let mut psr = Psr::new(3 << 8 | Flag::Pos as u16);
+        assert_eq!(psr.privilege(), Privilege::Supervisor);
+        psr.set_privilege(Privilege::User);
+        assert!(psr.is_user());
+        assert_eq!(psr.bits(), PSR_USER | 3 << 8 | Flag::Pos as u16);
+        psr.set_privilege(Privilege::Supervisor);
+        assert_eq!(psr.bits(), 3 << 8 | Flag::Pos as u16);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut psr = Psr::new(PSR_USER | Flag::Zero as u16);
+        psr.set_priority(6);
+        assert_eq!(psr.priority(), 6);
+        assert_eq!(psr.bits(), PSR_USER | 6 << 8 | Flag::Zero as u16);
+        psr.set_priority(9);
+        assert_eq!(psr.priority(), 1);
+    }
+}
@@ -0,0 +1,68 @@
+//! Runs device callbacks every N executed instructions, so timer ticks, UART draining
+//! and display refreshes share one notion of time instead of each hooking the run loop.
+
+use crate::vm::Vm;
+
+pub type TickCallback = Box<dyn FnMut(&mut Vm)>;
+
+struct Task {
+    period: u64,
+    /// Instruction count at which the task runs next.
+    next: u64,
+    callback: TickCallback,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    // earliest `next` of all tasks, so the run loop only has one comparison to make
+    next_due: Option<u64>,
+}
+
+impl Scheduler {
+    /// Runs `callback` every `period` instructions, counting from `now`.
+    pub fn add(&mut self, now: u64, period: u64, callback: TickCallback) {
+        let period = period.max(1);
+        self.tasks.push(Task {
+            period,
+            next: now + period,
+            callback,
+        });
+        self.update_next_due();
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        matches!(self.next_due, Some(next) if now >= next)
+    }
+
+    /// Runs the tasks that are due at `now`. The scheduler must have been taken out of
+    /// `vm`, so callbacks can borrow it mutably; tasks they add end up in `vm`'s new
+    /// scheduler and are picked up by [`Scheduler::merge`].
+    pub fn run_due(&mut self, now: u64, vm: &mut Vm) {
+        for task in &mut self.tasks {
+            if now >= task.next {
+                (task.callback)(vm);
+                task.next = now + task.period;
+            }
+        }
+        self.update_next_due();
+    }
+
+    /// Brings tasks that were due after `now` back to at most one period from it, after
+    /// the instruction count went back, e.g. when stepping backwards.
+    pub fn rewind(&mut self, now: u64) {
+        for task in &mut self.tasks {
+            task.next = task.next.min(now + task.period);
+        }
+        self.update_next_due();
+    }
+
+    pub fn merge(&mut self, other: Scheduler) {
+        self.tasks.extend(other.tasks);
+        self.update_next_due();
+    }
+
+    fn update_next_due(&mut self) {
+        self.next_due = self.tasks.iter().map(|task| task.next).min();
+    }
+}
@@ -0,0 +1,161 @@
+//! Two vms in one process that share a window of memory and can interrupt each other,
+//! for producer/consumer and synchronization exercises.
+//!
+//! [`connect`] maps the same words into both vms over the window, xE000-xEFFF by
+//! default, and a doorbell register at [`DOORBELL`] into each. Writing a value with bit
+//! 15 set to the doorbell rings the other vm's: its bit 15 is set until that vm reads the
+//! register, and if its bit 14 is set the ring also requests interrupt
+//! [`DOORBELL_INTERRUPT`]. Bit 14 of every write sets whether the writer's own doorbell
+//! interrupts, so `x4000` only enables it and `xC000` also rings. [`run_pair`] runs both
+//! programs, interleaving them a given number of instructions at a time.
+
+use std::{
+    cell::{Cell, RefCell},
+    io,
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use crate::{
+    console::STATUS_READY,
+    device::{Device, Interrupt},
+    vm::{RunResult, Vm, VmError},
+};
+
+pub const DEFAULT_WINDOW: RangeInclusive<u16> = 0xE000..=0xEFFF;
+
+/// Doorbell register: bit 15 is set when the other vm rang and cleared by reading it,
+/// bit 14 enables the interrupt. Writing a value with bit 15 set rings the other vm.
+pub const DOORBELL: u16 = 0xFE2E;
+const DOORBELL_RING: u16 = 1 << 15;
+const DOORBELL_IE: u16 = 1 << 14;
+
+pub const DOORBELL_INTERRUPT: u8 = 0x82;
+pub const DOORBELL_PRIORITY: u8 = 5;
+
+struct Window {
+    start: u16,
+    words: Rc<RefCell<Vec<u16>>>,
+}
+
+impl Device for Window {
+    fn read(&mut self, addr: u16) -> u16 {
+        self.words.borrow()[(addr - self.start) as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        self.words.borrow_mut()[(addr - self.start) as usize] = val;
+        Ok(())
+    }
+}
+
+struct Doorbell {
+    enabled: bool,
+    // set by the other vm's doorbell
+    rung: Rc<Cell<bool>>,
+    peer: Rc<Cell<bool>>,
+}
+
+impl Device for Doorbell {
+    fn read(&mut self, _addr: u16) -> u16 {
+        let rung = if self.rung.take() { STATUS_READY } else { 0 };
+        rung | if self.enabled { DOORBELL_IE } else { 0 }
+    }
+
+    fn write(&mut self, _addr: u16, val: u16) -> io::Result<()> {
+        self.enabled = val & DOORBELL_IE != 0;
+        if val & DOORBELL_RING != 0 {
+            self.peer.set(true);
+        }
+        Ok(())
+    }
+
+    fn interrupt(&mut self) -> Option<Interrupt> {
+        (self.enabled && self.rung.get()).then_some(Interrupt {
+            vector: DOORBELL_INTERRUPT,
+            priority: DOORBELL_PRIORITY,
+        })
+    }
+}
+
+/// Shares `window` between `a` and `b` and gives each a doorbell that rings the other.
+/// The window starts out as `a`'s memory there; what `b` had loaded there is hidden.
+pub fn connect(a: &mut Vm, b: &mut Vm, window: RangeInclusive<u16>) {
+    let words = a.memory()[*window.start() as usize..=*window.end() as usize].to_vec();
+    let words = Rc::new(RefCell::new(words));
+    for vm in [&mut *a, &mut *b] {
+        let window_device = Window {
+            start: *window.start(),
+            words: words.clone(),
+        };
+        vm.map_device(window.clone(), Box::new(window_device));
+    }
+
+    let (a_rung, b_rung) = (Rc::new(Cell::new(false)), Rc::new(Cell::new(false)));
+    let doorbell = |rung: &Rc<Cell<bool>>, peer: &Rc<Cell<bool>>| {
+        Box::new(Doorbell {
+            enabled: false,
+            rung: rung.clone(),
+            peer: peer.clone(),
+        })
+    };
+    a.map_device(DOORBELL..=DOORBELL, doorbell(&a_rung, &b_rung));
+    b.map_device(DOORBELL..=DOORBELL, doorbell(&b_rung, &a_rung));
+}
+
+/// Runs `a` and `b` in turns of `quantum` instructions until both have halted or one
+/// fails. A vm stopped by a step hook stops the pair.
+pub fn run_pair(a: &mut Vm, b: &mut Vm, quantum: u64) -> Result<(), VmError> {
+    let mut done = [false; 2];
+    while !done.iter().all(|&done| done) {
+        for (vm, done) in [&mut *a, &mut *b].into_iter().zip(&mut done) {
+            if *done {
+                continue;
+            }
+            match vm.run_for(quantum.max(1))? {
+                RunResult::Halted => *done = true,
+                RunResult::Stopped => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::vm_with_program;
+
+    #[test]
+    fn test_shared_window() {
+        // producer: LD R0, #7; STI R0, WINDOW; LD R0, x8000; STI R0, DOORBELL; HALT
+        let mut producer = vm_with_program(&[
+            0x2004, 0xB004, 0x2005, 0xB003, 0xF025, 7, 0xE000, DOORBELL, 0x8000,
+        ]);
+        // consumer: wait: LDI R1, DOORBELL; BRzp wait; LDI R0, WINDOW; HALT
+        let mut consumer = vm_with_program(&[0xA203, 0x07FE, 0xA002, 0xF025, DOORBELL, 0xE000]);
+        connect(&mut producer, &mut consumer, DEFAULT_WINDOW);
+
+        run_pair(&mut producer, &mut consumer, 1).unwrap();
+        assert_eq!(consumer.registers()[0], 7);
+        // the consumer had to wait for the doorbell
+        assert!(consumer.instructions() > 4);
+    }
+
+    #[test]
+    fn test_doorbell_enable() {
+        // LD R0, x4000; STI R0, DOORBELL; HALT
+        let mut a = vm_with_program(&[0x2002, 0xB002, 0xF025, DOORBELL_IE, DOORBELL]);
+        // LDI R1, DOORBELL; HALT
+        let mut b = vm_with_program(&[0xA201, 0xF025, DOORBELL]);
+        connect(&mut a, &mut b, DEFAULT_WINDOW);
+
+        a.run().unwrap();
+        b.run().unwrap();
+        // enabling the interrupt didn't ring b
+        assert_eq!(b.registers()[1], 0);
+        assert_eq!(a.read_mem_range(DOORBELL, 1).unwrap(), [DOORBELL_IE]);
+    }
+}
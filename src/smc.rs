@@ -0,0 +1,41 @@
+//! Which addresses have been executed, for warning about stores into code, see
+//! [`crate::vm::Vm::enable_smc_detection`].
+
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Executed {
+    // one bit per memory word
+    words: Vec<u64>,
+    // (pc, address) pairs already warned about, so a loop warns once
+    reported: BTreeSet<(u16, u16)>,
+}
+
+impl Executed {
+    /// Nothing executed, for memory of `len` words.
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            reported: BTreeSet::new(),
+        }
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        self.words
+            .get(addr / 64)
+            .is_some_and(|bits| bits & 1 << (addr % 64) != 0)
+    }
+
+    pub fn insert(&mut self, addr: u16) {
+        let addr = addr as usize;
+        if let Some(bits) = self.words.get_mut(addr / 64) {
+            *bits |= 1 << (addr % 64);
+        }
+    }
+
+    /// Whether the store to `addr` at `pc` hasn't been reported yet, remembering it.
+    pub fn first_report(&mut self, pc: u16, addr: u16) -> bool {
+        self.reported.insert((pc, addr))
+    }
+}
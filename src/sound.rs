@@ -0,0 +1,111 @@
+//! A beeper for programs that play tones, e.g. the sound effects of game ports: write the
+//! frequency in Hz to [`SND_FREQ`], then the duration in milliseconds to [`SND_DUR`] to
+//! play the tone. A frequency of 0 is a rest.
+//!
+//! The vm doesn't wait for a tone to end; programs that play melodies time the notes
+//! with the clock registers. [`install`] maps the registers and sends the tones to a
+//! [`Speaker`]: [`ToneLog`] lists them, for headless runs and tests, and [`Bell`] rings
+//! the terminal bell. Embedders with an audio library can play them with a speaker of
+//! their own.
+
+use std::io::{self, Write};
+
+use crate::{device::Device, vm::Vm};
+
+/// Frequency register, in Hz.
+pub const SND_FREQ: u16 = 0xFE30;
+/// Duration register, in milliseconds. Writing it plays the tone.
+pub const SND_DUR: u16 = 0xFE32;
+
+/// Where the tones go.
+pub trait Speaker {
+    /// Starts playing `freq` Hz for `duration_ms`, or nothing if `freq` is 0. Shouldn't
+    /// block; an error stops the program.
+    fn play(&mut self, freq: u16, duration_ms: u16) -> io::Result<()>;
+}
+
+/// Writes a line like `tone 440 Hz 250 ms` for every tone.
+pub struct ToneLog(pub Box<dyn Write>);
+
+impl Speaker for ToneLog {
+    fn play(&mut self, freq: u16, duration_ms: u16) -> io::Result<()> {
+        writeln!(self.0, "tone {freq} Hz {duration_ms} ms")
+    }
+}
+
+/// Writes BEL for every tone that isn't a rest, whatever its frequency and duration.
+pub struct Bell(pub Box<dyn Write>);
+
+impl Speaker for Bell {
+    fn play(&mut self, freq: u16, _duration_ms: u16) -> io::Result<()> {
+        if freq == 0 {
+            return Ok(());
+        }
+        self.0.write_all(b"\x07")?;
+        self.0.flush()
+    }
+}
+
+struct Beeper {
+    freq: u16,
+    duration_ms: u16,
+    speaker: Box<dyn Speaker>,
+}
+
+impl Device for Beeper {
+    fn read(&mut self, addr: u16) -> u16 {
+        if addr == SND_FREQ {
+            self.freq
+        } else {
+            self.duration_ms
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16) -> io::Result<()> {
+        if addr == SND_FREQ {
+            self.freq = val;
+            return Ok(());
+        }
+        self.duration_ms = val;
+        self.speaker.play(self.freq, val)
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        addr == SND_FREQ || addr == SND_DUR
+    }
+}
+
+/// Maps [`SND_FREQ`] and [`SND_DUR`] as the device `sound`, playing tones on `speaker`.
+pub fn install(vm: &mut Vm, speaker: Box<dyn Speaker>) {
+    let beeper = Beeper {
+        freq: 0,
+        duration_ms: 0,
+        speaker,
+    };
+    vm.map_named_device("sound", SND_FREQ..=SND_DUR, Box::new(beeper));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console::StreamIo, image::ImageBuilder, util::SharedBuf, vm::Flag};
+
+    #[test]
+    fn test_beeper() {
+        // STI R0, SND_FREQ; STI R1, SND_DUR; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        let image = ImageBuilder::new(0x3000)
+            .words(&[0xB002, 0xB202, 0xF025, SND_FREQ, SND_DUR])
+            .build();
+        vm.load_image_bytes(&image).unwrap();
+        vm.set_register(0, 440);
+        vm.set_register(1, 250);
+        vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+        let log = SharedBuf::default();
+        install(&mut vm, Box::new(ToneLog(Box::new(log.clone()))));
+
+        vm.run().unwrap();
+        assert_eq!(log.0.borrow().as_slice(), b"tone 440 Hz 250 ms\n");
+        assert_eq!(vm.device_names().last(), Some("sound"));
+    }
+}
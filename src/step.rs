@@ -0,0 +1,214 @@
+//! Per-instruction events, for building analyzers and custom trace formats on top of
+//! [`Vm::iter_steps`].
+
+use std::fmt::Write;
+
+use crate::{
+    decode::decode,
+    disasm::disassemble_with_symbols,
+    symbols::SymbolTable,
+    vm::{Opcode, RunResult, Vm, VmError},
+};
+
+/// A data memory access done by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAccess {
+    Read { addr: u16, val: u16 },
+    Write { addr: u16, val: u16 },
+}
+
+/// What one executed instruction did.
+#[derive(Debug, Clone)]
+pub struct StepEvent {
+    /// Address of the executed instruction.
+    pub pc: u16,
+    pub inst: u16,
+    pub opcode: Opcode,
+    /// Registers before the instruction executed.
+    pub reg_before: [u16; 8],
+    /// Registers after the instruction executed.
+    pub reg: [u16; 8],
+    pub psr: u16,
+    pub accesses: Vec<MemAccess>,
+}
+
+impl StepEvent {
+    /// Registers changed by the instruction, as (register, old value, new value).
+    pub fn reg_changes(&self) -> impl Iterator<Item = (usize, u16, u16)> + '_ {
+        self.reg_before
+            .iter()
+            .zip(&self.reg)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(r, (&old, &new))| (r, old, new))
+    }
+
+    /// The event as one line of JSON, e.g.
+    /// `{"pc":12288,"inst":4129,"asm":"ADD R0, R0, #1","regs":{"R0":1},"cc":"P"}`.
+    /// `regs` only has the registers the instruction changed.
+    pub fn to_json(&self) -> String {
+        self.to_json_with_symbols(&SymbolTable::default())
+    }
+
+    /// Like [`StepEvent::to_json`], with labels from `symbols` in `asm` and the PC as a
+    /// label in `sym`, e.g. `"sym":"LOOP+2"`, when it is close to one.
+    pub fn to_json_with_symbols(&self, symbols: &SymbolTable) -> String {
+        let mut json = format!(r#"{{"pc":{}"#, self.pc);
+        if let Some(sym) = symbols.describe(self.pc) {
+            write!(json, r#","sym":"{sym}""#).unwrap();
+        }
+        write!(
+            json,
+            r#","inst":{},"asm":"{}","regs":{{"#,
+            self.inst,
+            disassemble_with_symbols(self.inst, self.pc, symbols)
+        )
+        .unwrap();
+        for (i, (r, _, new)) in self.reg_changes().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(json, r#"{sep}"R{r}":{new}"#).unwrap();
+        }
+
+        let cc = match self.psr & 0b111 {
+            0b100 => "N",
+            0b010 => "Z",
+            0b001 => "P",
+            _ => "?",
+        };
+        write!(json, r#"}},"cc":"{cc}"}}"#).unwrap();
+
+        json
+    }
+}
+
+pub struct Steps<'a> {
+    vm: &'a mut Vm,
+    done: bool,
+}
+
+impl<'a> Steps<'a> {
+    pub(crate) fn new(vm: &'a mut Vm) -> Self {
+        Self { vm, done: false }
+    }
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Result<StepEvent, VmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // steps that execute nothing, e.g. skipped by a hook, have no event
+        while !self.done {
+            let result = self.vm.step();
+            self.done = matches!(result, Ok(RunResult::Halted | RunResult::Stopped) | Err(_));
+            if let Err(err) = result {
+                return Some(Err(err));
+            }
+            if let Some((pc, inst)) = self.vm.last_executed() {
+                return Some(Ok(StepEvent {
+                    pc,
+                    inst,
+                    opcode: decode(inst).map_or(Opcode::Reserved, |decoded| decoded.opcode()),
+                    reg_before: *self.vm.registers_before(),
+                    reg: *self.vm.registers(),
+                    psr: self.vm.psr(),
+                    accesses: self.vm.last_accesses().to_vec(),
+                }));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        console::StreamIo,
+        image::ImageBuilder,
+        testkit::vm_with_program,
+        vm::{Flag, HookAction},
+    };
+
+    #[test]
+    fn test_iter_steps() {
+        // ADD R0, R0, #2; ST R0, x3003; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[0x30, 0x00, 0x10, 0x22, 0x30, 0x01, 0xF0, 0x25])
+            .unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+
+        let events: Vec<_> = vm.iter_steps().collect::<Result<_, _>>().unwrap();
+        assert_eq!(events.len(), 3);
+
+        assert_eq!(events[0].opcode, Opcode::Add);
+        assert_eq!(events[0].reg_changes().collect::<Vec<_>>(), [(0, 0, 2)]);
+
+        assert_eq!(events[1].pc, 0x3001);
+        assert_eq!(
+            events[1].accesses,
+            [MemAccess::Write {
+                addr: 0x3003,
+                val: 2
+            }]
+        );
+
+        assert_eq!(events[2].opcode, Opcode::Trap);
+        assert_eq!(
+            events[0].to_json(),
+            r#"{"pc":12288,"inst":4130,"asm":"ADD R0, R0, #2","regs":{"R0":2},"cc":"P"}"#
+        );
+
+        let symbols = [("MAIN", 0x3000), ("DATA", 0x3003)].into_iter().collect();
+        assert_eq!(
+            events[1].to_json_with_symbols(&symbols),
+            r#"{"pc":12289,"sym":"MAIN+1","inst":12289,"asm":"ST R0, DATA","regs":{},"cc":"P"}"#
+        );
+    }
+
+    #[test]
+    fn test_iter_steps_interrupt() {
+        // LD R0, IE; STI R0, KBSR; ADD R0, R0, #1; IE; KBSR
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        let image = ImageBuilder::new(0x3000)
+            .words(&[0x2002, 0xB002, 0x1021, 0x4000, 0xFE00])
+            .build();
+        vm.load_image_bytes(&image).unwrap();
+        vm.set_io(Box::new(StreamIo::new(&b"a"[..], std::io::sink())));
+        vm.set_register(6, 0x2000);
+        // the ISR: LDI R1, KBDR; HALT
+        vm.set_memory(0x0180, 0x1000);
+        for (addr, word) in (0x1000..).zip([0xA201, 0xF025, 0xFE02]) {
+            vm.set_memory(addr, word);
+        }
+
+        let events: Vec<_> = vm.iter_steps().collect::<Result<_, _>>().unwrap();
+        // the interrupt is taken before the ADD, which never runs
+        assert_eq!(events[2].pc, 0x1000);
+        assert_eq!(events[2].inst, 0xA201);
+        assert_eq!(
+            events[2].reg_changes().collect::<Vec<_>>(),
+            [(1, 0, b'a' as u16)]
+        );
+        assert_eq!(events.len(), 4);
+    }
+
+    #[test]
+    fn test_iter_steps_hooks() {
+        // ADD R0, R0, #1; ADD R0, R0, #2; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x1022, 0xF025]);
+
+        vm.set_pre_step_hook(|_, _| HookAction::Stop);
+        assert_eq!(vm.iter_steps().count(), 0);
+        assert_eq!(vm.instructions(), 0);
+
+        vm.set_pre_step_hook(|vm, _| match vm.pc() {
+            0x3000 => HookAction::SkipInstruction,
+            _ => HookAction::Continue,
+        });
+        let events: Vec<_> = vm.iter_steps().collect::<Result<_, _>>().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].pc, 0x3001);
+        assert_eq!(vm.registers()[0], 2);
+    }
+}
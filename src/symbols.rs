@@ -0,0 +1,180 @@
+//! Symbol tables, read from the `.sym` files lc3as writes next to its object files, so
+//! addresses can be shown as labels like `LOOP+2`.
+
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::util::parse_literal;
+
+// farther from a label than this, an address is shown as plain hex
+const MAX_OFFSET: u16 = 0xFF;
+
+/// Labels and their addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    addrs: BTreeMap<String, u16>,
+    // the first label defined at each address
+    names: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    /// Parses a `.sym` file. Symbol lines are comments with a label and a hex address,
+    /// e.g. `// LOOP 3002` with tabs and padding; the header lines around them are
+    /// skipped.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut symbols = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let Some(line) = line.trim().strip_prefix("//") else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                bail!("line {}: expected a comment", i + 1);
+            };
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if let [name, addr] = fields[..] {
+                if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                    symbols.insert(name, addr);
+                }
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    /// Reads the `.sym` file next to the object file `image`, e.g. `prog.sym` for
+    /// `prog.obj`. Returns an empty table if there is none.
+    pub fn for_image(image: impl AsRef<Path>) -> Result<Self> {
+        let file = image.as_ref().with_extension("sym");
+        if !file.exists() {
+            return Ok(Self::default());
+        }
+
+        std::fs::read_to_string(&file)
+            .map_err(anyhow::Error::from)
+            .and_then(|symbols| Self::parse(&symbols))
+            .map_err(|err| anyhow!("{}: {err}", file.display()))
+    }
+
+    /// Adds a label, replacing an earlier definition of `name`.
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.addrs.insert(name.to_string(), addr) {
+            if self
+                .names
+                .get(&old)
+                .is_some_and(|old_name| old_name == name)
+            {
+                self.names.remove(&old);
+            }
+        }
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    /// Makes `name` the only label at `addr`.
+    pub fn rename(&mut self, addr: u16, name: &str) {
+        self.addrs.retain(|_, &mut label_addr| label_addr != addr);
+        self.names.remove(&addr);
+        self.insert(name, addr);
+    }
+
+    /// Adds all labels of `other`.
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (name, &addr) in &other.addrs {
+            self.insert(name, addr);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.addrs.get(name).copied()
+    }
+
+    /// The label defined at exactly `addr`.
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    /// `addr` relative to the closest label at or before it, e.g. `LOOP+2`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (&label_addr, name) = self.names.range(..=addr).next_back()?;
+        match addr - label_addr {
+            0 => Some(name.clone()),
+            offset if offset <= MAX_OFFSET => Some(format!("{name}+{offset}")),
+            _ => None,
+        }
+    }
+
+    /// Parses a label or a literal address like `x3000`.
+    pub fn parse_addr(&self, s: &str) -> Result<u16> {
+        if let Some(addr) = self.lookup(s) {
+            return Ok(addr);
+        }
+        match parse_literal(s) {
+            Some(n) if (0..=0xFFFF).contains(&n) => Ok(n as u16),
+            _ => bail!("bad address: {s}"),
+        }
+    }
+
+    /// `addr` as a label if there is one close enough, otherwise as hex.
+    pub fn format_addr(&self, addr: u16) -> String {
+        self.describe(addr)
+            .unwrap_or_else(|| format!("x{addr:04X}"))
+    }
+
+    /// The table in the `.sym` format lc3as writes.
+    pub fn to_sym_file(&self) -> String {
+        let mut out = String::from(
+            "// Symbol table\n\
+             // Scope level 0:\n\
+             //\tSymbol Name       Page Address\n\
+             //\t----------------  ------------\n",
+        );
+        let mut symbols: Vec<_> = self.addrs.iter().collect();
+        symbols.sort_by_key(|&(_, addr)| addr);
+        for (name, addr) in symbols {
+            writeln!(out, "//\t{name:<16}  {addr:04X}").unwrap();
+        }
+        out.push('\n');
+
+        out
+    }
+}
+
+impl<'a> FromIterator<(&'a str, u16)> for SymbolTable {
+    fn from_iter<I: IntoIterator<Item = (&'a str, u16)>>(iter: I) -> Self {
+        let mut symbols = Self::default();
+        for (name, addr) in iter {
+            symbols.insert(name, addr);
+        }
+        symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let symbols: SymbolTable = [("START", 0x3000), ("LOOP", 0x3002)].into_iter().collect();
+
+        let parsed = SymbolTable::parse(&symbols.to_sym_file()).unwrap();
+        assert_eq!(parsed, symbols);
+        assert_eq!(parsed.lookup("LOOP"), Some(0x3002));
+
+        assert_eq!(symbols.describe(0x3002).as_deref(), Some("LOOP"));
+        assert_eq!(symbols.describe(0x3004).as_deref(), Some("LOOP+2"));
+        assert_eq!(symbols.describe(0x2FFF), None);
+        assert_eq!(symbols.format_addr(0x4000), "x4000");
+        assert_eq!(symbols.parse_addr("LOOP").unwrap(), 0x3002);
+        assert_eq!(symbols.parse_addr("x3010").unwrap(), 0x3010);
+        assert!(symbols.parse_addr("NOPE").is_err());
+
+        assert!(SymbolTable::parse("LOOP 3002").is_err());
+    }
+}
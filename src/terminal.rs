@@ -0,0 +1,275 @@
+//! Raw terminal input, and the default [`IoDevice`] on top of it. The platform specific
+//! parts live in the `unix` and `windows` submodules.
+
+use std::{
+    collections::VecDeque,
+    io::{self, stdout, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{console::IoDevice, util::parse_literal};
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+use unix as sys;
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows as sys;
+
+pub use sys::{enable_raw_mode, getch, Terminal};
+use sys::{poll_stdin, read_stdin};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPT_ENDS_INPUT: AtomicBool = AtomicBool::new(false);
+
+/// Catches Ctrl-C from now on instead of letting it kill the process, so the caller can
+/// stop the program and restore the terminal; see [`interrupted`]. With `ends_input`, a
+/// key read that is waiting when Ctrl-C arrives gives up as if the input had ended,
+/// otherwise it keeps waiting. Does nothing on Windows, where a console in raw mode
+/// hands Ctrl-C to the program as a key.
+pub fn catch_interrupts(ends_input: bool) -> Result<()> {
+    INTERRUPT_ENDS_INPUT.store(ends_input, Ordering::Relaxed);
+    sys::catch_interrupts()
+}
+
+/// Whether Ctrl-C was pressed since the last [`take_interrupt`].
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Like [`interrupted`], and forgets the Ctrl-C.
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+/// How keyboard input is handed to the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputMode {
+    /// One byte at a time, as it arrives from the terminal.
+    Bytes,
+    /// When ESC arrives, read the rest of the escape sequence (arrow/function keys,
+    /// modifier parameters, Alt-prefixed keys) up front and deliver it byte by byte,
+    /// with KBSR reporting ready until the whole sequence has been consumed.
+    EscapeSequences,
+    /// Like [`InputMode::EscapeSequences`], except that the arrow keys, with or without
+    /// modifiers, arrive as a single code each.
+    ArrowKeys(ArrowKeys),
+}
+
+/// The codes [`InputMode::ArrowKeys`] delivers. By default x80-x83, which no other key
+/// sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArrowKeys {
+    pub up: u8,
+    pub down: u8,
+    pub right: u8,
+    pub left: u8,
+}
+
+impl Default for ArrowKeys {
+    fn default() -> Self {
+        Self {
+            up: 0x80,
+            down: 0x81,
+            right: 0x82,
+            left: 0x83,
+        }
+    }
+}
+
+impl ArrowKeys {
+    /// Parses `UP,DOWN,RIGHT,LEFT`, e.g. `w,s,d,a` or `x80,x81,x82,x83`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let codes = s
+            .split(',')
+            .map(|code| match (code.len(), parse_literal(code)) {
+                (1, _) => Ok(code.as_bytes()[0]),
+                (_, Some(n @ 0..=0xFF)) => Ok(n as u8),
+                _ => bail!("bad key code: {code}"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let [up, down, right, left] = codes[..] else {
+            bail!("expected four key codes, UP,DOWN,RIGHT,LEFT: {s}");
+        };
+        Ok(Self {
+            up,
+            down,
+            right,
+            left,
+        })
+    }
+
+    /// The code for the escape sequence `seq`, without its ESC, if it is an arrow key.
+    fn translate(&self, seq: &[u8]) -> Option<u8> {
+        let (&last, params) = seq.split_last()?;
+        let params = params
+            .strip_prefix(b"[")
+            .or_else(|| params.strip_prefix(b"O"))?;
+        if !params.iter().all(|&b| b.is_ascii_digit() || b == b';') {
+            return None;
+        }
+        match last {
+            b'A' => Some(self.up),
+            b'B' => Some(self.down),
+            b'C' => Some(self.right),
+            b'D' => Some(self.left),
+            _ => None,
+        }
+    }
+}
+
+/// Reads keys from stdin and prints to stdout.
+pub struct TerminalIo {
+    input_mode: InputMode,
+    // input read from the terminal but not handed out yet, e.g. the rest of a paste or
+    // of an escape sequence
+    queue: VecDeque<u8>,
+}
+
+impl TerminalIo {
+    pub fn new(input_mode: InputMode) -> Self {
+        Self {
+            input_mode,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+/// Waits for input and adds all of it to `queue`.
+fn fill(queue: &mut VecDeque<u8>) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    let n = read_stdin(&mut buf)?;
+    queue.extend(&buf[..n]);
+    Ok(())
+}
+
+impl IoDevice for TerminalIo {
+    fn key_ready(&mut self) -> bool {
+        !self.queue.is_empty() || poll_stdin(0)
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        if self.queue.is_empty() {
+            match fill(&mut self.queue) {
+                Ok(()) => (),
+                // Ctrl-C, see catch_interrupts, or the end of piped input
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    return None
+                }
+                Err(_) => return Some(0),
+            }
+        }
+
+        let byte = self.queue.pop_front()?;
+        if byte != ESC || self.input_mode == InputMode::Bytes {
+            return Some(byte);
+        }
+
+        let queue = &mut self.queue;
+        let seq = read_escape_sequence(|| {
+            if queue.is_empty() && poll_stdin(ESCAPE_TIMEOUT_MS) {
+                fill(queue).ok()?;
+            }
+            queue.pop_front()
+        });
+        if let InputMode::ArrowKeys(keys) = self.input_mode {
+            if let Some(code) = keys.translate(&seq) {
+                return Some(code);
+            }
+        }
+        for &byte in seq.iter().rev() {
+            self.queue.push_front(byte);
+        }
+
+        Some(byte)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        stdout().flush()
+    }
+}
+
+const ESC: u8 = 0x1B;
+
+// the rest of an escape sequence is written by the terminal together with the ESC,
+// so a short wait is enough to tell it apart from a lone ESC keypress
+const ESCAPE_TIMEOUT_MS: i32 = 10;
+
+/// Reads the bytes following an ESC, using `next` to fetch each byte.
+///
+/// Handles CSI (`ESC [ params final`, e.g. arrows with modifiers like `ESC [1;5A`),
+/// SS3 (`ESC O final`, e.g. F1-F4) and Alt-prefixed keys (`ESC key`).
+fn read_escape_sequence(mut next: impl FnMut() -> Option<u8>) -> Vec<u8> {
+    let mut seq = Vec::new();
+
+    match next() {
+        Some(b'[') => {
+            seq.push(b'[');
+            // parameter and intermediate bytes, until the final byte in x40-x7E
+            while let Some(byte) = next() {
+                seq.push(byte);
+                if (0x40..=0x7E).contains(&byte) {
+                    break;
+                }
+            }
+        }
+        Some(b'O') => {
+            seq.push(b'O');
+            seq.extend(next());
+        }
+        Some(byte) => seq.push(byte),
+        None => {}
+    }
+
+    seq
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_escape_sequence() {
+        fn read(bytes: &[u8]) -> Vec<u8> {
+            let mut bytes = bytes.iter().copied();
+            read_escape_sequence(|| bytes.next())
+        }
+
+        // up arrow, ctrl+right, F1, alt+x, lone ESC
+        assert_eq!(read(b"[Ax"), b"[A");
+        assert_eq!(read(b"[1;5Cx"), b"[1;5C");
+        assert_eq!(read(b"OPx"), b"OP");
+        assert_eq!(read(b"x"), b"x");
+        assert_eq!(read(b""), b"");
+    }
+
+    #[test]
+    fn test_arrow_keys() {
+        let keys = ArrowKeys::parse("w,s,d,xFF").unwrap();
+        assert_eq!(keys.translate(b"[A"), Some(b'w'));
+        assert_eq!(keys.translate(b"[1;5D"), Some(0xFF));
+        assert_eq!(keys.translate(b"OB"), Some(b's'));
+        assert_eq!(keys.translate(b"[3~"), None);
+        assert_eq!(keys.translate(b"x"), None);
+
+        assert_eq!(
+            ArrowKeys::parse("x80,x81,x82,x83").unwrap(),
+            ArrowKeys::default()
+        );
+        assert!(ArrowKeys::parse("w,s,d").is_err());
+        assert!(ArrowKeys::parse("w,s,d,x100").is_err());
+    }
+}
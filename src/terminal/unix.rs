@@ -0,0 +1,103 @@
+//! Terminal input on Unix, through termios and poll.
+
+use std::{
+    io::{self, stdin, IsTerminal},
+    os::{raw::c_int, unix::prelude::AsRawFd},
+    sync::atomic::Ordering,
+};
+
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    sys::{signal, termios},
+    unistd,
+};
+
+use super::{INTERRUPTED, INTERRUPT_ENDS_INPUT};
+
+/// Blocks until a byte can be read from stdin. Fails with [`io::ErrorKind::Interrupted`]
+/// on Ctrl-C if [`super::catch_interrupts`] was told to end the input, and with
+/// [`io::ErrorKind::UnexpectedEof`] at the end of piped input.
+pub fn getch() -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    read_stdin(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Blocks until stdin has input, then reads as much of it as fits in `buf`, so pasted
+/// text arrives whole. Fails like [`getch`]. Reads the file descriptor directly, since
+/// bytes left in a buffer of [`std::io::Stdin`] wouldn't show up in [`poll_stdin`].
+pub fn read_stdin(buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match unistd::read(stdin().as_raw_fd(), buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => return Ok(n),
+            Err(Errno::EINTR)
+                if !(INTERRUPTED.load(Ordering::Relaxed)
+                    && INTERRUPT_ENDS_INPUT.load(Ordering::Relaxed)) => {}
+            Err(errno) => return Err(errno.into()),
+        }
+    }
+}
+
+extern "C" fn on_interrupt(_: c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+pub fn catch_interrupts() -> Result<()> {
+    use signal::*;
+
+    // without SA_RESTART, so a blocking read returns and getch can give up
+    let action = SigAction::new(
+        SigHandler::Handler(on_interrupt),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic
+    unsafe { sigaction(Signal::SIGINT, &action) }?;
+    Ok(())
+}
+
+/// Restores the terminal's original attributes when dropped.
+pub struct Terminal(termios::Termios);
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        use termios::*;
+
+        tcsetattr(stdin().as_raw_fd(), SetArg::TCSAFLUSH, &self.0).unwrap();
+    }
+}
+
+/// Switches the terminal to raw mode until the returned guard is dropped. Does nothing
+/// if stdin is not a terminal, e.g. when input is piped in from a file.
+pub fn enable_raw_mode() -> Result<Option<Terminal>> {
+    use termios::*;
+
+    if !stdin().is_terminal() {
+        return Ok(None);
+    }
+
+    let stdin = stdin().as_raw_fd();
+    let mut termios = tcgetattr(stdin)?;
+
+    let local_flags = termios.local_flags;
+
+    let flags_to_remove = LocalFlags::ICANON | LocalFlags::ECHO;
+    termios.local_flags &= flags_to_remove.complement();
+
+    tcsetattr(stdin, SetArg::TCSAFLUSH, &termios)?;
+
+    termios.local_flags = local_flags;
+
+    // this struct has now the original attributes of the terminal
+    Ok(Some(Terminal(termios)))
+}
+
+/// Whether stdin has input within `timeout_ms`.
+pub fn poll_stdin(timeout_ms: i32) -> bool {
+    use nix::poll::*;
+
+    let mut fds = [PollFd::new(stdin().as_raw_fd(), PollFlags::POLLIN)];
+    matches!(poll(&mut fds, timeout_ms), Ok(n) if n > 0)
+}
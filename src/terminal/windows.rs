@@ -0,0 +1,142 @@
+//! Console input on Windows, through crossterm. The console reports key events rather
+//! than bytes, so keys are turned into the bytes a Unix terminal would send for them.
+
+use std::{
+    collections::VecDeque,
+    io::{self, stdin, IsTerminal, Read},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal,
+};
+
+use super::ESC;
+
+// bytes of a key that has been read but not handed out, e.g. the rest of an arrow key
+static PENDING: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+/// Blocks until a byte can be read from stdin.
+pub fn getch() -> io::Result<u8> {
+    if !stdin().is_terminal() {
+        let mut buf = [0u8; 1];
+        stdin().read_exact(&mut buf)?;
+        return Ok(buf[0]);
+    }
+
+    loop {
+        if let Some(byte) = PENDING.lock().unwrap().pop_front() {
+            return Ok(byte);
+        }
+        if let Event::Key(key) = event::read()? {
+            PENDING.lock().unwrap().extend(key_bytes(key));
+        }
+    }
+}
+
+/// Blocks until a byte can be read from stdin, then reads as much of the input that is
+/// already there as fits in `buf`, so pasted text arrives whole.
+pub fn read_stdin(buf: &mut [u8]) -> io::Result<usize> {
+    if !stdin().is_terminal() {
+        return match stdin().read(buf)? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            n => Ok(n),
+        };
+    }
+
+    buf[0] = getch()?;
+    let mut pending = PENDING.lock().unwrap();
+    let n = pending.len().min(buf.len() - 1);
+    for (dst, byte) in buf[1..].iter_mut().zip(pending.drain(..n)) {
+        *dst = byte;
+    }
+    Ok(n + 1)
+}
+
+/// Leaves raw mode when dropped.
+pub struct Terminal(());
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        terminal::disable_raw_mode().unwrap();
+    }
+}
+
+/// Switches the console to raw mode until the returned guard is dropped. Does nothing
+/// if stdin is not a console, e.g. when input is piped in from a file.
+pub fn enable_raw_mode() -> Result<Option<Terminal>> {
+    if !stdin().is_terminal() {
+        return Ok(None);
+    }
+
+    terminal::enable_raw_mode()?;
+    Ok(Some(Terminal(())))
+}
+
+/// Ctrl-C reaches a console in raw mode as a key, so there is no signal to catch.
+pub fn catch_interrupts() -> Result<()> {
+    Ok(())
+}
+
+/// Whether stdin has input within `timeout_ms`.
+pub fn poll_stdin(timeout_ms: i32) -> bool {
+    if !stdin().is_terminal() {
+        // piped input can't be polled, a read returns at the latest at its end
+        return true;
+    }
+    if !PENDING.lock().unwrap().is_empty() {
+        return true;
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms.max(0) as u64);
+    // mouse, focus and resize events and key releases don't count as input
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match event::poll(timeout) {
+            Ok(true) => (),
+            _ => return false,
+        }
+
+        if let Ok(Event::Key(key)) = event::read() {
+            let bytes = key_bytes(key);
+            if !bytes.is_empty() {
+                PENDING.lock().unwrap().extend(bytes);
+                return true;
+            }
+        }
+    }
+}
+
+fn key_bytes(key: KeyEvent) -> Vec<u8> {
+    if key.kind == KeyEventKind::Release {
+        return Vec::new();
+    }
+
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Char('c') if ctrl => {
+            // raw mode disables Ctrl-C, exit like a Unix terminal would
+            terminal::disable_raw_mode().ok();
+            std::process::exit(130);
+        }
+        KeyCode::Char(c) if ctrl && c.is_ascii_alphabetic() => {
+            vec![c.to_ascii_uppercase() as u8 & 0x1F]
+        }
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+        KeyCode::Enter => vec![b'\n'],
+        KeyCode::Tab => vec![b'\t'],
+        KeyCode::Backspace => vec![0x7F],
+        KeyCode::Esc => vec![ESC],
+        KeyCode::Up => b"\x1b[A".to_vec(),
+        KeyCode::Down => b"\x1b[B".to_vec(),
+        KeyCode::Right => b"\x1b[C".to_vec(),
+        KeyCode::Left => b"\x1b[D".to_vec(),
+        KeyCode::Home => b"\x1b[H".to_vec(),
+        KeyCode::End => b"\x1b[F".to_vec(),
+        KeyCode::Delete => b"\x1b[3~".to_vec(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,377 @@
+//! Helpers for unit testing LC-3 programs from Rust with `cargo test`: run an image with
+//! some keyboard input and check the machine it leaves behind.
+//!
+//! ```
+//! use lc3_vm::testkit::run_program;
+//!
+//! // GETC; OUT; ST R0, x3004; HALT
+//! let image = [0x30, 0x00, 0xF0, 0x20, 0xF0, 0x21, 0x30, 0x01, 0xF0, 0x25];
+//!
+//! let state = run_program(&image, b"a");
+//! state.assert_halted();
+//! state.assert_reg(0, 0x61);
+//! state.assert_mem_range(0x3004, &[0x61]);
+//! state.assert_output_contains("a");
+//! ```
+//!
+//! [`Fixture`]s test the execution core one instruction at a time: each gives the
+//! registers and memory before an instruction and what it should change, in TOML.
+//!
+//! ```toml
+//! [[fixture]]
+//! name = "ADD immediate, negative result"
+//! inst = "x1061"              # ADD R0, R1, #1, placed at pc
+//! pc = "x3000"                # the default
+//! psr = "x0002"               # the default, supervisor mode with Z set
+//! reg = { R1 = "xFFFE" }
+//! mem = { x3010 = 5 }
+//! input = ""                  # keyboard input for traps
+//!
+//! [fixture.expect]            # anything not listed must be unchanged
+//! reg = { R0 = "xFFFF" }
+//! cc = "n"                    # or the whole psr; pc defaults to the next instruction
+//! ```
+
+use std::{collections::BTreeMap, io};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use crate::{
+    asm,
+    console::StreamIo,
+    image::ImageBuilder,
+    util::{self, parse_literal, SharedBuf, Word},
+    vm::{Flag, Vm, VmError},
+};
+
+/// Programs that run longer than this are stopped with [`VmError::InstructionLimit`].
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 1_000_000;
+
+/// The machine after a program ran.
+#[derive(Debug)]
+pub struct MachineState {
+    pub registers: [u16; 8],
+    pub pc: u16,
+    pub psr: u16,
+    pub memory: Vec<u16>,
+    /// Everything the program printed, lossily decoded.
+    pub output: String,
+    pub instructions: u64,
+    pub halted: bool,
+    /// Why the program stopped, if it didn't halt.
+    pub error: Option<VmError>,
+}
+
+/// A machine with `program` loaded at x3000, where it starts, no keyboard input and its
+/// output discarded, for stepping through a program by hand.
+pub fn vm_with_program(program: &[u16]) -> Vm {
+    let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+    let image = ImageBuilder::new(0x3000).words(program).build();
+    vm.load_image_bytes(&image)
+        .unwrap_or_else(|err| panic!("cannot load program: {err}"));
+    vm.set_io(Box::new(StreamIo::new(io::empty(), io::sink())));
+    vm
+}
+
+/// Runs the .obj `image` until it halts, fails or runs out of
+/// [`DEFAULT_MAX_INSTRUCTIONS`], with `input` as its keyboard input.
+pub fn run_program(image: &[u8], input: &[u8]) -> MachineState {
+    run_program_with_limit(image, input, DEFAULT_MAX_INSTRUCTIONS)
+}
+
+/// Like [`run_program`], stopping after `max_instructions`.
+pub fn run_program_with_limit(image: &[u8], input: &[u8], max_instructions: u64) -> MachineState {
+    let output = SharedBuf::default();
+
+    let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+    vm.load_image_bytes(image)
+        .unwrap_or_else(|err| panic!("cannot load image: {err}"));
+    vm.set_io(Box::new(StreamIo::new(
+        io::Cursor::new(input.to_vec()),
+        output.clone(),
+    )));
+    vm.set_instruction_limit(Some(max_instructions));
+    let error = vm.run().err();
+
+    let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+    MachineState {
+        registers: *vm.registers(),
+        pc: vm.pc(),
+        psr: vm.psr(),
+        memory: vm.memory().to_vec(),
+        output,
+        instructions: vm.instructions(),
+        halted: vm.halted(),
+        error,
+    }
+}
+
+/// Assembles `source` and runs it with [`run_program`]. Panics if it doesn't assemble.
+pub fn run_asm(source: &str, input: &[u8]) -> MachineState {
+    let program = asm::assemble(source).unwrap_or_else(|err| panic!("cannot assemble: {err}"));
+    run_program(&program.image(), input)
+}
+
+impl MachineState {
+    #[track_caller]
+    pub fn assert_halted(&self) {
+        if let Some(err) = &self.error {
+            panic!("program did not halt: {err}");
+        }
+        assert!(self.halted, "program did not halt, PC is x{:04X}", self.pc);
+    }
+
+    /// Asserts that register `r` (0-7) holds `expected`.
+    #[track_caller]
+    pub fn assert_reg(&self, r: usize, expected: u16) {
+        let actual = self.registers[r];
+        assert!(
+            actual == expected,
+            "R{r} is x{actual:04X}, expected x{expected:04X}"
+        );
+    }
+
+    /// Asserts that memory from `start` on holds `expected`.
+    #[track_caller]
+    pub fn assert_mem_range(&self, start: u16, expected: &[u16]) {
+        for (i, &expected) in expected.iter().enumerate() {
+            let addr = start.wrapping_add(i as u16);
+            match self.memory.get(addr as usize) {
+                Some(&actual) => assert!(
+                    actual == expected,
+                    "x{addr:04X} is x{actual:04X}, expected x{expected:04X}"
+                ),
+                None => panic!("address x{addr:04X} out of range"),
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn assert_output_contains(&self, expected: &str) {
+        assert!(
+            self.output.contains(expected),
+            "output does not contain {expected:?}: {:?}",
+            self.output
+        );
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FixtureFile {
+    #[serde(rename = "fixture", default)]
+    fixtures: Vec<Fixture>,
+}
+
+/// One instruction and the state before and after it, see the [module docs](self).
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    pub name: String,
+    inst: Word,
+    pc: Option<Word>,
+    psr: Option<Word>,
+    #[serde(default)]
+    reg: BTreeMap<String, Word>,
+    #[serde(default)]
+    mem: BTreeMap<String, Word>,
+    #[serde(default)]
+    input: String,
+    #[serde(default)]
+    expect: Expected,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expected {
+    pc: Option<Word>,
+    psr: Option<Word>,
+    cc: Option<String>,
+    #[serde(default)]
+    reg: BTreeMap<String, Word>,
+    #[serde(default)]
+    mem: BTreeMap<String, Word>,
+    output: Option<String>,
+    #[serde(default)]
+    halted: bool,
+    /// Text the error stopping the instruction contains.
+    error: Option<String>,
+}
+
+/// Parses the `[[fixture]]` tables of a TOML document.
+pub fn parse_fixtures(text: &str) -> Result<Vec<Fixture>> {
+    let file: FixtureFile = toml::from_str(text)?;
+    Ok(file.fixtures)
+}
+
+fn registers(map: &BTreeMap<String, Word>) -> Result<Vec<(usize, u16)>> {
+    map.iter()
+        .map(|(reg, val)| {
+            let r = util::parse_register(reg).ok_or_else(|| anyhow!("bad register: {reg}"))?;
+            Ok((r, val.0))
+        })
+        .collect()
+}
+
+fn addresses(map: &BTreeMap<String, Word>) -> Result<Vec<(u16, u16)>> {
+    map.iter()
+        .map(|(addr, val)| match parse_literal(addr) {
+            Some(n @ 0..=0xFFFF) => Ok((n as u16, val.0)),
+            _ => Err(anyhow!("bad address: {addr}")),
+        })
+        .collect()
+}
+
+fn cc_bits(cc: &str) -> Result<u16> {
+    Ok(match cc {
+        "n" => Flag::Neg as u16,
+        "z" => Flag::Zero as u16,
+        "p" => Flag::Pos as u16,
+        _ => bail!("bad condition code: {cc}, expected n, z or p"),
+    })
+}
+
+impl Fixture {
+    /// Executes the instruction and returns how the state differs from the expected one,
+    /// empty if it matches. Errors are for fixtures that don't make sense.
+    pub fn run(&self) -> Result<Vec<String>> {
+        let pc = self.pc.map_or(0x3000, |pc| pc.0);
+        let output = SharedBuf::default();
+
+        let mut vm = Vm::new(pc, self.psr.map_or(Flag::Zero as u16, |psr| psr.0));
+        vm.set_io(Box::new(StreamIo::new(
+            io::Cursor::new(self.input.clone().into_bytes()),
+            output.clone(),
+        )));
+        for (r, val) in registers(&self.reg)? {
+            vm.set_register(r, val);
+        }
+        for (addr, val) in addresses(&self.mem)? {
+            if !vm.set_memory(addr, val) {
+                bail!("x{addr:04X} is out of range");
+            }
+        }
+        if !vm.set_memory(pc, self.inst.0) {
+            bail!("x{pc:04X} is out of range");
+        }
+
+        let mut expected_reg = *vm.registers();
+        for (r, val) in registers(&self.expect.reg)? {
+            expected_reg[r] = val;
+        }
+        let mut expected_mem = vm.memory().to_vec();
+        for (addr, val) in addresses(&self.expect.mem)? {
+            match expected_mem.get_mut(addr as usize) {
+                Some(word) => *word = val,
+                None => bail!("x{addr:04X} is out of range"),
+            }
+        }
+        let expected_psr = match (self.expect.psr, &self.expect.cc) {
+            (Some(psr), _) => psr.0,
+            (None, Some(cc)) => vm.psr() & !0b111 | cc_bits(cc)?,
+            (None, None) => vm.psr(),
+        };
+        let expected_pc = self.expect.pc.map_or(pc.wrapping_add(1), |pc| pc.0);
+
+        let mut diffs = Vec::new();
+        let result = vm.step();
+        match (&result, &self.expect.error) {
+            (Err(err), Some(expected)) if err.to_string().contains(expected.as_str()) => {
+                // a failed instruction leaves whatever state it got to
+                return Ok(diffs);
+            }
+            (Err(err), _) => diffs.push(format!("failed: {err}")),
+            (Ok(_), Some(expected)) => diffs.push(format!("didn't fail with {expected:?}")),
+            (Ok(_), None) => (),
+        }
+
+        for (r, (&actual, expected)) in vm.registers().iter().zip(expected_reg).enumerate() {
+            if actual != expected {
+                diffs.push(format!("R{r} is x{actual:04X}, expected x{expected:04X}"));
+            }
+        }
+        if vm.pc() != expected_pc {
+            diffs.push(format!(
+                "PC is x{:04X}, expected x{expected_pc:04X}",
+                vm.pc()
+            ));
+        }
+        if vm.psr() != expected_psr {
+            diffs.push(format!(
+                "PSR is x{:04X}, expected x{expected_psr:04X}",
+                vm.psr()
+            ));
+        }
+        for (addr, (&actual, &expected)) in vm.memory().iter().zip(&expected_mem).enumerate() {
+            if actual != expected {
+                diffs.push(format!(
+                    "x{addr:04X} is x{actual:04X}, expected x{expected:04X}"
+                ));
+            }
+        }
+        let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+        if output != self.expect.output.as_deref().unwrap_or("") {
+            diffs.push(format!("printed {output:?}"));
+        }
+        if vm.halted() != self.expect.halted {
+            diffs.push(format!("halted is {}", vm.halted()));
+        }
+
+        Ok(diffs)
+    }
+}
+
+/// Runs every fixture in `text`, panicking with the differences of those that fail.
+#[track_caller]
+pub fn assert_fixtures(text: &str) {
+    let fixtures = parse_fixtures(text).unwrap_or_else(|err| panic!("bad fixtures: {err}"));
+
+    let mut failures = String::new();
+    for fixture in &fixtures {
+        match fixture.run() {
+            Ok(diffs) if diffs.is_empty() => (),
+            Ok(diffs) => failures += &format!("{}: {}\n", fixture.name, diffs.join(", ")),
+            Err(err) => failures += &format!("{}: {err}\n", fixture.name),
+        }
+    }
+
+    assert!(failures.is_empty(), "fixtures failed:\n{failures}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_asm() {
+        let state = run_asm(
+            r#"
+                    .ORIG x3000
+                    LEA R0, MSG
+                    PUTS
+                    AND R1, R1, #0
+                    ADD R1, R1, #3
+                    ST R1, RESULT
+                    HALT
+            MSG     .STRINGZ "sum"
+            RESULT  .BLKW 1
+                    .END
+            "#,
+            b"",
+        );
+        state.assert_halted();
+        state.assert_reg(1, 3);
+        state.assert_mem_range(0x300A, &[3]);
+        state.assert_output_contains("sum");
+
+        // BR #-1
+        let state = run_program_with_limit(&[0x30, 0x00, 0x0F, 0xFF], b"", 10);
+        assert!(!state.halted);
+        assert!(matches!(state.error, Some(VmError::InstructionLimit(10))));
+    }
+
+    #[test]
+    fn test_opcode_fixtures() {
+        assert_fixtures(include_str!("../fixtures/opcodes.toml"));
+    }
+}
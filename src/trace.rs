@@ -0,0 +1,334 @@
+//! Compact binary instruction traces.
+//!
+//! A trace starts with the magic `LC3T` and a version byte, followed by one record per
+//! executed instruction. Records only store what changed since the previous one:
+//!
+//! ```text
+//! u8   register mask, bit n set if Rn changed
+//! u8   flags: PC_JUMP | PSR_CHANGED | MEM_WRITE
+//! u16  instruction word
+//! u16  PC, if PC_JUMP (the instruction is not the one after the previous one)
+//! u16  new value of each changed register, lowest register first
+//! u16  PSR, if PSR_CHANGED
+//! u16  address, u16 value, if MEM_WRITE
+//! ```
+//!
+//! All words are little-endian. The first record always carries its PC, and registers
+//! and PSR start out as zero on both ends.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::util::{parse_literal, parse_register};
+
+pub(crate) const MAGIC: &[u8; 4] = b"LC3T";
+const VERSION: u8 = 1;
+
+const PC_JUMP: u8 = 1 << 0;
+const PSR_CHANGED: u8 = 1 << 1;
+const MEM_WRITE: u8 = 1 << 2;
+
+/// Machine state after one executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Address of the executed instruction.
+    pub pc: u16,
+    pub inst: u16,
+    pub reg: [u16; 8],
+    pub psr: u16,
+    /// Memory write done by the instruction, as (address, value).
+    pub write: Option<(u16, u16)>,
+}
+
+impl std::fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pc={:#06x} inst={:#06x}", self.pc, self.inst)?;
+        for (r, val) in self.reg.iter().enumerate() {
+            write!(f, " r{r}={val:#06x}")?;
+        }
+        write!(f, " psr={:#06x}", self.psr)?;
+        if let Some((addr, val)) = self.write {
+            write!(f, " mem[{addr:#06x}]={val:#06x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TraceRecord {
+    /// Parses a record written with [`Display`](std::fmt::Display), as `trace-dump`
+    /// prints them. Only the memory write may be left out.
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut pc = None;
+        let mut inst = None;
+        let mut reg = [None; 8];
+        let mut psr = None;
+        let mut write = None;
+
+        for field in line.split_whitespace() {
+            let (key, val) = field
+                .split_once('=')
+                .ok_or_else(|| anyhow!("bad trace field: {field}"))?;
+            let word = |s: &str| {
+                parse_literal(s)
+                    .and_then(|val| u16::try_from(val).ok())
+                    .ok_or_else(|| anyhow!("bad trace field: {field}"))
+            };
+            let val = word(val)?;
+
+            match key {
+                "pc" => pc = Some(val),
+                "inst" => inst = Some(val),
+                "psr" => psr = Some(val),
+                _ => {
+                    if let Some(addr) = key.strip_prefix("mem[").and_then(|k| k.strip_suffix(']')) {
+                        write = Some((word(addr)?, val));
+                    } else if let Some(r) = parse_register(key) {
+                        reg[r] = Some(val);
+                    } else {
+                        bail!("bad trace field: {field}");
+                    }
+                }
+            }
+        }
+
+        let missing = |name: &str| anyhow!("trace record without {name}: {line}");
+        let mut regs = [0; 8];
+        for (r, val) in reg.into_iter().enumerate() {
+            regs[r] = val.ok_or_else(|| missing(&format!("r{r}")))?;
+        }
+        Ok(Self {
+            pc: pc.ok_or_else(|| missing("pc"))?,
+            inst: inst.ok_or_else(|| missing("inst"))?,
+            reg: regs,
+            psr: psr.ok_or_else(|| missing("psr"))?,
+            write,
+        })
+    }
+}
+
+pub struct TraceWriter {
+    out: Box<dyn Write>,
+    next_pc: Option<u16>,
+    reg: [u16; 8],
+    psr: u16,
+}
+
+impl TraceWriter {
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+
+        Ok(Self {
+            out,
+            next_pc: None,
+            reg: Default::default(),
+            psr: 0,
+        })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut mask = 0u8;
+        for (r, (old, new)) in self.reg.iter().zip(&record.reg).enumerate() {
+            if old != new {
+                mask |= 1 << r;
+            }
+        }
+
+        let mut flags = 0u8;
+        if self.next_pc != Some(record.pc) {
+            flags |= PC_JUMP;
+        }
+        if self.psr != record.psr {
+            flags |= PSR_CHANGED;
+        }
+        if record.write.is_some() {
+            flags |= MEM_WRITE;
+        }
+
+        let mut buf = Vec::with_capacity(24);
+        buf.extend([mask, flags]);
+        buf.extend(record.inst.to_le_bytes());
+        if flags & PC_JUMP != 0 {
+            buf.extend(record.pc.to_le_bytes());
+        }
+        for (r, val) in record.reg.iter().enumerate() {
+            if mask & (1 << r) != 0 {
+                buf.extend(val.to_le_bytes());
+            }
+        }
+        if flags & PSR_CHANGED != 0 {
+            buf.extend(record.psr.to_le_bytes());
+        }
+        if let Some((addr, val)) = record.write {
+            buf.extend(addr.to_le_bytes());
+            buf.extend(val.to_le_bytes());
+        }
+
+        self.out.write_all(&buf)?;
+
+        self.next_pc = Some(record.pc.wrapping_add(1));
+        self.reg = record.reg;
+        self.psr = record.psr;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Expands a binary trace back into full [`TraceRecord`]s.
+pub struct TraceReader<R> {
+    input: R,
+    next_pc: u16,
+    reg: [u16; 8],
+    psr: u16,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header)?;
+
+        if &header[..4] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a trace file",
+            ));
+        }
+        if header[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported trace version {}", header[4]),
+            ));
+        }
+
+        Ok(Self {
+            input,
+            next_pc: 0,
+            reg: Default::default(),
+            psr: 0,
+        })
+    }
+
+    fn read_word(&mut self) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        self.input.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut head = [0u8; 2];
+        // a clean end of file is only between records, a partial one is an error
+        loop {
+            match self.input.read(&mut head[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        self.input.read_exact(&mut head[1..])?;
+        let [mask, flags] = head;
+
+        let inst = self.read_word()?;
+        let pc = if flags & PC_JUMP != 0 {
+            self.read_word()?
+        } else {
+            self.next_pc
+        };
+        for r in 0..8 {
+            if mask & (1 << r) != 0 {
+                self.reg[r] = self.read_word()?;
+            }
+        }
+        if flags & PSR_CHANGED != 0 {
+            self.psr = self.read_word()?;
+        }
+        let write = if flags & MEM_WRITE != 0 {
+            Some((self.read_word()?, self.read_word()?))
+        } else {
+            None
+        };
+
+        self.next_pc = pc.wrapping_add(1);
+
+        Ok(Some(TraceRecord {
+            pc,
+            inst,
+            reg: self.reg,
+            psr: self.psr,
+            write,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::SharedBuf;
+
+    #[test]
+    fn test_roundtrip() {
+        let records = vec![
+            TraceRecord {
+                pc: 0x3000,
+                inst: 0x1021,
+                reg: [1, 0, 0, 0, 0, 0, 0, 0],
+                psr: 1,
+                write: None,
+            },
+            TraceRecord {
+                pc: 0x3001,
+                inst: 0x3001,
+                reg: [1, 0, 0, 0, 0, 0, 0, 0],
+                psr: 1,
+                write: Some((0x3003, 1)),
+            },
+            TraceRecord {
+                pc: 0x2000,
+                inst: 0x5020,
+                reg: [0, 0, 0, 0, 0, 0, 0, 0x3003],
+                psr: 2,
+                write: None,
+            },
+        ];
+
+        let buf = SharedBuf::default();
+        let mut writer = TraceWriter::new(Box::new(buf.clone())).unwrap();
+        for record in &records {
+            writer.record(record).unwrap();
+        }
+
+        let bytes = buf.0.borrow().clone();
+        // header, then 2+2+2+2+2, 2+2+4 and 2+2+2+2+2+2 bytes of records
+        assert_eq!(bytes.len(), 5 + 10 + 8 + 12);
+
+        let read: Vec<_> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+        for len in [bytes.len() - 1, bytes.len() - 11] {
+            let read: io::Result<Vec<_>> = TraceReader::new(&bytes[..len]).unwrap().collect();
+            let err = read.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{len}");
+        }
+
+        for record in &records {
+            assert_eq!(&TraceRecord::parse(&record.to_string()).unwrap(), record);
+        }
+        assert!(TraceRecord::parse("pc=0x3000 inst=0x1021").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! Checks a hand-written trace against what the program actually does.
+//!
+//! The expected trace is a CSV file with a header naming its columns, and one row per
+//! executed instruction giving the state after it ran:
+//!
+//! ```text
+//! PC,    R0,    R1, CC
+//! x3000, x0005,   , P
+//! x3001,      , #-1, N
+//! ```
+//!
+//! Columns are `PC` (the address of the executed instruction), `R0`-`R7`, `PSR` and
+//! `CC` (one of N, Z, P). Empty cells are not checked.
+
+use std::io::{self, Cursor};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    console::StreamIo,
+    trace::{TraceReader, TraceRecord, TraceWriter},
+    util::{parse_literal, SharedBuf},
+    vm::{Flag, Vm},
+};
+
+const MAX_INSTRUCTIONS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Pc,
+    Reg(usize),
+    Psr,
+    Cc,
+}
+
+impl Column {
+    fn parse(name: &str) -> Result<Self> {
+        let upper = name.trim().to_ascii_uppercase();
+
+        Ok(match upper.as_str() {
+            "PC" => Column::Pc,
+            "PSR" => Column::Psr,
+            "CC" => Column::Cc,
+            reg if reg.len() == 2
+                && reg.starts_with('R')
+                && matches!(reg.as_bytes()[1], b'0'..=b'7') =>
+            {
+                Column::Reg((reg.as_bytes()[1] - b'0') as usize)
+            }
+            _ => bail!("unknown column: {name}"),
+        })
+    }
+
+    /// The column's value in `record`.
+    fn value(&self, record: &TraceRecord) -> u16 {
+        match self {
+            Column::Pc => record.pc,
+            Column::Reg(r) => record.reg[*r],
+            Column::Psr => record.psr,
+            Column::Cc => record.psr & 0b111,
+        }
+    }
+
+    fn format(&self, val: u16) -> String {
+        match self {
+            Column::Cc => match val {
+                v if v == Flag::Neg as u16 => "N".into(),
+                v if v == Flag::Zero as u16 => "Z".into(),
+                v if v == Flag::Pos as u16 => "P".into(),
+                _ => format!("{val:03b}"),
+            },
+            _ => format!("x{val:04X}"),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Column::Pc => "PC".into(),
+            Column::Reg(r) => format!("R{r}"),
+            Column::Psr => "PSR".into(),
+            Column::Cc => "CC".into(),
+        }
+    }
+}
+
+/// One row of the expected trace, with `None` for cells left empty.
+struct Row {
+    line: usize,
+    values: Vec<Option<u16>>,
+}
+
+pub struct ExpectedTrace {
+    columns: Vec<Column>,
+    rows: Vec<Row>,
+}
+
+impl ExpectedTrace {
+    pub fn parse(csv: &str) -> Result<Self> {
+        let mut lines = csv
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        let Some((_, header)) = lines.next() else {
+            bail!("expected trace is empty");
+        };
+        let columns = header
+            .split(',')
+            .map(Column::parse)
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut rows = Vec::new();
+        for (line, text) in lines {
+            let cells: Vec<_> = text.split(',').map(str::trim).collect();
+            if cells.len() > columns.len() {
+                bail!(
+                    "line {line}: {} cells but only {} columns",
+                    cells.len(),
+                    columns.len()
+                );
+            }
+
+            let values = columns
+                .iter()
+                .zip(cells.iter().chain(std::iter::repeat(&"")))
+                .map(|(column, cell)| parse_cell(*column, cell))
+                .collect::<Option<Vec<_>>>();
+            match values {
+                Some(values) => rows.push(Row { line, values }),
+                None => bail!("line {line}: bad value in `{text}`"),
+            }
+        }
+
+        Ok(Self { columns, rows })
+    }
+}
+
+/// Parses a cell into `Some(value)`, `Some(None)` for an empty cell, or `None` if invalid.
+fn parse_cell(column: Column, cell: &str) -> Option<Option<u16>> {
+    if cell.is_empty() {
+        return Some(None);
+    }
+
+    let val = match (column, cell.to_ascii_uppercase().as_str()) {
+        (Column::Cc, "N") => Flag::Neg as i64,
+        (Column::Cc, "Z") => Flag::Zero as i64,
+        (Column::Cc, "P") => Flag::Pos as i64,
+        (Column::Cc, _) => return None,
+        _ => parse_literal(cell)?,
+    };
+
+    (-0x8000..=0xFFFF)
+        .contains(&val)
+        .then_some(Some(val as u16))
+}
+
+/// Runs `image` with `input` as keyboard input and compares its trace to `expected`.
+/// Returns a description of the first mismatch, or `None` if every row matched.
+pub fn check(image: &[u8], input: Vec<u8>, expected: &ExpectedTrace) -> Result<Option<String>> {
+    let trace = SharedBuf::default();
+
+    let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+    vm.load_image_bytes(image)?;
+    vm.set_io(Box::new(StreamIo::new(Cursor::new(input), io::sink())));
+    vm.set_instruction_limit(Some(MAX_INSTRUCTIONS.max(expected.rows.len() as u64)));
+    vm.set_trace(TraceWriter::new(Box::new(trace.clone()))?);
+
+    // the rows only cover the start of the run, so stopping early is fine
+    let run_error = vm.run().err();
+
+    let bytes = trace.0.borrow();
+    let mut actual = TraceReader::new(&bytes[..])?;
+
+    for (step, row) in expected.rows.iter().enumerate() {
+        let Some(record) = actual.next().transpose()? else {
+            let reason = match &run_error {
+                Some(err) => format!("stopped: {err}"),
+                None => "halted".into(),
+            };
+            return Ok(Some(format!(
+                "line {}: the program {reason} after {step} instructions",
+                row.line
+            )));
+        };
+
+        for (column, expected) in expected.columns.iter().zip(&row.values) {
+            let Some(expected) = *expected else {
+                continue;
+            };
+
+            let actual = column.value(&record);
+            if actual != expected {
+                return Ok(Some(format!(
+                    "line {}: instruction {} (x{:04X} at x{:04X}): {} is {}, expected {}",
+                    row.line,
+                    step + 1,
+                    record.inst,
+                    record.pc,
+                    column.name(),
+                    column.format(actual),
+                    column.format(expected),
+                )));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        // AND R0, R0, #0; ADD R0, R0, #5; ADD R1, R0, #-6; HALT
+        let image = [0x30, 0x00, 0x50, 0x20, 0x10, 0x25, 0x12, 0x3A, 0xF0, 0x25];
+
+        let expected = ExpectedTrace::parse(
+            "PC, R0, R1, CC\n\
+             x3000, 0, , Z\n\
+             x3001, #5, , P\n\
+             # comments and blank lines are skipped\n\
+             \n\
+             x3002, , xFFFF, N\n",
+        )
+        .unwrap();
+        assert_eq!(check(&image, Vec::new(), &expected).unwrap(), None);
+
+        let expected = ExpectedTrace::parse("PC,R1\nx3000\nx3001\nx3002,#-2\n").unwrap();
+        let mismatch = check(&image, Vec::new(), &expected).unwrap().unwrap();
+        assert!(mismatch.starts_with("line 4: instruction 3"), "{mismatch}");
+        assert!(
+            mismatch.ends_with("R1 is xFFFF, expected xFFFE"),
+            "{mismatch}"
+        );
+
+        assert!(ExpectedTrace::parse("PC,R9\n").is_err());
+        assert!(ExpectedTrace::parse("CC\nQ\n").is_err());
+    }
+}
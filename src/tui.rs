@@ -0,0 +1,379 @@
+//! Full screen front-end, entered with `--tui`: registers, disassembly around the PC,
+//! memory and the program's console in separate panes. Only built with the `tui`
+//! feature.
+
+use std::{cell::RefCell, collections::VecDeque, io, rc::Rc, time::Duration};
+
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::Stylize,
+    text::Line,
+    widgets::{Block, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    console::IoDevice,
+    disasm::disassemble,
+    util::SharedBuf,
+    vm::{RunResult, Vm},
+};
+
+const HELP: &str = "s step  c continue  b breakpoint  Esc pause  PgUp/PgDn memory  q quit";
+
+// instructions run between redraws while the program runs
+const BATCH: u32 = 10_000;
+
+const MEMORY_ROWS: u16 = 8;
+
+// TRAP GETC, IN, GETS and GETD wait for a key
+const INPUT_TRAPS: [u16; 4] = [0xF020, 0xF023, 0xF027, 0xF029];
+
+/// Keys typed while the program runs are queued for it, and its output is collected for
+/// the console pane.
+#[derive(Clone, Default)]
+struct TuiIo {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: SharedBuf,
+}
+
+impl IoDevice for TuiIo {
+    fn key_ready(&mut self) -> bool {
+        !self.input.borrow().is_empty()
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        loop {
+            if let Some(byte) = self.input.borrow_mut().pop_front() {
+                return Some(byte);
+            }
+
+            // the traps block until a key arrives, Ctrl-C ends the input instead
+            match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    if key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)
+                    {
+                        return None;
+                    }
+                    self.input.borrow_mut().extend(key_bytes(key));
+                }
+                Ok(_) => (),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.0.borrow_mut().extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// The bytes a key sends to the program.
+fn key_bytes(key: KeyEvent) -> Vec<u8> {
+    match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            vec![(c as u8) & 0x1F]
+        }
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+        KeyCode::Enter => vec![b'\n'],
+        KeyCode::Backspace => vec![0x7F],
+        KeyCode::Tab => vec![b'\t'],
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Paused,
+    Running,
+    /// Halted or stopped with an error, with the message to show.
+    Finished(String),
+}
+
+struct App<'a> {
+    vm: &'a mut Vm,
+    io: TuiIo,
+    state: State,
+    status: String,
+    memory_addr: u16,
+}
+
+/// Runs the front-end until the user quits.
+pub fn run(vm: &mut Vm) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = App::new(vm).run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl<'a> App<'a> {
+    fn new(vm: &'a mut Vm) -> Self {
+        let io = TuiIo::default();
+        vm.set_io(Box::new(io.clone()));
+        let memory_addr = vm.pc() & !0x7;
+
+        Self {
+            vm,
+            io,
+            state: State::Paused,
+            status: "Paused".to_string(),
+            memory_addr,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            if self.state == State::Running && self.waiting_for_input() {
+                self.status = "Waiting for input".to_string();
+                terminal.draw(|frame| self.draw(frame))?;
+                self.execute(1);
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if self.state == State::Running {
+                self.execute(BATCH);
+                while event::poll(Duration::ZERO)? {
+                    if !self.handle_event(event::read()?) {
+                        return Ok(());
+                    }
+                }
+            } else if !self.handle_event(event::read()?) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn waiting_for_input(&self) -> bool {
+        let inst = self.vm.memory().get(self.vm.pc() as usize).copied();
+        self.io.input.borrow().is_empty() && inst.is_some_and(|inst| INPUT_TRAPS.contains(&inst))
+    }
+
+    /// Runs up to `count` instructions, stopping at breakpoints, watchpoints and before
+    /// traps that would wait for input.
+    fn execute(&mut self, count: u32) {
+        for i in 0..count {
+            if i > 0 && self.waiting_for_input() {
+                return;
+            }
+
+            match self.vm.step() {
+                Ok(RunResult::Halted) => {
+                    self.state = State::Finished("Halted".to_string());
+                    return;
+                }
+                Ok(RunResult::Watchpoint { addr, old, new }) => {
+                    self.pause(format!("Watchpoint x{addr:04X}: x{old:04X} -> x{new:04X}"));
+                    return;
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    self.state = State::Finished(err.to_string());
+                    return;
+                }
+            }
+
+            if self.state == State::Running && self.vm.breakpoints().any(|bp| bp == self.vm.pc()) {
+                self.pause(format!("Breakpoint at x{:04X}", self.vm.pc()));
+                return;
+            }
+        }
+    }
+
+    fn pause(&mut self, status: String) {
+        self.state = State::Paused;
+        self.status = status;
+    }
+
+    /// Returns `false` to quit.
+    fn handle_event(&mut self, event: Event) -> bool {
+        let Event::Key(key) = event else {
+            return true;
+        };
+        if key.kind != KeyEventKind::Press {
+            return true;
+        }
+
+        if self.state == State::Running {
+            if key.code == KeyCode::Esc {
+                self.pause("Paused".to_string());
+            } else {
+                self.io.input.borrow_mut().extend(key_bytes(key));
+            }
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => return false,
+            KeyCode::PageUp => {
+                self.memory_addr = self.memory_addr.saturating_sub(8 * MEMORY_ROWS);
+            }
+            KeyCode::PageDown => {
+                self.memory_addr = self.memory_addr.saturating_add(8 * MEMORY_ROWS);
+            }
+            _ if matches!(self.state, State::Finished(_)) => (),
+            KeyCode::Char('s') => {
+                self.status = "Paused".to_string();
+                self.execute(1);
+            }
+            KeyCode::Char('c') => {
+                self.state = State::Running;
+                self.status = "Running".to_string();
+            }
+            KeyCode::Char('b') => {
+                let pc = self.vm.pc();
+                if self.vm.remove_breakpoint(pc) {
+                    self.status = format!("Removed breakpoint at x{pc:04X}");
+                } else {
+                    self.vm.add_breakpoint(pc);
+                    self.status = format!("Breakpoint at x{pc:04X}");
+                }
+            }
+            _ => (),
+        }
+
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [top, memory, console, status] = Layout::vertical([
+            Constraint::Length(12),
+            Constraint::Length(MEMORY_ROWS + 2),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [registers, disassembly] =
+            Layout::horizontal([Constraint::Length(24), Constraint::Min(20)]).areas(top);
+
+        frame.render_widget(self.registers(), registers);
+        frame.render_widget(self.disassembly(disassembly), disassembly);
+        frame.render_widget(self.memory(), memory);
+        frame.render_widget(self.console(console), console);
+
+        let status_text = match &self.state {
+            State::Finished(message) => message.as_str(),
+            _ => self.status.as_str(),
+        };
+        let status_line = Line::from(vec![
+            format!(" {status_text} ").reversed(),
+            format!("  {HELP}").into(),
+        ]);
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn registers(&self) -> Paragraph<'_> {
+        let mut lines: Vec<Line> = self
+            .vm
+            .registers()
+            .iter()
+            .enumerate()
+            .map(|(r, &val)| format!("R{r}  x{val:04X}  {}", val as i16).into())
+            .collect();
+
+        let psr = self.vm.psr();
+        let cc = match psr & 0b111 {
+            0b100 => "N",
+            0b010 => "Z",
+            0b001 => "P",
+            _ => "?",
+        };
+        lines.push(format!("PC  x{:04X}", self.vm.pc()).into());
+        lines.push(format!("PSR x{psr:04X}  CC {cc}").into());
+
+        Paragraph::new(lines).block(Block::bordered().title("Registers"))
+    }
+
+    fn disassembly(&self, area: Rect) -> Paragraph<'_> {
+        let memory = self.vm.memory();
+        let pc = self.vm.pc();
+        let start = pc.saturating_sub(3) as usize;
+        let rows = area.height.saturating_sub(2) as usize;
+
+        let lines: Vec<Line> = (start..memory.len())
+            .take(rows)
+            .map(|addr| {
+                let inst = memory[addr];
+                let addr = addr as u16;
+                let breakpoint = self.vm.breakpoints().any(|bp| bp == addr);
+                let marker = match (addr == pc, breakpoint) {
+                    (true, _) => "=>",
+                    (false, true) => " *",
+                    (false, false) => "  ",
+                };
+                let line = format!(
+                    "{marker} x{addr:04X}: x{inst:04X}  {}",
+                    disassemble(inst, addr)
+                );
+                if addr == pc {
+                    line.bold().into()
+                } else {
+                    line.into()
+                }
+            })
+            .collect();
+
+        Paragraph::new(lines).block(Block::bordered().title("Disassembly"))
+    }
+
+    fn memory(&self) -> Paragraph<'_> {
+        let end = self
+            .memory_addr
+            .saturating_add(8 * MEMORY_ROWS - 1)
+            .min(self.vm.memory().len() as u16 - 1);
+        let dump = self.vm.dump_memory(self.memory_addr..=end).to_string();
+
+        Paragraph::new(dump).block(Block::bordered().title("Memory"))
+    }
+
+    fn console(&self, area: Rect) -> Paragraph<'_> {
+        let output = self.io.output.0.borrow();
+        let output = String::from_utf8_lossy(&output);
+        let lines: Vec<_> = output.lines().map(str::to_string).collect();
+        // follow the end of the output
+        let rows = area.height.saturating_sub(2) as usize;
+        let shown = lines[lines.len().saturating_sub(rows)..].join("\n");
+
+        Paragraph::new(shown).block(Block::bordered().title("Console"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use super::*;
+    use crate::vm::Flag;
+
+    #[test]
+    fn test_tui() {
+        // ADD R0, R0, #5; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[0x30, 0x00, 0x10, 0x25, 0xF0, 0x25])
+            .unwrap();
+        let mut app = App::new(&mut vm);
+
+        app.handle_event(Event::Key(KeyCode::Char('s').into()));
+        app.handle_event(Event::Key(KeyCode::Char('c').into()));
+        app.execute(BATCH);
+        assert_eq!(app.state, State::Finished("Halted".to_string()));
+        assert_eq!(&app.io.output.0.borrow()[..], b"HALT\n");
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("R0  x0005  5"));
+        assert!(screen.contains("=> x3002"));
+        assert!(screen.contains(" Halted "));
+    }
+}
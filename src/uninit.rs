@@ -0,0 +1,70 @@
+//! Which registers and memory words have been given a value, for warning about reads of
+//! ones that never were, see [`crate::vm::Vm::enable_uninit_detection`].
+
+use std::{collections::BTreeSet, ops::Range};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Initialized {
+    // one bit per memory word
+    memory: Vec<u64>,
+    registers: u8,
+    // (pc, register) and (pc, address) pairs already warned about, so a loop warns once
+    reported_registers: BTreeSet<(u16, usize)>,
+    reported_memory: BTreeSet<(u16, u16)>,
+}
+
+impl Initialized {
+    /// Nothing initialized, for memory of `len` words.
+    pub fn new(len: usize) -> Self {
+        Self {
+            memory: vec![0; len.div_ceil(64)],
+            registers: 0,
+            reported_registers: BTreeSet::new(),
+            reported_memory: BTreeSet::new(),
+        }
+    }
+
+    pub fn memory(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        self.memory
+            .get(addr / 64)
+            .is_some_and(|bits| bits & 1 << (addr % 64) != 0)
+    }
+
+    pub fn set_memory(&mut self, addr: u16) {
+        let addr = addr as usize;
+        if let Some(bits) = self.memory.get_mut(addr / 64) {
+            *bits |= 1 << (addr % 64);
+        }
+    }
+
+    pub fn set_memory_range(&mut self, range: Range<usize>) {
+        for addr in range {
+            self.set_memory(addr as u16);
+        }
+    }
+
+    pub fn register(&self, r: usize) -> bool {
+        self.registers & 1 << r != 0
+    }
+
+    pub fn set_register(&mut self, r: usize) {
+        self.registers |= 1 << r;
+    }
+
+    /// Marks everything initialized, e.g. after restoring a snapshot.
+    pub fn set_all(&mut self) {
+        self.memory.fill(u64::MAX);
+        self.registers = 0xFF;
+    }
+
+    /// Whether the read of register `r` at `pc` hasn't been reported yet, remembering it.
+    pub fn first_register_report(&mut self, pc: u16, r: usize) -> bool {
+        self.reported_registers.insert((pc, r))
+    }
+
+    /// Whether the read of `addr` at `pc` hasn't been reported yet, remembering it.
+    pub fn first_memory_report(&mut self, pc: u16, addr: u16) -> bool {
+        self.reported_memory.insert((pc, addr))
+    }
+}
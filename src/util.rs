@@ -0,0 +1,95 @@
+//! Small helpers shared by the subcommands.
+
+use std::{cell::RefCell, io, rc::Rc};
+
+use serde::Deserialize;
+
+/// Parses `x3000`, `0x3000`, `#-5` and plain decimal numbers.
+pub fn parse_literal(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let hex = s
+        .strip_prefix(['x', 'X'])
+        .or_else(|| s.strip_prefix("0x"))
+        .or_else(|| s.strip_prefix("0X"));
+
+    if let Some(hex) = hex {
+        i64::from_str_radix(hex, 16).ok()
+    } else {
+        s.strip_prefix('#').unwrap_or(s).parse().ok()
+    }
+}
+
+/// Parses `R0`-`R7`, or `r0`-`r7`, into the register number.
+pub fn parse_register(reg: &str) -> Option<usize> {
+    match reg.strip_prefix(['R', 'r']).map(str::parse) {
+        Some(Ok(r @ 0..=7)) => Some(r),
+        _ => None,
+    }
+}
+
+/// A 16-bit value in a TOML file, written as an integer or as an LC-3 literal string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "WordRepr")]
+pub struct Word(pub u16);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WordRepr {
+    Int(i64),
+    Str(String),
+}
+
+impl TryFrom<WordRepr> for Word {
+    type Error = String;
+
+    fn try_from(repr: WordRepr) -> Result<Self, Self::Error> {
+        let n = match repr {
+            WordRepr::Int(n) => n,
+            WordRepr::Str(s) => parse_literal(&s).ok_or_else(|| format!("bad number: {s}"))?,
+        };
+
+        if !(-0x8000..=0xFFFF).contains(&n) {
+            return Err(format!("{n} does not fit in 16 bits"));
+        }
+
+        Ok(Word(n as u16))
+    }
+}
+
+/// 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// `s` as a quoted JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A writer whose contents can still be read after it has been boxed and handed to a vm.
+#[derive(Clone, Default)]
+pub struct SharedBuf(pub Rc<RefCell<Vec<u8>>>);
+
+impl io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! The video display of PennSim and lc3tools: a 128x124 framebuffer at xC000-xFDFF, one
+//! word per pixel in RGB555 (red in bits 14-10, green in 9-5, blue in 4-0), row by row.
+//!
+//! The framebuffer is ordinary memory, so the program draws with plain stores and an
+//! embedder reads it with [`framebuffer`]. [`install`] adds the video status register,
+//! whose bit 15 is set every frame for programs that wait for vsync, and optionally draws
+//! each frame to a terminal with ANSI colors.
+
+use std::{
+    cell::Cell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{console::STATUS_READY, device::Device, vm::Vm};
+
+pub const VIDEO_START: u16 = 0xC000;
+pub const VIDEO_END: u16 = 0xFDFF;
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 124;
+
+/// Video status register: bit 15 is set when a frame has been shown since it was last
+/// read, and cleared by reading it.
+pub const VSR: u16 = 0xFE0C;
+
+/// Instructions between frames by default, about 60 frames a second at typical speeds.
+pub const DEFAULT_FRAME_PERIOD: u64 = 50_000;
+
+/// The pixels of `vm`'s display, `WIDTH * HEIGHT` words row by row.
+pub fn framebuffer(vm: &Vm) -> &[u16] {
+    &vm.memory()[VIDEO_START as usize..=VIDEO_END as usize]
+}
+
+/// The 8-bit red, green and blue components of an RGB555 pixel.
+pub fn rgb(pixel: u16) -> (u8, u8, u8) {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    (
+        expand(pixel >> 10 & 0x1F),
+        expand(pixel >> 5 & 0x1F),
+        expand(pixel & 0x1F),
+    )
+}
+
+/// Draws `pixels` to a terminal supporting 24-bit color, two rows per line with upper
+/// half blocks, starting at the top left corner of the screen.
+pub fn render(pixels: &[u16], out: &mut dyn Write) -> io::Result<()> {
+    let mut frame = String::from("\x1b[H");
+    for y in (0..HEIGHT).step_by(2) {
+        let mut colors = None;
+        for x in 0..WIDTH {
+            let top = pixels[y * WIDTH + x];
+            let bottom = pixels[(y + 1) * WIDTH + x];
+            if colors != Some((top, bottom)) {
+                let (r, g, b) = rgb(top);
+                frame.push_str(&format!("\x1b[38;2;{r};{g};{b}m"));
+                let (r, g, b) = rgb(bottom);
+                frame.push_str(&format!("\x1b[48;2;{r};{g};{b}m"));
+                colors = Some((top, bottom));
+            }
+            frame.push('▀');
+        }
+        frame.push_str("\x1b[0m\r\n");
+    }
+
+    out.write_all(frame.as_bytes())?;
+    out.flush()
+}
+
+struct StatusRegister(Rc<Cell<bool>>);
+
+impl Device for StatusRegister {
+    fn read(&mut self, _addr: u16) -> u16 {
+        if self.0.take() {
+            STATUS_READY
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, _addr: u16, _val: u16) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Maps [`VSR`] and shows a frame every `frame_period` instructions, drawing it to
+/// `screen` if given. Unchanged frames aren't drawn again.
+pub fn install(vm: &mut Vm, frame_period: u64, mut screen: Option<Box<dyn Write>>) {
+    let vsync = Rc::new(Cell::new(false));
+    vm.map_device(VSR..=VSR, Box::new(StatusRegister(vsync.clone())));
+
+    let mut shown = Vec::new();
+    vm.every(
+        frame_period,
+        Box::new(move |vm| {
+            vsync.set(true);
+
+            let Some(out) = &mut screen else {
+                return;
+            };
+            let pixels = framebuffer(vm);
+            if shown != pixels {
+                if let Err(err) = render(pixels, out) {
+                    log::warn!("can't draw the video display: {err}");
+                    screen = None;
+                    return;
+                }
+                shown = pixels.to_vec();
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{util::SharedBuf, vm::Flag};
+
+    #[test]
+    fn test_video() {
+        // loop: LDI R1, VSR; BRzp loop; STI R0, first pixel; HALT
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.load_image_bytes(&[
+            0x30, 0x00, 0xA2, 0x03, 0x07, 0xFE, 0xB0, 0x02, 0xF0, 0x25, 0xFE, 0x0C, 0xC0, 0x00,
+        ])
+        .unwrap();
+        vm.set_register(0, 0x7C00);
+        let screen = SharedBuf::default();
+        install(&mut vm, 10, Some(Box::new(screen.clone())));
+
+        vm.run().unwrap();
+        // the store waited for the first frame
+        assert!(vm.instructions() > 10);
+        assert_eq!(framebuffer(&vm)[0], 0x7C00);
+        assert_eq!(rgb(0x7C00), (255, 0, 0));
+
+        // the blank first frame was drawn, the red pixel not yet
+        let drawn = String::from_utf8(screen.0.take()).unwrap();
+        assert!(drawn.starts_with("\x1b[H\x1b[38;2;0;0;0m\x1b[48;2;0;0;0m▀"));
+        assert_eq!(drawn.matches("\r\n").count(), HEIGHT / 2);
+    }
+}
@@ -1,25 +1,502 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use log::info;
 use std::{
-    io::{stdout, Write},
-    os::unix::prelude::AsRawFd,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Write as _},
+    ops::{Range, RangeInclusive},
     path::Path,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::SyncSender,
+    },
 };
 
-use crate::getch;
+use crate::{
+    asm,
+    console::{
+        parse_recording, ConsoleAddrs, ConsoleOptions, DeviceTiming, Display, ExtraConsole,
+        IoDevice, Keyboard, OutputTap, SharedIo, EOT,
+    },
+    coverage::Coverage,
+    cycles::CycleModel,
+    decode::{decode, spec_violation, DecodeError, Instruction, RegOrImm},
+    device::{Device, DeviceMap, MappedDevice, Rng, Timer, RNG, TMI, TMR},
+    disasm,
+    dump::MemoryDump,
+    env::{HostEnv, RealEnv, SharedEnv},
+    events::{self, Delivery, Event},
+    history::{Delta, History},
+    image::{self, LoadOptions, Segment},
+    loader,
+    machine::VmBuilder,
+    memory::MemoryBus,
+    pace::Pacer,
+    predicate::{Context, Predicate},
+    profile::Profile,
+    psr::{Privilege, Psr},
+    scheduler::{Scheduler, TickCallback},
+    smc::Executed,
+    step::{MemAccess, Steps},
+    symbols::SymbolTable,
+    terminal::{InputMode, TerminalIo},
+    trace::{TraceRecord, TraceWriter},
+    uninit::Initialized,
+    util::{fnv1a, json_string},
+};
 
 pub struct Vm {
-    memory: Vec<u16>,
+    memory: Box<dyn MemoryBus>,
     pc: u16,
     reg: [u16; 8],
-    psr: u16,
+    psr: Psr,
+    // the stack pointer of the mode that is not running, R6 holds the other one
+    saved_ssp: u16,
+    saved_usp: u16,
+    unknown_trap: UnknownTrap,
+    trap_mode: TrapMode,
+    // host routines registered for trap vectors, see Vm::register_trap
+    host_traps: BTreeMap<u8, Box<dyn TrapHandler>>,
+    // cleared by HALT or a store to the MCR, ends the run after the current instruction;
+    // the next step starts the clock again
+    clock_running: bool,
+    getenv: bool,
+    instructions: u64,
+    // simulated cycles, see Vm::set_cycle_model
+    cycles: u64,
+    cycle_model: CycleModel,
+    counter_latch: u16,
+    clock_mode: ClockMode,
+    // shared with the timer and the console
+    env: SharedEnv,
+    // state of the random number register, see `Rng`
+    rng: Rc<Cell<u64>>,
+    trace: Option<TraceWriter>,
+    trace_when: Option<Predicate>,
+    // memory accesses of the current instruction
+    accesses: Vec<MemAccess>,
+    io: SharedIo,
+    // device write errors and memory faults, reported once the instruction is done
+    pending_error: Option<VmError>,
+    max_instructions: Option<u64>,
+    max_cycles: Option<u64>,
+    scheduler: Scheduler,
+    // the main console's keyboard and display come first
+    devices: DeviceMap,
+    // address breakpoints, with the condition under which they stop if any
+    breakpoints: BTreeMap<u16, Option<Predicate>>,
+    // trap vectors whose TRAP instructions stop before executing
+    trap_breakpoints: BTreeSet<u8>,
+    // conditions that stop before any instruction
+    break_conditions: Vec<Predicate>,
+    watchpoints: BTreeMap<u16, WatchKind>,
+    // the first watched access of the current instruction
+    watch_hit: Option<RunResult>,
+    symbols: SymbolTable,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    halted: bool,
+    pre_step_hook: Option<StepHook>,
+    post_step_hook: Option<StepHook>,
+    history: Option<History>,
+    protections: Vec<(RangeInclusive<u16>, Protection)>,
+    r7_check: R7Check,
+    strict: bool,
+    // the address and word of the instruction the last step executed, and the registers
+    // and the length and top of the call stack before it, see Vm::last_executed
+    last_executed: Option<(u16, u16)>,
+    reg_before: [u16; 8],
+    calls_before: (usize, Option<Frame>),
+    // calls that haven't returned yet, innermost last, see Vm::backtrace
+    call_stack: Vec<Frame>,
+    warnings: Vec<Warning>,
+    warning_handler: Option<Box<dyn FnMut(Warning)>>,
+    initialized: Option<Initialized>,
+    executed: Option<Executed>,
+    pacer: Option<Pacer>,
+    // the decoded instructions of the cached engine, by address
+    decoded: Option<Vec<Option<Decoded>>>,
+}
+
+/// How [`Vm::step`] fetches and decodes instructions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Reads and decodes every instruction as it executes.
+    #[default]
+    Simple,
+    /// Keeps every decoded instruction until its word is written to, which makes
+    /// long-running loops faster. Instructions in device registers are never cached.
+    Cached,
+}
+
+// a decoded instruction and the word it came from
+type Decoded = (u16, Result<Instruction, DecodeError>);
+
+/// Time source of the millisecond clock register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockMode {
+    /// Milliseconds since the vm was created, from its [`HostEnv`].
+    Host,
+    /// Advances one millisecond every `insts_per_ms` executed instructions, so runs are
+    /// reproducible.
+    Deterministic { insts_per_ms: u64 },
+}
+
+impl ClockMode {
+    /// A deterministic clock at 1000 instructions per millisecond, close enough to real
+    /// time at typical speeds for delay loops to behave.
+    pub const FROZEN: Self = Self::Deterministic { insts_per_ms: 1000 };
+}
+
+/// Where [`Vm::step`] or [`Vm::resume`] left the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunResult {
+    /// The program can keep running.
+    Running,
+    Halted,
+    /// The PC reached the breakpoint at this address.
+    Breakpoint(u16),
+    /// The instruction accessed a watched address. For reads `old` and `new` are both
+    /// the value read.
+    Watchpoint {
+        addr: u16,
+        old: u16,
+        new: u16,
+    },
+    /// A step hook returned [`HookAction::Stop`].
+    Stopped,
+}
+
+/// What [`Vm::step`] does after calling a step hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    Continue,
+    /// Return [`RunResult::Stopped`]. From the pre-step hook the instruction is not
+    /// executed, so the hook sees it again on the next step.
+    Stop,
+    /// Move the PC past the instruction without executing it. The post-step hook treats
+    /// this like `Continue`.
+    SkipInstruction,
+}
+
+/// Called with the vm and the instruction word before or after each instruction, see
+/// [`Vm::set_pre_step_hook`].
+pub type StepHook = Box<dyn FnMut(&Vm, u16) -> HookAction>;
+
+/// Which accesses to a watched address stop the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn reads(self) -> bool {
+        self != WatchKind::Write
+    }
+
+    fn writes(self) -> bool {
+        self != WatchKind::Read
+    }
+}
+
+/// What the program may not do with a protected address range, see [`Vm::protect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// Stores fault.
+    ReadOnly,
+    /// Fetching an instruction faults.
+    NoExecute,
+    /// Loads, stores and fetches all fault.
+    NoAccess,
+}
+
+impl Protection {
+    /// Parses `ro`, `nx` or `none`.
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "ro" => Protection::ReadOnly,
+            "nx" => Protection::NoExecute,
+            "none" => Protection::NoAccess,
+            _ => bail!("bad protection {s}, expected ro, nx or none"),
+        })
+    }
+
+    fn denies(self, access: Access) -> bool {
+        match self {
+            Protection::ReadOnly => access == Access::Write,
+            Protection::NoExecute => access == Access::Execute,
+            Protection::NoAccess => true,
+        }
+    }
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protection::ReadOnly => "read-only",
+            Protection::NoExecute => "no-execute",
+            Protection::NoAccess => "no-access",
+        })
+    }
+}
+
+/// A call that hasn't returned yet, see [`Vm::backtrace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: CallKind,
+    /// The calling instruction, or the one that was about to execute when an interrupt
+    /// or exception was taken.
+    pub caller: u16,
+    /// Where the called routine starts.
+    pub entry: u16,
+    /// Where the routine returns to.
+    pub ret: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallKind {
+    /// JSR or JSRR.
+    Subroutine,
+    /// A TRAP into a service routine in memory.
+    Trap(u8),
+    /// An interrupt or exception handler, with its vector.
+    Handler(u8),
+}
+
+/// Whether the vm checks that RET returns to where the matching call came from, which
+/// fails when a subroutine overwrites R7 (e.g. with a nested call or a TRAP) without
+/// saving it, see [`Vm::set_r7_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum R7Check {
+    #[default]
+    Off,
+    /// Record a [`Warning::ReturnMismatch`].
+    Warn,
+    /// Stop with [`VmError::ReturnMismatch`].
+    Strict,
+}
+
+/// Something suspicious the program did that doesn't stop it, see [`Vm::take_warnings`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// The RET at `pc` jumped to `target` instead of `expected`, the address after the
+    /// call it should return from.
+    ReturnMismatch { pc: u16, target: u16, expected: u16 },
+    /// The instruction at `pc` used register `reg`, which was never written.
+    UninitializedRegister { pc: u16, reg: u8 },
+    /// The instruction at `pc` loaded from `addr`, which was never stored to or loaded
+    /// from an image.
+    UninitializedMemory { pc: u16, addr: u16 },
+    /// The instruction at `pc` stored to `addr`, where an instruction was executed
+    /// before.
+    SelfModifyingCode { pc: u16, addr: u16 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::ReturnMismatch {
+                pc,
+                target,
+                expected,
+            } => write!(
+                f,
+                "RET at pc {pc:#x} returns to {target:#x} instead of {expected:#x}, was R7 overwritten?"
+            ),
+            Warning::UninitializedRegister { pc, reg } => {
+                write!(f, "R{reg} is used at pc {pc:#x} before it was written")
+            }
+            Warning::UninitializedMemory { pc, addr } => write!(
+                f,
+                "Address {addr:#x} is read at pc {pc:#x} before it was written"
+            ),
+            Warning::SelfModifyingCode { pc, addr } => write!(
+                f,
+                "The store at pc {pc:#x} overwrites code at {addr:#x}, which was executed before"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Who implements the standard traps (GETC, OUT, PUTS, IN, PUTSP and HALT).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    /// The vm, in Rust. Fast, and needs no OS in memory.
+    Native,
+    /// OS code in memory, reached through the trap vector table at x0000-x00FF, e.g.
+    /// the one loaded by [`Vm::load_os`]. Extension traps and unknown traps jump
+    /// through the table too.
+    Os,
+}
+
+/// A host routine for trap vectors, see [`Vm::register_trap`] and
+/// [`UnknownTrap::Handler`]. It gets the vector of the TRAP, so one routine can serve
+/// several. Closures taking the vm and the vector are handlers too.
+pub trait TrapHandler {
+    fn call(&mut self, vm: &mut Vm, vector: u8) -> Result<(), VmError>;
+}
+
+impl<F: FnMut(&mut Vm, u8) -> Result<(), VmError>> TrapHandler for F {
+    fn call(&mut self, vm: &mut Vm, vector: u8) -> Result<(), VmError> {
+        self(vm, vector)
+    }
+}
+
+/// What to do when a TRAP vector has no native implementation.
+pub enum UnknownTrap {
+    /// Jump through the trap vector table at x0000-x00FF, like real hardware.
+    Vector,
+    /// Stop execution with [`VmError::BadTrap`].
+    Error,
+    /// Call a host routine. R7 already holds the return address, and an error from the
+    /// routine stops the program.
+    Handler(Box<dyn TrapHandler>),
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    BadTrap {
+        trap: u16,
+        pc: u16,
+    },
+    Io(std::io::Error),
+    /// The program read past the end of the input of its [`IoDevice`].
+    InputExhausted {
+        pc: u16,
+    },
+    InstructionLimit(u64),
+    /// The program ran for more than the limit set with [`Vm::set_cycle_limit`].
+    CycleLimit(u64),
+    /// The reserved opcode was executed and no illegal opcode handler is installed.
+    BadOpcode {
+        inst: u16,
+        pc: u16,
+    },
+    /// An access to an address outside of memory.
+    MemoryFault {
+        addr: u16,
+        pc: u16,
+    },
+    /// An exception or interrupt was raised but its interrupt vector table entry is
+    /// zero, i.e. no handler is installed. `pc` is the PC that would have been saved.
+    UnhandledException {
+        vector: u8,
+        pc: u16,
+    },
+    /// An access to a range protected with [`Vm::protect`] while no access violation
+    /// handler is installed.
+    ProtectionFault {
+        addr: u16,
+        pc: u16,
+        protection: Protection,
+    },
+    /// A RET that doesn't return to the caller, with [`R7Check::Strict`].
+    ReturnMismatch {
+        pc: u16,
+        target: u16,
+        expected: u16,
+    },
+    /// An instruction with bits the LC-3 specification doesn't allow, with
+    /// [`Vm::set_strict`]. `reason` says which.
+    SpecViolation {
+        inst: u16,
+        pc: u16,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::BadTrap { trap, pc } => write!(f, "Bad trap {trap:#x} at pc {pc:#x}"),
+            VmError::Io(err) => write!(f, "I/O error: {err}"),
+            VmError::InputExhausted { pc } => write!(f, "Input exhausted at pc {pc:#x}"),
+            VmError::InstructionLimit(limit) => {
+                write!(f, "Instruction limit of {limit} exceeded")
+            }
+            VmError::CycleLimit(limit) => write!(f, "Cycle limit of {limit} exceeded"),
+            VmError::BadOpcode { inst, pc } => write!(f, "Bad opcode {inst:#06x} at pc {pc:#x}"),
+            VmError::MemoryFault { addr, pc } => {
+                write!(f, "Memory fault accessing {addr:#x} at pc {pc:#x}")
+            }
+            VmError::UnhandledException { vector, pc } => {
+                write!(f, "Unhandled exception {vector:#x} at pc {pc:#x}")
+            }
+            VmError::ProtectionFault {
+                addr,
+                pc,
+                protection,
+            } => write!(f, "Access to {protection} address {addr:#x} at pc {pc:#x}"),
+            &VmError::ReturnMismatch {
+                pc,
+                target,
+                expected,
+            } => Warning::ReturnMismatch {
+                pc,
+                target,
+                expected,
+            }
+            .fmt(f),
+            VmError::SpecViolation { inst, pc, reason } => {
+                write!(
+                    f,
+                    "Instruction {inst:#06x} ({}) at pc {pc:#x} breaks the spec: {reason}",
+                    disasm::disassemble(*inst, *pc)
+                )
+            }
+        }
+    }
+}
+
+impl VmError {
+    /// Address of the instruction that caused the error, if there is one.
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            VmError::BadTrap { pc, .. }
+            | VmError::InputExhausted { pc }
+            | VmError::BadOpcode { pc, .. }
+            | VmError::MemoryFault { pc, .. }
+            | VmError::ProtectionFault { pc, .. }
+            | VmError::ReturnMismatch { pc, .. }
+            | VmError::SpecViolation { pc, .. } => Some(pc),
+            // the PC that would have been saved, which is past a faulting instruction
+            VmError::UnhandledException { .. }
+            | VmError::Io(_)
+            | VmError::InstructionLimit(_)
+            | VmError::CycleLimit(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for VmError {
+    fn from(err: std::io::Error) -> Self {
+        VmError::Io(err)
+    }
 }
 
-// addresses for the memory mapped regs
-const KBSR: u16 = 0xFE00;
-const KBDR: u16 = 0xFE02;
-const DSR: u16 = 0xFE04;
-const DDR: u16 = 0xFE06;
+impl std::error::Error for VmError {}
+
+// read-only performance counters, as 32-bit values split into low and high words.
+// Reading a low word latches its high word, so the pair is always consistent.
+const INSTCNT_LO: u16 = 0xFE20;
+const INSTCNT_HI: u16 = 0xFE22;
+const CYCCNT_LO: u16 = 0xFE24;
+const CYCCNT_HI: u16 = 0xFE26;
+// read-only, milliseconds since the vm started as a 32-bit value, latched like the
+// counters
+const CLOCK_MS: u16 = 0xFE28;
+const CLOCK_MS_HI: u16 = 0xFE2A;
 
 // traps
 const GETC: u16 = 0x20;
@@ -29,359 +506,2272 @@ const IN: u16 = 0x23;
 const PUTSP: u16 = 0x24;
 const HALT: u16 = 0x25;
 
+// extension traps
+const GETENV: u16 = 0x26;
+// reads a line with echo into the buffer at R0, at most R1 characters plus a NUL;
+// the number of characters read is returned in R1
+const GETS: u16 = 0x27;
+// prints R0 as a signed decimal integer
+const PUTD: u16 = 0x28;
+// reads a line with echo and parses it as a signed decimal integer into R0;
+// R1 is set to 0 on success and -1 if the input was not a number in range
+const GETD: u16 = 0x29;
+
+// PSR[15] is set in user mode, PSR[10:8] hold the priority level
+pub use crate::psr::{PSR_PRIORITY, PSR_USER};
+
+const OS_SOURCE: &str = include_str!("../os/os.asm");
+
+/// Exceptions and interrupts find their handlers in the interrupt vector table, which
+/// starts here.
+pub const IVT: u16 = 0x0100;
+
+const PRIVILEGE_VIOLATION: u8 = 0x00;
+const ILLEGAL_OPCODE: u8 = 0x01;
+const ACCESS_VIOLATION: u8 = 0x02;
+
+// instructions Vm::run_with_cancel executes between looks at its token
+const CANCEL_CHECK_INTERVAL: u64 = 1024;
+
+/// Words of memory, one for every address.
+pub const MEMORY_SIZE: usize = 1 << 16;
+
+// the addresses user mode code may access
+const USER_SPACE: Range<u16> = 0x3000..0xFE00;
+
+/// The machine control register, clearing its bit [`MCR_CLOCK`] stops the clock. Programs
+/// always read the bit as set, since they only run while the clock does; in
+/// [`Vm::memory`] it is clear while the machine is halted.
+pub const MCR: u16 = 0xFFFE;
+pub const MCR_CLOCK: u16 = 1 << 15;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"LC3S";
+// 2 added the word at xFFFF, 3 the cycle count
+const SNAPSHOT_VERSION: u8 = 3;
+// magic, version, instruction and cycle counts, then PC, PSR, two stack pointers, the
+// interrupt enable bit and the registers
+const SNAPSHOT_HEADER_LEN: usize = 4 + 1 + 8 + 8 + 2 * (5 + 8);
+
+/// A machine state saved by [`Vm::save_state`], read without a vm, e.g. to compare two.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub pc: u16,
+    pub psr: u16,
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    pub interrupt_enable: bool,
+    pub reg: [u16; 8],
+    pub memory: Vec<u16>,
+}
+
+impl Snapshot {
+    /// Reads a snapshot in the format written by [`Vm::save_state`].
+    pub fn parse(state: &[u8]) -> Result<Self> {
+        if state.len() < 5 || &state[..4] != SNAPSHOT_MAGIC {
+            bail!("not a snapshot");
+        }
+        if state[4] != SNAPSHOT_VERSION {
+            bail!("unsupported snapshot version {}", state[4]);
+        }
+        if state.len() < SNAPSHOT_HEADER_LEN
+            || !(state.len() - SNAPSHOT_HEADER_LEN).is_multiple_of(2)
+        {
+            bail!("snapshot has the wrong size");
+        }
+
+        let instructions = u64::from_le_bytes(state[5..13].try_into().unwrap());
+        let cycles = u64::from_le_bytes(state[13..21].try_into().unwrap());
+        let mut words = state[21..]
+            .chunks_exact(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]));
+        let mut next = || words.next().unwrap();
+
+        Ok(Self {
+            instructions,
+            cycles,
+            pc: next(),
+            psr: next(),
+            saved_ssp: next(),
+            saved_usp: next(),
+            interrupt_enable: next() != 0,
+            reg: std::array::from_fn(|_| next()),
+            memory: words.collect(),
+        })
+    }
+}
+
 impl Vm {
+    /// Creates a vm with zeroed memory and registers that starts executing at `pc`.
+    /// Loading an image moves the PC to the image's origin.
     pub fn new(pc: u16, psr: u16) -> Self {
+        let env: SharedEnv = Rc::new(RefCell::new(Box::new(RealEnv::default())));
+        let io = SharedIo::new(Box::new(TerminalIo::new(InputMode::Bytes)), env.clone());
+        let mut devices = DeviceMap::default();
+        devices.replace_front(0, console_devices(ConsoleAddrs::default(), &io));
+        let timer = Rc::new(RefCell::new(Timer::new(env.clone())));
+        devices.map(Some("timer"), TMR..=TMI, Box::new(timer.clone()));
+        let rng = Rc::new(Cell::new(env.borrow_mut().seed()));
+        devices.map(Some("rng"), RNG..=RNG, Box::new(Rng(rng.clone())));
+
+        // the timer and the console's device timing count every instruction
+        let mut scheduler = Scheduler::default();
+        let console = io.clone();
+        scheduler.add(
+            0,
+            1,
+            Box::new(move |_| {
+                timer.borrow_mut().tick();
+                console.tick();
+            }),
+        );
+
         Self {
-            memory: vec![0; std::u16::MAX as usize],
+            memory: Box::new(vec![0; MEMORY_SIZE]),
             pc,
             reg: Default::default(),
-            psr,
+            psr: Psr::new(psr),
+            saved_ssp: 0x3000,
+            saved_usp: 0,
+            unknown_trap: UnknownTrap::Error,
+            host_traps: BTreeMap::new(),
+            trap_mode: TrapMode::Native,
+            clock_running: true,
+            getenv: false,
+            instructions: 0,
+            cycles: 0,
+            cycle_model: CycleModel::default(),
+            counter_latch: 0,
+            clock_mode: ClockMode::Host,
+            env,
+            rng,
+            trace: None,
+            trace_when: None,
+            accesses: Vec::new(),
+            io,
+            pending_error: None,
+            max_instructions: None,
+            max_cycles: None,
+            scheduler,
+            devices,
+            breakpoints: BTreeMap::new(),
+            trap_breakpoints: BTreeSet::new(),
+            break_conditions: Vec::new(),
+            watchpoints: BTreeMap::new(),
+            watch_hit: None,
+            symbols: SymbolTable::default(),
+            profile: None,
+            coverage: None,
+            halted: false,
+            pre_step_hook: None,
+            post_step_hook: None,
+            history: None,
+            protections: Vec::new(),
+            r7_check: R7Check::Off,
+            strict: false,
+            last_executed: None,
+            reg_before: [0; 8],
+            calls_before: (0, None),
+            call_stack: Vec::new(),
+            warnings: Vec::new(),
+            warning_handler: None,
+            initialized: None,
+            executed: None,
+            pacer: None,
+            decoded: None,
         }
     }
 
-    pub fn read_image(&mut self, file: impl AsRef<Path>) -> Result<()> {
-        let u16_len = std::mem::size_of::<u16>();
-        let data = std::fs::read(file)?;
+    /// Replaces the console the program reads keys from and prints to, which is the
+    /// terminal by default. Reading past the end of its input stops the program with
+    /// [`VmError::InputExhausted`], unless [`ConsoleOptions::eof`] says otherwise.
+    pub fn set_io(&mut self, io: Box<dyn IoDevice>) {
+        self.io.set(io);
+    }
 
-        let (origin, data) = data.split_at(u16_len);
-        let origin = u16::from_be_bytes(origin.try_into().unwrap());
+    /// Writes every key the program reads to `out`, with the instruction count at which
+    /// it was read, so [`Vm::replay_input`] can deliver it again at the same point.
+    pub fn record_input(&mut self, out: Box<dyn std::io::Write>) {
+        self.io.record(out);
+    }
 
-        self.pc = origin;
+    /// Delivers the keys of a recording made by [`Vm::record_input`] instead of reading
+    /// the console, each becoming ready at the instruction count it was read at. The
+    /// input ends with the recording.
+    pub fn replay_input(&mut self, recording: &str) -> Result<()> {
+        self.io.replay(parse_recording(recording)?);
+        Ok(())
+    }
 
-        let len = data.len() / u16_len;
-        if len > u16::MAX as _ {
-            bail!(
-                "Input file too large - must not be greater than {} bytes",
-                u16::MAX
-            );
-        }
+    /// Makes `keys` the next input of the main console, ahead of what the [`IoDevice`]
+    /// or a replay delivers, e.g. to type into a running program from a test or a GUI.
+    pub fn queue_input(&mut self, keys: &[u8]) {
+        self.io.queue(keys);
+    }
 
-        let dst = &mut self.memory[(origin as usize)..(origin as usize + len)];
+    /// Sets how the main console translates newlines and what reading past the end of
+    /// the input does, see [`ConsoleOptions`].
+    pub fn set_console_options(&mut self, options: ConsoleOptions) {
+        self.io.set_options(options);
+    }
 
-        for (dst, src) in dst.iter_mut().zip(data.chunks(u16_len)) {
-            *dst = u16::from_be_bytes(src.try_into().unwrap());
-        }
+    /// Makes the main console's status registers report busy like real devices would,
+    /// see [`DeviceTiming`].
+    pub fn set_device_timing(&mut self, timing: DeviceTiming) {
+        self.io.set_timing(timing);
+    }
 
-        Ok(())
+    /// Stops the program with [`VmError::InstructionLimit`] once it has executed `limit`
+    /// instructions.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.max_instructions = limit;
     }
 
-    pub fn run(&mut self) {
-        let mut running = true;
+    /// Stops the program with [`VmError::CycleLimit`] before an instruction that starts
+    /// after `limit` cycles.
+    pub fn set_cycle_limit(&mut self, limit: Option<u64>) {
+        self.max_cycles = limit;
+    }
 
-        while running {
-            let inst = self.read_mem(self.pc);
-            let op: Opcode = (inst >> 12).try_into().unwrap();
+    /// Moves the keyboard and display registers of the main console.
+    pub fn set_console_addrs(&mut self, addrs: ConsoleAddrs) {
+        self.devices
+            .replace_front(2, console_devices(addrs, &self.io));
+        self.invalidate_decoded(0..self.memory.words().len());
+    }
 
-            info!("inst: {inst:#x} pc: {:#x}", self.pc);
+    /// Adds a keyboard/display pair with its own input and output, see
+    /// [`Vm::map_device`].
+    pub fn add_console(&mut self, console: ExtraConsole) {
+        self.map_device(console.addrs.range(), Box::new(console));
+    }
 
-            self.pc = self.pc.wrapping_add(1);
+    /// Installs `device` to handle loads and stores to `range`. Devices mapped earlier
+    /// take precedence, and the main console comes before all of them.
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) {
+        self.devices.map(None, range, device);
+        self.invalidate_decoded(0..self.memory.words().len());
+    }
 
-            match op {
-                Opcode::Br => {
-                    let nzp = inst >> 9 & 0b111;
-                    let current_nzp = self.psr & 0b111;
-                    let offset = sign_ext(inst, 9);
+    /// Starts building a vm with devices and trap routines from other crates.
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
 
-                    info!(
-                        "Br current: {}, desired: {}, offset: {:#x}",
-                        current_nzp, nzp, offset
-                    );
+    /// Like [`Vm::map_device`], with a name [`Vm::unmap_device`] can remove it by.
+    pub fn map_named_device(
+        &mut self,
+        name: &str,
+        range: RangeInclusive<u16>,
+        device: Box<dyn Device>,
+    ) {
+        self.devices.map(Some(name), range, device);
+        self.invalidate_decoded(0..self.memory.words().len());
+    }
 
-                    if nzp & current_nzp != 0 {
-                        self.pc = self.pc.wrapping_add(offset);
-                    }
-                }
-                Opcode::Add => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let sr1 = (inst >> 6 & 0b111) as usize;
+    /// Removes the devices named `name`, e.g. one of the
+    /// [`BUILTIN_DEVICES`](crate::device::BUILTIN_DEVICES), so their addresses are memory
+    /// again. Returns `false` if there were none.
+    pub fn unmap_device(&mut self, name: &str) -> bool {
+        self.devices.unmap(name)
+    }
 
-                    if inst & (1 << 5) != 0 {
-                        let imm5 = sign_ext(inst, 5);
+    /// The names of the mapped devices in the order they are looked up. The main
+    /// console and devices mapped without a name are left out.
+    pub fn device_names(&self) -> impl Iterator<Item = &str> {
+        self.devices.names()
+    }
 
-                        info!("Add r{dr}, r{sr1}, #{imm5}");
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.decoded = match engine {
+            Engine::Simple => None,
+            Engine::Cached => Some(vec![None; self.memory.words().len()]),
+        };
+    }
 
-                        self.reg[dr] = self.reg[sr1].wrapping_add(imm5);
-                    } else {
-                        let sr2 = (inst & 0b111) as usize;
+    pub fn engine(&self) -> Engine {
+        if self.decoded.is_some() {
+            Engine::Cached
+        } else {
+            Engine::Simple
+        }
+    }
 
-                        info!("Add r{dr}, r{sr1}, r{sr2}");
+    /// Forgets the decoded instructions in `range` after memory there changed.
+    fn invalidate_decoded(&mut self, range: Range<usize>) {
+        if let Some(decoded) = &mut self.decoded {
+            decoded[range].fill(None);
+        }
+    }
 
-                        self.reg[dr] = self.reg[sr1].wrapping_add(self.reg[sr2]);
-                    }
+    /// Calls `callback` every `period` executed instructions, for devices that need to
+    /// do work over time.
+    pub fn every(&mut self, period: u64, callback: TickCallback) {
+        self.scheduler.add(self.instructions, period, callback);
+    }
 
-                    self.set_cc(dr);
-                }
-                Opcode::Ld => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let offset = sign_ext(inst, 9);
+    /// Address of the next instruction to execute.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
 
-                    info!("Ld r{dr}, offset: {:#x}", offset);
+    /// Processor status register. The low three bits are the N, Z and P condition codes.
+    pub fn psr(&self) -> u16 {
+        self.psr.bits()
+    }
 
-                    self.reg[dr] = self.read_mem(self.pc.wrapping_add(offset));
-                    self.set_cc(dr);
-                }
-                Opcode::St => {
-                    let sr = (inst >> 9 & 0b111) as usize;
-                    let offset = sign_ext(inst, 9);
+    /// General purpose registers R0-R7.
+    pub fn registers(&self) -> &[u16; 8] {
+        &self.reg
+    }
 
-                    info!("St r{sr} offset: {:#x}", offset);
+    /// Memory contents, indexed by address. Device registers are not included.
+    pub fn memory(&self) -> &[u16] {
+        self.memory.words()
+    }
 
-                    self.write_mem(self.pc.wrapping_add(offset), self.reg[sr]);
-                }
-                Opcode::Jsr => {
-                    let temp = self.pc;
-                    self.pc = if inst & (1 << 11) != 0 {
-                        let offset = sign_ext(inst, 11);
+    /// Replaces the memory the program runs in, e.g. with a [`crate::memory::CowMemory`] shared with
+    /// other vms until they write to it. Memory shorter than 64K words faults past its
+    /// end.
+    pub fn set_memory_bus(&mut self, memory: Box<dyn MemoryBus>) {
+        self.memory = memory;
+        if let Some(decoded) = &mut self.decoded {
+            *decoded = vec![None; self.memory.words().len()];
+        }
+        if self.initialized.is_some() {
+            self.initialized = Some(Initialized::new(self.memory.words().len()));
+        }
+        if self.executed.is_some() {
+            self.executed = Some(Executed::new(self.memory.words().len()));
+        }
+    }
 
-                        info!("Jsr offset: {:#x}", offset);
+    /// Moves the PC, e.g. from a debugger.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
 
-                        self.pc.wrapping_add(offset)
-                    } else {
-                        let br = (inst >> 6 & 0b111) as usize;
-                        let br_val = self.reg[br];
+    /// Sets general purpose register `r` (0-7).
+    pub fn set_register(&mut self, r: usize, val: u16) {
+        self.reg[r] = val;
+        self.mark_register(r);
+    }
 
-                        info!("Jsr br_val: {}", br_val);
-                        br_val
-                    };
+    /// Stores `val` at `addr` without going through devices or watchpoints. Returns
+    /// `false` if `addr` is outside memory.
+    pub fn set_memory(&mut self, addr: u16, val: u16) -> bool {
+        if !self.memory.write(addr, val) {
+            return false;
+        }
+        if let Some(initialized) = &mut self.initialized {
+            initialized.set_memory(addr);
+        }
+        self.invalidate_decoded(addr as usize..addr as usize + 1);
+        true
+    }
 
-                    self.reg[7] = temp;
-                }
-                Opcode::And => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let sr1 = (inst >> 6 & 0b111) as usize;
+    /// Loads `len` words from `addr` on, continuing at x0000 past xFFFF, the way the
+    /// program would: device registers are read, with whatever side effects that has,
+    /// and ranges protected with [`Vm::protect`] against loads fail. Watchpoints,
+    /// cycles and the access log are left alone.
+    pub fn read_mem_range(&mut self, addr: u16, len: usize) -> Result<Vec<u16>, VmError> {
+        let addrs = (0..len).map(|i| addr.wrapping_add(i as u16));
+        for addr in addrs.clone() {
+            self.check_host_access(addr, Access::Read)?;
+        }
 
-                    if inst & (1 << 5) != 0 {
-                        let imm5 = sign_ext(inst, 5);
+        let words = addrs.map(|addr| self.load(addr)).collect();
+        match self.pending_error.take() {
+            Some(err) => Err(err),
+            None => Ok(words),
+        }
+    }
 
-                        info!("And r{dr}, r{sr1}, #{imm5}");
+    /// Stores `words` from `addr` on, like [`Vm::read_mem_range`] loads them: device
+    /// registers are written, and ranges protected against stores fail before anything
+    /// is written.
+    pub fn write_mem_range(&mut self, addr: u16, words: &[u16]) -> Result<(), VmError> {
+        let addrs = (0..words.len()).map(|i| addr.wrapping_add(i as u16));
+        for addr in addrs.clone() {
+            self.check_host_access(addr, Access::Write)?;
+        }
 
-                        self.reg[dr] = self.reg[sr1] & imm5;
-                    } else {
-                        let sr2 = (inst & 0b111) as usize;
+        for (addr, &val) in addrs.zip(words) {
+            self.store(addr, val);
+        }
+        match self.pending_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
 
-                        info!("And r{dr}, r{sr1}, r{sr2}");
+    /// Reads the string at `addr`, one character per word up to a terminating x0000, like
+    /// PUTS prints it. Fails with [`VmError::MemoryFault`] if the string runs past the end
+    /// of memory or has no terminator. Device registers aren't read.
+    pub fn read_string(&self, addr: u16) -> Result<String, VmError> {
+        let words = self.string_words(addr)?;
+        Ok(words.iter().map(|&word| word as u8 as char).collect())
+    }
 
-                        self.reg[dr] = self.reg[sr1] & self.reg[sr2];
-                    }
+    /// Stores the bytes of `s` one per word from `addr` on, with a terminating x0000,
+    /// bypassing devices like [`Vm::set_memory`].
+    pub fn write_string(&mut self, addr: u16, s: &str) -> Result<(), VmError> {
+        let words: Vec<_> = s.bytes().map(u16::from).collect();
+        self.write_string_words(addr, &words)
+    }
 
-                    self.set_cc(dr);
-                }
-                Opcode::Ldr => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let br = (inst >> 6 & 0b111) as usize;
-                    let offset = sign_ext(inst, 6);
+    /// Reads the string at `addr` packed two characters per word, the first in the low
+    /// byte, like PUTSP prints it. A zero high byte ends the string too.
+    pub fn read_packed_string(&self, addr: u16) -> Result<String, VmError> {
+        let words = self.string_words(addr)?;
+        let mut s = String::new();
+        for word in words {
+            let [lo, hi] = word.to_le_bytes();
+            s.push(lo as char);
+            if hi == 0 {
+                break;
+            }
+            s.push(hi as char);
+        }
+        Ok(s)
+    }
 
-                    info!("Ldr r{dr}, br: {br}, offset: {:#x}", offset);
+    /// Stores the bytes of `s` packed two per word, the first in the low byte, with a
+    /// terminating x0000.
+    pub fn write_packed_string(&mut self, addr: u16, s: &str) -> Result<(), VmError> {
+        let words: Vec<_> = s
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+            .collect();
+        self.write_string_words(addr, &words)
+    }
 
-                    let addr = self.reg[br].wrapping_add(offset);
-                    self.reg[dr] = self.read_mem(addr);
+    /// Whether the last executed instruction halted the machine.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
 
-                    self.set_cc(dr);
-                }
-                Opcode::Str => {
-                    let sr = (inst >> 9 & 0b111) as usize;
-                    let br = (inst >> 6 & 0b111) as usize;
-                    let offset = sign_ext(inst, 6);
+    /// Number of instructions executed so far.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
 
-                    info!("Str r{sr}, br: {br}, offset: {:#x}", offset);
+    /// Simulated cycles spent so far, see [`Vm::set_cycle_model`].
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
 
-                    let addr = self.reg[br].wrapping_add(offset);
-                    self.write_mem(addr, self.reg[sr]);
-                }
-                Opcode::Not => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let sr1 = (inst >> 6 & 0b111) as usize;
+    /// Sets what instructions and memory accesses cost in [`Vm::cycles`], which the
+    /// program can read from the cycle counter registers.
+    pub fn set_cycle_model(&mut self, model: CycleModel) {
+        self.cycle_model = model;
+    }
 
-                    info!("Not r{dr}, r{sr1}");
+    pub fn cycle_model(&self) -> &CycleModel {
+        &self.cycle_model
+    }
 
-                    self.reg[dr] = !self.reg[sr1];
+    /// 64-bit FNV-1a hash of all of memory, each word little-endian, for comparing final
+    /// states without storing them.
+    pub fn memory_digest(&self) -> u64 {
+        fnv1a(
+            self.memory
+                .words()
+                .iter()
+                .flat_map(|word| word.to_le_bytes()),
+        )
+    }
 
-                    self.set_cc(dr);
-                }
-                Opcode::Ldi => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let offset = sign_ext(inst, 9);
-                    let addr = self.read_mem(self.pc.wrapping_add(offset));
+    /// The PC, PSR, registers (as an array), instruction and cycle counts as a JSON object, for
+    /// scripts that check where a run ended. `halted` says whether the program halted, and
+    /// `error` holds the message of the error the run stopped with, if any. With
+    /// `memory_digest` the object also has [`Vm::memory_digest`] as 16 hex digits.
+    pub fn state_json(&self, error: Option<&VmError>, memory_digest: bool) -> String {
+        let mut json = format!(r#"{{"pc":{},"psr":{},"registers":["#, self.pc, self.psr());
+        for (i, value) in self.reg.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(json, "{sep}{value}").unwrap();
+        }
+        let error = error.map_or("null".to_string(), |err| json_string(&err.to_string()));
+        write!(
+            json,
+            r#"],"instructions":{},"cycles":{},"halted":{},"error":{error}"#,
+            self.instructions, self.cycles, self.halted
+        )
+        .unwrap();
+        if memory_digest {
+            write!(json, r#","memory_digest":"{:016x}""#, self.memory_digest()).unwrap();
+        }
+        json.push('}');
 
-                    info!("Ldi r{dr} offset: {:#x}", offset);
+        json
+    }
 
-                    self.reg[dr] = self.read_mem(addr);
-                    self.set_cc(dr);
-                }
-                Opcode::Sti => {
-                    let sr = (inst >> 9 & 0b111) as usize;
-                    let offset = sign_ext(inst, 9);
+    /// A printable view of the memory in `range`, e.g. `vm.dump_memory(0x3000..=0x30FF)`.
+    pub fn dump_memory(&self, range: RangeInclusive<u16>) -> MemoryDump<'_> {
+        MemoryDump::new(self, range)
+    }
 
-                    info!("Sti r{sr} offset: {:#x}", offset);
+    /// Records every executed instruction to `trace`.
+    pub fn set_trace(&mut self, trace: TraceWriter) {
+        self.trace = Some(trace);
+    }
 
-                    let addr = self.read_mem(self.pc.wrapping_add(offset));
+    /// Only records instructions after which `predicate` holds.
+    pub fn set_trace_when(&mut self, predicate: Predicate) {
+        self.trace_when = Some(predicate);
+    }
 
-                    self.write_mem(addr, self.reg[sr]);
-                }
-                Opcode::Jmp => {
-                    let br = (inst >> 6 & 0b111) as usize;
+    /// Limits execution to roughly `hz` instructions per second, so programs written for
+    /// slow simulators, e.g. games that animate in busy loops, run at a usable speed.
+    /// `None` runs at full speed, the default. Time spent waiting for input isn't made up
+    /// for later.
+    pub fn set_speed(&mut self, hz: Option<u64>) {
+        self.pacer = hz.map(|hz| Pacer::new(hz, self.instructions));
+    }
 
-                    info!("Jmp {br}");
+    pub fn set_clock_mode(&mut self, clock_mode: ClockMode) {
+        self.clock_mode = clock_mode;
+    }
 
-                    self.pc = self.reg[br];
-                }
-                Opcode::Lea => {
-                    let dr = (inst >> 9 & 0b111) as usize;
-                    let offset = sign_ext(inst, 9);
+    /// Replaces what the vm takes from the host: the time, the seed of the random number
+    /// register, which is reseeded, and whether a key is ready. See [`crate::env`].
+    pub fn set_host_env(&mut self, env: Box<dyn HostEnv>) {
+        *self.env.borrow_mut() = env;
+        self.rng.set(self.env.borrow_mut().seed());
+    }
 
-                    info!("Lea r{dr} offset: {:#x}", offset);
+    /// Seeds the random number register, which is seeded from the host clock by default,
+    /// so runs that use it can be repeated.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng.set(seed);
+    }
 
-                    self.reg[dr] = self.pc.wrapping_add(offset);
-                    self.set_cc(dr);
-                }
-                Opcode::Trap => {
-                    // implement traps in assembly or rust?
-                    self.reg[7] = self.pc;
+    /// Sets the supervisor stack pointer used when an exception or interrupt is taken in
+    /// user mode. Defaults to x3000.
+    pub fn set_supervisor_stack(&mut self, ssp: u16) {
+        if self.psr.is_user() {
+            self.saved_ssp = ssp;
+        } else {
+            self.reg[6] = ssp;
+            self.mark_register(6);
+        }
+    }
 
-                    let trap = inst & 0xFF;
-                    info!("Trap {trap}");
+    /// Enables the GETENV trap (x26), which copies a host environment variable into
+    /// memory: R0 points to the NUL-terminated name, R1 to the destination buffer and
+    /// R2 holds the buffer size in words, including the terminating NUL. The value is
+    /// copied as UTF-8, one byte per word, and only cut short between characters. On
+    /// return R0 holds the number of bytes copied, or -1 if the variable is not set.
+    pub fn enable_getenv(&mut self, enable: bool) {
+        self.getenv = enable;
+    }
 
-                    match trap {
-                        GETC => {
-                            self.reg[0] = getch().unwrap_or_default() as u16;
-                            self.set_cc(0);
-                        }
-                        OUT => {
-                            let byte = self.reg[0] as u8;
-                            let _ = stdout().write(&[byte]).unwrap();
-                        }
-                        PUTS => {
-                            let addr = self.reg[0] as usize;
-                            let slice = &self.memory[addr..];
-                            let end = slice.iter().position(|w| *w == 0x0000).unwrap_or_default();
-                            let slice_to_print = &slice[..end];
+    pub fn set_unknown_trap(&mut self, unknown_trap: UnknownTrap) {
+        self.unknown_trap = unknown_trap;
+    }
 
-                            let mut stdout = stdout().lock();
+    /// Runs `routine` for `TRAP vector` instead of the native routine or the one in the
+    /// OS, so embedders can offer services like file I/O to programs. R7 already holds the
+    /// return address, and the program continues after the TRAP unless the routine moves
+    /// the PC. An error from the routine stops the program. Replaces an earlier routine
+    /// for `vector`.
+    pub fn register_trap(&mut self, vector: u8, routine: impl TrapHandler + 'static) {
+        self.host_traps.insert(vector, Box::new(routine));
+    }
 
-                            for &word in slice_to_print {
-                                let _ = stdout.write(&[word as u8]).unwrap();
-                            }
+    /// Returns `false` if no routine was registered for `vector`.
+    pub fn unregister_trap(&mut self, vector: u8) -> bool {
+        self.host_traps.remove(&vector).is_some()
+    }
 
-                            stdout.flush().unwrap();
-                        }
-                        IN => {
-                            let mut stdout = stdout().lock();
-                            write!(stdout, "Enter a character: ").unwrap();
-                            stdout.flush().unwrap();
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
 
-                            let ch = getch().unwrap_or_default();
-                            let _ = stdout.write(&[ch]).unwrap();
-                        }
-                        PUTSP => {
-                            let addr = self.reg[0] as usize;
-                            let slice = &self.memory[addr..];
-
-                            let mut stdout = stdout().lock();
-
-                            for &word in slice {
-                                let bytes = u16::to_le_bytes(word);
-                                if bytes[1] != 0 {
-                                    let _ = stdout.write(&bytes).unwrap();
-                                } else {
-                                    let _ = stdout.write(&bytes[..1]).unwrap();
-                                }
-                            }
-
-                            stdout.flush().unwrap();
-                        }
-                        HALT => {
-                            println!("HALT");
-                            running = false;
-                        }
-                        _ => unimplemented!("Bad trap"),
-                    }
-                }
-                Opcode::Rti | Opcode::Reserved => unimplemented!("Bad opcode: {op:?}"),
-            }
+    /// Loads the bundled OS, whose trap routines drive the console through its device
+    /// registers, into x0000-x02FF. It is meant for [`TrapMode::Os`]. The PC is left
+    /// alone.
+    pub fn load_os(&mut self) -> Result<()> {
+        let os = asm::assemble(OS_SOURCE)?;
+        let origin = os.origin as usize;
+        let Some(dst) = self
+            .memory
+            .words_mut()
+            .get_mut(origin..origin + os.words.len())
+        else {
+            bail!("the OS does not fit in memory");
+        };
+        dst.copy_from_slice(&os.words);
+        if let Some(initialized) = &mut self.initialized {
+            initialized.set_memory_range(origin..origin + os.words.len());
         }
+        self.invalidate_decoded(origin..origin + os.words.len());
+        self.symbols.extend(&os.symbol_table());
+
+        Ok(())
     }
 
-    fn read_mem(&self, addr: u16) -> u16 {
-        match addr {
-            KBSR => {
-                if is_ready_to_read() {
-                    0x80
-                } else {
-                    0
-                }
-            }
-            KBDR => {
-                if self.read_mem(KBSR) != 0 {
-                    getch().unwrap_or_default() as u16
-                } else {
-                    0
-                }
-            }
-            DSR => 0x80,
-            DDR => 0,
-            _ => self.memory[addr as usize],
+    /// Hash of the machine state (PC, PSR, registers and memory), for cheaply checking
+    /// two machines or two runs for equivalence. Uses FNV-1a, so the value is stable
+    /// across runs and platforms.
+    pub fn state_hash(&self) -> u64 {
+        let words = [self.pc, self.psr.bits()]
+            .into_iter()
+            .chain(self.reg)
+            .chain(self.memory.words().iter().copied());
+
+        fnv1a(words.flat_map(u16::to_le_bytes))
+    }
+
+    /// Serializes the machine state, for [`Vm::restore_state`]. The snapshot starts with
+    /// the magic `LC3S` and a version byte, followed by the instruction and cycle counts
+    /// as u64s, then PC, PSR, the saved stack pointers, the keyboard interrupt enable bit, R0-R7
+    /// and all of memory as u16s, everything little-endian.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(SNAPSHOT_HEADER_LEN + 2 * self.memory.words().len());
+        state.extend(SNAPSHOT_MAGIC);
+        state.push(SNAPSHOT_VERSION);
+        state.extend(self.instructions.to_le_bytes());
+        state.extend(self.cycles.to_le_bytes());
+
+        let words = [
+            self.pc,
+            self.psr.bits(),
+            self.saved_ssp,
+            self.saved_usp,
+            self.io.interrupt_enable() as u16,
+        ]
+        .into_iter()
+        .chain(self.reg)
+        .chain(self.memory.words().iter().copied());
+        for word in words {
+            state.extend(word.to_le_bytes());
         }
+
+        state
     }
 
-    fn write_mem(&mut self, addr: u16, val: u16) {
-        match addr {
-            // do nothing
-            KBSR | KBDR | DSR => (),
-            DDR => {
-                let mut stdout = stdout().lock();
-                let _ = stdout.write(&[val as u8]).unwrap();
-                stdout.flush().unwrap();
-            }
-            _ => self.memory[addr as usize] = val,
+    /// Restores a snapshot taken by [`Vm::save_state`]. The machine is no longer halted,
+    /// and the call stack and undo history start over, since they don't lead to the
+    /// restored state. Devices, traces and other settings are left as they are.
+    pub fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let snapshot = Snapshot::parse(state)?;
+        if snapshot.memory.len() != self.memory.words().len() {
+            bail!("snapshot has the wrong size");
+        }
+
+        self.instructions = snapshot.instructions;
+        self.cycles = snapshot.cycles;
+        self.scheduler.rewind(self.instructions);
+        self.pc = snapshot.pc;
+        self.psr = Psr::new(snapshot.psr);
+        self.saved_ssp = snapshot.saved_ssp;
+        self.saved_usp = snapshot.saved_usp;
+        self.io.set_interrupt_enable(snapshot.interrupt_enable);
+        self.reg = snapshot.reg;
+        self.memory.words_mut().copy_from_slice(&snapshot.memory);
+        if let Some(initialized) = &mut self.initialized {
+            initialized.set_all();
+        }
+        self.invalidate_decoded(0..self.memory.words().len());
+        self.halted = false;
+        self.call_stack.clear();
+        if let Some(history) = &mut self.history {
+            history.clear();
         }
+
+        Ok(())
     }
 
-    fn set_cc(&mut self, r: usize) {
-        let reg = self.reg[r];
-        self.psr = if reg == 0 {
-            Flag::Zero
-        } else if reg & (1 << 15) != 0 {
-            Flag::Neg
+    /// Loads an .obj image from `file`, see [`Vm::load_image_bytes`], and the symbols
+    /// from the .sym file next to it if there is one.
+    pub fn read_image(&mut self, file: impl AsRef<Path>) -> Result<()> {
+        let data = std::fs::read(&file)?;
+        self.load_image_bytes(&data)?;
+        self.symbols.extend(&SymbolTable::for_image(file)?);
+
+        Ok(())
+    }
+
+    /// Loads the .obj image `file` again after it was reassembled, with its .sym file, so a
+    /// debugging session can go on without restarting the vm. Breakpoints, watchpoints,
+    /// devices and memory outside the image are kept, and the history is forgotten. With
+    /// `keep_registers` the registers, PC and PSR are kept too; otherwise the registers
+    /// are cleared and the PC starts at the origin of the image.
+    pub fn reload_image(&mut self, file: impl AsRef<Path>, keep_registers: bool) -> Result<()> {
+        let (pc, psr, reg) = (self.pc, self.psr, self.reg);
+        self.read_image(file)?;
+
+        if keep_registers {
+            self.pc = pc;
+            self.psr = psr;
+            self.reg = reg;
         } else {
-            Flag::Pos
-        } as u16;
+            self.reg = [0; 8];
+            self.call_stack.clear();
+        }
+        self.halted = false;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+
+        Ok(())
     }
-}
 
-const fn sign_ext(mut val: u16, bits: u16) -> u16 {
-    val &= (1 << bits) - 1;
+    /// Adds labels for the debugger, traces and [`Vm::lookup_symbol`].
+    pub fn add_symbols(&mut self, symbols: &SymbolTable) {
+        self.symbols.extend(symbols);
+    }
 
-    if (val >> (bits - 1) & 1) != 0 {
-        val |= 0xFFFF << bits;
+    /// Makes `name` the only label at `addr`, see [`SymbolTable::rename`].
+    pub fn rename_symbol(&mut self, addr: u16, name: &str) {
+        self.symbols.rename(addr, name);
     }
 
-    val
-}
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
 
-fn is_ready_to_read() -> bool {
-    use nix::sys::{
-        select::*,
-        time::{TimeVal, TimeValLike},
-    };
+    /// Address of the label `name`, from the loaded symbol tables.
+    pub fn lookup_symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.lookup(name)
+    }
 
-    let mut read_fds = FdSet::default();
-    read_fds.insert(std::io::stdin().as_raw_fd());
+    /// Starts counting executed instructions per opcode and address, see
+    /// [`Profile::report`]. Counts from an earlier call are discarded.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+    }
 
-    let mut timeout: TimeVal = TimeValLike::zero();
+    /// The counts since [`Vm::enable_profiling`], if it was called.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
 
-    select(1, &mut read_fds, None, None, &mut timeout).is_ok()
-}
+    /// Starts recording which addresses execute and which way conditional branches go,
+    /// see [`Coverage`]. Counts from an earlier call are discarded.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::default());
+    }
 
-impl Default for Vm {
-    fn default() -> Self {
-        Self::new(0, 0)
+    /// The coverage since [`Vm::enable_coverage`], if it was called.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
     }
-}
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
-enum Opcode {
-    Br = 0b0000,
+    /// Loads several .obj images, e.g. an OS and a user program, each at its own origin,
+    /// with their .sym files. The PC starts at the origin of the last one. Fails if two
+    /// images overlap.
+    pub fn read_images<P: AsRef<Path>>(&mut self, files: &[P]) -> Result<()> {
+        self.read_images_with(files, &LoadOptions::default())
+    }
+
+    /// Like [`Vm::read_images`], for images in another format or byte order.
+    pub fn read_images_with<P: AsRef<Path>>(
+        &mut self,
+        files: &[P],
+        options: &LoadOptions,
+    ) -> Result<()> {
+        let mut loaded: Vec<(Range<usize>, &Path)> = Vec::new();
+
+        for file in files {
+            let file = file.as_ref();
+            let segments = std::fs::read(file)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| loader::decode_file(file, &data, options))
+                .map_err(|err| anyhow!("{}: {err}", file.display()))?;
+
+            for range in segments.iter().map(Segment::range) {
+                let overlap = loaded
+                    .iter()
+                    .find(|(other, _)| other.start < range.end && range.start < other.end);
+                if let Some((other, other_file)) = overlap {
+                    bail!(
+                        "{} (x{:04X}-x{:04X}) overlaps {} (x{:04X}-x{:04X})",
+                        file.display(),
+                        range.start,
+                        range.end - 1,
+                        other_file.display(),
+                        other.start,
+                        other.end - 1
+                    );
+                }
+            }
+
+            self.load_segments(&segments)
+                .map_err(|err| anyhow!("{}: {err}", file.display()))?;
+            self.symbols.extend(&SymbolTable::for_image(file)?);
+            loaded.extend(segments.iter().map(|segment| (segment.range(), file)));
+        }
+
+        Ok(())
+    }
+
+    /// Loads an image in the .obj format: a big-endian origin followed by big-endian words.
+    pub fn load_image_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.load_segments(&image::decode(data, &LoadOptions::default())?)
+    }
+
+    /// Copies `segments` into memory and starts the PC at the first one.
+    pub fn load_segments(&mut self, segments: &[Segment]) -> Result<()> {
+        for segment in segments {
+            let Some(dst) = self.memory.words_mut().get_mut(segment.range()) else {
+                bail!("Image at x{:04X} does not fit in memory", segment.origin);
+            };
+            dst.copy_from_slice(&segment.words);
+            if let Some(initialized) = &mut self.initialized {
+                initialized.set_memory_range(segment.range());
+            }
+            self.invalidate_decoded(segment.range());
+        }
+        if let Some(first) = segments.first() {
+            self.pc = first.origin;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the program until it halts or a step hook stops it. Breakpoints and
+    /// watchpoints are ignored.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        loop {
+            if let RunResult::Halted | RunResult::Stopped = self.step()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes at most `max_instructions` instructions. Returns [`RunResult::Halted`] if
+    /// the program halted, or [`RunResult::Running`] if the budget ran out first, in
+    /// which case it can be continued by calling this again.
+    pub fn run_for(&mut self, max_instructions: u64) -> Result<RunResult, VmError> {
+        for _ in 0..max_instructions {
+            if let result @ (RunResult::Halted | RunResult::Stopped) = self.step()? {
+                return Ok(result);
+            }
+        }
+
+        Ok(RunResult::Running)
+    }
+
+    /// Runs the program like [`Vm::run`] until it halts, a step hook stops it or another
+    /// thread sets `cancel`, e.g. a host's stop button, which is looked at every thousand
+    /// or so instructions. Returns [`RunResult::Stopped`] when cancelled, leaving the vm
+    /// where it stopped, so the run can go on after clearing `cancel`. A program waiting
+    /// for input from a blocking console is only cancelled once the input arrives.
+    pub fn run_with_cancel(&mut self, cancel: &AtomicBool) -> Result<RunResult, VmError> {
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(RunResult::Stopped);
+            }
+            match self.run_for(CANCEL_CHECK_INTERVAL)? {
+                RunResult::Running => (),
+                result => return Ok(result),
+            }
+        }
+    }
+
+    /// Stores `inst` at the PC and executes it, e.g. to fuzz the decoder with arbitrary
+    /// words. Errors are reported like [`Vm::step`]'s.
+    pub fn execute_word(&mut self, inst: u16) -> Result<RunResult, VmError> {
+        self.poke(self.pc, inst);
+        if let Some(err) = self.pending_error.take() {
+            return Err(err);
+        }
+        self.step()
+    }
+
+    /// Executes one instruction, or if it calls a subroutine (JSR, JSRR or a TRAP into the
+    /// OS), runs until the call returns. Stops early like [`Vm::resume`], and returns
+    /// [`RunResult::Running`] once back at the caller.
+    pub fn step_over(&mut self) -> Result<RunResult, VmError> {
+        self.run_to_depth(self.call_depth())
+    }
+
+    /// Runs until the current subroutine, trap routine or handler returns, stopping early
+    /// like [`Vm::resume`]. Outside of any call this executes one instruction.
+    pub fn step_out(&mut self) -> Result<RunResult, VmError> {
+        self.run_to_depth(self.call_depth().saturating_sub(1))
+    }
+
+    fn run_to_depth(&mut self, depth: usize) -> Result<RunResult, VmError> {
+        loop {
+            match self.step()? {
+                RunResult::Running => (),
+                result => return Ok(result),
+            }
+            if self.call_depth() <= depth {
+                return Ok(RunResult::Running);
+            }
+            if self.at_breakpoint() {
+                return Ok(RunResult::Breakpoint(self.pc));
+            }
+        }
+    }
+
+    /// Remembers what the last `limit` instructions changed, so they can be undone with
+    /// [`Vm::step_back`]. Registers, the PC, the PSR and memory are restored; device state,
+    /// console output and consumed input are not.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    /// How many instructions [`Vm::step_back`] can undo.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    /// Undoes the last executed instruction. Returns `false` if there is no history left.
+    pub fn step_back(&mut self) -> bool {
+        let Some(delta) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+
+        for &(addr, old) in delta.memory.iter().rev() {
+            self.memory.write(addr, old);
+            self.invalidate_decoded(addr as usize..addr as usize + 1);
+        }
+        self.pc = delta.pc;
+        self.psr = Psr::new(delta.psr);
+        self.reg = delta.reg;
+        self.saved_ssp = delta.saved_ssp;
+        self.saved_usp = delta.saved_usp;
+        self.instructions = delta.instructions;
+        self.scheduler.rewind(self.instructions);
+        self.cycles = delta.cycles;
+        // an instruction pops at most one frame
+        self.call_stack
+            .truncate(delta.call_stack_len.saturating_sub(1));
+        self.call_stack.extend(delta.call_stack_top);
+        self.halted = false;
+
+        true
+    }
+
+    /// Steps back until the PC is at a breakpoint and returns it, or returns `None` once
+    /// the history runs out. Always undoes at least one instruction.
+    pub fn reverse_resume(&mut self) -> Option<u16> {
+        while self.step_back() {
+            if self.at_breakpoint() {
+                return Some(self.pc);
+            }
+        }
+
+        None
+    }
+
+    /// Calls that haven't returned yet: JSR, JSRR, TRAPs into the OS, interrupts and
+    /// exceptions count up, RET and RTI count down.
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// The calls that haven't returned yet, innermost first, see [`Vm::call_depth`].
+    pub fn backtrace(&self) -> impl Iterator<Item = &Frame> {
+        self.call_stack.iter().rev()
+    }
+
+    /// Runs until the program halts, reaches a breakpoint or hits a watchpoint. The
+    /// instruction at the current PC always executes, so resuming from a breakpoint makes
+    /// progress.
+    pub fn resume(&mut self) -> Result<RunResult, VmError> {
+        loop {
+            match self.step()? {
+                RunResult::Running => (),
+                result => return Ok(result),
+            }
+            if self.at_breakpoint() {
+                return Ok(RunResult::Breakpoint(self.pc));
+            }
+        }
+    }
+
+    /// Calls `hook` with each instruction before it executes, for tracing, coverage,
+    /// fault injection or custom break conditions. See [`HookAction`] for what the hook
+    /// can make [`Vm::step`] do.
+    pub fn set_pre_step_hook(&mut self, hook: impl FnMut(&Vm, u16) -> HookAction + 'static) {
+        self.pre_step_hook = Some(Box::new(hook));
+    }
+
+    /// Calls `hook` with each instruction after it executed, unless it halted the
+    /// machine or failed.
+    pub fn set_post_step_hook(&mut self, hook: impl FnMut(&Vm, u16) -> HookAction + 'static) {
+        self.post_step_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_step_hooks(&mut self) {
+        self.pre_step_hook = None;
+        self.post_step_hook = None;
+    }
+
+    /// Returns `false` if there already was a breakpoint at `addr`, which loses its
+    /// condition.
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr, None).is_none()
+    }
+
+    /// Adds a breakpoint at `addr` that only stops when `condition` holds before the
+    /// instruction there executes, replacing one already at `addr`.
+    pub fn add_conditional_breakpoint(&mut self, addr: u16, condition: Predicate) {
+        self.breakpoints.insert(addr, Some(condition));
+    }
+
+    /// Returns `false` if there was no breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Breakpoint addresses in ascending order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    /// The condition of the breakpoint at `addr`, if it has one.
+    pub fn breakpoint_condition(&self, addr: u16) -> Option<&Predicate> {
+        self.breakpoints.get(&addr)?.as_ref()
+    }
+
+    /// Stops before every `TRAP vector`. Returns `false` if there already was one.
+    pub fn add_trap_breakpoint(&mut self, vector: u8) -> bool {
+        self.trap_breakpoints.insert(vector)
+    }
+
+    pub fn remove_trap_breakpoint(&mut self, vector: u8) -> bool {
+        self.trap_breakpoints.remove(&vector)
+    }
+
+    pub fn trap_breakpoints(&self) -> impl Iterator<Item = u8> + '_ {
+        self.trap_breakpoints.iter().copied()
+    }
+
+    /// Stops before any instruction at which `condition` holds, e.g. once a variable in
+    /// memory reaches a value. Checking it slows running down.
+    pub fn add_break_condition(&mut self, condition: Predicate) {
+        self.break_conditions.push(condition);
+    }
+
+    /// Removes the `index`th condition added by [`Vm::add_break_condition`].
+    pub fn remove_break_condition(&mut self, index: usize) -> Option<Predicate> {
+        (index < self.break_conditions.len()).then(|| self.break_conditions.remove(index))
+    }
+
+    pub fn break_conditions(&self) -> &[Predicate] {
+        &self.break_conditions
+    }
+
+    /// Whether a breakpoint stops the program before the instruction at the PC.
+    fn at_breakpoint(&self) -> bool {
+        let condition = match self.breakpoints.get(&self.pc) {
+            Some(None) => return true,
+            Some(Some(condition)) => Some(condition),
+            None => None,
+        };
+        if condition.is_none()
+            && self.trap_breakpoints.is_empty()
+            && self.break_conditions.is_empty()
+        {
+            return false;
+        }
+
+        let inst = self.memory.read(self.pc).unwrap_or_default();
+        if let Ok(Instruction::Trap { vector }) = decode(inst) {
+            if self.trap_breakpoints.contains(&vector) {
+                return true;
+            }
+        }
+        condition
+            .into_iter()
+            .chain(&self.break_conditions)
+            .any(|condition| self.holds(condition))
+    }
+
+    /// Whether `condition` holds in the current state, before the instruction at the PC.
+    pub fn holds(&self, condition: &Predicate) -> bool {
+        let state = TraceRecord {
+            pc: self.pc,
+            inst: self.memory.read(self.pc).unwrap_or_default(),
+            reg: self.reg,
+            psr: self.psr.bits(),
+            write: None,
+        };
+        let context = Context {
+            memory: self.memory.words(),
+            frames: &self.call_stack,
+            symbols: &self.symbols,
+        };
+        condition.eval(&state, &context)
+    }
+
+    /// Makes [`Vm::step`] return [`RunResult::Watchpoint`] after an instruction reads or
+    /// writes `addr`, depending on `kind`. Instruction fetches don't count. Replaces an
+    /// existing watchpoint at `addr`.
+    pub fn add_watchpoint(&mut self, addr: u16, kind: WatchKind) {
+        self.watchpoints.insert(addr, kind);
+    }
+
+    /// Returns `false` if there was no watchpoint at `addr`.
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    /// Watchpoints in ascending address order.
+    pub fn watchpoints(&self) -> impl Iterator<Item = (u16, WatchKind)> + '_ {
+        self.watchpoints.iter().map(|(&addr, &kind)| (addr, kind))
+    }
+
+    /// Protects `range` from the program's loads, stores and instruction fetches, e.g.
+    /// `0x0000..=0x2FFF` as [`Protection::ReadOnly`] to keep a program from overwriting
+    /// the OS. A violation raises an access control violation exception, or stops the
+    /// program with [`VmError::ProtectionFault`] if no handler is installed. The checks
+    /// apply in both privilege modes, but not to native trap routines or to the stack
+    /// pushes of exceptions and interrupts.
+    pub fn protect(&mut self, range: RangeInclusive<u16>, protection: Protection) {
+        self.protections.push((range, protection));
+    }
+
+    /// Protected ranges in the order they were added.
+    pub fn protections(&self) -> &[(RangeInclusive<u16>, Protection)] {
+        &self.protections
+    }
+
+    pub fn clear_protections(&mut self) {
+        self.protections.clear();
+    }
+
+    /// Checks that every RET returns to the instruction after its call: JSR, JSRR or a
+    /// TRAP into the OS. Mismatches are [`Warning`]s or errors depending on `check`.
+    pub fn set_r7_check(&mut self, check: R7Check) {
+        self.r7_check = check;
+    }
+
+    /// Stops with [`VmError::SpecViolation`] before executing an instruction whose unused
+    /// bits aren't what the LC-3 specification requires, e.g. `ADD` with bits 4-3 set,
+    /// instead of ignoring them like hardware does. Privilege and the reserved opcode are
+    /// checked either way.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn r7_check(&self) -> R7Check {
+        self.r7_check
+    }
+
+    /// Returns the warnings recorded since the last call, oldest first.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Calls `handler` with each warning as it happens instead of recording it, e.g. to
+    /// report it while a program that went wrong is still looping.
+    pub fn set_warning_handler(&mut self, handler: impl FnMut(Warning) + 'static) {
+        self.warning_handler = Some(Box::new(handler));
+    }
+
+    /// Warns about instructions that use a register that was never written or load from
+    /// an address that was never stored to, with [`Warning::UninitializedRegister`] and
+    /// [`Warning::UninitializedMemory`], once per instruction and register or address.
+    /// Enable it before loading images, since loading is what initializes memory. Storing
+    /// a register doesn't count as using it, so routines can save registers they don't
+    /// know the state of, and neither does clearing it with `AND R, R, #0`.
+    pub fn enable_uninit_detection(&mut self) {
+        self.initialized = Some(Initialized::new(self.memory.words().len()));
+    }
+
+    /// Warns with [`Warning::SelfModifyingCode`] when the program stores to an address it
+    /// executed an instruction from, usually a stray ST into its own code, once per
+    /// instruction and address. Only stores by instructions count, not input the traps
+    /// write to memory.
+    pub fn enable_smc_detection(&mut self) {
+        self.executed = Some(Executed::new(self.memory.words().len()));
+    }
+
+    fn warn(&mut self, warning: Warning) {
+        match &mut self.warning_handler {
+            Some(handler) => handler(warning),
+            None => self.warnings.push(warning),
+        }
+    }
+
+    /// Runs the program like [`Vm::run`], sending what it does to `sender` as it goes, so
+    /// another thread can follow it live. With [`Delivery::Lossy`], instructions and
+    /// memory writes the receiver can't keep up with are dropped instead of slowing the
+    /// program down. Replaces any output tap of the console.
+    pub fn run_with_events(
+        &mut self,
+        sender: &SyncSender<Event>,
+        delivery: Delivery,
+    ) -> Result<(), VmError> {
+        events::run(self, sender, delivery)
+    }
+
+    /// Calls `tap` with every byte the program writes to the console, or stops calling a
+    /// tap if `None`. Returns the tap it replaces.
+    pub(crate) fn set_output_tap(&mut self, tap: Option<OutputTap>) -> Option<OutputTap> {
+        self.io.set_tap(tap)
+    }
+
+    /// Executes instructions one at a time, yielding what each one did. Stops after the
+    /// program halts, a step hook stops it or an error is yielded. Instructions a
+    /// pre-step hook stops or skips didn't execute, so they aren't yielded.
+    pub fn iter_steps(&mut self) -> Steps<'_> {
+        Steps::new(self)
+    }
+
+    /// Memory reads and writes done by the last executed instruction, excluding its fetch.
+    pub fn last_accesses(&self) -> &[MemAccess] {
+        &self.accesses
+    }
+
+    /// The address and word of the instruction the last [`Vm::step`] executed, which is
+    /// the first instruction of a handler when the step took an interrupt or exception.
+    /// `None` if it executed none, e.g. because a pre-step hook stopped or skipped it.
+    pub fn last_executed(&self) -> Option<(u16, u16)> {
+        self.last_executed
+    }
+
+    /// The registers right before the instruction of [`Vm::last_executed`].
+    pub(crate) fn registers_before(&self) -> &[u16; 8] {
+        &self.reg_before
+    }
+
+    /// Executes one instruction, returning [`RunResult::Halted`] if it was HALT, or
+    /// [`RunResult::Watchpoint`] if it accessed a watched address.
+    pub fn step(&mut self) -> Result<RunResult, VmError> {
+        self.last_executed = None;
+        if let Some(limit) = self.max_instructions {
+            if self.instructions >= limit {
+                return Err(VmError::InstructionLimit(limit));
+            }
+        }
+        if let Some(limit) = self.max_cycles {
+            if self.cycles >= limit {
+                return Err(VmError::CycleLimit(limit));
+            }
+        }
+
+        if let Some(history) = &mut self.history {
+            history.begin(Delta {
+                pc: self.pc,
+                psr: self.psr.bits(),
+                reg: self.reg,
+                saved_ssp: self.saved_ssp,
+                saved_usp: self.saved_usp,
+                instructions: self.instructions,
+                cycles: self.cycles,
+                call_stack_len: self.call_stack.len(),
+                call_stack_top: self.call_stack.last().copied(),
+                memory: Vec::new(),
+            });
+        }
+
+        if !self.clock_running {
+            self.set_clock(true);
+        }
+
+        self.io.set_instructions(self.instructions);
+        if let Some(interrupt) = self.devices.interrupt() {
+            if interrupt.priority > self.psr.priority() {
+                self.enter_handler(interrupt.vector, Some(interrupt.priority))?;
+            }
+        }
+
+        // a faulting fetch enters the handler, whose first instruction runs instead
+        self.check_protection(self.pc, Access::Execute, self.pc)?;
+
+        let pc = self.pc;
+        let (inst, decoded) = self.fetch();
+        // only data accesses are reported, not the fetch
+        self.accesses.clear();
+        self.watch_hit = None;
+
+        if let Some(mut hook) = self.pre_step_hook.take() {
+            let action = hook(self, inst);
+            self.pre_step_hook = Some(hook);
+            match action {
+                HookAction::Continue => (),
+                HookAction::Stop => return Ok(RunResult::Stopped),
+                HookAction::SkipInstruction => {
+                    self.pc = self.pc.wrapping_add(1);
+                    return Ok(RunResult::Running);
+                }
+            }
+        }
+        self.last_executed = Some((pc, inst));
+        self.reg_before = self.reg;
+        self.calls_before = (self.call_stack.len(), self.call_stack.last().copied());
+        if self.strict {
+            if let Some(reason) = spec_violation(inst) {
+                return Err(VmError::SpecViolation { inst, pc, reason });
+            }
+        }
+        self.check_register_reads(pc, inst);
+        if let Some(executed) = &mut self.executed {
+            executed.insert(pc);
+        }
+
+        info!("inst: {inst:#x} pc: {:#x}", self.pc);
+        if let Some(profile) = &mut self.profile {
+            profile.record_stack(pc, &self.call_stack);
+        }
+
+        self.pc = self.pc.wrapping_add(1);
+        self.instructions += 1;
+        self.cycles += self.cycle_model.opcodes[(inst >> 12) as usize];
+
+        match decoded {
+            Ok(Instruction::Br { nzp, offset }) => {
+                let current_nzp = self.psr.cc();
+
+                info!(
+                    "Br current: {}, desired: {}, offset: {:#x}",
+                    current_nzp, nzp, offset
+                );
+
+                if nzp & current_nzp != 0 {
+                    self.pc = self.pc.wrapping_add_signed(offset);
+                }
+            }
+            Ok(Instruction::Add { dr, sr1, src2 }) => {
+                info!("Add r{dr}, r{sr1}, {src2:?}");
+
+                self.reg[dr] = self.reg[sr1].wrapping_add(self.operand(src2));
+                self.set_cc(dr);
+            }
+            Ok(Instruction::Ld { dr, offset }) => {
+                info!("Ld r{dr}, offset: {:#x}", offset);
+
+                let addr = self.pc.wrapping_add_signed(offset);
+                if self.check_access(addr, Access::Read)? {
+                    self.check_memory_read(addr);
+                    self.reg[dr] = self.read_mem(addr);
+                    self.set_cc(dr);
+                }
+            }
+            Ok(Instruction::St { sr, offset }) => {
+                info!("St r{sr} offset: {:#x}", offset);
+
+                let addr = self.pc.wrapping_add_signed(offset);
+                if self.check_access(addr, Access::Write)? {
+                    self.write_mem(addr, self.reg[sr]);
+                }
+            }
+            Ok(Instruction::Jsr { offset }) => {
+                info!("Jsr offset: {:#x}", offset);
+
+                self.call_subroutine(self.pc.wrapping_add_signed(offset));
+            }
+            Ok(Instruction::Jsrr { base }) => {
+                let br_val = self.reg[base];
+
+                info!("Jsr br_val: {}", br_val);
+                self.call_subroutine(br_val);
+            }
+            Ok(Instruction::And { dr, sr1, src2 }) => {
+                info!("And r{dr}, r{sr1}, {src2:?}");
+
+                self.reg[dr] = self.reg[sr1] & self.operand(src2);
+                self.set_cc(dr);
+            }
+            Ok(Instruction::Ldr { dr, base, offset }) => {
+                info!("Ldr r{dr}, br: {base}, offset: {:#x}", offset);
+
+                let addr = self.reg[base].wrapping_add_signed(offset);
+                if self.check_access(addr, Access::Read)? {
+                    self.check_memory_read(addr);
+                    self.reg[dr] = self.read_mem(addr);
+                    self.set_cc(dr);
+                }
+            }
+            Ok(Instruction::Str { sr, base, offset }) => {
+                info!("Str r{sr}, br: {base}, offset: {:#x}", offset);
+
+                let addr = self.reg[base].wrapping_add_signed(offset);
+                if self.check_access(addr, Access::Write)? {
+                    self.write_mem(addr, self.reg[sr]);
+                }
+            }
+            Ok(Instruction::Not { dr, sr }) => {
+                info!("Not r{dr}, r{sr}");
+
+                self.reg[dr] = !self.reg[sr];
+
+                self.set_cc(dr);
+            }
+            Ok(Instruction::Ldi { dr, offset }) => {
+                info!("Ldi r{dr} offset: {:#x}", offset);
+
+                let pointer = self.pc.wrapping_add_signed(offset);
+                if self.check_access(pointer, Access::Read)? {
+                    self.check_memory_read(pointer);
+                    let addr = self.read_mem(pointer);
+                    if self.check_access(addr, Access::Read)? {
+                        self.check_memory_read(addr);
+                        self.reg[dr] = self.read_mem(addr);
+                        self.set_cc(dr);
+                    }
+                }
+            }
+            Ok(Instruction::Sti { sr, offset }) => {
+                info!("Sti r{sr} offset: {:#x}", offset);
+
+                let pointer = self.pc.wrapping_add_signed(offset);
+                if self.check_access(pointer, Access::Read)? {
+                    self.check_memory_read(pointer);
+                    let addr = self.read_mem(pointer);
+                    if self.check_access(addr, Access::Write)? {
+                        self.write_mem(addr, self.reg[sr]);
+                    }
+                }
+            }
+            Ok(Instruction::Jmp { base }) => {
+                info!("Jmp {base}");
+
+                self.pc = self.reg[base];
+                if base == 7 {
+                    self.check_return(pc)?;
+                }
+            }
+            Ok(Instruction::Lea { dr, offset }) => {
+                info!("Lea r{dr} offset: {:#x}", offset);
+
+                self.reg[dr] = self.pc.wrapping_add_signed(offset);
+                self.set_cc(dr);
+            }
+            Ok(Instruction::Trap { vector }) => {
+                // implement traps in assembly or rust?
+                self.reg[7] = self.pc;
+                self.mark_register(7);
+
+                let trap = vector as u16;
+                info!("Trap {trap}");
+
+                if let Some(mut routine) = self.host_traps.remove(&vector) {
+                    // taken out so it can borrow the vm mutably
+                    let result = routine.call(self, vector);
+                    self.host_traps.entry(vector).or_insert(routine);
+                    result?;
+                } else if self.trap_mode == TrapMode::Os {
+                    self.pc = self.read_mem(trap);
+                } else {
+                    self.native_trap(trap)?;
+                }
+                // native routines return right away, routines in memory are calls
+                if self.pc != pc.wrapping_add(1) {
+                    self.enter_call(CallKind::Trap(vector), pc);
+                }
+            }
+            Ok(Instruction::Rti) => {
+                info!("Rti");
+
+                if self.psr.is_user() {
+                    self.enter_handler(PRIVILEGE_VIOLATION, None)?;
+                } else {
+                    self.pc = self.pop();
+                    self.psr = Psr::new(self.pop());
+                    self.call_stack.pop();
+
+                    if self.psr.is_user() {
+                        self.saved_ssp = self.reg[6];
+                        self.reg[6] = self.saved_usp;
+                        self.mark_register(6);
+                    }
+                }
+            }
+            Err(DecodeError { inst }) => {
+                if self.ivt_entry(ILLEGAL_OPCODE) == 0 {
+                    return Err(VmError::BadOpcode { inst, pc });
+                }
+                self.enter_handler(ILLEGAL_OPCODE, None)?;
+            }
+        }
+
+        let running = self.clock_running;
+
+        if let Some(trace) = &mut self.trace {
+            let record = TraceRecord {
+                pc,
+                inst,
+                reg: self.reg,
+                psr: self.psr.bits(),
+                write: self.accesses.iter().rev().find_map(|access| match *access {
+                    MemAccess::Write { addr, val } => Some((addr, val)),
+                    MemAccess::Read { .. } => None,
+                }),
+            };
+
+            let wanted = match &self.trace_when {
+                Some(predicate) => {
+                    // `in` is about the routine the instruction ran in, so the JSR
+                    // calling it doesn't count and its RET does
+                    let context = Context {
+                        memory: self.memory.words(),
+                        frames: &frames_before(&self.call_stack, self.calls_before),
+                        symbols: &self.symbols,
+                    };
+                    predicate.eval(&record, &context)
+                }
+                None => true,
+            };
+            if wanted {
+                trace.record(&record)?;
+            }
+        }
+
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, inst, self.pc);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, inst, self.psr.cc());
+        }
+
+        if let Some(pacer) = &mut self.pacer {
+            pacer.wait(self.instructions);
+        }
+        if self.scheduler.is_due(self.instructions) {
+            let mut scheduler = std::mem::take(&mut self.scheduler);
+            scheduler.run_due(self.instructions, self);
+            scheduler.merge(std::mem::take(&mut self.scheduler));
+            self.scheduler = scheduler;
+        }
+
+        if self.io.input_exhausted() {
+            return Err(VmError::InputExhausted { pc });
+        }
+        match self.pending_error.take() {
+            Some(VmError::MemoryFault { addr, .. }) => {
+                return Err(VmError::MemoryFault { addr, pc })
+            }
+            Some(err) => return Err(err),
+            None => (),
+        }
+
+        self.halted = !running;
+        if !running {
+            if let Some(trace) = &mut self.trace {
+                trace.flush()?;
+            }
+        }
+
+        let mut stopped = false;
+        if running {
+            if let Some(mut hook) = self.post_step_hook.take() {
+                stopped = hook(self, inst) == HookAction::Stop;
+                self.post_step_hook = Some(hook);
+            }
+        }
+
+        Ok(if !running {
+            RunResult::Halted
+        } else if stopped {
+            RunResult::Stopped
+        } else if let Some(hit) = self.watch_hit.take() {
+            hit
+        } else {
+            RunResult::Running
+        })
+    }
+
+    /// Runs the Rust implementation of `trap`. HALT stops the clock.
+    fn native_trap(&mut self, trap: u16) -> Result<(), VmError> {
+        match trap {
+            GETC => {
+                self.reg[0] = self.io.read_key(false)? as u16;
+                self.set_cc(0);
+            }
+            OUT => {
+                let byte = self.reg[0] as u8;
+                self.io.write(&[byte])?;
+            }
+            PUTS => {
+                let Some(words) = self.read_string_words(self.reg[0]) else {
+                    return Ok(());
+                };
+                let bytes: Vec<_> = words.iter().map(|&word| word as u8).collect();
+
+                self.io.write(&bytes)?;
+                self.io.flush()?;
+            }
+            IN => {
+                self.io.write(b"Enter a character: ")?;
+                self.io.flush()?;
+
+                let ch = self.io.read_key(true)?;
+                self.reg[0] = ch as u16;
+                self.set_cc(0);
+            }
+            PUTSP => {
+                let Some(words) = self.read_string_words(self.reg[0]) else {
+                    return Ok(());
+                };
+
+                let mut bytes = Vec::new();
+                for word in words {
+                    let [lo, hi] = u16::to_le_bytes(word);
+                    bytes.push(lo);
+                    if hi != 0 {
+                        bytes.push(hi);
+                    }
+                }
+
+                self.io.write(&bytes)?;
+                self.io.flush()?;
+            }
+            GETS => {
+                let buf = self.reg[0];
+                let line = self.read_line(self.reg[1] as usize)?;
+
+                let mut addr = buf;
+                for &byte in &line {
+                    self.poke(addr, byte as u16);
+                    addr = addr.wrapping_add(1);
+                }
+                self.poke(addr, 0);
+
+                self.reg[1] = line.len() as u16;
+                self.set_cc(1);
+            }
+            PUTD => {
+                self.io.write((self.reg[0] as i16).to_string().as_bytes())?;
+                self.io.flush()?;
+            }
+            GETD => {
+                // "-32768" is the longest valid input
+                let line = self.read_line(6)?;
+                let line = String::from_utf8_lossy(&line);
+
+                (self.reg[0], self.reg[1]) = match line.trim().parse::<i16>() {
+                    Ok(n) => (n as u16, 0),
+                    Err(_) => (0, 0xFFFF),
+                };
+                self.mark_register(1);
+                self.set_cc(0);
+            }
+            GETENV if self.getenv => {
+                let name = self.string_at(self.reg[0]);
+                let buf = self.reg[1];
+                let size = self.reg[2] as usize;
+
+                self.reg[0] = match std::env::var_os(&name) {
+                    Some(value) if size > 0 => {
+                        let value = value.to_string_lossy();
+                        let mut len = value.len().min(size - 1);
+                        while !value.is_char_boundary(len) {
+                            len -= 1;
+                        }
+
+                        let mut addr = buf;
+                        for &byte in &value.as_bytes()[..len] {
+                            self.poke(addr, byte as u16);
+                            addr = addr.wrapping_add(1);
+                        }
+                        self.poke(addr, 0);
+
+                        len as u16
+                    }
+                    Some(_) => 0,
+                    None => 0xFFFF,
+                };
+                self.set_cc(0);
+            }
+            HALT => {
+                self.io.write(b"HALT\n")?;
+                self.io.flush()?;
+                self.set_clock(false);
+            }
+            _ => self.unknown_trap(trap)?,
+        }
+
+        Ok(())
+    }
+
+    fn unknown_trap(&mut self, trap: u16) -> Result<(), VmError> {
+        match &mut self.unknown_trap {
+            UnknownTrap::Vector => {
+                self.pc = self.read_mem(trap);
+            }
+            UnknownTrap::Error => {
+                return Err(VmError::BadTrap {
+                    trap,
+                    pc: self.pc.wrapping_sub(1),
+                })
+            }
+            UnknownTrap::Handler(_) => {
+                // take the handler out so it can borrow the vm mutably
+                let mut handler = std::mem::replace(&mut self.unknown_trap, UnknownTrap::Error);
+                let result = match &mut handler {
+                    UnknownTrap::Handler(routine) => routine.call(self, trap as u8),
+                    _ => Ok(()),
+                };
+                self.unknown_trap = handler;
+                result?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a NUL-terminated string stored one character per word.
+    pub(crate) fn string_at(&self, addr: u16) -> String {
+        // running off the end of memory or all the way around it ends the string
+        (0..MEMORY_SIZE)
+            .map_while(|i| self.memory.read(addr.wrapping_add(i as u16)))
+            .take_while(|&word| word != 0)
+            .map(|word| word as u8 as char)
+            .collect()
+    }
+
+    /// The handler address for `vector` in the interrupt vector table, 0 if memory is
+    /// too short to hold it.
+    fn ivt_entry(&self, vector: u8) -> u16 {
+        self.memory.read(IVT + vector as u16).unwrap_or(0)
+    }
+
+    /// Reads a line of at most `max` characters with echo and backspace handling.
+    /// The terminating newline is echoed but not returned, and EOT ends the line too.
+    fn read_line(&mut self, max: usize) -> std::io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+
+        loop {
+            let ch = self.io.read_key(false)?;
+            if self.io.input_exhausted() || ch == EOT {
+                return Ok(line);
+            }
+
+            match ch {
+                b'\r' | b'\n' => {
+                    self.io.write(b"\n")?;
+                    self.io.flush()?;
+                    return Ok(line);
+                }
+                // backspace and DEL
+                0x08 | 0x7F if !line.is_empty() => {
+                    line.pop();
+                    self.io.write(b"\x08 \x08")?;
+                }
+                _ if line.len() < max && !ch.is_ascii_control() => {
+                    line.push(ch);
+                    self.io.write(&[ch])?;
+                }
+                _ => {}
+            }
+
+            self.io.flush()?;
+        }
+    }
+
+    fn read_mem(&mut self, addr: u16) -> u16 {
+        self.cycles += self.cycle_model.memory_read + self.device_penalty(addr);
+
+        let val = self.load(addr);
+        self.accesses.push(MemAccess::Read { addr, val });
+        if matches!(self.watchpoints.get(&addr), Some(kind) if kind.reads()) {
+            self.watch_hit.get_or_insert(RunResult::Watchpoint {
+                addr,
+                old: val,
+                new: val,
+            });
+        }
+        val
+    }
+
+    /// The extra cycles an access to `addr` costs if it is a device register.
+    fn device_penalty(&mut self, addr: u16) -> u64 {
+        match self.cycle_model.device_access {
+            0 => 0,
+            penalty if self.devices.get(addr).is_some() => penalty,
+            _ => 0,
+        }
+    }
+
+    /// Reads a device register or memory word.
+    fn load(&mut self, addr: u16) -> u16 {
+        if let Some(device) = self.devices.get(addr) {
+            return device.read(addr);
+        }
+
+        match addr {
+            INSTCNT_LO => {
+                self.counter_latch = (self.instructions >> 16) as u16;
+                self.instructions as u16
+            }
+            CYCCNT_LO => {
+                self.counter_latch = (self.cycles >> 16) as u16;
+                self.cycles as u16
+            }
+            CLOCK_MS => {
+                let ms = self.clock_ms();
+                self.counter_latch = (ms >> 16) as u16;
+                ms as u16
+            }
+            INSTCNT_HI | CYCCNT_HI | CLOCK_MS_HI => self.counter_latch,
+            MCR => match self.memory.read(MCR) {
+                Some(val) => val | MCR_CLOCK,
+                None => {
+                    self.fault(addr);
+                    0
+                }
+            },
+            _ => match self.memory.read(addr) {
+                Some(val) => val,
+                None => {
+                    self.fault(addr);
+                    0
+                }
+            },
+        }
+    }
+
+    fn write_mem(&mut self, addr: u16, val: u16) {
+        self.cycles += self.cycle_model.memory_write + self.device_penalty(addr);
+        self.accesses.push(MemAccess::Write { addr, val });
+        if matches!(self.watchpoints.get(&addr), Some(kind) if kind.writes()) {
+            let old = self.memory.read(addr).unwrap_or_default();
+            self.watch_hit.get_or_insert(RunResult::Watchpoint {
+                addr,
+                old,
+                new: val,
+            });
+        }
+        if let Some(executed) = &mut self.executed {
+            // the PC has already moved past the instruction
+            let pc = self.pc.wrapping_sub(1);
+            if executed.contains(addr) && executed.first_report(pc, addr) {
+                self.warn(Warning::SelfModifyingCode { pc, addr });
+            }
+        }
+
+        self.store(addr, val);
+    }
+
+    /// Writes a device register or memory word.
+    fn store(&mut self, addr: u16, val: u16) {
+        if let Some(device) = self.devices.get(addr) {
+            if let Err(err) = device.write(addr, val) {
+                self.pending_error.get_or_insert(VmError::Io(err));
+            }
+            return;
+        }
+
+        match addr {
+            // do nothing
+            INSTCNT_LO | INSTCNT_HI | CYCCNT_LO | CYCCNT_HI | CLOCK_MS | CLOCK_MS_HI => (),
+            MCR => {
+                self.poke(addr, val);
+                if val & MCR_CLOCK == 0 {
+                    self.clock_running = false;
+                }
+            }
+            _ => self.poke(addr, val),
+        }
+    }
+
+    /// The words of the string at `start` up to its terminating x0000, continuing at x0000
+    /// past xFFFF. Reports a memory fault and returns `None` if the string runs into an
+    /// address outside of memory or has no terminator at all.
+    fn read_string_words(&mut self, start: u16) -> Option<Vec<u16>> {
+        match self.string_words(start) {
+            Ok(words) => Some(words),
+            Err(err) => {
+                self.pending_error.get_or_insert(err);
+                None
+            }
+        }
+    }
+
+    /// Like [`Vm::read_string_words`], returning the fault instead of reporting it.
+    fn string_words(&self, start: u16) -> Result<Vec<u16>, VmError> {
+        let mut words = Vec::new();
+        let mut addr = start;
+        loop {
+            match self.memory.read(addr) {
+                Some(0) => return Ok(words),
+                Some(word) => words.push(word),
+                None => break,
+            }
+            addr = addr.wrapping_add(1);
+            if addr == start {
+                break;
+            }
+        }
+
+        Err(VmError::MemoryFault { addr, pc: self.pc })
+    }
+
+    /// Stores `words` and a terminating x0000 from `start` on.
+    fn write_string_words(&mut self, start: u16, words: &[u16]) -> Result<(), VmError> {
+        for (i, &word) in words.iter().chain(&[0]).enumerate() {
+            let addr = start.wrapping_add(i as u16);
+            if !self.set_memory(addr, word) {
+                return Err(VmError::MemoryFault { addr, pc: self.pc });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and decodes the instruction at the PC, from the cache if the engine has one.
+    fn fetch(&mut self) -> Decoded {
+        let pc = self.pc as usize;
+        if let Some(&Some(cached)) = self.decoded.as_ref().and_then(|decoded| decoded.get(pc)) {
+            // only memory is cached, so there is no device penalty
+            self.cycles += self.cycle_model.memory_read;
+            return cached;
+        }
+
+        let inst = self.read_mem(self.pc);
+        let fetched = (inst, decode(inst));
+        let cacheable = self.pc < USER_SPACE.end && self.devices.get(self.pc).is_none();
+        if self.decoded.is_some() && cacheable {
+            if let Some(entry) = self
+                .decoded
+                .as_mut()
+                .and_then(|decoded| decoded.get_mut(pc))
+            {
+                *entry = Some(fetched);
+            }
+        }
+        fetched
+    }
+
+    /// Stores `val` in memory, bypassing devices.
+    pub(crate) fn poke(&mut self, addr: u16, val: u16) {
+        let Some(old) = self.memory.read(addr) else {
+            self.fault(addr);
+            return;
+        };
+        if let Some(history) = &mut self.history {
+            history.store(addr, old);
+        }
+        if let Some(initialized) = &mut self.initialized {
+            initialized.set_memory(addr);
+        }
+        self.memory.write(addr, val);
+        self.invalidate_decoded(addr as usize..addr as usize + 1);
+    }
+
+    /// Starts or stops the clock, keeping bit 15 of the MCR in step when memory has it.
+    fn set_clock(&mut self, running: bool) {
+        self.clock_running = running;
+        let Some(mcr) = self.memory.read(MCR) else {
+            return;
+        };
+        let new = if running {
+            mcr | MCR_CLOCK
+        } else {
+            mcr & !MCR_CLOCK
+        };
+        if new != mcr {
+            self.poke(MCR, new);
+        }
+    }
+
+    /// Reports a memory fault at `addr` after the current instruction.
+    fn fault(&mut self, addr: u16) {
+        self.pending_error
+            .get_or_insert(VmError::MemoryFault { addr, pc: self.pc });
+    }
+
+    fn clock_ms(&self) -> u64 {
+        match self.clock_mode {
+            ClockMode::Host => self.env.borrow_mut().now_ms(self.instructions),
+            ClockMode::Deterministic { insts_per_ms } => self.instructions / insts_per_ms.max(1),
+        }
+    }
+
+    /// Whether the running instruction may access `addr`. User mode code may not touch
+    /// x0000-x2FFF (the OS) and xFE00-xFFFF (devices); trying to raises an access
+    /// control violation. Only data accesses are checked, so TRAP can still jump into
+    /// the OS in [`TrapMode::Os`]. Ranges protected with [`Vm::protect`] are checked
+    /// too.
+    fn check_access(&mut self, addr: u16, access: Access) -> Result<bool, VmError> {
+        if self.psr.is_user() && !USER_SPACE.contains(&addr) {
+            self.enter_handler(ACCESS_VIOLATION, None)?;
+            return Ok(false);
+        }
+
+        // the PC has already moved past the instruction
+        self.check_protection(addr, access, self.pc.wrapping_sub(1))
+    }
+
+    /// Whether `access` to `addr` is allowed by the protected ranges. If not, enters the
+    /// access control violation handler, or fails if there is none.
+    fn check_protection(&mut self, addr: u16, access: Access, pc: u16) -> Result<bool, VmError> {
+        let Some(protection) = self.denying_protection(addr, access) else {
+            return Ok(true);
+        };
+
+        if self.ivt_entry(ACCESS_VIOLATION) == 0 {
+            return Err(VmError::ProtectionFault {
+                addr,
+                pc,
+                protection,
+            });
+        }
+        self.enter_handler(ACCESS_VIOLATION, None)?;
+        Ok(false)
+    }
+
+    /// Fails if the protected ranges deny `access` to `addr` to an embedder, see
+    /// [`Vm::read_mem_range`].
+    fn check_host_access(&self, addr: u16, access: Access) -> Result<(), VmError> {
+        match self.denying_protection(addr, access) {
+            Some(protection) => Err(VmError::ProtectionFault {
+                addr,
+                pc: self.pc,
+                protection,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn denying_protection(&self, addr: u16, access: Access) -> Option<Protection> {
+        self.protections
+            .iter()
+            .find(|(range, protection)| range.contains(&addr) && protection.denies(access))
+            .map(|&(_, protection)| protection)
+    }
+
+    fn mark_register(&mut self, r: usize) {
+        if let Some(initialized) = &mut self.initialized {
+            initialized.set_register(r);
+        }
+    }
+
+    /// Warns about the registers `inst` at `pc` uses that were never written, see
+    /// [`Vm::enable_uninit_detection`].
+    fn check_register_reads(&mut self, pc: u16, inst: u16) {
+        let Some(initialized) = &mut self.initialized else {
+            return;
+        };
+
+        let used: &[usize] = match decode(inst) {
+            // AND R, R, #0 clears R whatever it held
+            Ok(Instruction::And {
+                src2: RegOrImm::Imm(0),
+                ..
+            }) => &[],
+            Ok(Instruction::Add { sr1, src2, .. } | Instruction::And { sr1, src2, .. }) => {
+                match src2 {
+                    RegOrImm::Imm(_) => &[sr1],
+                    RegOrImm::Reg(sr2) => &[sr1, sr2],
+                }
+            }
+            Ok(
+                Instruction::Not { sr: r, .. }
+                | Instruction::Ldr { base: r, .. }
+                | Instruction::Str { base: r, .. }
+                | Instruction::Jmp { base: r }
+                | Instruction::Jsrr { base: r },
+            ) => &[r],
+            Ok(Instruction::Trap { vector }) if self.trap_mode == TrapMode::Native => {
+                match vector as u16 {
+                    OUT | PUTS | PUTSP | PUTD => &[0],
+                    GETS => &[0, 1],
+                    GETENV if self.getenv => &[0, 1, 2],
+                    _ => &[],
+                }
+            }
+            _ => &[],
+        };
+
+        let mut warnings = Vec::new();
+        for &r in used {
+            if !initialized.register(r) && initialized.first_register_report(pc, r) {
+                warnings.push(Warning::UninitializedRegister { pc, reg: r as u8 });
+            }
+        }
+        for warning in warnings {
+            self.warn(warning);
+        }
+    }
+
+    /// Warns if the running instruction loads from `addr` before anything was stored
+    /// there. Device registers are never uninitialized.
+    fn check_memory_read(&mut self, addr: u16) {
+        if addr >= USER_SPACE.end || self.devices.get(addr).is_some() {
+            return;
+        }
+        let Some(initialized) = &mut self.initialized else {
+            return;
+        };
+
+        // the PC has already moved past the instruction
+        let pc = self.pc.wrapping_sub(1);
+        if !initialized.memory(addr) && initialized.first_memory_report(pc, addr) {
+            self.warn(Warning::UninitializedMemory { pc, addr });
+        }
+    }
+
+    /// Jumps to `target` for JSR and JSRR, saving the return address in R7.
+    fn call_subroutine(&mut self, target: u16) {
+        let ret = self.pc;
+        self.pc = target;
+        self.reg[7] = ret;
+        self.mark_register(7);
+        self.enter_call(CallKind::Subroutine, ret.wrapping_sub(1));
+    }
+
+    /// The value of the second operand of ADD and AND.
+    fn operand(&self, src2: RegOrImm) -> u16 {
+        match src2 {
+            RegOrImm::Reg(r) => self.reg[r],
+            RegOrImm::Imm(imm) => imm as u16,
+        }
+    }
+
+    /// Records the call made by the instruction at `caller`, which already jumped.
+    fn enter_call(&mut self, kind: CallKind, caller: u16) {
+        self.call_stack.push(Frame {
+            kind,
+            caller,
+            entry: self.pc,
+            ret: caller.wrapping_add(1),
+        });
+    }
+
+    /// Ends the innermost call, checking that the RET at `pc`, which already jumped, went
+    /// back to it.
+    fn check_return(&mut self, pc: u16) -> Result<(), VmError> {
+        let Some(frame) = self.call_stack.pop() else {
+            return Ok(());
+        };
+        let expected = frame.ret;
+        if self.r7_check == R7Check::Off || self.pc == expected {
+            return Ok(());
+        }
+
+        if self.r7_check == R7Check::Strict {
+            return Err(VmError::ReturnMismatch {
+                pc,
+                target: self.pc,
+                expected,
+            });
+        }
+        self.warn(Warning::ReturnMismatch {
+            pc,
+            target: self.pc,
+            expected,
+        });
+        Ok(())
+    }
+
+    /// Enters the handler for `vector` through the interrupt vector table, switching to
+    /// the supervisor stack and pushing the PSR and PC for RTI. Interrupts also raise the
+    /// priority level to `priority`.
+    fn enter_handler(&mut self, vector: u8, priority: Option<u8>) -> Result<(), VmError> {
+        let handler = self.ivt_entry(vector);
+        if handler == 0 {
+            return Err(VmError::UnhandledException {
+                vector,
+                pc: self.pc,
+            });
+        }
+
+        let psr = self.psr;
+        if psr.is_user() {
+            self.saved_usp = self.reg[6];
+            self.reg[6] = self.saved_ssp;
+            self.mark_register(6);
+        }
+
+        self.psr.set_privilege(Privilege::Supervisor);
+        if let Some(priority) = priority {
+            self.psr.set_priority(priority);
+        }
+
+        self.push(psr.bits());
+        self.push(self.pc);
+        let caller = self.pc;
+        self.pc = handler;
+        self.call_stack.push(Frame {
+            kind: CallKind::Handler(vector),
+            caller,
+            entry: handler,
+            ret: caller,
+        });
+
+        Ok(())
+    }
+
+    fn push(&mut self, val: u16) {
+        self.reg[6] = self.reg[6].wrapping_sub(1);
+        self.write_mem(self.reg[6], val);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let val = self.read_mem(self.reg[6]);
+        self.reg[6] = self.reg[6].wrapping_add(1);
+        val
+    }
+
+    /// Sets the condition codes from register `r`. Every instruction that writes a
+    /// register does this, so it also marks `r` as initialized.
+    pub(crate) fn set_cc(&mut self, r: usize) {
+        self.mark_register(r);
+        let reg = self.reg[r];
+        let cc = if reg == 0 {
+            Flag::Zero
+        } else if reg & (1 << 15) != 0 {
+            Flag::Neg
+        } else {
+            Flag::Pos
+        };
+
+        self.psr.set_cc(cc);
+    }
+}
+
+/// The keyboard and display of the main console at `addrs`.
+fn console_devices(addrs: ConsoleAddrs, io: &SharedIo) -> Vec<MappedDevice> {
+    let keyboard = Keyboard::new(addrs, io.clone());
+    let display = Display::new(addrs, io.clone());
+    vec![
+        MappedDevice {
+            name: None,
+            range: keyboard.range(),
+            device: Box::new(keyboard),
+        },
+        MappedDevice {
+            name: None,
+            range: display.range(),
+            device: Box::new(display),
+        },
+    ]
+}
+
+/// The call stack before an instruction that left it as `call_stack`, from its length and
+/// top before, since an instruction makes or ends at most one call.
+fn frames_before(call_stack: &[Frame], (len, top): (usize, Option<Frame>)) -> Cow<'_, [Frame]> {
+    if call_stack.len() == len && call_stack.last().copied() == top {
+        return Cow::Borrowed(call_stack);
+    }
+    let mut frames = call_stack[..len.saturating_sub(1).min(call_stack.len())].to_vec();
+    frames.extend(top);
+    Cow::Owned(frames)
+}
+
+pub const fn sign_ext(mut val: u16, bits: u16) -> u16 {
+    val &= (1 << bits) - 1;
+
+    if (val >> (bits - 1) & 1) != 0 {
+        val |= 0xFFFF << bits;
+    }
+
+    val
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Br = 0b0000,
     Add = 0b0001,
     Ld = 0b0010,
     St = 0b0011,
@@ -400,15 +2790,29 @@ enum Opcode {
 }
 
 #[derive(Debug)]
-struct OpcodeConvertErr;
+pub struct OpcodeConvertErr;
 impl TryFrom<u16> for Opcode {
     type Error = OpcodeConvertErr;
     fn try_from(val: u16) -> Result<Self, Self::Error> {
-        if val > Opcode::Trap as u16 {
-            return Err(OpcodeConvertErr);
-        }
-
-        Ok(unsafe { std::mem::transmute(val as u8) })
+        Ok(match val {
+            0b0000 => Opcode::Br,
+            0b0001 => Opcode::Add,
+            0b0010 => Opcode::Ld,
+            0b0011 => Opcode::St,
+            0b0100 => Opcode::Jsr,
+            0b0101 => Opcode::And,
+            0b0110 => Opcode::Ldr,
+            0b0111 => Opcode::Str,
+            0b1000 => Opcode::Rti,
+            0b1001 => Opcode::Not,
+            0b1010 => Opcode::Ldi,
+            0b1011 => Opcode::Sti,
+            0b1100 => Opcode::Jmp,
+            0b1101 => Opcode::Reserved,
+            0b1110 => Opcode::Lea,
+            0b1111 => Opcode::Trap,
+            _ => return Err(OpcodeConvertErr),
+        })
     }
 }
 
@@ -421,10 +2825,1241 @@ pub enum Flag {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        console::{EofMode, StreamIo, STATUS_READY},
+        env::DeterministicEnv,
+        memory::CowMemory,
+        testkit::vm_with_program,
+        trace::TraceReader,
+        util::SharedBuf,
+    };
 
     #[test]
     fn test_sign_ext() {
         assert_eq!(sign_ext(0b10011, 5), 0xfff3);
         assert_eq!(sign_ext(0x30, 5), 0xfff0);
     }
+
+    fn set_input(vm: &mut Vm, input: &'static [u8]) {
+        vm.set_io(Box::new(StreamIo::new(input, std::io::sink())));
+    }
+
+    #[test]
+    fn test_gets() {
+        let mut vm = vm_with_program(&[0xF027, 0xF025]);
+        set_input(&mut vm, b"ab\x7fcdef\n");
+        vm.reg[0] = 0x4000;
+        vm.reg[1] = 3;
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[1], 3);
+        assert_eq!(vm.string_at(0x4000), "acd");
+    }
+
+    #[test]
+    fn test_getd() {
+        let mut vm = vm_with_program(&[0xF029, 0xF025]);
+        set_input(&mut vm, b"-123\n");
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], -123i16 as u16);
+        assert_eq!(vm.reg[1], 0);
+        assert_eq!(vm.psr(), Flag::Neg as u16);
+
+        let mut vm = vm_with_program(&[0xF029, 0xF025]);
+        set_input(&mut vm, b"4x\n");
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[1], 0xFFFF);
+    }
+
+    #[test]
+    fn test_console_options() {
+        // IN; OUT; GETC; OUT; GETC; HALT
+        let program = [0xF023, 0xF021, 0xF020, 0xF021, 0xF020, 0xF025];
+        let out = SharedBuf::default();
+        let mut vm = vm_with_program(&program);
+        vm.set_io(Box::new(StreamIo::new(&b"a\n"[..], out.clone())));
+        vm.set_console_options(ConsoleOptions {
+            crlf: true,
+            eof: EofMode::Eot,
+        });
+
+        // IN echoes and GETC doesn't, and the second GETC gets EOT instead of stopping
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], EOT as u16);
+        assert_eq!(out.0.take(), b"Enter a character: aa\r\nHALT\r\n");
+
+        let mut vm = vm_with_program(&program);
+        set_input(&mut vm, b"a\n");
+        assert!(matches!(vm.run(), Err(VmError::InputExhausted { .. })));
+    }
+
+    #[test]
+    fn test_perf_counters() {
+        // LDI R0, INSTCNT_LO; LDI R1, INSTCNT_HI; LDI R2, CYCCNT_LO; HALT
+        let mut vm = vm_with_program(&[0xA003, 0xA203, 0xA403, 0xF025, 0xFE20, 0xFE22, 0xFE24]);
+        vm.instructions = 0x1_0000;
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 1);
+        assert_eq!(vm.reg[1], 1);
+        // each LDI costs 4 cycles: execute, fetch, pointer read and the final read,
+        // which is when the third one samples the counter
+        assert_eq!(vm.reg[2], 3 * 4);
+    }
+
+    #[test]
+    fn test_cycle_model() {
+        // LDI R0, RNG; HALT
+        let program = [0xA001, 0xF025, RNG];
+        let mut model = CycleModel {
+            memory_read: 2,
+            device_access: 10,
+            ..CycleModel::default()
+        };
+        model.set_opcode(Opcode::Ldi, 3);
+
+        let mut vm = vm_with_program(&program);
+        vm.set_cycle_model(model.clone());
+        vm.run().unwrap();
+        // LDI: fetch, execute, pointer, device register; HALT: fetch, execute
+        assert_eq!(vm.cycles(), 2 + 3 + 2 + (2 + 10) + 2 + 1);
+
+        let mut vm = vm_with_program(&program);
+        vm.set_cycle_model(model);
+        vm.set_cycle_limit(Some(5));
+        assert!(matches!(vm.run(), Err(VmError::CycleLimit(5))));
+        assert_eq!(vm.instructions(), 1);
+    }
+
+    #[test]
+    fn test_rng() {
+        // LDI R0, RNG; LDI R1, RNG; HALT
+        let program = [0xA002, 0xA201, 0xF025, RNG];
+        let mut vm = vm_with_program(&program);
+        vm.seed_rng(42);
+        vm.run().unwrap();
+        let (first, second) = (vm.reg[0], vm.reg[1]);
+        assert_ne!(first, second);
+
+        let mut vm = vm_with_program(&program);
+        vm.seed_rng(42);
+        vm.run().unwrap();
+        assert_eq!((vm.reg[0], vm.reg[1]), (first, second));
+
+        // writing the register seeds it too: AND R0, R0, #0; STI R0, RNG; LDI R1, RNG
+        let mut vm = vm_with_program(&[0x5020, 0xB002, 0xA201, 0xF025, RNG]);
+        vm.run().unwrap();
+        let mut other = vm_with_program(&[0xA201, 0xF025, RNG]);
+        other.seed_rng(0);
+        other.run().unwrap();
+        assert_eq!(vm.reg[1], other.reg[1]);
+    }
+
+    #[test]
+    fn test_host_env() {
+        // poll: LDI R0, KBSR; BRzp poll; LDI R1, KBDR; LDI R2, CLOCK_MS; LDI R3, RNG; HALT
+        let program = [
+            0xA005, 0x07FE, 0xA204, 0xA404, 0xA604, 0xF025, 0xFE00, 0xFE02, 0xFE28, RNG,
+        ];
+        let run = |engine| {
+            let mut vm = vm_with_program(&program);
+            set_input(&mut vm, b"a");
+            vm.set_engine(engine);
+            vm.set_host_env(Box::new(DeterministicEnv {
+                insts_per_ms: 1,
+                seed: 7,
+            }));
+            let trace = SharedBuf::default();
+            vm.set_trace(TraceWriter::new(Box::new(trace.clone())).unwrap());
+            vm.run().unwrap();
+            (vm.reg, trace.0.take())
+        };
+
+        let (reg, trace) = run(Engine::Simple);
+        // the key was ready at the first poll
+        assert_eq!(reg[1], b'a' as u16);
+        assert_eq!(reg[2], 4);
+        assert_eq!((reg, trace), run(Engine::Cached));
+    }
+
+    #[test]
+    fn test_trace_when_in() {
+        // JSR SUB; HALT
+        // SUB: ADD R1, R7, #0; JSR LEAF; ADD R7, R1, #0; RET
+        // LEAF: ADD R0, R0, #1; RET
+        let mut vm = vm_with_program(&[
+            0x4801, 0xF025, 0x13E0, 0x4802, 0x1E60, 0xC1C0, 0x1021, 0xC1C0,
+        ]);
+        let mut symbols = SymbolTable::default();
+        symbols.insert("SUB", 0x3002);
+        vm.add_symbols(&symbols);
+        let trace = SharedBuf::default();
+        vm.set_trace(TraceWriter::new(Box::new(trace.clone())).unwrap());
+        vm.set_trace_when(Predicate::parse("in SUB").unwrap());
+        vm.run().unwrap();
+
+        let trace = trace.0.take();
+        let pcs: Vec<_> = TraceReader::new(&trace[..])
+            .unwrap()
+            .map(|record| record.unwrap().pc)
+            .collect();
+        assert_eq!(pcs, [0x3002, 0x3003, 0x3006, 0x3007, 0x3004, 0x3005]);
+    }
+
+    #[test]
+    fn test_deterministic_clock() {
+        // LDI R0, CLOCK_MS; LDI R1, CLOCK_MS_HI; HALT
+        let mut vm = vm_with_program(&[0xA002, 0xA202, 0xF025, 0xFE28, 0xFE2A]);
+        vm.set_clock_mode(ClockMode::Deterministic { insts_per_ms: 10 });
+        vm.instructions = 25;
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 2);
+        assert_eq!(vm.reg[1], 0);
+
+        let mut vm = vm_with_program(&[0xA002, 0xA202, 0xF025, 0xFE28, 0xFE2A]);
+        vm.set_clock_mode(ClockMode::FROZEN);
+        vm.instructions = 0x1_0002 * 1000;
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 2);
+        assert_eq!(vm.reg[1], 1);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let mut a = vm_with_program(&[0x1021, 0xF025]);
+        let mut b = vm_with_program(&[0x1021, 0xF025]);
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        a.run().unwrap();
+        assert_ne!(a.state_hash(), b.state_hash());
+
+        b.run().unwrap();
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        b.memory[0x4000] = 1;
+        assert_ne!(a.state_hash(), b.state_hash());
+    }
+
+    #[test]
+    fn test_scheduler() {
+        use std::{cell::Cell, rc::Rc};
+
+        // ADD R0, R0, #1 six times; HALT
+        let mut vm = vm_with_program(&[0x1021; 6]);
+        vm.memory[0x3006] = 0xF025;
+
+        let ticks = Rc::new(Cell::new(Vec::new()));
+        let seen = ticks.clone();
+        vm.every(
+            3,
+            Box::new(move |vm| {
+                let mut v = seen.take();
+                v.push(vm.reg[0]);
+                seen.set(v);
+            }),
+        );
+
+        vm.run().unwrap();
+        assert_eq!(ticks.take(), [3, 6]);
+    }
+
+    #[test]
+    fn test_console_addrs() {
+        // LDI R0, KBSR; LDI R1, KBDR; STI R1, DDR; HALT
+        let mut vm = vm_with_program(&[0xA003, 0xA203, 0xB203, 0xF025, 0xFE10, 0xFE12, 0xFE16]);
+        vm.set_console_addrs(ConsoleAddrs::parse("xFE10,xFE12,xFE14,xFE16").unwrap());
+        set_input(&mut vm, b"k");
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], STATUS_READY);
+        assert_eq!(vm.reg[1], b'k' as u16);
+        // the old addresses are plain memory now
+        assert_eq!(vm.read_mem(0xFE00), 0);
+    }
+
+    #[test]
+    fn test_device_timing() {
+        let run = |program: &[u16]| {
+            let output = SharedBuf::default();
+            let mut vm = vm_with_program(program);
+            vm.set_io(Box::new(StreamIo::new(std::io::empty(), output.clone())));
+            vm.set_device_timing(DeviceTiming {
+                display_busy: 5,
+                key_delay: 0,
+            });
+            vm.run().unwrap();
+            String::from_utf8(output.0.take()).unwrap()
+        };
+
+        // LD R0, 'a'; STI R0, DDR; STI R0, DDR; HALT
+        let output = run(&[0x2003, 0xB003, 0xB002, 0xF025, 0x61, 0xFE06]);
+        // the second character was written while the display was busy
+        assert_eq!(output, "aHALT\n");
+
+        // the same, waiting for DSR before each write
+        let output = run(&[
+            0x2007, 0xA207, 0x07FE, 0xB006, 0xA204, 0x07FE, 0xB003, 0xF025, 0x61, 0xFE04, 0xFE06,
+        ]);
+        assert_eq!(output, "aaHALT\n");
+    }
+
+    #[test]
+    fn test_record_replay() {
+        // polls KBSR, counting the polls in R2, reads KBDR, then GETC
+        // ADD R2, R2, #1; LDI R1, KBSR; BRzp #-3; LDI R0, KBDR; GETC; HALT
+        let program = [
+            0x14A1, 0xA204, 0x07FD, 0xA003, 0xF020, 0xF025, 0xFE00, 0xFE02,
+        ];
+        let recording = SharedBuf::default();
+        let mut vm = vm_with_program(&program);
+        set_input(&mut vm, b"ab");
+        vm.record_input(Box::new(recording.clone()));
+        vm.run().unwrap();
+        let recording = String::from_utf8(recording.0.take()).unwrap();
+        assert_eq!(recording, "1 x61\n4 x62\n");
+
+        // keys show up at the recorded points, not as soon as they are polled for
+        let mut vm = vm_with_program(&program);
+        vm.replay_input("5 x61\n6 x62\n").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.reg[2], 3);
+        assert_eq!(vm.reg[0], b'b' as u16);
+    }
+
+    #[test]
+    fn test_r7_check() {
+        // JSR SUB; HALT; SUB: JSR SUB2; RET; SUB2: RET
+        let program = [0x4801, 0xF025, 0x4801, 0xC1C0, 0xC1C0];
+        let mut vm = vm_with_program(&program);
+        vm.set_r7_check(R7Check::Warn);
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        // SUB's RET went back to itself, R7 having been set by JSR SUB2
+        assert_eq!(
+            vm.take_warnings(),
+            [Warning::ReturnMismatch {
+                pc: 0x3003,
+                target: 0x3003,
+                expected: 0x3001
+            }]
+        );
+        assert!(vm.take_warnings().is_empty());
+
+        let mut vm = vm_with_program(&program);
+        vm.set_r7_check(R7Check::Strict);
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::ReturnMismatch {
+                pc: 0x3003,
+                target: 0x3003,
+                expected: 0x3001
+            })
+        ));
+
+        // a correct program doesn't warn
+        let mut vm = vm_with_program(&[0x4801, 0xF025, 0xC1C0]);
+        vm.set_r7_check(R7Check::Warn);
+        vm.run().unwrap();
+        assert!(vm.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_queue_input() {
+        // GETC; OUT; GETC; OUT; HALT
+        let mut vm = vm_with_program(&[0xF020, 0xF021, 0xF020, 0xF021, 0xF025]);
+        let out = SharedBuf::default();
+        vm.set_io(Box::new(StreamIo::new(&b"c"[..], out.clone())));
+        vm.queue_input(b"a");
+        vm.run_for(2).unwrap();
+        vm.queue_input(b"b");
+        vm.run().unwrap();
+        assert!(out.0.borrow().starts_with(b"ab"));
+    }
+
+    #[test]
+    fn test_run_with_cancel() {
+        // loop: ADD R0, R0, #1; BR loop
+        let mut vm = vm_with_program(&[0x1021, 0x0FFE]);
+        let cancel = std::sync::Arc::new(AtomicBool::new(true));
+        assert_eq!(vm.run_with_cancel(&cancel).unwrap(), RunResult::Stopped);
+        assert_eq!(vm.instructions(), 0);
+
+        cancel.store(false, Ordering::Relaxed);
+        let canceller = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        assert_eq!(vm.run_with_cancel(&cancel).unwrap(), RunResult::Stopped);
+        canceller.join().unwrap();
+        assert!(vm.instructions() > 0);
+        assert!(!vm.halted());
+    }
+
+    #[test]
+    fn test_strict() {
+        // ADD R0, R0, R0 with bit 3 set; HALT
+        let program = [0x1008, 0xF025];
+        let mut vm = vm_with_program(&program);
+        vm.run().unwrap();
+
+        let mut vm = vm_with_program(&program);
+        vm.set_strict(true);
+        let err = vm.run().unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::SpecViolation {
+                inst: 0x1008,
+                pc: 0x3000,
+                reason: "bits 4-3 must be zero"
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Instruction 0x1008 (ADD R0, R0, R0) at pc 0x3000 breaks the spec: bits 4-3 must \
+             be zero"
+        );
+    }
+
+    #[test]
+    fn test_smc_detection() {
+        // loop: ADD R0, R0, #1; ST R0, loop; ADD R1, R1, #1; ADD R2, R1, #-2; BRn loop;
+        // ST R0, #1; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x31FE, 0x1261, 0x147E, 0x09FB, 0x3001, 0xF025]);
+        vm.enable_smc_detection();
+        vm.run().unwrap();
+        // the second store targets a word that never executed, and the first warns once
+        assert_eq!(
+            vm.take_warnings(),
+            [Warning::SelfModifyingCode {
+                pc: 0x3001,
+                addr: 0x3000
+            }]
+        );
+    }
+
+    #[test]
+    fn test_uninit_detection() {
+        // ADD R0, R1, #1; LD R2, #2; ST R3, #-4; AND R4, R4, #0; HALT
+        let mut vm = Vm::default();
+        vm.enable_uninit_detection();
+        vm.load_image_bytes(&[
+            0x30, 0x00, 0x10, 0x61, 0x24, 0x02, 0x37, 0xFC, 0x59, 0x20, 0xF0, 0x25,
+        ])
+        .unwrap();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), std::io::sink())));
+        vm.run().unwrap();
+        // LD R2 reads x3004, the HALT, so only R1 is uninitialized; storing R3 and
+        // clearing R4 don't count as uses
+        assert_eq!(
+            vm.take_warnings(),
+            [Warning::UninitializedRegister { pc: 0x3000, reg: 1 }]
+        );
+
+        // loads x0000 twice from the same instruction, which warns once:
+        // AND R2, R2, #0; AND R1, R1, #0; LDR R0, R2, #0; ADD R1, R1, #1; ADD R3, R1, #-2;
+        // BRn #-4; HALT
+        let mut vm = vm_with_program(&[0x54A0, 0x5260, 0x6080, 0x1261, 0x167E, 0x09FC, 0xF025]);
+        vm.enable_uninit_detection();
+        vm.run().unwrap();
+        assert_eq!(vm.registers()[1], 2);
+        assert_eq!(
+            vm.take_warnings(),
+            [Warning::UninitializedMemory {
+                pc: 0x3002,
+                addr: 0x0000
+            }]
+        );
+    }
+
+    #[test]
+    fn test_state_json() {
+        // ADD R0, R0, #5; HALT
+        let mut vm = vm_with_program(&[0x1025, 0xF025]);
+        let digest = vm.memory_digest();
+        vm.run().unwrap();
+        assert_eq!(
+            vm.state_json(None, false),
+            r#"{"pc":12290,"psr":1,"registers":[5,0,0,0,0,0,0,12290],"instructions":2,"cycles":4,"halted":true,"error":null}"#
+        );
+        assert_eq!(vm.memory_digest(), digest);
+
+        let err = VmError::BadOpcode {
+            inst: 0xD000,
+            pc: 0x3000,
+        };
+        let json = vm.state_json(Some(&err), true);
+        assert!(
+            json.ends_with(&format!(
+                r#""error":"Bad opcode 0xd000 at pc 0x3000","memory_digest":"{digest:016x}"}}"#
+            )),
+            "{json}"
+        );
+    }
+
+    #[test]
+    fn test_kbdr_latch() {
+        // LDI R3, KBSR; LDI R0, KBDR; LDI R1, KBDR; LDI R2, KBSR; HALT
+        let mut vm = vm_with_program(&[0xA604, 0xA004, 0xA203, 0xA401, 0xF025, 0xFE00, 0xFE02]);
+        vm.replay_input("0 x61\n100 x62\n").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.reg[3], STATUS_READY);
+        // KBDR keeps the key, and reading it cleared KBSR
+        assert_eq!(vm.reg[0], b'a' as u16);
+        assert_eq!(vm.reg[1], b'a' as u16);
+        assert_eq!(vm.reg[2], 0);
+    }
+
+    #[test]
+    fn test_execute_word() {
+        // every word decodes and runs without panicking, from whatever state the
+        // previous ones left behind
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        let input = std::io::repeat(b'\n');
+        vm.set_io(Box::new(StreamIo::new(input, std::io::sink())));
+        for inst in 0..=u16::MAX {
+            vm.set_pc(0x3000);
+            let _ = vm.execute_word(inst);
+        }
+
+        // ADD R0, R0, #5
+        vm.set_pc(0x3000);
+        vm.set_register(0, 0);
+        assert_eq!(vm.execute_word(0x1025).unwrap(), RunResult::Running);
+        assert_eq!(vm.registers()[0], 5);
+    }
+
+    #[test]
+    fn test_cached_engine() {
+        // loop: ADD R0, R0, #1; ST R2, loop; ADD R1, R1, #-1; BRp loop; HALT
+        // where R2 is ADD R0, R0, #2, so the second pass runs the rewritten instruction
+        let program = [0x1021, 0x35FE, 0x127F, 0x03FC, 0xF025];
+        for engine in [Engine::Simple, Engine::Cached] {
+            let mut vm = vm_with_program(&program);
+            vm.set_engine(engine);
+            vm.set_register(1, 2);
+            vm.set_register(2, 0x1022);
+            vm.run().unwrap();
+            assert_eq!(vm.reg[0], 3, "{engine:?}");
+        }
+
+        // instructions from a device are fetched every time: ADD R0, R0, #1, then #2
+        struct Rom(u16);
+
+        impl Device for Rom {
+            fn read(&mut self, _addr: u16) -> u16 {
+                self.0 += 1;
+                0x1020 | self.0
+            }
+
+            fn write(&mut self, _addr: u16, _val: u16) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vm = vm_with_program(&[]);
+        vm.set_engine(Engine::Cached);
+        vm.map_device(0x4000..=0x4000, Box::new(Rom(0)));
+        for _ in 0..2 {
+            vm.set_pc(0x4000);
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.reg[0], 3);
+    }
+
+    #[test]
+    fn test_socket_io() {
+        use std::{
+            io::{Read, Write},
+            net::{Shutdown, TcpListener, TcpStream},
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(b"a").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        // GETC; OUT; HALT
+        let mut vm = vm_with_program(&[0xF020, 0xF021, 0xF025]);
+        vm.set_io(Box::new(crate::console::SocketIo::new(server).unwrap()));
+        vm.run().unwrap();
+        drop(vm);
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with('a'));
+    }
+
+    #[test]
+    fn test_map_device() {
+        // counts the reads of its first register and remembers the last store to the second
+        struct Counter {
+            reads: u16,
+            stored: u16,
+        }
+
+        impl Device for Counter {
+            fn read(&mut self, addr: u16) -> u16 {
+                if addr == 0xFE20 {
+                    self.reads += 1;
+                    self.reads
+                } else {
+                    self.stored
+                }
+            }
+
+            fn write(&mut self, _addr: u16, val: u16) -> std::io::Result<()> {
+                self.stored = val;
+                Ok(())
+            }
+        }
+
+        // LDI R0, xFE20; LDI R0, xFE20; STI R0, xFE21; LDI R1, xFE21; HALT
+        let mut vm = vm_with_program(&[0xA004, 0xA003, 0xB003, 0xA202, 0xF025, 0xFE20, 0xFE21]);
+        vm.map_device(
+            0xFE20..=0xFE21,
+            Box::new(Counter {
+                reads: 0,
+                stored: 0,
+            }),
+        );
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 2);
+        assert_eq!(vm.reg[1], 2);
+        assert_eq!(vm.memory[0xFE21], 0);
+    }
+
+    #[test]
+    fn test_rti_privilege() {
+        // supervisor code at x3000 drops to user mode at x4000 through RTI
+        let mut vm = vm_with_program(&[0x8000]);
+        vm.reg[6] = 0x2FFE;
+        vm.memory[0x2FFE] = 0x4000;
+        vm.memory[0x2FFF] = PSR_USER | Flag::Zero as u16;
+        vm.saved_usp = 0x5000;
+
+        // user code: ADD R0, R0, #-1; RTI
+        vm.memory[0x4000] = 0x103F;
+        vm.memory[0x4001] = 0x8000;
+        // the privilege violation handler: HALT
+        vm.memory[0x0100] = 0x1000;
+        vm.memory[0x1000] = 0xF025;
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x4000);
+        assert_eq!(vm.reg[6], 0x5000);
+
+        vm.step().unwrap();
+        assert_eq!(vm.psr(), PSR_USER | Flag::Neg as u16);
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x1000);
+        assert!(!vm.psr.is_user());
+        // back on the supervisor stack, with the user PSR and PC pushed
+        assert_eq!(vm.reg[6], 0x2FFE);
+        assert_eq!(vm.memory[0x2FFE], 0x4002);
+        assert_eq!(vm.memory[0x2FFF], PSR_USER | Flag::Neg as u16);
+        assert_eq!(vm.saved_usp, 0x5000);
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        // LD R0, IE; STI R0, KBSR; BR #-1 (spin); IE; KBSR
+        let mut vm = vm_with_program(&[0x2002, 0xB002, 0x0FFF, 0x4000, 0xFE00]);
+        set_input(&mut vm, b"a");
+        vm.reg[6] = 0x2000;
+
+        // the ISR: LDI R1, KBDR; HALT
+        vm.memory[0x0180] = 0x1000;
+        vm.memory[0x1000..0x1003].copy_from_slice(&[0xA201, 0xF025, 0xFE02]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[1], b'a' as u16);
+        assert_eq!(vm.psr.priority(), 4);
+        assert_eq!(vm.reg[6], 0x1FFE);
+        // interrupted before executing the spin loop at x3002
+        assert_eq!(vm.memory[0x1FFE], 0x3002);
+        assert_eq!(vm.memory[0x1FFF] & PSR_PRIORITY, 0);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        // LD R0, #10; STI R0, TMI; LD R0, IE; STI R0, TMR; ADD R2, R2, #1; BR #-2
+        let mut vm = vm_with_program(&[
+            0x2007, 0xB007, 0x2007, 0xB007, 0x14A1, 0x0FFE, 0, 0, 10, 0xFE0A, 0x4000, 0xFE08,
+        ]);
+        vm.reg[6] = 0x2000;
+
+        // the ISR: LDI R1, TMR; HALT
+        vm.memory[0x0181] = 0x1000;
+        vm.memory[0x1000..0x1003].copy_from_slice(&[0xA201, 0xF025, 0xFE08]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[1], STATUS_READY | 0x4000);
+        assert_eq!(vm.psr.priority(), 6);
+        // ten instructions after the timer was started
+        assert_eq!(vm.reg[2], 5);
+        assert_eq!(vm.memory[0x1FFE], 0x3005);
+    }
+
+    #[test]
+    fn test_save_state() {
+        // ADD R0, R0, #3; ST R0, #1; HALT
+        let mut vm = vm_with_program(&[0x1023, 0x3001, 0xF025]);
+        vm.step().unwrap();
+        let state = vm.save_state();
+        let cycles = vm.cycles();
+        vm.run().unwrap();
+        let halted = vm.state_hash();
+
+        // HALT
+        let mut restored = vm_with_program(&[0xF025]);
+        restored.enable_history(10);
+        restored.run().unwrap();
+        restored.restore_state(&state).unwrap();
+        assert!(!restored.halted());
+        assert_eq!(restored.pc(), 0x3001);
+        assert_eq!(restored.instructions(), 1);
+        assert_eq!(restored.cycles(), cycles);
+        assert!(!restored.step_back());
+        restored.run().unwrap();
+        assert_eq!(restored.state_hash(), halted);
+
+        assert!(restored.restore_state(&state[..100]).is_err());
+        assert!(restored.restore_state(b"LC3T\x01").is_err());
+    }
+
+    #[test]
+    fn test_read_images() {
+        let dir = std::env::temp_dir().join(format!("lc3-read-images-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let os = dir.join("os.obj");
+        let prog = dir.join("prog.obj");
+        let clash = dir.join("clash.obj");
+        std::fs::write(&os, [0x02, 0x00, 0x12, 0x34, 0x56, 0x78]).unwrap();
+        std::fs::write(&prog, [0x30, 0x00, 0xF0, 0x25]).unwrap();
+        std::fs::write(&clash, [0x02, 0x01, 0x00, 0x00]).unwrap();
+
+        let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+        vm.read_images(&[&os, &prog]).unwrap();
+        assert_eq!(vm.pc(), 0x3000);
+        assert_eq!(vm.memory()[0x0200..0x0202], [0x1234, 0x5678]);
+        assert_eq!(vm.memory()[0x3000], 0xF025);
+
+        let err = vm.read_images(&[&os, &clash]).unwrap_err().to_string();
+        assert!(err.contains("clash.obj (x0201-x0201) overlaps"), "{err}");
+        assert!(err.ends_with("os.obj (x0200-x0201)"), "{err}");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_mcr() {
+        // LDI R0, #4; AND R1, R1, #0; STI R1, #2; ADD R2, R2, #1; HALT; xFFFE
+        let mut vm = vm_with_program(&[0xA004, 0x5260, 0xB202, 0x14A1, 0xF025, MCR]);
+        vm.set_memory(MCR, 0x0123);
+
+        // the store stops the clock, and the program reads it as running
+        vm.run().unwrap();
+        assert!(vm.halted());
+        assert_eq!(vm.pc(), 0x3003);
+        assert_eq!(vm.registers()[0], MCR_CLOCK | 0x0123);
+        assert_eq!(vm.memory()[MCR as usize], 0);
+
+        // running again starts the clock, until the native HALT stops it
+        vm.set_memory(MCR, 0x0123);
+        vm.step().unwrap();
+        assert_eq!(vm.memory()[MCR as usize], MCR_CLOCK | 0x0123);
+        assert!(matches!(vm.step(), Ok(RunResult::Halted)));
+        assert_eq!(vm.registers()[2], 1);
+        assert_eq!(vm.memory()[MCR as usize], 0x0123);
+    }
+
+    #[test]
+    fn test_os_traps() {
+        let program = asm::assemble(
+            r#"
+                    .ORIG x3000
+                    LEA R0, MSG
+                    PUTS
+                    LEA R0, PACKED
+                    PUTSP
+                    GETC
+                    OUT
+                    IN
+                    ADD R2, R0, #0
+                    HALT
+            MSG     .STRINGZ "hi "
+            PACKED  .FILL x6261     ; "ab"
+                    .FILL x0063     ; "c"
+                    .FILL 0
+                    .END
+            "#,
+        )
+        .unwrap();
+
+        let run = |trap_mode| {
+            let output = SharedBuf::default();
+            let mut vm = Vm::new(0x3000, Flag::Zero as u16);
+            vm.load_os().unwrap();
+            vm.set_trap_mode(trap_mode);
+            vm.load_image_bytes(&program.image()).unwrap();
+            vm.set_io(Box::new(StreamIo::new(&b"xy"[..], output.clone())));
+            vm.reg[5] = 0x1234;
+
+            vm.run().unwrap();
+            let output = String::from_utf8(output.0.take()).unwrap();
+            (output, vm.reg)
+        };
+
+        let (native_output, native_reg) = run(TrapMode::Native);
+        let (os_output, os_reg) = run(TrapMode::Os);
+        assert_eq!(os_output, "hi abcxEnter a character: yHALT\n");
+        assert_eq!(native_output, "hi abcxEnter a character: yHALT\n");
+        // the OS HALT routine uses R0, R1 and R7 to stop the clock
+        assert_eq!(os_reg[2..7], native_reg[2..7]);
+        assert_eq!(os_reg[2], b'y' as u16);
+    }
+
+    #[test]
+    fn test_exceptions() {
+        // the reserved opcode, with and without a handler
+        let mut vm = vm_with_program(&[0xD000]);
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::BadOpcode {
+                inst: 0xD000,
+                pc: 0x3000
+            })
+        ));
+
+        let mut vm = vm_with_program(&[0xD000]);
+        vm.memory[0x0101] = 0x1000;
+        vm.reg[6] = 0x3000;
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x1000);
+        assert_eq!(vm.memory[0x2FFE], 0x3001);
+
+        // user mode: ST R0, #1 to x3002 is fine, LDR R0, R1, #0 from OS space is not
+        let mut vm = vm_with_program(&[0x3001, 0x6040]);
+        vm.memory[0x0102] = 0x1000;
+        vm.psr.set_privilege(Privilege::User);
+        vm.reg[0] = 7;
+        vm.reg[1] = 0x2000;
+        vm.step().unwrap();
+        assert_eq!(vm.memory[0x3002], 7);
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x1000);
+        assert!(!vm.psr.is_user());
+        assert_eq!(vm.reg[0], 7);
+        assert_eq!(vm.memory[0x2FFE], 0x3002);
+    }
+
+    #[test]
+    fn test_protect() {
+        // ST R0, #1 into a read-only word, without and with a handler
+        let mut vm = vm_with_program(&[0x3001]);
+        vm.protect(0x3002..=0x3002, Protection::ReadOnly);
+        let err = vm.step().unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::ProtectionFault {
+                addr: 0x3002,
+                pc: 0x3000,
+                protection: Protection::ReadOnly
+            }
+        ));
+        assert_eq!(err.pc(), Some(0x3000));
+
+        let mut vm = vm_with_program(&[0x3001]);
+        vm.protect(0x3002..=0x3002, Protection::ReadOnly);
+        vm.memory[0x0102] = 0x1000;
+        vm.reg[0] = 7;
+        vm.reg[6] = 0x3000;
+        vm.step().unwrap();
+        assert_eq!(vm.memory[0x3002], 0);
+        assert_eq!(vm.pc, 0x1000);
+
+        // reading is fine, executing is not
+        let mut vm = vm_with_program(&[0x2000, 0x1021]);
+        vm.protect(0x3000..=0x3000, Protection::NoExecute);
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::ProtectionFault {
+                addr: 0x3000,
+                pc: 0x3000,
+                protection: Protection::NoExecute
+            })
+        ));
+        vm.clear_protections();
+        vm.protect(0x3001..=0x3001, Protection::NoExecute);
+        vm.step().unwrap();
+        assert_eq!(vm.reg[0], 0x1021);
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_mem_range() {
+        let mut vm = vm_with_program(&[0x1021, 0x1021]);
+        vm.seed_rng(1);
+        let words = vm.read_mem_range(RNG - 1, 2).unwrap();
+        vm.seed_rng(1);
+        assert_eq!(words[1], vm.read_mem_range(RNG, 1).unwrap()[0]);
+        assert_eq!(vm.cycles(), 0);
+
+        vm.write_mem_range(0x4000, &[1, 2, 3]).unwrap();
+        assert_eq!(vm.read_mem_range(0x3FFF, 5).unwrap(), [0, 1, 2, 3, 0]);
+        // the timer's interval register
+        vm.write_mem_range(TMI, &[5]).unwrap();
+        assert_eq!(vm.memory[TMI as usize], 0);
+        assert_eq!(vm.read_mem_range(TMI, 1).unwrap(), [5]);
+
+        vm.protect(0x4001..=0x4001, Protection::ReadOnly);
+        assert!(matches!(
+            vm.write_mem_range(0x4000, &[7, 7]),
+            Err(VmError::ProtectionFault { addr: 0x4001, .. })
+        ));
+        assert_eq!(vm.memory[0x4000], 1);
+        vm.protect(0x4002..=0x4002, Protection::NoAccess);
+        assert!(vm.read_mem_range(0x4000, 2).is_ok());
+        assert!(vm.read_mem_range(0x4000, 3).is_err());
+    }
+
+    #[test]
+    fn test_set_speed() {
+        // BR #-1, an endless loop
+        let mut vm = vm_with_program(&[0x0FFF]);
+        vm.set_speed(Some(1000));
+        let start = std::time::Instant::now();
+        vm.run_for(100).unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(90));
+
+        vm.set_speed(None);
+        let start = std::time::Instant::now();
+        vm.run_for(100_000).unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_for() {
+        // BR #-1, an endless loop
+        let mut vm = vm_with_program(&[0x0FFF]);
+        assert_eq!(vm.run_for(100).unwrap(), RunResult::Running);
+        assert_eq!(vm.instructions(), 100);
+
+        let mut vm = vm_with_program(&[0xF025]);
+        assert_eq!(vm.run_for(100).unwrap(), RunResult::Halted);
+    }
+
+    #[test]
+    fn test_memory_bus() {
+        // ADD R0, R0, #1; ST R0, x3003; HALT
+        let loaded = vm_with_program(&[0x1021, 0x3001, 0xF025]);
+        let memory = CowMemory::new(loaded.memory().to_vec());
+
+        let mut a = vm_with_program(&[]);
+        a.set_memory_bus(Box::new(memory.clone()));
+        a.run().unwrap();
+        assert_eq!(a.memory()[0x3003], 1);
+        // the other fork still has the original
+        assert_eq!(memory.words()[0x3003], 0);
+
+        // a short memory faults past its end
+        let mut b = vm_with_program(&[]);
+        b.set_memory_bus(Box::new(memory.words()[..0x3003].to_vec()));
+        assert!(matches!(
+            b.run(),
+            Err(VmError::MemoryFault { addr: 0x3003, .. })
+        ));
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        // LDI R0, #0; xFFFF, which is past the end of a short memory
+        let mut vm = vm_with_program(&[0xA000, 0xFFFF]);
+        let mut memory = vm.memory().to_vec();
+        memory.truncate(0xFFFF);
+        vm.set_memory_bus(Box::new(memory));
+        let err = vm.step().unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::MemoryFault {
+                addr: 0xFFFF,
+                pc: 0x3000
+            }
+        ));
+        assert_eq!(err.pc(), Some(0x3000));
+
+        let mut vm = Vm::default();
+        assert!(vm.load_image_bytes(&[0xFF, 0xFF, 0x12, 0x34]).is_ok());
+        assert_eq!(vm.memory()[0xFFFF], 0x1234);
+        assert!(vm
+            .load_image_bytes(&[0xFF, 0xFF, 0x12, 0x34, 0x56, 0x78])
+            .is_err());
+    }
+
+    #[test]
+    fn test_memory_edges() {
+        assert_eq!(Vm::default().memory().len(), MEMORY_SIZE);
+
+        // every LDR/STR offset from bases at both ends of memory, wrapping around
+        let mut vm = vm_with_program(&[]);
+        for base in (0xFFE0..=0xFFFF).chain(0x0000..=0x001F) {
+            for offset in -32..=31 {
+                let addr = u16::wrapping_add_signed(base, offset);
+                // bit 15 keeps the clock running when the store hits the MCR
+                let val = 0x8000 | addr;
+                vm.set_register(0, val);
+                vm.set_register(1, base);
+                let offset = offset as u16 & 0x3F;
+
+                // STR R0, R1, offset; LDR R2, R1, offset
+                vm.execute_word(0x7040 | offset).unwrap();
+                vm.execute_word(0x6440 | offset).unwrap();
+                assert_eq!(vm.memory()[addr as usize], val);
+                assert_eq!(vm.registers()[2], val);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        // LD R0, #1; PUTS or PUTSP; xFFFD, in memory without a single x0000
+        for trap in [0xF022, 0xF024] {
+            let mut vm = vm_with_program(&[]);
+            vm.memory.words_mut().fill(b'a' as u16);
+            vm.memory[0x3000..0x3003].copy_from_slice(&[0x2001, trap, 0xFFFD]);
+            vm.step().unwrap();
+            assert!(matches!(
+                vm.step(),
+                Err(VmError::MemoryFault {
+                    addr: 0xFFFD,
+                    pc: 0x3001
+                })
+            ));
+            assert_eq!(vm.string_at(0xFFFD).chars().count(), MEMORY_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_string_helpers() {
+        // LEA R0, #4; PUTS; LEA R0, #6; PUTSP; HALT
+        let mut vm = vm_with_program(&[0xE004, 0xF022, 0xE006, 0xF024, 0xF025]);
+        vm.write_string(0x3005, "hi ").unwrap();
+        vm.write_packed_string(0x3009, "there").unwrap();
+        assert_eq!(vm.memory[0x3008], 0);
+        assert_eq!(vm.memory[0x3009..0x300D], [0x6874, 0x7265, 0x0065, 0]);
+        assert_eq!(vm.read_string(0x3005).unwrap(), "hi ");
+        assert_eq!(vm.read_packed_string(0x3009).unwrap(), "there");
+
+        let out = SharedBuf::default();
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), out.clone())));
+        vm.run().unwrap();
+        assert!(out.0.borrow().starts_with(b"hi there"));
+
+        vm.memory.words_mut().fill(1);
+        assert!(matches!(
+            vm.read_string(0x3000),
+            Err(VmError::MemoryFault { addr: 0x3000, .. })
+        ));
+    }
+
+    #[test]
+    fn test_step_hooks() {
+        use std::{cell::Cell, rc::Rc};
+
+        // ADD R0, R0, #1 three times; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x1021, 0x1021, 0xF025]);
+        let executed = Rc::new(Cell::new(0));
+
+        vm.set_pre_step_hook(|vm, _| match vm.pc() {
+            0x3001 => HookAction::SkipInstruction,
+            _ => HookAction::Continue,
+        });
+        let counter = executed.clone();
+        vm.set_post_step_hook(move |vm, inst| {
+            assert_eq!(inst, 0x1021);
+            counter.set(counter.get() + 1);
+            match vm.registers()[0] {
+                2 => HookAction::Stop,
+                _ => HookAction::Continue,
+            }
+        });
+
+        vm.run().unwrap();
+        assert_eq!(vm.pc(), 0x3003);
+        assert_eq!(vm.registers()[0], 2);
+        assert_eq!(executed.get(), 2);
+
+        vm.clear_step_hooks();
+        vm.run().unwrap();
+        assert!(vm.halted());
+    }
+
+    #[test]
+    fn test_step_back() {
+        // ADD R0, R0, #1; ST R0, #1; HALT; x1234
+        let mut vm = vm_with_program(&[0x1021, 0x3001, 0xF025, 0x1234]);
+        vm.enable_history(2);
+
+        vm.run().unwrap();
+        assert_eq!(vm.memory[0x3003], 1);
+        assert_eq!(vm.history_len(), 2);
+
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.pc(), 0x3001);
+        assert_eq!(vm.memory[0x3003], 0x1234);
+
+        vm.enable_history(10);
+        vm.run().unwrap();
+        vm.add_breakpoint(0x3001);
+        assert_eq!(vm.reverse_resume(), Some(0x3001));
+        assert_eq!(vm.memory[0x3003], 0x1234);
+        assert_eq!(vm.instructions(), 1);
+        assert!(!vm.halted());
+        assert_eq!(vm.reverse_resume(), None);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // ADD R0, R0, #5; ST R0, #2; LD R1, #1; HALT
+        let mut vm = vm_with_program(&[0x1025, 0x3002, 0x2201, 0xF025, 0x0000]);
+        vm.add_watchpoint(0x3004, WatchKind::Write);
+
+        assert_eq!(
+            vm.resume().unwrap(),
+            RunResult::Watchpoint {
+                addr: 0x3004,
+                old: 0,
+                new: 5
+            }
+        );
+        assert_eq!(vm.pc(), 0x3002);
+        // the read is not watched
+        assert_eq!(vm.resume().unwrap(), RunResult::Halted);
+
+        let mut vm = vm_with_program(&[0x1025, 0x3002, 0x2201, 0xF025, 0x0000]);
+        vm.add_watchpoint(0x3004, WatchKind::Read);
+        assert!(matches!(
+            vm.resume().unwrap(),
+            RunResult::Watchpoint { new: 5, .. }
+        ));
+        assert_eq!(vm.pc(), 0x3003);
+
+        assert!(vm.remove_watchpoint(0x3004));
+        assert!(!vm.remove_watchpoint(0x3004));
+    }
+
+    #[test]
+    fn test_getenv() {
+        std::env::set_var("LC3_VM_TEST_GETENV", "hello");
+
+        let mut vm = vm_with_program(&[0xF026, 0xF025]);
+        vm.enable_getenv(true);
+        vm.write_string(0x4000, "LC3_VM_TEST_GETENV").unwrap();
+        vm.reg[0] = 0x4000;
+        vm.reg[1] = 0x5000;
+        vm.reg[2] = 4;
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 3);
+        assert_eq!(vm.string_at(0x5000), "hel");
+
+        // room for three bytes, but the second character takes two
+        std::env::set_var("LC3_VM_TEST_GETENV", "h\u{e9}llo");
+        let mut vm = vm_with_program(&[0xF026, 0xF025]);
+        vm.enable_getenv(true);
+        vm.write_string(0x4000, "LC3_VM_TEST_GETENV").unwrap();
+        vm.reg[0] = 0x4000;
+        vm.reg[1] = 0x5000;
+        vm.reg[2] = 3;
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 1);
+        assert_eq!(vm.memory[0x5000..0x5002], [b'h' as u16, 0]);
+    }
+
+    #[test]
+    fn test_unknown_trap_error() {
+        let mut vm = vm_with_program(&[0xF030]);
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::BadTrap {
+                trap: 0x30,
+                pc: 0x3000
+            })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_trap_vector() {
+        // TRAP x30 jumps to x4000, which copies R7 into R1 and halts
+        let mut vm = vm_with_program(&[0xF030]);
+        vm.memory[0x30] = 0x4000;
+        vm.memory[0x4000] = 0x13E0;
+        vm.memory[0x4001] = 0xF025;
+        vm.set_unknown_trap(UnknownTrap::Vector);
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[1], 0x3001);
+    }
+
+    #[test]
+    fn test_unknown_trap_handler() {
+        let mut vm = vm_with_program(&[0xF030, 0xF025]);
+        vm.set_unknown_trap(UnknownTrap::Handler(Box::new(|vm: &mut Vm, vector| {
+            vm.reg[0] = vector as u16;
+            Ok(())
+        })));
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg[0], 0x30);
+    }
+
+    #[test]
+    fn test_register_trap() {
+        // TRAP x30; OUT; TRAP x31
+        let output = SharedBuf::default();
+        let mut vm = vm_with_program(&[0xF030, 0xF021, 0xF031]);
+        vm.set_io(Box::new(StreamIo::new(std::io::empty(), output.clone())));
+        vm.register_trap(0x30, |vm: &mut Vm, _| {
+            vm.set_register(0, b'!' as u16);
+            Ok(())
+        });
+        // overrides the native OUT
+        vm.register_trap(0x21, |_: &mut Vm, _| Ok(()));
+        vm.register_trap(0x31, |vm: &mut Vm, vector| {
+            Err(VmError::BadTrap {
+                trap: vector as u16,
+                pc: vm.pc().wrapping_sub(1),
+            })
+        });
+
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::BadTrap {
+                trap: 0x31,
+                pc: 0x3002
+            })
+        ));
+        assert_eq!(vm.reg[0], b'!' as u16);
+        assert!(output.0.borrow().is_empty());
+
+        assert!(vm.unregister_trap(0x21));
+        assert!(!vm.unregister_trap(0x21));
+    }
 }